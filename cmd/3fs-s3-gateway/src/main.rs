@@ -1,11 +1,153 @@
-use threefs_gateway::{config::GatewayConfig, run_server};
+use std::sync::Arc;
+use threefs_gateway::{changes, compression::{self, BucketCompression}, config::{Durability, GatewayConfig}, keyring, run_server, storage::{self, dedup, durability, external, fsck, index, pack, posix, StorageBackend}};
 use tracing_subscriber::{fmt, EnvFilter};
 
+/// The storage the server would use, with bucket configuration changes published to the
+/// running gateways.
+async fn open_storage(cfg: &GatewayConfig) -> anyhow::Result<Arc<dyn StorageBackend>> {
+    let storage = storage::open(cfg).await?;
+    Ok(match changes::open(cfg) {
+        Some(feed) => changes::wrap(feed, storage),
+        None => storage,
+    })
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     fmt().with_env_filter(filter).json().init();
     let cfg = GatewayConfig::from_env()?;
-    run_server(cfg).await
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] | ["serve"] => run_server(cfg).await,
+        ["index", "rebuild", bucket] => {
+            let n = index::rebuild(&cfg, bucket)?;
+            println!("indexed {n} objects in {bucket}");
+            Ok(())
+        }
+        ["index", "check", bucket] => {
+            let problems = index::check(&cfg, bucket)?;
+            for p in &problems { println!("{}", serde_json::to_string(p)?); }
+            if !problems.is_empty() { anyhow::bail!("{} inconsistencies in {bucket}; run `index rebuild {bucket}` to fix", problems.len()); }
+            Ok(())
+        }
+        ["fsck", rest @ ..] if rest.len() <= 2 => {
+            let repair = rest.contains(&"--repair");
+            let bucket = rest.iter().copied().find(|a| *a != "--repair");
+            if rest.len() == 2 && (!repair || bucket.is_none()) { anyhow::bail!("usage: 3fs-s3-gateway fsck [<bucket>] [--repair]"); }
+            let report = fsck::run(&cfg, open_storage(&cfg).await?.as_ref(), bucket, repair).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            let left = report.unrepaired();
+            if left > 0 { anyhow::bail!("{left} problems left{}", if repair { "" } else { "; run with --repair to fix them" }); }
+            Ok(())
+        }
+        ["metadata", "migrate", bucket] => {
+            let n = posix::migrate_metadata(&cfg, open_storage(&cfg).await?.as_ref(), bucket).await?;
+            println!("moved metadata of {n} objects in {bucket} to {:?} storage", cfg.metadata_store);
+            Ok(())
+        }
+        ["bucket", "register", bucket, path, rest @ ..] if rest.is_empty() || rest == ["--read-only"] => {
+            external::register(&cfg, bucket, path, !rest.is_empty())?;
+            println!("registered {path} as {bucket}");
+            Ok(())
+        }
+        ["bucket", "unregister", bucket] => {
+            external::unregister(&cfg, bucket)?;
+            println!("unregistered {bucket}");
+            Ok(())
+        }
+        ["bucket", "durability", bucket] => {
+            let storage = open_storage(&cfg).await?;
+            println!("{}", serde_json::to_string(&durability::for_bucket(&cfg, storage.as_ref(), bucket).await)?);
+            Ok(())
+        }
+        ["bucket", "durability", bucket, mode] => {
+            let mode = match *mode {
+                "default" => None,
+                m => Some(Durability::parse(m).ok_or_else(|| anyhow::anyhow!("unknown durability {m}; use none, data, data+dir or default"))?),
+            };
+            let storage = open_storage(&cfg).await?;
+            durability::set(storage.as_ref(), bucket, mode).await?;
+            println!("{bucket} now uses {}", serde_json::to_string(&durability::for_bucket(&cfg, storage.as_ref(), bucket).await)?);
+            Ok(())
+        }
+        ["bucket", "compression", bucket] => {
+            match compression::get(open_storage(&cfg).await?.as_ref(), bucket).await? {
+                Some(config) => println!("{}", serde_json::to_string(&config)?),
+                None => println!("off"),
+            }
+            Ok(())
+        }
+        ["bucket", "compression", bucket, "off"] => {
+            compression::set(open_storage(&cfg).await?.as_ref(), bucket, None).await?;
+            println!("{bucket} no longer compresses new objects");
+            Ok(())
+        }
+        ["bucket", "compression", bucket, rest @ ..] => {
+            let mut config = BucketCompression { content_types: Vec::new(), prefixes: Vec::new(), level: 3 };
+            for pair in rest.chunks(2) {
+                match pair {
+                    ["--content-type", t] => config.content_types.push(t.to_string()),
+                    ["--prefix", p] => config.prefixes.push(p.to_string()),
+                    ["--level", n] => config.level = n.parse().map_err(|_| anyhow::anyhow!("invalid zstd level {n}"))?,
+                    _ => anyhow::bail!("usage: 3fs-s3-gateway bucket compression <bucket> [off | [--content-type <type>]... [--prefix <prefix>]... [--level <n>]]"),
+                }
+            }
+            anyhow::ensure!(!config.content_types.is_empty() || !config.prefixes.is_empty(), "give at least one --content-type or --prefix");
+            compression::set(open_storage(&cfg).await?.as_ref(), bucket, Some(&config)).await?;
+            println!("{bucket} now compresses {}", serde_json::to_string(&config)?);
+            Ok(())
+        }
+        ["bucket", "pack", bucket] => {
+            match pack::get(open_storage(&cfg).await?.as_ref(), bucket).await? {
+                Some(config) => println!("{}", serde_json::to_string(&config)?),
+                None => println!("off"),
+            }
+            Ok(())
+        }
+        ["bucket", "pack", bucket, "off"] => {
+            pack::set(&cfg, open_storage(&cfg).await?.as_ref(), bucket, None).await?;
+            println!("{bucket} no longer packs new objects");
+            Ok(())
+        }
+        ["bucket", "pack", bucket, max] => {
+            let max_bytes = match *max {
+                "on" => pack::DEFAULT_MAX_BYTES,
+                n => n.parse().map_err(|_| anyhow::anyhow!("invalid size {n}; give a number of bytes, on or off"))?,
+            };
+            pack::set(&cfg, open_storage(&cfg).await?.as_ref(), bucket, Some(max_bytes)).await?;
+            println!("{bucket} now packs new objects of up to {max_bytes} bytes");
+            Ok(())
+        }
+        ["bucket", "list"] => {
+            for (bucket, reg) in external::list(&cfg)? {
+                println!("{}", serde_json::json!({ "bucket": bucket, "path": reg.path, "read_only": reg.read_only }));
+            }
+            Ok(())
+        }
+        ["dedup", "stats"] => {
+            println!("{}", serde_json::to_string_pretty(&dedup::scan(&cfg, false)?)?);
+            Ok(())
+        }
+        ["dedup", "gc"] => {
+            println!("{}", serde_json::to_string_pretty(&dedup::scan(&cfg, true)?)?);
+            Ok(())
+        }
+        ["pack", "compact", bucket] => {
+            println!("{}", serde_json::to_string_pretty(&pack::compact(&cfg, open_storage(&cfg).await?.as_ref(), bucket).await?)?);
+            Ok(())
+        }
+        ["keyring", "rotate", key_id] => {
+            let version = keyring::rotate(&cfg, key_id)?;
+            println!("{key_id} is now at version {version}");
+            Ok(())
+        }
+        ["keyring", "list"] => {
+            for (key_id, versions) in keyring::list(&cfg)? {
+                println!("{}", serde_json::json!({ "key_id": key_id, "versions": versions }));
+            }
+            Ok(())
+        }
+        _ => anyhow::bail!("usage: 3fs-s3-gateway [serve | index rebuild <bucket> | index check <bucket> | bucket register <bucket> <path> [--read-only] | bucket unregister <bucket> | bucket list | bucket durability <bucket> [none|data|data+dir|default] | bucket compression <bucket> [off | --content-type <type>... --prefix <prefix>... --level <n>] | bucket pack <bucket> [<max-bytes>|on|off] | metadata migrate <bucket> | fsck [<bucket>] [--repair] | keyring rotate <key-id> | keyring list | dedup stats | dedup gc | pack compact <bucket>]"),
+    }
 }
-
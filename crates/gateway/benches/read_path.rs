@@ -0,0 +1,58 @@
+//! GET throughput of the io_uring data path against the `ReaderStream` path it replaces.
+//!
+//! Reads a 256 MiB file under `BENCH_DIR` (default: the system temp dir). Point `BENCH_DIR` at a
+//! 3FS mount to measure FUSE rather than the page cache:
+//!
+//! ```bash
+//! BENCH_DIR=/var/lib/3fs/mnt/stage/.tmp cargo bench -p threefs-gateway --features io-uring
+//! ```
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::StreamExt;
+use std::io::Write;
+use std::path::PathBuf;
+use threefs_gateway::storage::{uring, ByteStream};
+
+const FILE_SIZE: usize = 256 << 20;
+
+async fn drain(mut body: ByteStream) -> usize {
+    let mut n = 0;
+    while let Some(chunk) = body.next().await {
+        n += chunk.expect("read failed").len();
+    }
+    n
+}
+
+fn bench_file() -> PathBuf {
+    let dir = std::env::var_os("BENCH_DIR").map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    let path = dir.join(format!("read-path-bench-{}", std::process::id()));
+    let mut f = std::fs::File::create(&path).expect("create bench file");
+    let block: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    for _ in 0..FILE_SIZE / block.len() {
+        f.write_all(&block).expect("write bench file");
+    }
+    path
+}
+
+fn read_path(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().expect("runtime");
+    let path = bench_file();
+    let ring = uring::open().expect("io_uring unavailable");
+
+    let mut group = c.benchmark_group("get");
+    group.sample_size(10).throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.bench_function("reader_stream", |b| b.iter(|| rt.block_on(async {
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        assert_eq!(drain(Box::pin(tokio_util::io::ReaderStream::new(file))).await, FILE_SIZE);
+    })));
+    group.bench_function("io_uring", |b| b.iter(|| rt.block_on(async {
+        let file = std::fs::File::open(&path).unwrap();
+        let body = ring.register(&file).unwrap().read_stream(0, FILE_SIZE as u64);
+        assert_eq!(drain(body).await, FILE_SIZE);
+    })));
+    group.finish();
+    let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, read_path);
+criterion_main!(benches);
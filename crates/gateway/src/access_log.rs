@@ -0,0 +1,366 @@
+use crate::config::GatewayConfig;
+use crate::s3::models::{BucketLoggingStatus, LoggingEnabled};
+use crate::storage::{self, ObjectAttrs, StorageBackend};
+use axum::{body::Body, extract::{ConnectInfo, Request, State}, http::{header, HeaderValue}, middleware::Next, response::Response};
+use dashmap::DashMap;
+use futures::StreamExt;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::time::{sleep, Duration};
+use tracing::warn;
+
+pub const CONFIG_NAME: &str = "logging";
+
+// How long a bucket's logging configuration is trusted before it is re-read from 3FS
+const CONFIG_TTL: Duration = Duration::from_secs(10);
+// Flush early once a target has this many buffered lines
+const MAX_BUFFERED_LINES: usize = 10_000;
+
+/// Collects S3 server access log records and writes them, batched, as objects into each
+/// source bucket's configured target bucket and prefix.
+#[derive(Clone)]
+pub struct AccessLogger { inner: Arc<Inner> }
+
+struct Inner {
+    cfg: GatewayConfig,
+    storage: Arc<dyn StorageBackend>,
+    configs: DashMap<String, (Option<LoggingEnabled>, Instant)>,
+    buffers: Mutex<HashMap<LoggingEnabled, Vec<String>>>,
+}
+
+impl AccessLogger {
+    pub fn new(cfg: GatewayConfig, storage: Arc<dyn StorageBackend>) -> Self {
+        Self { inner: Arc::new(Inner { cfg, storage, configs: DashMap::new(), buffers: Mutex::new(HashMap::new()) }) }
+    }
+
+    pub fn spawn_flusher(&self) {
+        let logger = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(logger.inner.cfg.access_log_flush_secs.max(1))).await;
+                logger.flush().await;
+            }
+        });
+    }
+
+    /// Drops the cached configuration so the next request re-reads it.
+    pub fn invalidate(&self, bucket: &str) {
+        self.inner.configs.remove(bucket);
+    }
+
+    /// Drops every cached configuration.
+    pub fn invalidate_all(&self) {
+        self.inner.configs.clear();
+    }
+
+    async fn target_for(&self, bucket: &str) -> Option<LoggingEnabled> {
+        if let Some(e) = self.inner.configs.get(bucket) {
+            if e.1.elapsed() < CONFIG_TTL { return e.0.clone(); }
+        }
+        let target = match storage::read_bucket_config::<BucketLoggingStatus>(self.inner.storage.as_ref(), bucket, CONFIG_NAME).await {
+            Ok(status) => status.and_then(|s| s.LoggingEnabled),
+            Err(e) => { warn!(%bucket, error = %e, "failed to load logging config"); None }
+        };
+        self.inner.configs.insert(bucket.to_string(), (target.clone(), Instant::now()));
+        target
+    }
+
+    fn push(&self, target: LoggingEnabled, line: String) {
+        let full = {
+            let mut buffers = self.inner.buffers.lock();
+            let buf = buffers.entry(target).or_default();
+            buf.push(line);
+            buf.len() >= MAX_BUFFERED_LINES
+        };
+        if full {
+            let logger = self.clone();
+            tokio::spawn(async move { logger.flush().await });
+        }
+    }
+
+    /// Writes every buffered batch as one log object per target.
+    pub async fn flush(&self) {
+        let batches = std::mem::take(&mut *self.inner.buffers.lock());
+        for (target, lines) in batches {
+            if lines.is_empty() { continue; }
+            let now = chrono::Utc::now();
+            let key = format!("{}{}-{:016X}", target.TargetPrefix, now.format("%Y-%m-%d-%H-%M-%S"), rand::random::<u64>());
+            let mut body = lines.join("\n");
+            body.push('\n');
+            let attrs = ObjectAttrs { content_type: "text/plain".into(), ..Default::default() };
+            if let Err(e) = self.inner.storage.put_object(&target.TargetBucket, &key, storage::bytes_stream(body), attrs).await {
+                warn!(bucket = %target.TargetBucket, %key, error = %e, "failed to write access log object");
+            }
+        }
+    }
+}
+
+/// Fields known when the response headers are produced; the rest are filled in when the
+/// response body has been fully sent (or the client went away).
+struct PendingRecord {
+    logger: AccessLogger,
+    target: LoggingEnabled,
+    bucket: String,
+    key: String,
+    time: chrono::DateTime<chrono::Utc>,
+    remote_ip: String,
+    requester: String,
+    request_id: String,
+    operation: String,
+    request_line: String,
+    status: u16,
+    object_size: String,
+    referer: String,
+    user_agent: String,
+    host: String,
+    start: Instant,
+    turn_around_ms: u128,
+    bytes_sent: u64,
+}
+
+impl PendingRecord {
+    fn sent(&mut self, n: usize) { self.bytes_sent += n as u64; }
+}
+
+impl Drop for PendingRecord {
+    fn drop(&mut self) {
+        let dash = |s: &str| if s.is_empty() { "-".to_string() } else { s.to_string() };
+        let bytes = if self.bytes_sent == 0 { "-".to_string() } else { self.bytes_sent.to_string() };
+        let line = format!(
+            "{owner} {bucket} [{time}] {ip} {requester} {rid} {op} {key} \"{req}\" {status} - {bytes} {size} {total} {tat} \"{referer}\" \"{ua}\" - - SigV4 - AuthHeader {host} - - -",
+            owner = self.logger.inner.cfg.access_key,
+            bucket = self.bucket,
+            time = self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            ip = dash(&self.remote_ip),
+            requester = dash(&self.requester),
+            rid = self.request_id,
+            op = self.operation,
+            key = dash(&self.key),
+            req = self.request_line,
+            status = self.status,
+            size = self.object_size,
+            total = self.start.elapsed().as_millis(),
+            tat = self.turn_around_ms,
+            referer = dash(&self.referer),
+            ua = dash(&self.user_agent),
+            host = dash(&self.host),
+        );
+        self.logger.push(self.target.clone(), line);
+    }
+}
+
+fn header_str(req: &Request, name: header::HeaderName) -> String {
+    req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string()
+}
+
+fn requester(req: &Request) -> String {
+    let authz = header_str(req, header::AUTHORIZATION);
+    if let Some(cred) = authz.split("Credential=").nth(1) { return cred.split('/').next().unwrap_or_default().to_string(); }
+    let query = req.uri().query().unwrap_or_default();
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == "X-Amz-Credential")
+        .and_then(|(_, v)| v.split('/').next().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// S3 operation name, e.g. `REST.GET.OBJECT` or `REST.PUT.NOTIFICATION`.
+fn operation(req: &Request, has_key: bool) -> String {
+    let method = req.method().as_str();
+    let query = req.uri().query().unwrap_or_default();
+    let subresource = form_urlencoded::parse(query.as_bytes()).map(|(k, _)| k.into_owned()).find(|k| {
+        matches!(k.as_str(), "location" | "notification" | "logging" | "uploads" | "uploadId" | "tagging" | "acl" | "cors" | "policy")
+    });
+    let resource = match subresource.as_deref() {
+        Some("logging") => "LOGGING_STATUS".to_string(),
+        // Parts are uploaded with PUT; the other requests naming an upload act on all of it
+        Some("uploadId") if method == "PUT" => "PART".into(),
+        Some("uploadId") => "UPLOAD".into(),
+        Some(s) => s.to_ascii_uppercase(),
+        None if req.headers().contains_key("x-amz-copy-source") && method == "PUT" => return "REST.COPY.OBJECT".into(),
+        None if has_key => "OBJECT".into(),
+        None => "BUCKET".into(),
+    };
+    format!("REST.{method}.{resource}")
+}
+
+/// Where a request came from: the id it is answered with in `x-amz-request-id` and the client
+/// address. [`access_log`] puts it in the request's extensions for the handlers.
+#[derive(Debug, Clone, Default)]
+pub struct RequestOrigin {
+    pub request_id: String,
+    pub remote_ip: String,
+}
+
+/// Middleware assigning every request an `x-amz-request-id` and recording it in the access
+/// log of its bucket when logging is enabled there.
+pub async fn access_log(State(logger): State<AccessLogger>, mut req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let time = chrono::Utc::now();
+    let request_id = format!("{:016X}", rand::random::<u64>());
+    // The key stays URL-encoded, as in S3's own access logs, so records remain space-separated
+    let path = req.uri().path().trim_start_matches('/');
+    let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+    let bucket = percent_encoding::percent_decode_str(bucket).decode_utf8_lossy().into_owned();
+    let key = key.to_string();
+    let internal = matches!(bucket.as_str(), "" | "healthz" | "readyz" | "metrics");
+    let target = if internal { None } else { logger.target_for(&bucket).await };
+    let remote_ip = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|c| c.0.ip().to_string()).unwrap_or_default();
+    req.extensions_mut().insert(RequestOrigin { request_id: request_id.clone(), remote_ip: remote_ip.clone() });
+
+    let pending = target.map(|target| PendingRecord {
+        logger: logger.clone(),
+        target,
+        bucket: bucket.clone(),
+        operation: operation(&req, !key.is_empty()),
+        key,
+        time,
+        remote_ip,
+        requester: requester(&req),
+        request_id: request_id.clone(),
+        request_line: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+        status: 0,
+        object_size: "-".into(),
+        referer: header_str(&req, header::REFERER),
+        user_agent: header_str(&req, header::USER_AGENT),
+        host: header_str(&req, header::HOST),
+        start,
+        turn_around_ms: 0,
+        bytes_sent: 0,
+    });
+
+    let mut resp = next.run(req).await;
+    if let Ok(v) = HeaderValue::from_str(&request_id) { resp.headers_mut().insert("x-amz-request-id", v); }
+    let Some(mut pending) = pending else { return resp };
+
+    pending.status = resp.status().as_u16();
+    pending.turn_around_ms = start.elapsed().as_millis();
+    if let Some(len) = resp.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()) { pending.object_size = len.to_string(); }
+    let (parts, body) = resp.into_parts();
+    // The record is emitted when the body stream is dropped, so bytes and total time cover the transfer
+    let stream = body.into_data_stream().map(move |chunk| {
+        if let Ok(c) = &chunk { pending.sent(c.len()); }
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryBackend;
+    use crate::{build_router, AppState};
+    use tower::ServiceExt;
+
+    fn request(method: &str, uri: &str) -> Request {
+        Request::builder().method(method).uri(uri).body(Body::empty()).unwrap()
+    }
+
+    fn target(bucket: &str, prefix: &str) -> LoggingEnabled {
+        LoggingEnabled { TargetBucket: bucket.into(), TargetPrefix: prefix.into() }
+    }
+
+    async fn logs(storage: &dyn StorageBackend, bucket: &str) -> Vec<(String, String)> {
+        let page = storage.list_objects(bucket, "", None, "", 1000).await.unwrap();
+        let mut out = Vec::new();
+        for o in page.objects {
+            let mut body = storage.get_object(bucket, &o.key, None).await.unwrap().body;
+            let mut text = Vec::new();
+            while let Some(chunk) = body.next().await { text.extend_from_slice(&chunk.unwrap()); }
+            out.push((o.key, String::from_utf8(text).unwrap()));
+        }
+        out
+    }
+
+    #[test]
+    fn operations_are_named_as_s3_names_them() {
+        let op = |method: &str, uri: &str| {
+            let req = request(method, uri);
+            let has_key = uri.trim_start_matches('/').split('?').next().unwrap().contains('/');
+            operation(&req, has_key)
+        };
+        assert_eq!(op("GET", "/bkt/key"), "REST.GET.OBJECT");
+        assert_eq!(op("HEAD", "/bkt/key"), "REST.HEAD.OBJECT");
+        assert_eq!(op("GET", "/bkt?list-type=2"), "REST.GET.BUCKET");
+        assert_eq!(op("PUT", "/bkt?logging"), "REST.PUT.LOGGING_STATUS");
+        assert_eq!(op("GET", "/bkt?notification"), "REST.GET.NOTIFICATION");
+        assert_eq!(op("POST", "/bkt/key?uploads"), "REST.POST.UPLOADS");
+        assert_eq!(op("PUT", "/bkt/key?partNumber=1&uploadId=u"), "REST.PUT.PART");
+        assert_eq!(op("POST", "/bkt/key?uploadId=u"), "REST.POST.UPLOAD");
+        assert_eq!(op("DELETE", "/bkt/key?uploadId=u"), "REST.DELETE.UPLOAD");
+        let mut copy = request("PUT", "/bkt/key");
+        copy.headers_mut().insert("x-amz-copy-source", HeaderValue::from_static("/bkt/src"));
+        assert_eq!(operation(&copy, true), "REST.COPY.OBJECT");
+    }
+
+    #[tokio::test]
+    async fn records_follow_the_s3_format() {
+        let storage = Arc::new(MemoryBackend::new());
+        let state = AppState::new(GatewayConfig::in_memory(), prometheus::Registry::new(), storage.clone());
+        let logger = state.access_log.clone();
+        for bucket in ["src", "logs"] { storage.create_bucket(bucket).await.unwrap(); }
+        let status = BucketLoggingStatus { LoggingEnabled: Some(target("logs", "access/")) };
+        storage::write_bucket_config(storage.as_ref(), "src", CONFIG_NAME, Some(&status)).await.unwrap();
+
+        let mut put = Request::builder().method("PUT").uri("/src/dir/a%20b.txt").header(header::USER_AGENT, "tester/1.0").header(header::HOST, "s3.local");
+        put = put.header(header::AUTHORIZATION, "AWS4-HMAC-SHA256 Credential=AKID/20240101/us-east-1/s3/aws4_request, SignedHeaders=host, Signature=00");
+        let resp = build_router(state).oneshot(put.body(Body::from("hello")).unwrap()).await.unwrap();
+        let request_id = resp.headers()["x-amz-request-id"].to_str().unwrap().to_string();
+        // The record is complete once the response body is gone
+        drop(resp);
+        logger.flush().await;
+
+        let logs = logs(storage.as_ref(), "logs").await;
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].0.starts_with("access/"));
+        let line = logs[0].1.trim_end();
+        let (head, quoted) = line.split_once(" \"").unwrap();
+        let fields: Vec<&str> = head.split(' ').collect();
+        let cfg = GatewayConfig::in_memory();
+        assert_eq!(fields[0], cfg.access_key);
+        assert_eq!(fields[1], "src");
+        assert!(fields[2].starts_with('[') && fields[3].ends_with(']'));
+        // No connection info without a socket; the signer's access key is the requester
+        assert_eq!(&fields[4..], ["-", "AKID", request_id.as_str(), "REST.PUT.OBJECT", "dir/a%20b.txt"]);
+        assert!(quoted.starts_with("PUT /src/dir/a%20b.txt HTTP/1.1\" 200 - - - "));
+        assert!(quoted.contains(" \"-\" \"tester/1.0\" - - SigV4 - AuthHeader s3.local - - -"));
+    }
+
+    #[tokio::test]
+    async fn records_are_written_in_one_object_per_target_and_flush() {
+        let storage = Arc::new(MemoryBackend::new());
+        for bucket in ["logs", "other"] { storage.create_bucket(bucket).await.unwrap(); }
+        let logger = AccessLogger::new(GatewayConfig::in_memory(), storage.clone());
+        logger.push(target("logs", "a/"), "one".into());
+        logger.push(target("other", ""), "elsewhere".into());
+        logger.push(target("logs", "a/"), "two".into());
+        logger.push(target("logs", "b/"), "three".into());
+        logger.flush().await;
+
+        let written = logs(storage.as_ref(), "logs").await;
+        assert_eq!(written.len(), 2);
+        assert!(written[0].0.starts_with("a/") && written[0].1 == "one\ntwo\n");
+        assert!(written[1].0.starts_with("b/") && written[1].1 == "three\n");
+        assert_eq!(logs(storage.as_ref(), "other").await[0].1, "elsewhere\n");
+        // Nothing is written twice
+        logger.flush().await;
+        assert_eq!(logs(storage.as_ref(), "logs").await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn full_buffers_are_flushed_without_waiting() {
+        let storage = Arc::new(MemoryBackend::new());
+        storage.create_bucket("logs").await.unwrap();
+        let logger = AccessLogger::new(GatewayConfig::in_memory(), storage.clone());
+        for i in 0..MAX_BUFFERED_LINES { logger.push(target("logs", ""), i.to_string()); }
+        for _ in 0..100 {
+            if !logs(storage.as_ref(), "logs").await.is_empty() { break; }
+            sleep(Duration::from_millis(10)).await;
+        }
+        let written = logs(storage.as_ref(), "logs").await;
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].1.lines().count(), MAX_BUFFERED_LINES);
+    }
+}
@@ -0,0 +1,338 @@
+use crate::config::{GatewayConfig, StorageKind};
+use crate::storage::{AttrsUpdate, BucketInfo, ByteRange, ByteStream, DeferredEtag, GetObject, ListPage, ObjectAttrs, ObjectMeta, PartInfo, StorageBackend, StorageResult};
+use async_trait::async_trait;
+use fs_err as fs;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Start a new segment past this size
+const SEGMENT_MAX_BYTES: u64 = 16 << 20;
+// A segment untouched for this long belongs to a pod that rotated or died and is removed
+const SEGMENT_IDLE: Duration = Duration::from_secs(600);
+
+/// Something another pod may have cached that changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Change {
+    Object { bucket: String, key: String },
+    /// The bucket was created or deleted
+    Bucket { bucket: String },
+    BucketConfig { bucket: String, name: String },
+    /// This pod may have missed changes, so everything cached must go
+    #[serde(skip)]
+    Reset,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    ts: i64,
+    #[serde(flatten)]
+    change: Change,
+}
+
+struct Writer {
+    file: std::fs::File,
+    len: u64,
+    last_append: Instant,
+}
+
+#[derive(Default)]
+struct Tail {
+    /// Bytes consumed per other pod's segment
+    offsets: HashMap<String, u64>,
+    last_ok: Option<Instant>,
+}
+
+type Subscriber = Box<dyn Fn(&Change) + Send + Sync>;
+
+/// Cross-pod cache invalidation through a change journal on the 3FS mount.
+///
+/// Every gateway process appends a JSON line to its own segment under `.changes/` after each
+/// write it serves, before answering the client, and tails the other processes' segments every
+/// `CHANGE_FEED_POLL_MS`. A write acknowledged by one pod is therefore dropped from every other
+/// pod's caches within one poll interval. Whenever a pod cannot vouch for having seen every
+/// record (the journal was unreadable, a segment shrank underneath it, or the tailer fell
+/// behind) it resets its caches instead.
+pub struct ChangeFeed {
+    /// Prefix of this feed's own segments, unique per process
+    id: String,
+    dir: PathBuf,
+    poll: Duration,
+    writer: Mutex<Option<Writer>>,
+    seq: Mutex<u64>,
+    tail: Mutex<Tail>,
+    subscribers: RwLock<Vec<Subscriber>>,
+}
+
+/// The change feed, or `None` when `CHANGE_FEED` is off or nothing is shared between pods.
+pub fn open(cfg: &GatewayConfig) -> Option<Arc<ChangeFeed>> {
+    if !cfg.change_feed || cfg.storage_backend != StorageKind::Posix { return None; }
+    let dir = Path::new(&cfg.mountpoint).join(".changes");
+    let feed = Arc::new(ChangeFeed {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        dir,
+        poll: Duration::from_millis(cfg.change_feed_poll_ms.max(10)),
+        writer: Mutex::new(None),
+        seq: Mutex::new(0),
+        tail: Mutex::new(Tail::default()),
+        subscribers: RwLock::new(Vec::new()),
+    });
+    // Records written before this process started concern caches it does not have yet
+    feed.skip_existing();
+    info!(dir = %feed.dir.display(), poll_ms = feed.poll.as_millis() as u64, "change feed enabled");
+    Some(feed)
+}
+
+impl ChangeFeed {
+    /// Calls `f` for every change made by another pod, and with [`Change::Reset`].
+    pub fn subscribe(&self, f: impl Fn(&Change) + Send + Sync + 'static) {
+        self.subscribers.write().push(Box::new(f));
+    }
+
+    fn dispatch(&self, change: &Change) {
+        for s in self.subscribers.read().iter() { s(change); }
+    }
+
+    /// Records a change made through this pod.
+    pub async fn publish(self: &Arc<Self>, change: Change) {
+        let feed = self.clone();
+        let result = tokio::task::spawn_blocking(move || feed.append(&Record { ts: chrono::Utc::now().timestamp_millis(), change }))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r);
+        if let Err(e) = result { warn!(error = %e, "failed to append to change journal; other pods see this change only when their caches expire"); }
+    }
+
+    fn append(&self, rec: &Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(rec)?;
+        line.push(b'\n');
+        let mut writer = self.writer.lock();
+        // Move on before the segment looks idle to pruners
+        let rotate = writer.as_ref().is_none_or(|w| w.len >= SEGMENT_MAX_BYTES || w.last_append.elapsed() > SEGMENT_IDLE / 2);
+        if rotate {
+            let name = {
+                let mut seq = self.seq.lock();
+                *seq += 1;
+                format!("{}-{:06}.log", self.id, *seq)
+            };
+            fs::create_dir_all(&self.dir)?;
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(self.dir.join(&name))?;
+            *writer = Some(Writer { file, len: 0, last_append: Instant::now() });
+        }
+        let w = writer.as_mut().expect("active segment");
+        w.file.write_all(&line)?;
+        w.len += line.len() as u64;
+        w.last_append = Instant::now();
+        Ok(())
+    }
+
+    fn is_own(&self, name: &str) -> bool {
+        name.starts_with(self.id.as_str())
+    }
+
+    fn skip_existing(&self) {
+        let mut tail = self.tail.lock();
+        if let Ok(rd) = fs::read_dir(&self.dir) {
+            for e in rd.flatten() {
+                let name = e.file_name().to_string_lossy().into_owned();
+                if let Ok(md) = e.metadata() { tail.offsets.insert(name, md.len()); }
+            }
+        }
+        tail.last_ok = Some(Instant::now());
+    }
+
+    /// Tails the journal every poll interval and hands other pods' changes to subscribers.
+    pub fn spawn_tailer(self: &Arc<Self>) {
+        let feed = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(feed.poll).await;
+                let f = feed.clone();
+                match tokio::task::spawn_blocking(move || f.poll_once()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => { warn!(error = %e, "failed to read change journal; resetting caches"); feed.dispatch(&Change::Reset); }
+                    Err(e) => warn!(error = %e, "change journal tailer panicked"),
+                }
+            }
+        });
+    }
+
+    fn poll_once(&self) -> anyhow::Result<()> {
+        let mut tail = self.tail.lock();
+        let mut reset = tail.last_ok.is_none_or(|t| t.elapsed() > self.poll * 4);
+        let mut seen = HashMap::new();
+        let mut changes = Vec::new();
+        let rd = match fs::read_dir(&self.dir) {
+            Ok(rd) => rd,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => { tail.offsets.clear(); tail.last_ok = Some(Instant::now()); return Ok(()); }
+            Err(e) => return Err(e.into()),
+        };
+        for e in rd {
+            let e = e?;
+            let name = e.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".log") { continue; }
+            let md = e.metadata()?;
+            let idle = md.modified().ok().and_then(|t| t.elapsed().ok()).is_some_and(|age| age > SEGMENT_IDLE);
+            if self.is_own(&name) {
+                if idle { let _ = fs::remove_file(e.path()); } else { seen.insert(name, md.len()); }
+                continue;
+            }
+            let mut offset = tail.offsets.get(&name).copied().unwrap_or(0);
+            if md.len() < offset {
+                // Truncated or replaced underneath us
+                reset = true;
+                offset = 0;
+            }
+            let consumed = if md.len() > offset { read_records(&e.path(), offset, &mut changes)? } else { 0 };
+            if idle { let _ = fs::remove_file(e.path()); } else { seen.insert(name, offset + consumed); }
+        }
+        tail.offsets = seen;
+        tail.last_ok = Some(Instant::now());
+        drop(tail);
+        if reset {
+            self.dispatch(&Change::Reset);
+        } else {
+            for c in &changes { self.dispatch(c); }
+        }
+        Ok(())
+    }
+}
+
+/// Appends the complete records from `offset` on to `out` and returns how many bytes they span.
+fn read_records(path: &Path, offset: u64, out: &mut Vec<Change>) -> anyhow::Result<u64> {
+    let mut f = fs::File::open(path)?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    // A partially written last line is picked up once it is complete
+    let Some(end) = buf.iter().rposition(|b| *b == b'\n') else { return Ok(0) };
+    for line in buf[..end].split(|b| *b == b'\n') {
+        match serde_json::from_slice::<Record>(line) {
+            Ok(r) => out.push(r.change),
+            Err(e) => warn!(segment = %path.display(), error = %e, "skipping corrupt change record"),
+        }
+    }
+    Ok(end as u64 + 1)
+}
+
+/// Publishes every write made through `inner` to the change feed.
+pub fn wrap(feed: Arc<ChangeFeed>, inner: Arc<dyn StorageBackend>) -> Arc<dyn StorageBackend> {
+    Arc::new(PublishingBackend { feed, inner })
+}
+
+struct PublishingBackend {
+    feed: Arc<ChangeFeed>,
+    inner: Arc<dyn StorageBackend>,
+}
+
+impl PublishingBackend {
+    async fn object_changed<T>(&self, bucket: &str, key: &str, result: StorageResult<T>) -> StorageResult<T> {
+        self.feed.publish(Change::Object { bucket: bucket.to_string(), key: key.to_string() }).await;
+        result
+    }
+}
+
+#[async_trait]
+impl StorageBackend for PublishingBackend {
+    async fn is_ready(&self) -> bool {
+        self.inner.is_ready().await
+    }
+
+    async fn list_buckets(&self) -> StorageResult<Vec<BucketInfo>> {
+        self.inner.list_buckets().await
+    }
+
+    async fn head_bucket(&self, bucket: &str) -> StorageResult<()> {
+        self.inner.head_bucket(bucket).await
+    }
+
+    async fn create_bucket(&self, bucket: &str) -> StorageResult<()> {
+        self.inner.create_bucket(bucket).await?;
+        self.feed.publish(Change::Bucket { bucket: bucket.to_string() }).await;
+        Ok(())
+    }
+
+    async fn delete_bucket(&self, bucket: &str) -> StorageResult<()> {
+        self.inner.delete_bucket(bucket).await?;
+        self.feed.publish(Change::Bucket { bucket: bucket.to_string() }).await;
+        Ok(())
+    }
+
+    async fn get_bucket_config(&self, bucket: &str, name: &str) -> StorageResult<Option<Vec<u8>>> {
+        self.inner.get_bucket_config(bucket, name).await
+    }
+
+    async fn put_bucket_config(&self, bucket: &str, name: &str, value: Option<Vec<u8>>) -> StorageResult<()> {
+        self.inner.put_bucket_config(bucket, name, value).await?;
+        self.feed.publish(Change::BucketConfig { bucket: bucket.to_string(), name: name.to_string() }).await;
+        Ok(())
+    }
+
+    async fn put_object(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        let result = self.inner.put_object(bucket, key, body, attrs).await;
+        self.object_changed(bucket, key, result).await
+    }
+
+    async fn put_object_with_etag(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs, etag: DeferredEtag) -> StorageResult<ObjectMeta> {
+        let result = self.inner.put_object_with_etag(bucket, key, body, attrs, etag).await;
+        self.object_changed(bucket, key, result).await
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str, range: Option<ByteRange>) -> StorageResult<GetObject> {
+        self.inner.get_object(bucket, key, range).await
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> StorageResult<ObjectMeta> {
+        self.inner.head_object(bucket, key).await
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> StorageResult<bool> {
+        let result = self.inner.delete_object(bucket, key).await;
+        self.object_changed(bucket, key, result).await
+    }
+
+    async fn copy_object(&self, src_bucket: &str, src_key: &str, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        let result = self.inner.copy_object(src_bucket, src_key, bucket, key, attrs).await;
+        self.object_changed(bucket, key, result).await
+    }
+
+    async fn update_attrs(&self, bucket: &str, key: &str, f: AttrsUpdate) -> StorageResult<()> {
+        let result = self.inner.update_attrs(bucket, key, f).await;
+        self.object_changed(bucket, key, result).await
+    }
+
+    async fn list_objects(&self, bucket: &str, prefix: &str, delimiter: Option<&str>, marker: &str, max_keys: usize) -> StorageResult<ListPage> {
+        self.inner.list_objects(bucket, prefix, delimiter, marker, max_keys).await
+    }
+
+    async fn create_multipart_upload(&self, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<String> {
+        self.inner.create_multipart_upload(bucket, key, attrs).await
+    }
+
+    async fn upload_attrs(&self, bucket: &str, upload_id: &str) -> StorageResult<ObjectAttrs> {
+        self.inner.upload_attrs(bucket, upload_id).await
+    }
+
+    async fn upload_part(&self, bucket: &str, upload_id: &str, part_number: u32, body: ByteStream) -> StorageResult<String> {
+        self.inner.upload_part(bucket, upload_id, part_number, body).await
+    }
+
+    async fn list_parts(&self, bucket: &str, upload_id: &str) -> StorageResult<Vec<PartInfo>> {
+        self.inner.list_parts(bucket, upload_id).await
+    }
+
+    async fn complete_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str, parts: &[(u32, String)], update: Option<AttrsUpdate>) -> StorageResult<ObjectMeta> {
+        let result = self.inner.complete_multipart_upload(bucket, key, upload_id, parts, update).await;
+        self.object_changed(bucket, key, result).await
+    }
+
+    async fn abort_multipart_upload(&self, bucket: &str, upload_id: &str) -> StorageResult<()> {
+        self.inner.abort_multipart_upload(bucket, upload_id).await
+    }
+}
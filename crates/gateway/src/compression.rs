@@ -0,0 +1,346 @@
+//! Transparent zstd compression of object data at rest.
+//!
+//! A bucket's `compression` configuration, set with `3fs-s3-gateway bucket compression`, names
+//! the content types and key prefixes whose objects are compressed when written. Data is stored
+//! in the zstd seekable format: independent frames of [`FRAME`] uncompressed bytes followed by a
+//! seek table in a skippable frame, so the file stays a valid zstd stream and a range GET only
+//! decompresses the frames it covers. Clients see the original size and, except for SSE-C
+//! objects, the MD5 of the original content as ETag. Compression is applied before encryption.
+
+use crate::sse::{self, Secret};
+use crate::storage::{self, ByteRange, ByteStream, DeferredEtag, ObjectMeta, StorageBackend, StorageError, StorageResult};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+pub const CONFIG_NAME: &str = "compression";
+/// Uncompressed bytes per frame of newly written objects
+pub const FRAME: u64 = 1 << 20;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+const FOOTER: usize = 9;
+
+/// Contents of the `compression` bucket configuration. An object is compressed if its content
+/// type or its key matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BucketCompression {
+    /// Content types such as `application/json`; `text/*` matches every subtype
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content_types: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prefixes: Vec<String>,
+    /// zstd level
+    #[serde(default = "default_level")]
+    pub level: i32,
+}
+
+fn default_level() -> i32 {
+    3
+}
+
+impl BucketCompression {
+    pub fn matches(&self, key: &str, content_type: &str) -> bool {
+        let ct = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        self.prefixes.iter().any(|p| key.starts_with(p.as_str())) || self.content_types.iter().any(|t| {
+            let t = t.to_ascii_lowercase();
+            match t.strip_suffix("/*") {
+                Some(major) => ct.split_once('/').is_some_and(|(m, _)| m == major),
+                None => ct == t,
+            }
+        })
+    }
+}
+
+/// How an object's data is compressed, kept with its metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compression {
+    /// Size of the uncompressed content
+    pub size: u64,
+    /// Uncompressed bytes in every frame but the last
+    pub frame: u64,
+}
+
+impl Compression {
+    fn frames(&self) -> usize {
+        self.size.div_ceil(self.frame) as usize
+    }
+
+    /// Length of the seek table frame closing the compressed data
+    fn table_len(&self) -> u64 {
+        (8 + self.frames() * 8 + FOOTER) as u64
+    }
+}
+
+/// The compression configuration of `bucket`, if it has one.
+pub async fn get(storage: &dyn StorageBackend, bucket: &str) -> anyhow::Result<Option<BucketCompression>> {
+    storage::read_bucket_config(storage, bucket, CONFIG_NAME).await
+}
+
+/// Sets the compression configuration of `bucket`, or turns compression off with `None`.
+/// Objects already written keep the form they were written in.
+pub async fn set(storage: &dyn StorageBackend, bucket: &str, config: Option<&BucketCompression>) -> anyhow::Result<()> {
+    if let Some(config) = config {
+        anyhow::ensure!((zstd::zstd_safe::min_c_level()..=zstd::zstd_safe::max_c_level()).contains(&config.level), "invalid zstd level {}", config.level);
+    }
+    storage::set_bucket_setting(storage, bucket, CONFIG_NAME, config).await
+}
+
+/// The zstd level to write a new object at, if `bucket` compresses it.
+pub async fn level_for(storage: &dyn StorageBackend, bucket: &str, key: &str, content_type: &str) -> Option<i32> {
+    storage::bucket_setting::<BucketCompression>(storage, bucket, CONFIG_NAME).await
+        .filter(|c| c.matches(key, content_type))
+        .map(|c| c.level)
+}
+
+/// Compresses `body`, which must be exactly `size` bytes, into seekable frames, and sets `etag`
+/// to the quoted MD5 of the uncompressed bytes once they all went by.
+pub fn compress(body: ByteStream, level: i32, size: u64, etag: DeferredEtag) -> ByteStream {
+    struct Compressor { inner: futures::stream::Fuse<ByteStream>, level: i32, buf: BytesMut, md5: md5::Context, seen: u64, size: u64, table: Vec<u8>, frames: u32, etag: DeferredEtag, done: bool }
+    let compressor = Compressor { inner: body.fuse(), level, buf: BytesMut::new(), md5: md5::Context::new(), seen: 0, size, table: Vec::new(), frames: 0, etag, done: false };
+    Box::pin(futures::stream::unfold(compressor, |mut s| async move {
+        if s.done { return None; }
+        while (s.buf.len() as u64) < FRAME {
+            match s.inner.next().await {
+                Some(Ok(b)) => {
+                    s.md5.consume(&b);
+                    s.seen += b.len() as u64;
+                    s.buf.extend_from_slice(&b);
+                }
+                Some(Err(e)) => {
+                    s.done = true;
+                    return Some((Err(e), s));
+                }
+                None => break,
+            }
+        }
+        let complete = s.buf.is_empty();
+        if s.seen > s.size || (complete && s.seen < s.size) {
+            s.done = true;
+            let e = std::io::Error::new(std::io::ErrorKind::InvalidData, format!("body is {} bytes, not the {} announced", s.seen, s.size));
+            return Some((Err(e), s));
+        }
+        if complete {
+            s.done = true;
+            let _ = s.etag.set(format!("\"{:x}\"", std::mem::replace(&mut s.md5, md5::Context::new()).compute()));
+            let table = seek_table(&s.table, s.frames);
+            return Some((Ok(table), s));
+        }
+        let frame = s.buf.split_to(s.buf.len().min(FRAME as usize)).freeze();
+        let (len, level) = (frame.len() as u32, s.level);
+        let compressed = match tokio::task::spawn_blocking(move || zstd::bulk::compress(&frame, level)).await {
+            Ok(Ok(c)) => c,
+            Ok(Err(e)) => { s.done = true; return Some((Err(e), s)); }
+            Err(e) => { s.done = true; return Some((Err(std::io::Error::other(e)), s)); }
+        };
+        s.table.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        s.table.extend_from_slice(&len.to_le_bytes());
+        s.frames += 1;
+        Some((Ok(Bytes::from(compressed)), s))
+    }))
+}
+
+/// The skippable frame holding the seek table, per the zstd seekable format.
+fn seek_table(entries: &[u8], frames: u32) -> Bytes {
+    let mut out = Vec::with_capacity(8 + entries.len() + FOOTER);
+    out.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+    out.extend_from_slice(&((entries.len() + FOOTER) as u32).to_le_bytes());
+    out.extend_from_slice(entries);
+    out.extend_from_slice(&frames.to_le_bytes());
+    // Seek_Table_Descriptor: no per-frame checksums
+    out.push(0);
+    out.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+    out.into()
+}
+
+/// The compressed offset of every frame in the seek table `bytes`, plus the end of the last.
+fn frame_offsets(bytes: &[u8], c: &Compression) -> StorageResult<Vec<u64>> {
+    let corrupt = |what: &str| StorageError::Other(anyhow::anyhow!("corrupt seek table: {what}"));
+    let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().expect("4 bytes"));
+    let n = c.frames();
+    if bytes.len() as u64 != c.table_len() { return Err(corrupt("length")); }
+    let footer = bytes.len() - FOOTER;
+    if u32_at(0) != SKIPPABLE_MAGIC || u32_at(footer + 5) != SEEKABLE_MAGIC { return Err(corrupt("magic")); }
+    if u32_at(footer) as usize != n || bytes[footer + 4] != 0 { return Err(corrupt("frame count")); }
+    let mut offsets = Vec::with_capacity(n + 1);
+    offsets.push(0u64);
+    for i in 0..n {
+        let (compressed, plain) = (u32_at(8 + i * 8) as u64, u32_at(12 + i * 8) as u64);
+        if plain != c.frame.min(c.size - i as u64 * c.frame) { return Err(corrupt("frame size")); }
+        offsets.push(offsets[i] + compressed);
+    }
+    Ok(offsets)
+}
+
+/// Size of the data an object's stored bytes hold once decrypted.
+fn inner_size(head: &ObjectMeta) -> StorageResult<u64> {
+    match &head.attrs.sse {
+        Some(enc) => sse::plaintext_size(enc, head.size).ok_or_else(|| anyhow::anyhow!("encrypted object of {} bytes is corrupt", head.size).into()),
+        None => Ok(head.size),
+    }
+}
+
+/// Reads the inclusive `range` of an object's data after decryption, checking that the object
+/// is still the version `head` describes.
+async fn read_inner(storage: &dyn StorageBackend, bucket: &str, key: &str, head: &ObjectMeta, secret: Option<&Secret>, range: (u64, u64)) -> StorageResult<ByteStream> {
+    let stored = match &head.attrs.sse {
+        Some(enc) => sse::stored_range(enc, range, head.size).ok_or_else(|| anyhow::anyhow!("encrypted object {bucket}/{key} is corrupt"))?,
+        None => range,
+    };
+    let obj = match storage.get_object(bucket, key, Some(ByteRange::Bounded(stored.0, stored.1))).await {
+        // A range worked out from `head` fits that version, so the object shrank since
+        Err(StorageError::InvalidRange) => return Err(StorageError::SlowDown),
+        r => r?,
+    };
+    // Replaced since `head`, so the ranges and keys worked out from it no longer apply
+    if obj.meta.size != head.size || obj.meta.etag != head.etag || obj.meta.attrs.sse != head.attrs.sse || obj.meta.attrs.compression != head.attrs.compression {
+        return Err(StorageError::SlowDown);
+    }
+    match (&head.attrs.sse, secret) {
+        (Some(enc), Some(secret)) => Ok(sse::decrypt(obj.body, secret, enc, head.size, range)),
+        (Some(_), None) => Err(StorageError::Other(anyhow::anyhow!("no key to decrypt {bucket}/{key}"))),
+        (None, _) => Ok(obj.body),
+    }
+}
+
+async fn collect(mut body: ByteStream) -> StorageResult<Vec<u8>> {
+    let mut out = Vec::new();
+    while let Some(chunk) = body.next().await { out.extend_from_slice(&chunk?); }
+    Ok(out)
+}
+
+/// Reads the inclusive range `wanted` of an object's content as the client wrote it, decrypting
+/// it with `secret` and decompressing it as `head` says.
+pub async fn read_content(storage: &dyn StorageBackend, bucket: &str, key: &str, head: &ObjectMeta, secret: Option<&Secret>, wanted: (u64, u64)) -> StorageResult<ByteStream> {
+    let Some(c) = &head.attrs.compression else { return read_inner(storage, bucket, key, head, secret, wanted).await };
+    let inner = inner_size(head)?;
+    let table_start = inner.checked_sub(c.table_len()).ok_or_else(|| StorageError::Other(anyhow::anyhow!("compressed object {bucket}/{key} is truncated")))?;
+    let table = collect(read_inner(storage, bucket, key, head, secret, (table_start, inner - 1)).await?).await?;
+    let offsets = frame_offsets(&table, c)?;
+    let (first, last) = ((wanted.0 / c.frame) as usize, (wanted.1 / c.frame) as usize);
+    let body = read_inner(storage, bucket, key, head, secret, (offsets[first], offsets[last + 1] - 1)).await?;
+    let sizes = offsets[first..=last + 1].windows(2).map(|w| w[1] - w[0]).collect();
+    Ok(decompress(body, sizes, c.clone(), first as u64, wanted))
+}
+
+/// Reads all of an object's content as the client wrote it.
+pub async fn read_all(storage: &dyn StorageBackend, bucket: &str, key: &str, head: &ObjectMeta, secret: Option<&Secret>) -> StorageResult<ByteStream> {
+    match head.attrs.logical_size(head.size)? {
+        0 => Ok(storage::bytes_stream(Bytes::new())),
+        n => read_content(storage, bucket, key, head, secret, (0, n - 1)).await,
+    }
+}
+
+/// Decompresses consecutive frames of the compressed lengths `sizes`, starting at frame `first`,
+/// and yields the part of them inside `wanted`.
+fn decompress(body: ByteStream, sizes: std::collections::VecDeque<u64>, c: Compression, first: u64, wanted: (u64, u64)) -> ByteStream {
+    struct Decompressor { inner: ByteStream, sizes: std::collections::VecDeque<u64>, c: Compression, index: u64, buf: BytesMut, skip: usize, remaining: u64 }
+    let decompressor = Decompressor { inner: body, sizes, index: first, buf: BytesMut::new(), skip: (wanted.0 - first * c.frame) as usize, remaining: wanted.1 + 1 - wanted.0, c };
+    Box::pin(futures::stream::unfold(decompressor, |mut s| async move {
+        if s.remaining == 0 { return None; }
+        let need = s.sizes.pop_front()? as usize;
+        while s.buf.len() < need {
+            match s.inner.next().await {
+                Some(Ok(b)) => s.buf.extend_from_slice(&b),
+                Some(Err(e)) => {
+                    s.remaining = 0;
+                    return Some((Err(e), s));
+                }
+                None => {
+                    s.remaining = 0;
+                    return Some((Err(std::io::Error::other("compressed object is truncated")), s));
+                }
+            }
+        }
+        let frame = s.buf.split_to(need).freeze();
+        let len = s.c.frame.min(s.c.size - s.index * s.c.frame) as usize;
+        let plain = match tokio::task::spawn_blocking(move || zstd::bulk::decompress(&frame, len)).await {
+            Ok(Ok(p)) if p.len() == len => Bytes::from(p),
+            Ok(Ok(_)) => { s.remaining = 0; return Some((Err(std::io::Error::other("compressed frame has the wrong size")), s)); }
+            Ok(Err(e)) => { s.remaining = 0; return Some((Err(e), s)); }
+            Err(e) => { s.remaining = 0; return Some((Err(std::io::Error::other(e)), s)); }
+        };
+        let start = s.skip.min(plain.len());
+        let end = (start as u64 + s.remaining).min(plain.len() as u64) as usize;
+        let out = plain.slice(start..end);
+        s.skip = 0;
+        s.remaining -= out.len() as u64;
+        s.index += 1;
+        Some((Ok(out), s))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryBackend;
+    use crate::storage::ObjectAttrs;
+
+    /// Two and a half frames of compressible content.
+    fn content() -> Vec<u8> {
+        (0..FRAME * 5 / 2).map(|i| b"abcdefgh"[(i / 1000 % 8) as usize]).collect()
+    }
+
+    async fn store(storage: &MemoryBackend, key: &str, body: &[u8]) -> ObjectMeta {
+        let etag = DeferredEtag::default();
+        let size = body.len() as u64;
+        let attrs = ObjectAttrs { compression: Some(Compression { size, frame: FRAME }), ..Default::default() };
+        let compressed = compress(storage::bytes_stream(body.to_vec()), 3, size, etag.clone());
+        storage.put_object_with_etag("bkt", key, compressed, attrs, etag).await.unwrap()
+    }
+
+    async fn read(storage: &MemoryBackend, head: &ObjectMeta, wanted: (u64, u64)) -> Vec<u8> {
+        collect(read_content(storage, "bkt", "obj", head, None, wanted).await.unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trips_with_the_plaintext_md5() {
+        let storage = MemoryBackend::new();
+        storage.create_bucket("bkt").await.unwrap();
+        let body = content();
+        let head = store(&storage, "obj", &body).await;
+        assert_eq!(head.etag, format!("\"{:x}\"", md5::compute(&body)));
+        assert!(head.size < body.len() as u64 / 10);
+        assert_eq!(head.attrs.logical_size(head.size).unwrap(), body.len() as u64);
+        assert_eq!(collect(read_all(&storage, "bkt", "obj", &head, None).await.unwrap()).await.unwrap(), body);
+
+        // An empty object is a seek table of no frames
+        let empty = store(&storage, "empty", b"").await;
+        assert_eq!(collect(read_all(&storage, "bkt", "empty", &empty, None).await.unwrap()).await.unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn ranges_decompress_only_the_frames_they_cover() {
+        let storage = MemoryBackend::new();
+        storage.create_bucket("bkt").await.unwrap();
+        let body = content();
+        let head = store(&storage, "obj", &body).await;
+        let last = body.len() as u64 - 1;
+        for (start, end) in [(0, 0), (FRAME - 10, FRAME + 10), (FRAME, 2 * FRAME - 1), (FRAME / 2, last), (last, last)] {
+            assert_eq!(read(&storage, &head, (start, end)).await, body[start as usize..=end as usize], "{start}-{end}");
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_of_another_size_than_announced_fail() {
+        for (announced, actual) in [(10, 11), (11, 10)] {
+            let body = compress(storage::bytes_stream(vec![b'x'; actual]), 3, announced, DeferredEtag::default());
+            let err = collect(body).await.unwrap_err();
+            assert!(err.to_string().contains("not the"), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn replaced_objects_are_read_again() {
+        let storage = MemoryBackend::new();
+        storage.create_bucket("bkt").await.unwrap();
+        let head = store(&storage, "obj", &content()).await;
+        // By something larger, whose ranges still exist, and by something smaller, whose don't
+        let mut larger = content();
+        larger.extend_from_slice(b"more");
+        for replacement in [larger, b"replaced between HEAD and GET".to_vec()] {
+            store(&storage, "obj", &replacement).await;
+            let err = read_content(&storage, "bkt", "obj", &head, None, (0, 10)).await.err().expect("stale head");
+            assert!(matches!(err, StorageError::SlowDown), "{err}");
+        }
+    }
+}
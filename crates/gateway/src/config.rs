@@ -1,6 +1,68 @@
 use serde::{Serialize, Deserialize};
 use std::env;
 
+/// Where objects are stored, chosen with `STORAGE_BACKEND`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// Plain files on the 3FS mount
+    #[default]
+    Posix,
+    /// Process memory; needs the `memory` feature
+    Memory,
+}
+
+/// How object keys map to paths on the mount, chosen with `KEY_ENCODING`; see
+/// [`crate::storage::keys`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyEncoding {
+    /// Keys are paths as they are; keys that cannot be are rejected
+    #[default]
+    Compat,
+    /// Keys that cannot be paths as they are get escaped
+    Escaped,
+}
+
+/// Where object metadata (ETag, content headers, user metadata, tags) is kept, chosen with
+/// `METADATA_STORE`; see [`crate::storage::xattr`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataStore {
+    /// A `<key>.meta.json` file next to the data file
+    #[default]
+    Sidecar,
+    /// A `user.*` extended attribute on the data file, or a sidecar where that is unsupported
+    Xattr,
+}
+
+/// How far an object write is synced before it is acknowledged, chosen with `DURABILITY` and
+/// per bucket; see [`crate::storage::durability`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Durability {
+    /// Data and metadata are flushed to the filesystem but not synced
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    /// Data and metadata files are fsynced before they are renamed into place
+    #[serde(rename = "data")]
+    Data,
+    /// As `Data`, and the directories holding the new entries are fsynced after the renames
+    #[serde(rename = "data+dir")]
+    DataDir,
+}
+
+impl Durability {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Some(Self::None),
+            "data" => Some(Self::Data),
+            "data+dir" => Some(Self::DataDir),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GatewayConfig {
     pub cluster_id: String,
@@ -14,7 +76,49 @@ pub struct GatewayConfig {
     pub access_key: String,
     pub secret_key: String,
     pub use_usrbio: bool,
+    /// Runs the USRBIO data path against an in-process mock ring instead of 3FS
+    pub usrbio_mock: bool,
+    /// Reads and writes object data through io_uring when USRBIO is not in use
+    pub io_uring: bool,
+    /// Size of each read issued ahead of a GET on the default data path
+    pub get_chunk_size: usize,
+    /// Reads kept in flight per GET; 0 streams the file sequentially instead
+    pub get_readahead: usize,
     pub auth_disabled: bool,
+    pub notify_webhooks: Option<String>,
+    pub notify_max_attempts: u32,
+    pub access_log_flush_secs: u64,
+    pub replication_targets: Option<String>,
+    pub replication_max_attempts: u32,
+    pub metadata_index: bool,
+    pub storage_backend: StorageKind,
+    /// Buckets whose small objects are cached in memory, comma separated, or `*` for all
+    pub object_cache_buckets: Option<String>,
+    pub object_cache_bytes: u64,
+    /// Larger objects bypass the cache
+    pub object_cache_max_object_bytes: u64,
+    /// Age after which a cached object is checked against the backend's ETag before use
+    pub object_cache_revalidate_secs: u64,
+    /// Shares cache invalidations with the other pods through a journal on the mount
+    pub change_feed: bool,
+    /// How often other pods' changes are picked up, which bounds how stale caches can be
+    pub change_feed_poll_ms: u64,
+    /// Serializes writers of one key across pods with lock files on the mount
+    pub key_locks: bool,
+    /// How long a key lock outlives a pod that stopped renewing it
+    pub key_lock_lease_secs: u64,
+    /// How long a writer waits for a key before giving up with SlowDown
+    pub key_lock_wait_secs: u64,
+    pub key_encoding: KeyEncoding,
+    pub metadata_store: MetadataStore,
+    /// Default for buckets without a `durability` configuration
+    pub durability: Durability,
+    /// Accepts SSE-C keys on requests a proxy has not marked as HTTPS
+    pub sse_c_allow_http: bool,
+    /// Keyring file holding the master keys of SSE-S3 and SSE-KMS; both are refused without one
+    pub sse_keyring: Option<String>,
+    /// Stores identical object contents once, in the pool under `.dedup/`
+    pub dedup: bool,
 }
 
 impl GatewayConfig {
@@ -31,8 +135,95 @@ impl GatewayConfig {
         let token_file = env::var("TokenFile").ok();
         let mgmtd_addresses = env::var("MgmtdAddresses").ok();
         let use_usrbio = env::var("UseUsrBio").ok().map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(false);
+        let usrbio_mock = env::var("USRBIO_MOCK").ok().map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(false);
+        let io_uring = env::var("IO_URING").ok().map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(false);
+        let get_chunk_size = env::var("GET_CHUNK_SIZE").ok().and_then(|v| v.parse().ok()).filter(|&n| n > 0).unwrap_or(4 << 20);
+        let get_readahead = env::var("GET_READAHEAD").ok().and_then(|v| v.parse().ok()).filter(|&n| n > 0).unwrap_or(4);
         let auth_disabled = env::var("AUTH_DISABLED").ok().map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(false);
-        Ok(Self { cluster_id, mountpoint, hf3fs_binary, token_file, mgmtd_addresses, bind_addr, region, data_root, access_key, secret_key, use_usrbio, auth_disabled })
+        let notify_webhooks = env::var("NOTIFY_WEBHOOKS").ok();
+        let notify_max_attempts = env::var("NOTIFY_MAX_ATTEMPTS").ok().and_then(|v| v.parse().ok()).unwrap_or(10);
+        let access_log_flush_secs = env::var("ACCESS_LOG_FLUSH_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(60);
+        let replication_targets = env::var("REPLICATION_TARGETS").ok();
+        let replication_max_attempts = env::var("REPLICATION_MAX_ATTEMPTS").ok().and_then(|v| v.parse().ok()).unwrap_or(10);
+        let metadata_index = env::var("METADATA_INDEX").ok().map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(false);
+        let storage_backend = match env::var("STORAGE_BACKEND").unwrap_or_default().to_lowercase().as_str() {
+            "" | "posix" => StorageKind::Posix,
+            "memory" => StorageKind::Memory,
+            other => anyhow::bail!("unknown STORAGE_BACKEND {other}"),
+        };
+        let object_cache_buckets = env::var("OBJECT_CACHE_BUCKETS").ok();
+        let object_cache_bytes = env::var("OBJECT_CACHE_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(256 << 20);
+        let object_cache_max_object_bytes = env::var("OBJECT_CACHE_MAX_OBJECT_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(1 << 20);
+        let object_cache_revalidate_secs = env::var("OBJECT_CACHE_REVALIDATE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(5);
+        let change_feed = env::var("CHANGE_FEED").ok().map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(false);
+        let change_feed_poll_ms = env::var("CHANGE_FEED_POLL_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(500);
+        let key_locks = env::var("KEY_LOCKS").ok().map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(true);
+        let key_lock_lease_secs = env::var("KEY_LOCK_LEASE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30);
+        let key_lock_wait_secs = env::var("KEY_LOCK_WAIT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(60);
+        let key_encoding = match env::var("KEY_ENCODING").unwrap_or_default().to_lowercase().as_str() {
+            "" | "compat" => KeyEncoding::Compat,
+            "escaped" => KeyEncoding::Escaped,
+            other => anyhow::bail!("unknown KEY_ENCODING {other}"),
+        };
+        let metadata_store = match env::var("METADATA_STORE").unwrap_or_default().to_lowercase().as_str() {
+            "" | "sidecar" => MetadataStore::Sidecar,
+            "xattr" => MetadataStore::Xattr,
+            other => anyhow::bail!("unknown METADATA_STORE {other}"),
+        };
+        let durability = match env::var("DURABILITY").unwrap_or_default().as_str() {
+            "" => Durability::None,
+            other => Durability::parse(other).ok_or_else(|| anyhow::anyhow!("unknown DURABILITY {other}"))?,
+        };
+        let sse_c_allow_http = env::var("SSE_C_ALLOW_HTTP").ok().map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(false);
+        let sse_keyring = env::var("SSE_KEYRING").ok().filter(|v| !v.is_empty());
+        let dedup = env::var("DEDUP").ok().map(|v| v == "1" || v.to_lowercase() == "true").unwrap_or(false);
+        Ok(Self { cluster_id, mountpoint, hf3fs_binary, token_file, mgmtd_addresses, bind_addr, region, data_root, access_key, secret_key, use_usrbio, usrbio_mock, io_uring, get_chunk_size, get_readahead, auth_disabled, notify_webhooks, notify_max_attempts, access_log_flush_secs, replication_targets, replication_max_attempts, metadata_index, storage_backend, object_cache_buckets, object_cache_bytes, object_cache_max_object_bytes, object_cache_revalidate_secs, change_feed, change_feed_poll_ms, key_locks, key_lock_lease_secs, key_lock_wait_secs, key_encoding, metadata_store, durability, sse_c_allow_http, sse_keyring, dedup })
+    }
+
+    /// A configuration for the in-memory backend with authentication disabled, for spinning up
+    /// [`crate::build_router`] in tests without 3FS or environment variables.
+    #[cfg(feature = "memory")]
+    pub fn in_memory() -> Self {
+        Self {
+            cluster_id: "memory".into(),
+            mountpoint: String::new(),
+            hf3fs_binary: String::new(),
+            token_file: None,
+            mgmtd_addresses: None,
+            bind_addr: "127.0.0.1:0".into(),
+            region: "us-east-1".into(),
+            data_root: String::new(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            use_usrbio: false,
+            usrbio_mock: false,
+            io_uring: false,
+            get_chunk_size: 4 << 20,
+            get_readahead: 4,
+            auth_disabled: true,
+            notify_webhooks: None,
+            notify_max_attempts: 10,
+            access_log_flush_secs: 60,
+            replication_targets: None,
+            replication_max_attempts: 10,
+            metadata_index: false,
+            storage_backend: StorageKind::Memory,
+            object_cache_buckets: None,
+            object_cache_bytes: 256 << 20,
+            object_cache_max_object_bytes: 1 << 20,
+            object_cache_revalidate_secs: 5,
+            change_feed: false,
+            change_feed_poll_ms: 500,
+            key_locks: false,
+            key_lock_lease_secs: 30,
+            key_lock_wait_secs: 60,
+            key_encoding: KeyEncoding::Compat,
+            metadata_store: MetadataStore::Sidecar,
+            durability: Durability::None,
+            sse_c_allow_http: false,
+            sse_keyring: None,
+            dedup: false,
+        }
     }
 }
 
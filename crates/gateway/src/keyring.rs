@@ -0,0 +1,252 @@
+//! Master keys for SSE-S3 and SSE-KMS, kept in a local keyring file (`SSE_KEYRING`).
+//!
+//! Each key ID holds a list of versions. New data keys are wrapped by the newest version of their
+//! key; older versions stay in the file so objects written under them remain readable. A key is
+//! rotated with `3fs-s3-gateway keyring rotate <key-id>`, which also creates it. Gateways notice
+//! a changed file on their next encrypted request.
+
+use crate::config::GatewayConfig;
+use crate::sse::{Secret, SseError};
+use base64::Engine;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The key SSE-S3 objects, and SSE-KMS objects that name no key, are wrapped with
+pub const DEFAULT_KEY_ID: &str = "s3";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Keyring {
+    #[serde(default)]
+    pub keys: BTreeMap<String, Vec<MasterKey>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MasterKey {
+    pub version: u32,
+    /// Base64 of 32 random bytes
+    key: String,
+    /// RFC 3339
+    pub created: String,
+}
+
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MasterKey").field("version", &self.version).field("created", &self.created).finish_non_exhaustive()
+    }
+}
+
+/// An object's data key sealed by a master key, kept with the object's metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub key_id: String,
+    pub version: u32,
+    /// Hex
+    pub nonce: String,
+    /// Base64 of the sealed data key
+    pub sealed: String,
+}
+
+fn keyring_path(cfg: &GatewayConfig) -> Result<PathBuf, SseError> {
+    cfg.sse_keyring.as_ref().map(PathBuf::from)
+        .ok_or_else(|| SseError::Invalid("server-side encryption needs SSE_KEYRING to be configured".into()))
+}
+
+/// The keyring last read, with the path and modification time it was read at
+type Cached = Option<(PathBuf, SystemTime, Arc<Keyring>)>;
+
+static CACHE: Lazy<Mutex<Cached>> = Lazy::new(|| Mutex::new(None));
+
+/// The configured keyring, re-read whenever its file changes.
+pub fn load(cfg: &GatewayConfig) -> Result<Arc<Keyring>, SseError> {
+    let path = keyring_path(cfg)?;
+    let unreadable = |e: &dyn std::fmt::Display| SseError::Keyring(format!("cannot read keyring {}: {e}", path.display()));
+    let modified = std::fs::metadata(&path).and_then(|m| m.modified()).map_err(|e| unreadable(&e))?;
+    let mut cache = CACHE.lock();
+    if let Some((p, m, keyring)) = cache.as_ref() {
+        if *p == path && *m == modified { return Ok(keyring.clone()); }
+    }
+    let bytes = std::fs::read(&path).map_err(|e| unreadable(&e))?;
+    let keyring: Arc<Keyring> = Arc::new(serde_json::from_slice(&bytes).map_err(|e| unreadable(&e))?);
+    *cache = Some((path, modified, keyring.clone()));
+    Ok(keyring)
+}
+
+fn master(key: &MasterKey, key_id: &str) -> Result<LessSafeKey, SseError> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(&key.key).ok().filter(|k| k.len() == 32)
+        .ok_or_else(|| SseError::Keyring(format!("version {} of key {key_id} is not 256 bits of base64", key.version)))?;
+    Ok(LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &bytes).expect("key length checked")))
+}
+
+/// Binds a sealed data key to the master key that sealed it.
+fn aad(key_id: &str, version: u32) -> Vec<u8> {
+    [key_id.as_bytes(), b"\0", &version.to_be_bytes()].concat()
+}
+
+impl Keyring {
+    pub fn contains(&self, key_id: &str) -> bool {
+        self.keys.get(key_id).is_some_and(|v| !v.is_empty())
+    }
+
+    /// Seals `data_key` with the newest version of `key_id`.
+    pub fn wrap(&self, key_id: &str, data_key: &Secret) -> Result<WrappedKey, SseError> {
+        let key = self.keys.get(key_id).and_then(|v| v.iter().max_by_key(|k| k.version))
+            .ok_or_else(|| SseError::UnknownKey(key_id.to_string()))?;
+        let nonce: [u8; 12] = rand::random();
+        let mut sealed = data_key.expose().to_vec();
+        master(key, key_id)?.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad(key_id, key.version)), &mut sealed)
+            .map_err(|_| SseError::Keyring("wrapping a data key failed".into()))?;
+        Ok(WrappedKey {
+            key_id: key_id.to_string(),
+            version: key.version,
+            nonce: hex::encode(nonce),
+            sealed: base64::engine::general_purpose::STANDARD.encode(sealed),
+        })
+    }
+
+    /// Recovers the data key in `wrapped`.
+    pub fn unwrap(&self, wrapped: &WrappedKey) -> Result<Secret, SseError> {
+        let key = self.keys.get(&wrapped.key_id).and_then(|v| v.iter().find(|k| k.version == wrapped.version))
+            .ok_or_else(|| SseError::Keyring(format!("version {} of key {} is not in the keyring", wrapped.version, wrapped.key_id)))?;
+        let corrupt = || SseError::Keyring(format!("data key wrapped by {} version {} is corrupt", wrapped.key_id, wrapped.version));
+        let nonce: [u8; 12] = hex::decode(&wrapped.nonce).ok().and_then(|n| n.try_into().ok()).ok_or_else(corrupt)?;
+        let mut sealed = base64::engine::general_purpose::STANDARD.decode(&wrapped.sealed).map_err(|_| corrupt())?;
+        let data_key = master(key, &wrapped.key_id)?
+            .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(aad(&wrapped.key_id, wrapped.version)), &mut sealed)
+            .map_err(|_| corrupt())?;
+        let data_key: [u8; 32] = (&*data_key).try_into().map_err(|_| corrupt())?;
+        Ok(Secret::from(data_key))
+    }
+}
+
+/// Adds a new version of `key_id`, creating the key and the keyring file as needed, and returns
+/// the version number.
+pub fn rotate(cfg: &GatewayConfig, key_id: &str) -> anyhow::Result<u32> {
+    anyhow::ensure!(!key_id.is_empty() && key_id.chars().all(|c| c.is_ascii_alphanumeric() || "-_/.".contains(c)), "invalid key id {key_id:?}");
+    let path = keyring_path(cfg)?;
+    let mut keyring: Keyring = match std::fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Keyring::default(),
+        Err(e) => return Err(e.into()),
+    };
+    let versions = keyring.keys.entry(key_id.to_string()).or_default();
+    let version = versions.iter().map(|k| k.version).max().unwrap_or(0) + 1;
+    let key = base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 32]>());
+    versions.push(MasterKey { version, key, created: chrono::Utc::now().to_rfc3339() });
+    write_private(&path, &serde_json::to_vec_pretty(&keyring)?)?;
+    Ok(version)
+}
+
+/// Key IDs in the keyring with their versions, without the key material.
+pub fn list(cfg: &GatewayConfig) -> anyhow::Result<Vec<(String, Vec<u32>)>> {
+    let keyring = load(cfg)?;
+    Ok(keyring.keys.iter().map(|(id, v)| (id.clone(), v.iter().map(|k| k.version).collect())).collect())
+}
+
+/// Replaces `path` with `bytes`, readable by the owner only.
+fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() { std::fs::create_dir_all(parent)?; }
+    let tmp = path.with_extension("tmp");
+    let mut f = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+    f.write_all(bytes)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path) -> GatewayConfig {
+        GatewayConfig { sse_keyring: Some(dir.join("keyring.json").to_string_lossy().into_owned()), ..GatewayConfig::in_memory() }
+    }
+
+    /// The keyring file as it is now, bypassing the cache `load` keeps by modification time.
+    fn read(cfg: &GatewayConfig) -> Keyring {
+        serde_json::from_slice(&std::fs::read(keyring_path(cfg).unwrap()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn wrapped_keys_unwrap_to_the_data_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        assert_eq!(rotate(&cfg, DEFAULT_KEY_ID).unwrap(), 1);
+        let keyring = read(&cfg);
+        let data_key = Secret::random();
+        let wrapped = keyring.wrap(DEFAULT_KEY_ID, &data_key).unwrap();
+        assert_eq!((wrapped.key_id.as_str(), wrapped.version), (DEFAULT_KEY_ID, 1));
+        assert_ne!(wrapped.sealed, base64::engine::general_purpose::STANDARD.encode(data_key.expose()));
+        assert_eq!(keyring.unwrap(&wrapped).unwrap().expose(), data_key.expose());
+        // Every wrap gets its own nonce
+        assert_ne!(keyring.wrap(DEFAULT_KEY_ID, &data_key).unwrap().nonce, wrapped.nonce);
+    }
+
+    #[test]
+    fn keys_wrapped_before_a_rotation_still_unwrap() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        rotate(&cfg, "tenant-a").unwrap();
+        let data_key = Secret::random();
+        let old = read(&cfg).wrap("tenant-a", &data_key).unwrap();
+
+        assert_eq!(rotate(&cfg, "tenant-a").unwrap(), 2);
+        let keyring = read(&cfg);
+        assert_eq!(keyring.wrap("tenant-a", &data_key).unwrap().version, 2);
+        assert_eq!(keyring.unwrap(&old).unwrap().expose(), data_key.expose());
+        assert_eq!(list(&cfg).unwrap(), [("tenant-a".to_string(), vec![1, 2])]);
+    }
+
+    #[test]
+    fn objects_sealed_before_a_rotation_stay_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        rotate(&cfg, DEFAULT_KEY_ID).unwrap();
+        let protection = crate::sse::Protection::Managed { scheme: crate::sse::Scheme::S3, key_id: DEFAULT_KEY_ID.into() };
+        let (enc, secret) = crate::sse::seal_new(&cfg, &protection).unwrap();
+        rotate(&cfg, DEFAULT_KEY_ID).unwrap();
+        let (newer, _) = crate::sse::seal_new(&cfg, &protection).unwrap();
+        assert_eq!(newer.managed.unwrap().wrapped.version, 2);
+        assert_eq!(crate::sse::object_secret(&cfg, &enc, None).unwrap().expose(), secret.expose());
+    }
+
+    #[test]
+    fn unknown_keys_and_tampering_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        rotate(&cfg, DEFAULT_KEY_ID).unwrap();
+        let keyring = read(&cfg);
+        assert!(!keyring.contains("missing"));
+        assert!(matches!(keyring.wrap("missing", &Secret::random()), Err(SseError::UnknownKey(id)) if id == "missing"));
+
+        let wrapped = keyring.wrap(DEFAULT_KEY_ID, &Secret::random()).unwrap();
+        let gone = WrappedKey { version: 7, ..wrapped.clone() };
+        assert!(matches!(keyring.unwrap(&gone), Err(SseError::Keyring(_))));
+        // A data key cannot be passed off as wrapped by another key
+        rotate(&cfg, "other").unwrap();
+        let moved = WrappedKey { key_id: "other".into(), ..wrapped.clone() };
+        assert!(matches!(read(&cfg).unwrap(&moved), Err(SseError::Keyring(_))));
+        let mut sealed = base64::engine::general_purpose::STANDARD.decode(&wrapped.sealed).unwrap();
+        sealed[0] ^= 1;
+        let flipped = WrappedKey { sealed: base64::engine::general_purpose::STANDARD.encode(sealed), ..wrapped };
+        assert!(matches!(keyring.unwrap(&flipped), Err(SseError::Keyring(_))));
+    }
+
+    #[test]
+    fn key_ids_are_checked_and_a_keyring_is_required() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        assert!(rotate(&cfg, "").is_err());
+        assert!(rotate(&cfg, "bad key").is_err());
+        assert!(matches!(load(&GatewayConfig::in_memory()), Err(SseError::Invalid(_))));
+        rotate(&cfg, DEFAULT_KEY_ID).unwrap();
+        let mode = std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(keyring_path(&cfg).unwrap()).unwrap().permissions());
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -1,12 +1,23 @@
+pub mod access_log;
+pub mod changes;
+pub mod compression;
 pub mod config;
+pub mod keyring;
 pub mod mount;
+pub mod notify;
+pub mod replication;
 pub mod s3;
+pub mod spool;
+pub mod sse;
 pub mod storage;
 
 use axum::{routing::{get, put, post, delete, head}, Router};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tracing::{info};
-use crate::config::GatewayConfig;
+use crate::changes::Change;
+use crate::config::{GatewayConfig, StorageKind};
+use crate::storage::StorageBackend;
 use crate::s3::handlers;
 use crate::s3::auth::SigV4Layer;
 use tower_http::{trace::TraceLayer, cors::CorsLayer};
@@ -20,6 +31,21 @@ pub struct AppState {
     pub registry: Registry,
     pub req_counter: IntCounter,
     pub req_latency: Histogram,
+    pub access_log: crate::access_log::AccessLogger,
+    pub storage: Arc<dyn StorageBackend>,
+}
+
+impl AppState {
+    /// Wires request metrics and the access logger around `storage`. The access log flusher is
+    /// not started, so a state built here in tests does no background work.
+    pub fn new(cfg: GatewayConfig, registry: Registry, storage: Arc<dyn StorageBackend>) -> Self {
+        let req_counter = IntCounter::new("http_requests_total", "Total HTTP requests").unwrap();
+        registry.register(Box::new(req_counter.clone())).ok();
+        let req_latency = Histogram::with_opts(HistogramOpts::new("http_request_duration_seconds", "Request latencies")).unwrap();
+        registry.register(Box::new(req_latency.clone())).ok();
+        let access_log = crate::access_log::AccessLogger::new(cfg.clone(), storage.clone());
+        Self { cfg, registry, req_counter, req_latency, access_log, storage }
+    }
 }
 
 static GLOBAL_REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
@@ -27,11 +53,11 @@ static GLOBAL_REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
 pub fn build_router(state: AppState) -> Router {
     let cors = CorsLayer::permissive();
     let healthz = get(|| async { "ok" });
-    let readyz_state = state.cfg.clone();
+    let readyz_storage = state.storage.clone();
     let readyz = get(move || {
-        let cfg = readyz_state.clone();
+        let storage = readyz_storage.clone();
         async move {
-            if crate::mount::is_mounted_and_writeable(&cfg.mountpoint).await { "ready".into_response() } else { (axum::http::StatusCode::SERVICE_UNAVAILABLE, "not ready").into_response() }
+            if storage.is_ready().await { "ready".into_response() } else { (axum::http::StatusCode::SERVICE_UNAVAILABLE, "not ready").into_response() }
         }
     });
 
@@ -58,7 +84,7 @@ pub fn build_router(state: AppState) -> Router {
         .route("/:bucket", put(handlers::create_bucket)
             .head(handlers::head_bucket)
             .delete(handlers::delete_bucket)
-            .get(handlers::list_objects_v2))
+            .get(handlers::list_objects))
         .route("/:bucket", post(handlers::bucket_post))
         .route("/:bucket/*key", put(handlers::put_object).get(handlers::get_object).head(handlers::head_object).delete(handlers::delete_object).post(handlers::object_post))
         .route("/:bucket/*key", axum::routing::options(handlers::cors_preflight))
@@ -72,25 +98,39 @@ pub fn build_router(state: AppState) -> Router {
         .layer(TraceLayer::new_for_http())
         .layer(cors)
         .layer(SigV4Layer::new(state.cfg.clone()))
+        .layer(axum::middleware::from_fn_with_state(state.access_log.clone(), crate::access_log::access_log))
         .with_state(state)
 }
 
 pub async fn run_server(cfg: GatewayConfig) -> anyhow::Result<()> {
-    crate::mount::ensure_mount(&cfg).await?;
-    crate::storage::posix::ensure_roots(&cfg).await?;
-
+    if cfg.storage_backend == StorageKind::Posix {
+        crate::mount::ensure_mount(&cfg).await?;
+    }
     let registry = GLOBAL_REGISTRY.clone();
-    let req_counter = IntCounter::new("http_requests_total", "Total HTTP requests").unwrap();
-    registry.register(Box::new(req_counter.clone())).ok();
-    let req_latency = Histogram::with_opts(HistogramOpts::new("http_request_duration_seconds", "Request latencies")).unwrap();
-    registry.register(Box::new(req_latency.clone())).ok();
+    let feed = crate::changes::open(&cfg);
+    let mut storage = crate::storage::cache::wrap(&cfg, crate::storage::open(&cfg).await?, &registry, feed.as_deref());
+    if let Some(feed) = &feed { storage = crate::changes::wrap(feed.clone(), storage); }
+    crate::notify::spawn_worker(cfg.clone());
+
+    crate::replication::spawn_worker(cfg.clone(), storage.clone(), crate::replication::ReplicationMetrics::register(&registry));
 
-    let state = AppState { cfg: cfg.clone(), registry, req_counter, req_latency };
+    let state = AppState::new(cfg.clone(), registry, storage);
+    state.access_log.spawn_flusher();
+    if let Some(feed) = &feed {
+        let logger = state.access_log.clone();
+        feed.subscribe(move |change| match change {
+            Change::Bucket { bucket } => logger.invalidate(bucket),
+            Change::BucketConfig { bucket, name } if name == crate::access_log::CONFIG_NAME => logger.invalidate(bucket),
+            Change::Reset => logger.invalidate_all(),
+            _ => {}
+        });
+        feed.spawn_tailer();
+    }
     let app = build_router(state);
 
     let addr: SocketAddr = cfg.bind_addr.parse()?;
     info!(%addr, "binding");
-    axum::serve(tokio::net::TcpListener::bind(addr).await?, app).await?;
+    axum::serve(tokio::net::TcpListener::bind(addr).await?, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
     Ok(())
 }
 
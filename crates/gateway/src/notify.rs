@@ -0,0 +1,215 @@
+use crate::access_log::RequestOrigin;
+use crate::config::GatewayConfig;
+use crate::s3::models::{NotificationConfiguration, QueueConfiguration};
+use crate::spool::Spool;
+use crate::storage::{self, StorageBackend};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, warn};
+
+pub const CONFIG_NAME: &str = "notification";
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_CONCURRENCY: usize = 8;
+/// Spooled events are kept on the 3FS mount so any gateway pod can deliver them after a restart.
+fn event_spool(cfg: &GatewayConfig) -> Spool {
+    Spool::new(Path::new(&cfg.mountpoint).join(".notify"))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SpoolEntry {
+    target: String,
+    payload: serde_json::Value,
+}
+
+/// Parses `NOTIFY_WEBHOOKS` (`id=url,id2=url2`) into a target id -> URL map.
+pub fn webhook_targets(cfg: &GatewayConfig) -> HashMap<String, String> {
+    cfg.notify_webhooks.clone().unwrap_or_default()
+        .split(',')
+        .filter_map(|s| s.split_once('='))
+        .map(|(id, url)| (id.trim().to_string(), url.trim().to_string()))
+        .collect()
+}
+
+/// Extracts the target id from `arn:<partition>:sqs:<region>:<id>:webhook`.
+fn target_id(arn: &str) -> Option<&str> {
+    let parts: Vec<&str> = arn.split(':').collect();
+    match parts.as_slice() {
+        ["arn", _, "sqs", _, id, "webhook"] if !id.is_empty() => Some(id),
+        _ => None,
+    }
+}
+
+pub fn validate(cfg: &GatewayConfig, nc: &NotificationConfiguration) -> Result<(), String> {
+    let targets = webhook_targets(cfg);
+    for qc in &nc.QueueConfiguration {
+        let id = target_id(&qc.Queue).ok_or_else(|| format!("invalid queue ARN {}", qc.Queue))?;
+        if !targets.contains_key(id) { return Err(format!("unknown webhook target {id}")); }
+        if qc.Event.is_empty() { return Err("at least one Event is required".into()); }
+        for ev in &qc.Event {
+            if !(ev.starts_with("s3:ObjectCreated:") || ev.starts_with("s3:ObjectRemoved:")) {
+                return Err(format!("unsupported event {ev}"));
+            }
+        }
+        if let Some(f) = &qc.Filter {
+            for rule in &f.S3Key.FilterRule {
+                if !rule.Name.eq_ignore_ascii_case("prefix") && !rule.Name.eq_ignore_ascii_case("suffix") {
+                    return Err(format!("unsupported filter rule {}", rule.Name));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn event_matches(pattern: &str, event: &str) -> bool {
+    let pattern = pattern.strip_prefix("s3:").unwrap_or(pattern);
+    match pattern.strip_suffix('*') {
+        Some(family) => event.starts_with(family),
+        None => pattern == event,
+    }
+}
+
+fn key_matches(qc: &QueueConfiguration, key: &str) -> bool {
+    let Some(f) = &qc.Filter else { return true };
+    f.S3Key.FilterRule.iter().all(|r| {
+        if r.Name.eq_ignore_ascii_case("prefix") { key.starts_with(&r.Value) } else { key.ends_with(&r.Value) }
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn event_record(cfg: &GatewayConfig, origin: &RequestOrigin, bucket: &str, key: &str, event: &str, size: u64, etag: &str, config_id: &str) -> serde_json::Value {
+    let now = chrono::Utc::now();
+    let encoded_key: String = form_urlencoded::byte_serialize(key.as_bytes()).collect();
+    serde_json::json!({
+        "Records": [{
+            "eventVersion": "2.1",
+            "eventSource": "aws:s3",
+            "awsRegion": cfg.region,
+            "eventTime": now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "eventName": event,
+            "userIdentity": { "principalId": cfg.access_key },
+            "requestParameters": { "sourceIPAddress": origin.remote_ip },
+            "responseElements": { "x-amz-request-id": origin.request_id },
+            "s3": {
+                "s3SchemaVersion": "1.0",
+                "configurationId": config_id,
+                "bucket": { "name": bucket, "ownerIdentity": { "principalId": cfg.access_key }, "arn": format!("arn:aws:s3:::{bucket}") },
+                "object": { "key": encoded_key, "size": size, "eTag": etag.trim_matches('"'), "sequencer": format!("{:016X}", now.timestamp_nanos_opt().unwrap_or_default()) }
+            }
+        }]
+    })
+}
+
+/// Spools an event for every matching notification rule of `bucket`. `event` is the S3 event
+/// name without the `s3:` prefix, e.g. `ObjectCreated:Put`, and `origin` the request that caused
+/// it. Failures are logged, never surfaced to the client whose request triggered the event.
+#[allow(clippy::too_many_arguments)]
+pub async fn emit(cfg: &GatewayConfig, storage: &dyn StorageBackend, origin: &RequestOrigin, bucket: &str, key: &str, event: &str, size: u64, etag: &str) {
+    let nc: NotificationConfiguration = match storage::read_bucket_config(storage, bucket, CONFIG_NAME).await {
+        Ok(Some(nc)) => nc,
+        Ok(None) => return,
+        Err(e) => { warn!(%bucket, error = %e, "failed to load notification config"); return; }
+    };
+    let targets = webhook_targets(cfg);
+    for qc in nc.QueueConfiguration.iter() {
+        if !qc.Event.iter().any(|p| event_matches(p, event)) || !key_matches(qc, key) { continue; }
+        let Some(url) = target_id(&qc.Queue).and_then(|id| targets.get(id)) else { continue };
+        let entry = SpoolEntry {
+            target: url.clone(),
+            payload: event_record(cfg, origin, bucket, key, &format!("s3:{event}"), size, etag, qc.Id.as_deref().unwrap_or_default()),
+        };
+        if let Err(e) = event_spool(cfg).push(&entry).await {
+            warn!(%bucket, %key, error = %e, "failed to spool event");
+        }
+    }
+}
+
+/// Background delivery loop; every gateway pod runs one and they share the spool.
+pub fn spawn_worker(cfg: GatewayConfig) {
+    tokio::spawn(async move {
+        let client = match reqwest::Client::builder().timeout(DELIVERY_TIMEOUT).build() {
+            Ok(c) => c,
+            Err(e) => { warn!(error = %e, "notification worker disabled"); return; }
+        };
+        let spool = event_spool(&cfg);
+        loop {
+            let pass = spool.run_pass(cfg.notify_max_attempts, DELIVERY_CONCURRENCY, |entry: SpoolEntry, _| deliver(&client, entry)).await;
+            if let Err(e) = pass { warn!(error = %e, "notification delivery pass failed"); }
+            sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+async fn deliver(client: &reqwest::Client, entry: SpoolEntry) -> anyhow::Result<()> {
+    let resp = client.post(&entry.target).json(&entry.payload).send().await?;
+    if !resp.status().is_success() { anyhow::bail!("webhook {} returned {}", entry.target, resp.status()); }
+    debug!(target = %entry.target, "event delivered");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s3::models::{FilterRule, NotificationFilter, S3KeyFilter};
+
+    fn queue(events: &[&str], rules: &[(&str, &str)]) -> QueueConfiguration {
+        QueueConfiguration {
+            Id: Some("ingest-rule".into()),
+            Queue: "arn:3fs3:sqs:us-east-1:ingest:webhook".into(),
+            Event: events.iter().map(|e| e.to_string()).collect(),
+            Filter: (!rules.is_empty()).then(|| NotificationFilter {
+                S3Key: S3KeyFilter { FilterRule: rules.iter().map(|(n, v)| FilterRule { Name: n.to_string(), Value: v.to_string() }).collect() },
+            }),
+        }
+    }
+
+    #[test]
+    fn records_say_who_caused_the_event() {
+        let cfg = GatewayConfig::in_memory();
+        let origin = RequestOrigin { request_id: "0123456789ABCDEF".into(), remote_ip: "10.0.0.7".into() };
+        let ev = event_record(&cfg, &origin, "bkt", "dir/a b+c.txt", "s3:ObjectCreated:Put", 42, "\"abc\"", "ingest-rule");
+        let rec = &ev["Records"][0];
+        assert_eq!(rec["eventName"], "s3:ObjectCreated:Put");
+        assert_eq!(rec["awsRegion"], cfg.region.as_str());
+        assert_eq!(rec["requestParameters"]["sourceIPAddress"], "10.0.0.7");
+        assert_eq!(rec["responseElements"]["x-amz-request-id"], "0123456789ABCDEF");
+        assert_eq!(rec["s3"]["configurationId"], "ingest-rule");
+        assert_eq!(rec["s3"]["bucket"]["arn"], "arn:aws:s3:::bkt");
+        assert_eq!(rec["s3"]["object"]["key"], "dir%2Fa+b%2Bc.txt");
+        assert_eq!(rec["s3"]["object"]["size"], 42);
+        assert_eq!(rec["s3"]["object"]["eTag"], "abc");
+    }
+
+    #[test]
+    fn events_match_by_name_or_family() {
+        assert!(event_matches("s3:ObjectCreated:*", "ObjectCreated:Put"));
+        assert!(event_matches("s3:ObjectCreated:Put", "ObjectCreated:Put"));
+        assert!(event_matches("ObjectRemoved:*", "ObjectRemoved:Delete"));
+        assert!(!event_matches("s3:ObjectCreated:Put", "ObjectCreated:Copy"));
+        assert!(!event_matches("s3:ObjectCreated:*", "ObjectRemoved:Delete"));
+    }
+
+    #[test]
+    fn keys_must_pass_every_filter_rule() {
+        let qc = queue(&["s3:ObjectCreated:*"], &[("Prefix", "logs/"), ("suffix", ".gz")]);
+        assert!(key_matches(&qc, "logs/2024/app.gz"));
+        assert!(!key_matches(&qc, "logs/2024/app.txt"));
+        assert!(!key_matches(&qc, "data/app.gz"));
+        assert!(key_matches(&queue(&["s3:ObjectCreated:*"], &[]), "anything"));
+    }
+
+    #[test]
+    fn validation_needs_known_targets_and_supported_rules() {
+        let cfg = GatewayConfig { notify_webhooks: Some("ingest=http://127.0.0.1:1/hook".into()), ..GatewayConfig::in_memory() };
+        let nc = |qc: QueueConfiguration| NotificationConfiguration { QueueConfiguration: vec![qc] };
+        assert!(validate(&cfg, &nc(queue(&["s3:ObjectCreated:*"], &[("prefix", "a/")]))).is_ok());
+        assert!(validate(&cfg, &nc(queue(&[], &[]))).is_err());
+        assert!(validate(&cfg, &nc(queue(&["s3:ObjectRestore:*"], &[]))).is_err());
+        assert!(validate(&cfg, &nc(queue(&["s3:ObjectCreated:*"], &[("regex", ".*")]))).is_err());
+        let unknown = QueueConfiguration { Queue: "arn:3fs3:sqs:us-east-1:other:webhook".into(), ..queue(&["s3:ObjectCreated:*"], &[]) };
+        assert!(validate(&cfg, &nc(unknown)).is_err());
+    }
+}
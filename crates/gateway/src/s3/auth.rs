@@ -1,5 +1,6 @@
 use axum::{http::{Request, StatusCode, HeaderMap, HeaderValue, Uri, Method}, response::Response};
 use tower::{Layer, Service};
+use std::future::Future;
 use std::task::{Context, Poll};
 use std::pin::Pin;
 use crate::config::GatewayConfig;
@@ -8,7 +9,6 @@ use sha2::{Sha256, Digest};
 use http::header::{AUTHORIZATION, HOST};
 use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use std::collections::BTreeMap;
-use anyhow::Context as _;
 
 #[derive(Clone)]
 pub struct SigV4Layer { cfg: GatewayConfig }
@@ -26,26 +26,27 @@ pub struct SigV4Middleware<S> { inner: S, cfg: GatewayConfig }
 impl<S, B> Service<Request<B>> for SigV4Middleware<S>
 where S: Service<Request<B>, Response = Response> + Clone + Send + 'static + Unpin,
       S::Future: Send + 'static,
+      S::Error: Send + 'static,
 {
     type Response = S::Response;
     type Error = S::Error;
-    type Future = S::Future;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> { Pin::new(&mut self.inner).poll_ready(cx) }
 
     fn call(&mut self, req: Request<B>) -> Self::Future {
         // For MVP: allow unauthenticated health/metrics
         let path = req.uri().path();
-        if path == "/healthz" || path == "/readyz" || path == "/metrics" { return self.inner.call(req); }
-        if self.cfg.auth_disabled { return self.inner.call(req); }
+        if path == "/healthz" || path == "/readyz" || path == "/metrics" { return Box::pin(self.inner.call(req)); }
+        if self.cfg.auth_disabled { return Box::pin(self.inner.call(req)); }
 
         match verify_sigv4(&self.cfg, req.method(), req.uri(), req.headers()) {
-            Ok(()) => self.inner.call(req),
+            Ok(()) => Box::pin(self.inner.call(req)),
             Err(_e) => {
                 // For security, avoid leaking details
                 let mut r = Response::new(axum::body::Body::from("SignatureDoesNotMatch"));
                 *r.status_mut() = StatusCode::FORBIDDEN;
-                self.inner.call(req)
+                Box::pin(std::future::ready(Ok(r)))
             }
         }
     }
@@ -55,6 +56,11 @@ where S: Service<Request<B>, Response = Response> + Clone + Send + 'static + Unp
 const AWS_QUERY_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
     .remove(b'-').remove(b'_').remove(b'.').remove(b'~');
 
+/// Percent-encodes an object key for use in a request path, keeping `/` separators.
+pub fn encode_key_path(key: &str) -> String {
+    key.split('/').map(|seg| utf8_percent_encode(seg, AWS_QUERY_ENCODE).to_string()).collect::<Vec<_>>().join("/")
+}
+
 fn hex_sha256(input: &str) -> String { hex::encode(Sha256::digest(input.as_bytes())) }
 
 fn canonical_uri(uri: &Uri) -> String {
@@ -63,15 +69,13 @@ fn canonical_uri(uri: &Uri) -> String {
     if p.is_empty() { "/".to_string() } else { p.to_string() }
 }
 
-fn canonical_query(uri: &Uri) -> String {
+/// The canonical query string; a presigned URL's own signature is not part of what it signs.
+fn canonical_query(uri: &Uri, presigned: bool) -> String {
     let mut pairs: Vec<(String, String)> = Vec::new();
     if let Some(q) = uri.query() {
-        for part in q.split('&') {
-            if part.is_empty() { continue; }
-            let mut iter = part.splitn(2, '=');
-            let k = iter.next().unwrap_or("");
-            let v = iter.next().unwrap_or("");
-            pairs.push((k.to_string(), v.to_string()));
+        for (k, v) in form_urlencoded::parse(q.as_bytes()) {
+            if presigned && k == "X-Amz-Signature" { continue; }
+            pairs.push((k.into_owned(), v.into_owned()));
         }
         pairs.sort_by(|a,b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
     }
@@ -117,8 +121,7 @@ fn collapse_ws(val: &HeaderValue) -> String {
 
 fn parse_authz(authz: &str) -> Option<(String/*access_key*/, String/*signed_headers*/, String/*signature*/, String/*date*/, String/*scope*/)> {
     // Example: AWS4-HMAC-SHA256 Credential=AKID/20250101/us-east-1/s3/aws4_request, SignedHeaders=host;x-amz-date, Signature=...
-    if !authz.starts_with("AWS4-HMAC-SHA256 ") { return None; }
-    let rest = &authz[19..];
+    let rest = authz.strip_prefix("AWS4-HMAC-SHA256 ")?;
     let mut access_key = String::new();
     let mut signed_headers = String::new();
     let mut signature = String::new();
@@ -130,7 +133,8 @@ fn parse_authz(authz: &str) -> Option<(String/*access_key*/, String/*signed_head
             let mut it = v.split('/');
             access_key = it.next().unwrap_or("").to_string();
             let date = it.next().unwrap_or("").to_string();
-            scope = it.collect::<Vec<_>>().join("/");
+            // Keep the date in the scope, as for presigned URLs
+            scope = std::iter::once(date.as_str()).chain(it).collect::<Vec<_>>().join("/");
             amz_date = date;
         } else if let Some(v) = p.strip_prefix("SignedHeaders=") {
             signed_headers = v.to_string();
@@ -165,7 +169,7 @@ fn verify_sigv4(cfg: &GatewayConfig, method: &Method, uri: &Uri, headers: &Heade
         let mut it = ak.split('/');
         let access_key = it.next().unwrap_or("").to_string();
         let date = qp.get("X-Amz-Date").cloned().unwrap_or_default();
-        let scope_rest = ak.splitn(2, '/').nth(1).unwrap_or("").to_string();
+        let scope_rest = ak.split_once('/').map(|(_, rest)| rest).unwrap_or("").to_string();
         let signature = qp.get("X-Amz-Signature").cloned().unwrap_or_default();
         let signed_headers = qp.get("X-Amz-SignedHeaders").cloned().unwrap_or_else(|| "host".to_string());
         (access_key, signed_headers, signature, date, scope_rest, true)
@@ -185,7 +189,7 @@ fn verify_sigv4(cfg: &GatewayConfig, method: &Method, uri: &Uri, headers: &Heade
 
     // Build canonical request
     let can_uri = canonical_uri(uri);
-    let can_qs = if is_query { canonical_query(uri) } else { canonical_query(uri) };
+    let can_qs = canonical_query(uri, is_query);
     let (can_headers, signed_list) = signed_headers_list(headers, &signed_headers);
     let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method.as_str(), can_uri, can_qs, can_headers, signed_list, payload_hash);
     let cr_hash = hex_sha256(&canonical_request);
@@ -194,17 +198,127 @@ fn verify_sigv4(cfg: &GatewayConfig, method: &Method, uri: &Uri, headers: &Heade
     let amz_date = headers.get("x-amz-date").and_then(|v| v.to_str().ok()).unwrap_or(&date);
     // Scope: <date>/<region>/s3/aws4_request
     let mut scope_parts = scope.split('/');
-    let _date_sc = scope_parts.next().unwrap_or("");
+    // The scope's date, which X-Amz-Date of a presigned URL only starts with
+    let date_sc = scope_parts.next().unwrap_or("");
     let region_sc = scope_parts.next().unwrap_or(&cfg.region);
     let service_sc = scope_parts.next().unwrap_or("s3");
-    let sts = format!("AWS4-HMAC-SHA256\n{}\n{}/{}/{}/aws4_request\n{}", amz_date, date, region_sc, service_sc, cr_hash);
+    let sts = format!("AWS4-HMAC-SHA256\n{}\n{}/{}/{}/aws4_request\n{}", amz_date, date_sc, region_sc, service_sc, cr_hash);
 
     // Derive signing key and compute signature
-    let k = derive_signing_key(&cfg.secret_key, &date, region_sc, service_sc);
+    let k = derive_signing_key(&cfg.secret_key, date_sc, region_sc, service_sc);
     let sig_bytes = hmac_sha256(&k, &sts);
     let calc_sig = hex::encode(sig_bytes);
     if calc_sig != signature { return Err(anyhow::anyhow!("SignatureDoesNotMatch")); }
     Ok(())
 }
 
+/// Signs an outgoing request to another S3 endpoint with an unsigned payload, returning the
+/// `x-amz-date`, `x-amz-content-sha256` and `authorization` headers to attach.
+pub fn sign_request(method: &Method, uri: &Uri, host: &str, access_key: &str, secret_key: &str, region: &str) -> Vec<(&'static str, String)> {
+    let now = chrono::Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = "UNSIGNED-PAYLOAD";
+    let mut headers = HeaderMap::new();
+    if let Ok(v) = HeaderValue::from_str(host) { headers.insert(HOST, v); }
+    if let Ok(v) = HeaderValue::from_str(&amz_date) { headers.insert("x-amz-date", v); }
+    headers.insert("x-amz-content-sha256", HeaderValue::from_static(payload_hash));
+    let (can_headers, signed_list) = signed_headers_list(&headers, "host;x-amz-content-sha256;x-amz-date");
+    let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method.as_str(), canonical_uri(uri), canonical_query(uri, false), can_headers, signed_list, payload_hash);
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let sts = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex_sha256(&canonical_request));
+    let signature = hex::encode(hmac_sha256(&derive_signing_key(secret_key, &date, region, "s3"), &sts));
+    vec![
+        ("x-amz-date", amz_date),
+        ("x-amz-content-sha256", payload_hash.to_string()),
+        ("authorization", format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", access_key, scope, signed_list, signature)),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg() -> GatewayConfig {
+        GatewayConfig { access_key: "AKID".into(), secret_key: "secret".into(), auth_disabled: false, ..GatewayConfig::in_memory() }
+    }
+
+    fn headers(host: &str, signed: Vec<(&'static str, String)>) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert(HOST, HeaderValue::from_str(host).unwrap());
+        for (k, v) in signed { h.insert(k, HeaderValue::from_str(&v).unwrap()); }
+        h
+    }
+
+    #[test]
+    fn parse_authz_keeps_the_date_in_the_scope() {
+        let authz = "AWS4-HMAC-SHA256 Credential=AKID/20250101/eu-west-1/s3/aws4_request, SignedHeaders=host;x-amz-date, Signature=abc";
+        let (ak, signed, sig, date, scope) = parse_authz(authz).unwrap();
+        assert_eq!((ak.as_str(), signed.as_str(), sig.as_str(), date.as_str()), ("AKID", "host;x-amz-date", "abc", "20250101"));
+        assert_eq!(scope, "20250101/eu-west-1/s3/aws4_request");
+        assert!(parse_authz("AWS4-HMAC-SHA256 SignedHeaders=host, Signature=abc").is_none());
+    }
+
+    #[test]
+    fn header_signature_verifies_in_any_region() {
+        let uri: Uri = "/bucket/a%20b?list-type=2&prefix=a%2Fb".parse().unwrap();
+        for region in ["us-east-1", "eu-west-1"] {
+            let h = headers("gw:9000", sign_request(&Method::GET, &uri, "gw:9000", "AKID", "secret", region));
+            assert!(verify_sigv4(&cfg(), &Method::GET, &uri, &h).is_ok(), "{region}");
+            assert!(verify_sigv4(&cfg(), &Method::PUT, &uri, &h).is_err());
+        }
+        let h = headers("gw:9000", sign_request(&Method::GET, &uri, "gw:9000", "AKID", "wrong", "us-east-1"));
+        assert!(verify_sigv4(&cfg(), &Method::GET, &uri, &h).is_err());
+        let h = headers("gw:9000", sign_request(&Method::GET, &uri, "gw:9000", "OTHER", "secret", "us-east-1"));
+        assert!(verify_sigv4(&cfg(), &Method::GET, &uri, &h).is_err());
+    }
+
+    #[test]
+    fn presigned_url_verifies_without_its_own_signature() {
+        let credential = utf8_percent_encode("AKID/20260101/us-east-1/s3/aws4_request", AWS_QUERY_ENCODE).to_string();
+        let query = format!("X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={credential}&X-Amz-Date=20260101T000000Z&X-Amz-Expires=300&X-Amz-SignedHeaders=host");
+        let unsigned: Uri = format!("/bucket/key?{query}").parse().unwrap();
+        let canonical_request = format!("GET\n/bucket/key\n{}\nhost:gw\n\nhost\nUNSIGNED-PAYLOAD", canonical_query(&unsigned, false));
+        let sts = format!("AWS4-HMAC-SHA256\n20260101T000000Z\n20260101/us-east-1/s3/aws4_request\n{}", hex_sha256(&canonical_request));
+        let signature = hex::encode(hmac_sha256(&derive_signing_key("secret", "20260101", "us-east-1", "s3"), &sts));
+        let h = headers("gw", Vec::new());
+        let uri: Uri = format!("/bucket/key?{query}&X-Amz-Signature={signature}").parse().unwrap();
+        assert!(verify_sigv4(&cfg(), &Method::GET, &uri, &h).is_ok());
+        let tampered: Uri = format!("/bucket/other?{query}&X-Amz-Signature={signature}").parse().unwrap();
+        assert!(verify_sigv4(&cfg(), &Method::GET, &tampered, &h).is_err());
+    }
+
+    /// Answers every request it is handed with 200.
+    #[derive(Clone)]
+    struct Accept;
 
+    impl Service<Request<()>> for Accept {
+        type Response = Response;
+        type Error = std::convert::Infallible;
+        type Future = std::future::Ready<Result<Response, Self::Error>>;
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> { Poll::Ready(Ok(())) }
+        fn call(&mut self, _req: Request<()>) -> Self::Future { std::future::ready(Ok(Response::new(axum::body::Body::empty()))) }
+    }
+
+    fn request(uri: &str, signed: Vec<(&'static str, String)>) -> Request<()> {
+        let mut req = Request::get(uri).header(HOST, "gw").body(()).unwrap();
+        for (k, v) in signed { req.headers_mut().insert(k, HeaderValue::from_str(&v).unwrap()); }
+        req
+    }
+
+    #[tokio::test]
+    async fn requests_that_fail_verification_are_refused() {
+        let mut svc = SigV4Layer::new(cfg()).layer(Accept);
+        let uri: Uri = "/bucket/key".parse().unwrap();
+        let status = |resp: Result<Response, _>| resp.unwrap().status();
+        assert_eq!(status(svc.call(request("/bucket/key", Vec::new())).await), StatusCode::FORBIDDEN);
+        let forged = sign_request(&Method::GET, &uri, "gw", "AKID", "guessed", "us-east-1");
+        assert_eq!(status(svc.call(request("/bucket/key", forged)).await), StatusCode::FORBIDDEN);
+        let signed = sign_request(&Method::GET, &uri, "gw", "AKID", "secret", "us-east-1");
+        assert_eq!(status(svc.call(request("/bucket/key", signed)).await), StatusCode::OK);
+        // Probes stay open, and AUTH_DISABLED lets everything through
+        assert_eq!(status(svc.call(request("/healthz", Vec::new())).await), StatusCode::OK);
+        let mut open = SigV4Layer::new(GatewayConfig { auth_disabled: true, ..cfg() }).layer(Accept);
+        assert_eq!(status(open.call(request("/bucket/key", Vec::new())).await), StatusCode::OK);
+    }
+}
@@ -1,224 +1,663 @@
-use axum::{extract::{Path, Query, State}, http::{StatusCode, header, HeaderMap}, response::{IntoResponse, Response}, body::Body};
+use axum::{extract::{Extension, Path, Query, State}, http::{StatusCode, header, HeaderMap}, response::{IntoResponse, Response}, body::{Body, Bytes}};
 use serde::Deserialize;
-use crate::{AppState};
-use crate::s3::{models::*, xml};
-use crate::storage::posix;
-use fs_err as fs;
-use std::path::PathBuf;
-use tokio::io::AsyncWriteExt;
-use md5::Context as Md5Context;
-use tokio::fs as tfs;
+use crate::{AppState, access_log, compression, notify, replication, sse};
+use crate::access_log::RequestOrigin;
+use crate::sse::{CustomerKey, Encryption, Protection, SealedPart, SseError};
+use crate::s3::{auth, models::*, xml};
+use crate::storage::{self, AttrsUpdate, ByteRange, ByteStream, DeferredEtag, ObjectAttrs, ObjectMeta, StorageError};
+use futures::StreamExt;
+use std::collections::BTreeMap;
 
 pub async fn service_root() -> impl IntoResponse {
     (StatusCode::OK, "")
 }
 
 pub async fn cors_preflight() -> impl IntoResponse {
-    let mut resp = Response::builder().status(StatusCode::NO_CONTENT)
+    Response::builder().status(StatusCode::NO_CONTENT)
         .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
         .header(header::ACCESS_CONTROL_ALLOW_METHODS, "GET, PUT, POST, DELETE, HEAD, OPTIONS")
         .header(header::ACCESS_CONTROL_ALLOW_HEADERS, "*")
-        .body(Body::empty()).unwrap();
-    resp
+        .body(Body::empty()).unwrap()
 }
 
-pub async fn bucket_list(State(state): State<AppState>) -> impl IntoResponse {
-    // Enumerate buckets under data_root
-    let mut buckets = Vec::new();
-    if let Ok(rd) = fs::read_dir(&state.cfg.data_root) {
-        for e in rd.flatten() {
-            if e.file_type().map(|t| t.is_dir()).unwrap_or(false) {
-                buckets.push(Bucket { Name: e.file_name().to_string_lossy().into_owned(), CreationDate: chrono::Utc::now().to_rfc3339() })
-            }
-        }
-    }
+/// Maps a backend failure onto the S3 error code and HTTP status clients expect.
+fn storage_error(e: StorageError) -> Response {
+    let status = match &e {
+        StorageError::NoSuchBucket | StorageError::NoSuchKey | StorageError::NoSuchUpload => StatusCode::NOT_FOUND,
+        StorageError::BucketAlreadyExists | StorageError::BucketNotEmpty => StatusCode::CONFLICT,
+        StorageError::InvalidRange => StatusCode::RANGE_NOT_SATISFIABLE,
+        StorageError::InvalidPart(_) | StorageError::InvalidPartOrder | StorageError::EntityTooSmall | StorageError::InvalidArgument(_) => StatusCode::BAD_REQUEST,
+        StorageError::AccessDenied => StatusCode::FORBIDDEN,
+        StorageError::SlowDown => StatusCode::SERVICE_UNAVAILABLE,
+        StorageError::Io(_) | StorageError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    Response::builder().status(status).body(Body::from(e.to_string())).unwrap()
+}
+
+/// Maps an encryption problem onto its S3 error.
+fn sse_error(e: SseError) -> Response {
+    let status = match &e {
+        SseError::WrongKey => StatusCode::FORBIDDEN,
+        SseError::Invalid(_) | SseError::Insecure | SseError::KeyRequired | SseError::NotEncrypted | SseError::UnknownKey(_) => StatusCode::BAD_REQUEST,
+        SseError::Keyring(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    Response::builder().status(status).body(Body::from(e.to_string())).unwrap()
+}
+
+/// The SSE-C key in the headers starting with `prefix`, which may only arrive over HTTPS.
+fn request_key(state: &AppState, headers: &HeaderMap, prefix: &str) -> Result<Option<CustomerKey>, SseError> {
+    let key = sse::customer_key(headers, prefix)?;
+    if key.is_some() { sse::require_secure(&state.cfg, headers)?; }
+    Ok(key)
+}
+
+/// How a new object in `bucket` is to be encrypted, given the request and the bucket's default.
+async fn new_protection(state: &AppState, bucket: &str, headers: &HeaderMap, customer_key: Option<CustomerKey>) -> Result<Option<Protection>, Response> {
+    let default: Option<ServerSideEncryptionConfiguration> = match storage::read_bucket_config(state.storage.as_ref(), bucket, sse::CONFIG_NAME).await {
+        Ok(d) => d,
+        Err(e) => return Err(Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap()),
+    };
+    sse::requested(headers, customer_key, default.as_ref()).map_err(sse_error)
+}
+
+/// The replication a new object takes, found before it is written so that it is stored marked
+/// `PENDING`. Objects encrypted with a customer key cannot be read by the replication worker.
+async fn pending_replication(state: &AppState, bucket: &str, key: &str, attrs: &mut ObjectAttrs, customer: bool) -> replication::Pending {
+    if customer { return replication::Pending::default(); }
+    let pending = replication::pending(&state.cfg, state.storage.as_ref(), bucket, key, Some(&attrs.tags)).await;
+    if !pending.is_empty() { attrs.replication_status = Some("PENDING".into()); }
+    pending
+}
+
+fn body_stream(body: Body) -> ByteStream {
+    Box::pin(body.into_data_stream().map(|r| r.map_err(std::io::Error::other)))
+}
+
+pub async fn bucket_list(State(state): State<AppState>) -> Response {
+    let buckets = match state.storage.list_buckets().await { Ok(b) => b, Err(e) => return storage_error(e) };
+    let buckets = buckets.into_iter().map(|b| Bucket { Name: b.name, CreationDate: b.created.to_rfc3339() }).collect();
     let result = ListBucketsResult { Owner: Owner { ID: "gateway".into(), DisplayName: "gateway".into() }, Buckets: Buckets { Bucket: buckets } };
     let xml_body = xml::to_xml(&result, "ListAllMyBucketsResult");
-    ([(header::CONTENT_TYPE, "application/xml")], xml_body)
+    ([(header::CONTENT_TYPE, "application/xml")], xml_body).into_response()
 }
 
 pub async fn head_bucket(State(state): State<AppState>, Path(bucket): Path<String>) -> impl IntoResponse {
-    let dir = posix::bucket_dir(&state.cfg, &bucket);
-    if dir.is_dir() { StatusCode::OK } else { StatusCode::NOT_FOUND }
+    if state.storage.head_bucket(&bucket).await.is_ok() { StatusCode::OK } else { StatusCode::NOT_FOUND }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BucketConfigQuery {
+    pub notification: Option<String>,
+    pub logging: Option<String>,
+    pub replication: Option<String>,
+    pub encryption: Option<String>,
+}
+
+pub async fn create_bucket(State(state): State<AppState>, Path(bucket): Path<String>, Query(q): Query<BucketConfigQuery>, body: Bytes) -> Response {
+    if q.notification.is_some() { return put_bucket_notification(&state, &bucket, &body).await; }
+    if q.logging.is_some() { return put_bucket_logging(&state, &bucket, &body).await; }
+    if q.replication.is_some() { return put_bucket_replication(&state, &bucket, &body).await; }
+    if q.encryption.is_some() { return put_bucket_encryption(&state, &bucket, &body).await; }
+    match state.storage.create_bucket(&bucket).await {
+        Ok(()) => Response::builder().status(StatusCode::OK).body(Body::empty()).unwrap(),
+        Err(e) => storage_error(e),
+    }
+}
+
+pub async fn delete_bucket(State(state): State<AppState>, Path(bucket): Path<String>, Query(q): Query<BucketConfigQuery>) -> Response {
+    if q.replication.is_some() {
+        if let Err(e) = state.storage.head_bucket(&bucket).await { return storage_error(e); }
+        return match storage::write_bucket_config::<ReplicationConfiguration>(state.storage.as_ref(), &bucket, replication::CONFIG_NAME, None).await {
+            Ok(()) => StatusCode::NO_CONTENT.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+    }
+    if q.encryption.is_some() {
+        if let Err(e) = state.storage.head_bucket(&bucket).await { return storage_error(e); }
+        return match storage::write_bucket_config::<ServerSideEncryptionConfiguration>(state.storage.as_ref(), &bucket, sse::CONFIG_NAME, None).await {
+            Ok(()) => StatusCode::NO_CONTENT.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+    }
+    match state.storage.delete_bucket(&bucket).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => storage_error(e),
+    }
 }
 
-pub async fn create_bucket(State(state): State<AppState>, Path(bucket): Path<String>) -> Response {
-    let dir = posix::bucket_dir(&state.cfg, &bucket);
-    if dir.exists() { return Response::builder().status(StatusCode::CONFLICT).body(Body::from("BucketAlreadyOwnedByYou")).unwrap(); }
-    if let Err(e) = tfs::create_dir_all(&dir).await { return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(); }
+async fn put_bucket_notification(state: &AppState, bucket: &str, body: &[u8]) -> Response {
+    if let Err(e) = state.storage.head_bucket(bucket).await { return storage_error(e); }
+    let nc: NotificationConfiguration = match xml::from_xml(body) { Ok(nc) => nc, Err(_) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("MalformedXML")).unwrap() };
+    if let Err(e) = notify::validate(&state.cfg, &nc) { return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from(format!("InvalidArgument: {e}"))).unwrap(); }
+    // An empty configuration turns notifications off
+    let value = if nc.QueueConfiguration.is_empty() { None } else { Some(&nc) };
+    if let Err(e) = storage::write_bucket_config(state.storage.as_ref(), bucket, notify::CONFIG_NAME, value).await { return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(); }
     Response::builder().status(StatusCode::OK).body(Body::empty()).unwrap()
 }
 
-pub async fn delete_bucket(State(state): State<AppState>, Path(bucket): Path<String>) -> impl IntoResponse {
-    let dir = posix::bucket_dir(&state.cfg, &bucket);
-    match tfs::remove_dir(&dir).await { // only empty bucket
-        Ok(_) => StatusCode::NO_CONTENT,
-        Err(_) => StatusCode::CONFLICT,
+async fn get_bucket_notification(state: &AppState, bucket: &str) -> Response {
+    if let Err(e) = state.storage.head_bucket(bucket).await { return storage_error(e); }
+    let nc: NotificationConfiguration = match storage::read_bucket_config(state.storage.as_ref(), bucket, notify::CONFIG_NAME).await {
+        Ok(nc) => nc.unwrap_or_default(),
+        Err(e) => return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(),
+    };
+    let body = xml::to_xml(&nc, "NotificationConfiguration");
+    Response::builder().status(StatusCode::OK).header(header::CONTENT_TYPE, "application/xml").body(Body::from(body)).unwrap()
+}
+
+async fn put_bucket_logging(state: &AppState, bucket: &str, body: &[u8]) -> Response {
+    if let Err(e) = state.storage.head_bucket(bucket).await { return storage_error(e); }
+    let status: BucketLoggingStatus = match xml::from_xml(body) { Ok(s) => s, Err(_) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("MalformedXML")).unwrap() };
+    if let Some(le) = &status.LoggingEnabled {
+        if state.storage.head_bucket(&le.TargetBucket).await.is_err() { return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("InvalidTargetBucketForLogging")).unwrap(); }
     }
+    if let Err(e) = storage::write_bucket_config(state.storage.as_ref(), bucket, access_log::CONFIG_NAME, status.LoggingEnabled.as_ref().map(|_| &status)).await { return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(); }
+    state.access_log.invalidate(bucket);
+    Response::builder().status(StatusCode::OK).body(Body::empty()).unwrap()
+}
+
+async fn get_bucket_logging(state: &AppState, bucket: &str) -> Response {
+    if let Err(e) = state.storage.head_bucket(bucket).await { return storage_error(e); }
+    let status: BucketLoggingStatus = match storage::read_bucket_config(state.storage.as_ref(), bucket, access_log::CONFIG_NAME).await {
+        Ok(s) => s.unwrap_or_default(),
+        Err(e) => return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(),
+    };
+    let body = xml::to_xml(&status, "BucketLoggingStatus");
+    Response::builder().status(StatusCode::OK).header(header::CONTENT_TYPE, "application/xml").body(Body::from(body)).unwrap()
+}
+
+async fn put_bucket_replication(state: &AppState, bucket: &str, body: &[u8]) -> Response {
+    if let Err(e) = state.storage.head_bucket(bucket).await { return storage_error(e); }
+    let rc: ReplicationConfiguration = match xml::from_xml(body) { Ok(rc) => rc, Err(_) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("MalformedXML")).unwrap() };
+    if let Err(e) = replication::validate(&state.cfg, &rc) { return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from(format!("InvalidRequest: {e}"))).unwrap(); }
+    if let Err(e) = storage::write_bucket_config(state.storage.as_ref(), bucket, replication::CONFIG_NAME, Some(&rc)).await { return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(); }
+    Response::builder().status(StatusCode::OK).body(Body::empty()).unwrap()
+}
+
+async fn get_bucket_replication(state: &AppState, bucket: &str) -> Response {
+    if let Err(e) = state.storage.head_bucket(bucket).await { return storage_error(e); }
+    let rc: ReplicationConfiguration = match storage::read_bucket_config(state.storage.as_ref(), bucket, replication::CONFIG_NAME).await {
+        Ok(Some(rc)) => rc,
+        Ok(None) => return Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("ReplicationConfigurationNotFoundError")).unwrap(),
+        Err(e) => return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(),
+    };
+    let body = xml::to_xml(&rc, "ReplicationConfiguration");
+    Response::builder().status(StatusCode::OK).header(header::CONTENT_TYPE, "application/xml").body(Body::from(body)).unwrap()
+}
+
+/// PutBucketEncryption. The default also covers multipart uploads, whose parts then keep the
+/// ETag of their ciphertext, unlike on S3.
+async fn put_bucket_encryption(state: &AppState, bucket: &str, body: &[u8]) -> Response {
+    if let Err(e) = state.storage.head_bucket(bucket).await { return storage_error(e); }
+    let conf: ServerSideEncryptionConfiguration = match xml::from_xml(body) { Ok(c) => c, Err(_) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("MalformedXML")).unwrap() };
+    if let Err(e) = sse::validate_default(&state.cfg, &conf) { return sse_error(e); }
+    if let Err(e) = storage::write_bucket_config(state.storage.as_ref(), bucket, sse::CONFIG_NAME, Some(&conf)).await { return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(); }
+    Response::builder().status(StatusCode::OK).body(Body::empty()).unwrap()
+}
+
+async fn get_bucket_encryption(state: &AppState, bucket: &str) -> Response {
+    if let Err(e) = state.storage.head_bucket(bucket).await { return storage_error(e); }
+    let conf: ServerSideEncryptionConfiguration = match storage::read_bucket_config(state.storage.as_ref(), bucket, sse::CONFIG_NAME).await {
+        Ok(Some(c)) => c,
+        Ok(None) => return Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("ServerSideEncryptionConfigurationNotFoundError")).unwrap(),
+        Err(e) => return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(),
+    };
+    let body = xml::to_xml(&conf, "ServerSideEncryptionConfiguration");
+    Response::builder().status(StatusCode::OK).header(header::CONTENT_TYPE, "application/xml").body(Body::from(body)).unwrap()
 }
 
 #[derive(Debug, Deserialize)]
-pub struct ListV2Query {
+pub struct ListObjectsQuery {
     #[serde(rename = "list-type")] pub list_type: Option<u8>,
     pub prefix: Option<String>,
     pub delimiter: Option<String>,
     #[serde(rename = "start-after")] pub start_after: Option<String>,
     #[serde(rename = "continuation-token")] pub continuation_token: Option<String>,
     #[serde(rename = "max-keys")] pub max_keys: Option<i32>,
+    pub marker: Option<String>,
+    #[serde(rename = "encoding-type")] pub encoding_type: Option<String>,
+    #[serde(rename = "fetch-owner")] pub fetch_owner: Option<bool>,
     pub location: Option<String>,
+    pub notification: Option<String>,
+    pub logging: Option<String>,
+    pub replication: Option<String>,
+    pub encryption: Option<String>,
 }
 
-pub async fn bucket_post(State(state): State<AppState>, Path(_bucket): Path<String>, Query(_q): Query<ListV2Query>) -> impl IntoResponse {
+pub async fn bucket_post(State(_state): State<AppState>, Path(_bucket): Path<String>, Query(_q): Query<ListObjectsQuery>) -> impl IntoResponse {
     // Placeholder for operations like ListObjectsV2 via POST (for AWS SDKs)
     (StatusCode::NOT_IMPLEMENTED, "NotImplemented")
 }
 
-pub async fn list_objects_v2(State(state): State<AppState>, Path(bucket): Path<String>, Query(q): Query<ListV2Query>) -> impl IntoResponse {
+pub async fn list_objects(State(state): State<AppState>, Path(bucket): Path<String>, Query(q): Query<ListObjectsQuery>) -> Response {
     // Handle GetBucketLocation
     if q.location.is_some() {
         let body = format!("<LocationConstraint xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">{}</LocationConstraint>", state.cfg.region);
-        return ([(header::CONTENT_TYPE, "application/xml")], body);
-    }
-    let prefix = q.prefix.unwrap_or_default();
-    let max_keys = q.max_keys.unwrap_or(1000).min(1000);
-    let base = posix::bucket_dir(&state.cfg, &bucket);
-    let mut contents = Vec::new();
-    let mut count = 0;
-    let delimiter = q.delimiter;
-    let mut common_prefixes: Vec<CommonPrefix> = Vec::new();
-
-    let start_after = q.start_after.unwrap_or_default();
-    let continuation = q.continuation_token.unwrap_or_default();
-    let start_marker = if !continuation.is_empty() { continuation } else { start_after };
-    if base.is_dir() {
-        let mut keys: Vec<(String, u64)> = Vec::new();
-        for entry in walkdir::WalkDir::new(&base).min_depth(0).max_depth(usize::MAX).into_iter().filter_map(Result::ok) {
-            if entry.file_type().is_file() {
-                let p = entry.path().to_path_buf();
-                if p.extension().map(|e| e == "json").unwrap_or(false) && p.file_name().and_then(|f| f.to_str()).map(|n| n.ends_with(".meta.json")).unwrap_or(false) { continue; }
-                let rel = p.strip_prefix(&base).unwrap().to_string_lossy().to_string();
-                if !rel.starts_with(&prefix) { continue; }
-                if !start_marker.is_empty() && rel <= start_marker { continue; }
-                if let Ok(md) = fs::metadata(&p) { keys.push((rel, md.len())); }
-            }
-        }
-        keys.sort();
-        for (rel, size) in keys.into_iter() {
-            if let Some(ref d) = delimiter {
-                if let Some(idx) = rel[prefix.len()..].find(d) {
-                    let cp = rel[..prefix.len()+idx+1].to_string();
-                    if !common_prefixes.iter().any(|c| c.Prefix == cp) { common_prefixes.push(CommonPrefix { Prefix: cp }); }
-                    continue;
-                }
-            }
-            contents.push(Object { Key: rel, LastModified: chrono::Utc::now().to_rfc3339(), ETag: String::new(), Size: size, StorageClass: "STANDARD".into() });
-            count += 1;
-            if count >= max_keys { break; }
-        }
+        return ([(header::CONTENT_TYPE, "application/xml")], body).into_response();
     }
-    let is_truncated = count >= max_keys;
-    let next_cont = if is_truncated { contents.last().map(|o| o.Key.clone()) } else { None };
-    let out = ListObjectsV2Result { Name: bucket, Prefix: Some(prefix), Delimiter: delimiter.clone(), KeyCount: contents.len() as i32, MaxKeys: max_keys, IsTruncated: is_truncated, Contents: contents, CommonPrefixes: if common_prefixes.is_empty() { None } else { Some(common_prefixes) }, NextContinuationToken: next_cont };
-    let body = xml::to_xml(&out, "ListBucketResult");
-    ([(header::CONTENT_TYPE, "application/xml")], body)
+    if q.notification.is_some() { return get_bucket_notification(&state, &bucket).await; }
+    if q.logging.is_some() { return get_bucket_logging(&state, &bucket).await; }
+    if q.replication.is_some() { return get_bucket_replication(&state, &bucket).await; }
+    if q.encryption.is_some() { return get_bucket_encryption(&state, &bucket).await; }
+
+    let v2 = q.list_type == Some(2);
+    let url_encoding = match q.encoding_type.as_deref() {
+        None => false,
+        Some("url") => true,
+        Some(_) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("InvalidArgument: Invalid Encoding Method specified in Request")).unwrap(),
+    };
+    let prefix = q.prefix.clone().unwrap_or_default();
+    let max_keys = q.max_keys.unwrap_or(1000).clamp(0, 1000);
+    let marker = if v2 {
+        match q.continuation_token.as_deref() {
+            Some(token) => match decode_continuation_token(token) {
+                Some(m) => m,
+                None => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("InvalidArgument: The continuation token provided is incorrect")).unwrap(),
+            },
+            None => q.start_after.clone().unwrap_or_default(),
+        }
+    } else {
+        q.marker.clone().unwrap_or_default()
+    };
+
+    let delimiter = q.delimiter.as_deref().filter(|d| !d.is_empty());
+    let page = match state.storage.list_objects(&bucket, &prefix, delimiter, &marker, max_keys as usize).await {
+        Ok(page) => page,
+        Err(e) => return storage_error(e),
+    };
+    let owner = || Owner { ID: state.cfg.access_key.clone(), DisplayName: state.cfg.access_key.clone() };
+    let with_owner = !v2 || q.fetch_owner.unwrap_or(false);
+    let enc = |s: &str| if url_encoding { auth::encode_key_path(s) } else { s.to_string() };
+    let contents: Vec<Object> = page.objects.into_iter().map(|o| Object {
+        Key: enc(&o.key),
+        LastModified: o.last_modified.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        ETag: o.etag,
+        Size: o.size,
+        StorageClass: "STANDARD".into(),
+        Owner: with_owner.then(owner),
+    }).collect();
+    let common_prefixes: Vec<CommonPrefix> = page.common_prefixes.iter().map(|p| CommonPrefix { Prefix: enc(p) }).collect();
+    let encoding_type = url_encoding.then(|| "url".to_string());
+
+    let body = if v2 {
+        let out = ListObjectsV2Result {
+            Name: bucket,
+            Prefix: enc(&prefix),
+            Delimiter: q.delimiter.as_deref().map(enc),
+            EncodingType: encoding_type,
+            KeyCount: (contents.len() + common_prefixes.len()) as i32,
+            MaxKeys: max_keys,
+            IsTruncated: page.next_marker.is_some(),
+            ContinuationToken: q.continuation_token.clone(),
+            NextContinuationToken: page.next_marker.as_deref().map(encode_continuation_token),
+            StartAfter: q.start_after.as_deref().map(enc),
+            Contents: contents,
+            CommonPrefixes: common_prefixes,
+        };
+        xml::to_xml(&out, "ListBucketResult")
+    } else {
+        let out = ListObjectsV1Result {
+            Name: bucket,
+            Prefix: enc(&prefix),
+            Marker: enc(&marker),
+            // S3 only returns NextMarker when a delimiter is used; otherwise clients resume from the last key
+            NextMarker: page.next_marker.as_deref().filter(|_| q.delimiter.is_some()).map(enc),
+            Delimiter: q.delimiter.as_deref().map(enc),
+            EncodingType: encoding_type,
+            MaxKeys: max_keys,
+            IsTruncated: page.next_marker.is_some(),
+            Contents: contents,
+            CommonPrefixes: common_prefixes,
+        };
+        xml::to_xml(&out, "ListBucketResult")
+    };
+    ([(header::CONTENT_TYPE, "application/xml")], body).into_response()
+}
+
+fn encode_continuation_token(marker: &str) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD.encode(marker)
+}
+
+fn decode_continuation_token(token: &str) -> Option<String> {
+    use base64::Engine;
+    let bytes = base64::engine::general_purpose::STANDARD.decode(token).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Response headers describing an object, shared by GET and HEAD.
+fn object_headers(mut resp: axum::http::response::Builder, meta: &ObjectMeta) -> axum::http::response::Builder {
+    resp = resp
+        .header(header::CONTENT_TYPE, &meta.attrs.content_type)
+        .header(header::LAST_MODIFIED, meta.last_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+        .header(header::ACCEPT_RANGES, "bytes");
+    if !meta.etag.is_empty() { resp = resp.header(header::ETAG, &meta.etag); }
+    for (k, v) in &meta.attrs.user_meta { resp = resp.header(format!("x-amz-meta-{k}"), v); }
+    if let Some(status) = &meta.attrs.replication_status { resp = resp.header("x-amz-replication-status", status); }
+    sse_headers(resp, &meta.attrs)
 }
 
-pub async fn head_object(State(state): State<AppState>, Path((bucket, key)): Path<(String, String)>) -> impl IntoResponse {
-    let (data, meta) = posix::object_paths(&state.cfg, &bucket, &key);
-    if data.is_file() {
-        if let Ok(m) = fs::metadata(&data) { return (StatusCode::OK, [(header::CONTENT_LENGTH, m.len().to_string())]).into_response(); }
-        return StatusCode::OK.into_response();
+/// Reports how an object is encrypted, as S3 does on every response about one.
+fn sse_headers(mut resp: axum::http::response::Builder, attrs: &ObjectAttrs) -> axum::http::response::Builder {
+    let Some(enc) = &attrs.sse else { return resp };
+    if let Some(md5) = &enc.customer_key_md5 {
+        resp = resp
+            .header(format!("{}algorithm", sse::CUSTOMER), sse::ALGORITHM)
+            .header(format!("{}key-MD5", sse::CUSTOMER), md5);
+    }
+    if let Some(managed) = &enc.managed {
+        resp = resp.header(sse::SERVER_SIDE, managed.scheme.as_str());
+        if managed.scheme == sse::Scheme::Kms { resp = resp.header(sse::KMS_KEY_ID, &managed.wrapped.key_id); }
     }
-    StatusCode::NOT_FOUND.into_response()
+    resp
+}
+
+pub async fn head_object(State(state): State<AppState>, Path((bucket, key)): Path<(String, String)>, headers: HeaderMap) -> Response {
+    let meta = match state.storage.head_object(&bucket, &key).await { Ok(m) => m, Err(e) => return storage_error(e) };
+    let customer_key = match request_key(&state, &headers, sse::CUSTOMER) { Ok(k) => k, Err(e) => return sse_error(e) };
+    if let Err(e) = sse::check_access(meta.attrs.sse.as_ref(), customer_key.as_ref()) { return sse_error(e); }
+    let size = match meta.attrs.logical_size(meta.size) { Ok(s) => s, Err(e) => return storage_error(e) };
+    object_headers(Response::builder().status(StatusCode::OK), &meta)
+        .header(header::CONTENT_LENGTH, size)
+        .body(Body::empty())
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
 }
 
-pub async fn delete_object(State(state): State<AppState>, Path((bucket, key)): Path<(String, String)>) -> impl IntoResponse {
-    let (data, meta) = posix::object_paths(&state.cfg, &bucket, &key);
-    let _ = tfs::remove_file(&data).await;
-    let _ = tfs::remove_file(&meta).await;
-    StatusCode::NO_CONTENT
+#[derive(Debug, Deserialize)]
+pub struct ObjectQuery {
+    pub uploads: Option<String>,
+    #[serde(rename = "uploadId")] pub upload_id: Option<String>,
+    #[serde(rename = "partNumber")] pub part_number: Option<u32>,
+}
+
+pub async fn delete_object(State(state): State<AppState>, Extension(origin): Extension<RequestOrigin>, Path((bucket, key)): Path<(String, String)>, Query(q): Query<ObjectQuery>) -> Response {
+    // AbortMultipartUpload
+    if let Some(upload_id) = &q.upload_id {
+        return match state.storage.abort_multipart_upload(&bucket, upload_id).await {
+            Ok(()) => StatusCode::NO_CONTENT.into_response(),
+            Err(e) => storage_error(e),
+        };
+    }
+    match state.storage.delete_object(&bucket, &key).await {
+        Ok(true) => {
+            notify::emit(&state.cfg, state.storage.as_ref(), &origin, &bucket, &key, "ObjectRemoved:Delete", 0, "").await;
+            replication::enqueue_delete(&state.cfg, state.storage.as_ref(), &bucket, &key).await;
+        }
+        Ok(false) => {}
+        Err(e) => return storage_error(e),
+    }
+    StatusCode::NO_CONTENT.into_response()
 }
 
-pub async fn put_object(State(state): State<AppState>, Path((bucket, key)): Path<(String, String)>, headers: HeaderMap, body: Body) -> Response {
-    use futures::StreamExt;
+pub async fn put_object(State(state): State<AppState>, Extension(origin): Extension<RequestOrigin>, Path((bucket, key)): Path<(String, String)>, Query(q): Query<ObjectQuery>, headers: HeaderMap, body: Body) -> Response {
+    if let (Some(upload_id), Some(part_number)) = (&q.upload_id, q.part_number) {
+        return upload_part(&state, &bucket, upload_id, part_number, &headers, body).await;
+    }
+    let customer_key = match request_key(&state, &headers, sse::CUSTOMER) { Ok(k) => k, Err(e) => return sse_error(e) };
+    let protection = match new_protection(&state, &bucket, &headers, customer_key).await { Ok(p) => p, Err(resp) => return resp };
     // Handle CopyObject
     if let Some(src) = headers.get("x-amz-copy-source").and_then(|v| v.to_str().ok()) {
+        let src = percent_encoding::percent_decode_str(src.split('?').next().unwrap_or_default()).decode_utf8_lossy().into_owned();
         let src = src.trim_start_matches('/');
         let (src_bucket, src_key) = match src.split_once('/') { Some((b,k)) => (b.to_string(), k.to_string()), None => (bucket.clone(), src.to_string()) };
-        let (src_data, src_meta) = posix::object_paths(&state.cfg, &src_bucket, &src_key);
-        let (dst_data, dst_meta) = posix::object_paths(&state.cfg, &bucket, &key);
-        if !src_data.is_file() { return Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("NoSuchKey")).unwrap(); }
-        if let Err(e) = posix::ensure_parent_dirs(&dst_data).await { return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(); }
-        if let Err(e) = tfs::copy(&src_data, &dst_data).await { return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(); }
-        if let Ok(bytes) = posix::read_file(&src_meta).await { let _ = posix::write_file_atomic(&dst_meta, &bytes).await; }
-        // Compute ETag of dest
-        let data = match posix::read_file(&dst_data).await { Ok(d) => d, Err(e) => return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap() };
-        let mut hasher = Md5Context::new(); hasher.consume(&data); let etag = format!("\"{:x}\"", hasher.compute());
-        let xml_body = format!("<CopyObjectResult><LastModified>{}</LastModified><ETag>{}</ETag></CopyObjectResult>", chrono::Utc::now().to_rfc3339(), etag);
-        return Response::builder().status(StatusCode::OK).header(header::CONTENT_TYPE, "application/xml").body(Body::from(xml_body)).unwrap();
-    }
-    let (data, meta) = posix::object_paths(&state.cfg, &bucket, &key);
-    if let Err(e) = posix::ensure_parent_dirs(&data).await { return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(); }
-    let mut f = match tfs::File::create(&data).await { Ok(f) => f, Err(e) => return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap() };
-    let mut stream = body.into_data_stream();
-    let mut hasher = Md5Context::new();
-    while let Some(chunk) = stream.next().await { match chunk { Ok(bytes) => { hasher.consume(&bytes); if let Err(e) = f.write_all(&bytes).await { return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(e.to_string())).unwrap(); } }, Err(e) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from(e.to_string())).unwrap() } }
-    let _ = f.flush().await;
-    let etag = format!("\"{:x}\"", hasher.compute());
-    let meta_body = serde_json::json!({"etag": etag, "content_type": "application/octet-stream"});
-    let _ = posix::write_file_atomic(&meta, meta_body.to_string().as_bytes()).await;
-    Response::builder().status(StatusCode::OK).header(header::ETAG, etag).body(Body::empty()).unwrap()
-}
-
-pub async fn get_object(State(state): State<AppState>, Path((bucket, key)): Path<(String, String)>, headers: HeaderMap) -> impl IntoResponse {
-    let (data, meta) = posix::object_paths(&state.cfg, &bucket, &key);
-    if !data.is_file() { return StatusCode::NOT_FOUND.into_response(); }
-    let mut file = tfs::File::open(&data).await.unwrap();
-    let metadata = file.metadata().await.ok();
-    use tokio::io::{AsyncSeekExt, AsyncReadExt};
-    let mut status = StatusCode::OK;
-    let mut len_header: Option<u64> = None;
-    let mut stream_body: Body;
-    if let Some(range_val) = headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
-        if let Some(r) = range_val.strip_prefix("bytes=") {
-            if let Some((start_s, end_s)) = r.split_once('-') {
-                if let Ok(mut start) = start_s.parse::<u64>() {
-                    let total = metadata.as_ref().map(|m| m.len()).unwrap_or(0);
-                    let end = if end_s.is_empty() { total - 1 } else { end_s.parse::<u64>().unwrap_or(total - 1) };
-                    if start <= end && end < total {
-                        status = StatusCode::PARTIAL_CONTENT;
-                        let len = end - start + 1;
-                        len_header = Some(len);
-                        let _ = file.seek(std::io::SeekFrom::Start(start)).await;
-                        let limited = file.take(len);
-                        let stream = tokio_util::io::ReaderStream::new(limited);
-                        stream_body = Body::from_stream(stream);
-                        let resp = Response::builder()
-                            .status(status)
-                            .header(header::ACCEPT_RANGES, "bytes")
-                            .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, total))
-                            .header(header::CONTENT_LENGTH, len)
-                            .header(header::CONTENT_TYPE, "application/octet-stream")
-                            .body(stream_body)
-                            .unwrap();
-                        return resp;
-                    }
-                }
-            }
-        }
+        return copy_object(&state, &origin, &headers, &src_bucket, &src_key, &bucket, &key, protection).await;
     }
-    let stream = tokio_util::io::ReaderStream::new(file);
-    stream_body = Body::from_stream(stream);
-    let mut headers = vec![(header::CONTENT_TYPE, "application/octet-stream".to_string())];
-    if let Ok(bytes) = posix::read_file(&meta).await { if let Ok(v) = serde_json::from_slice::<serde_json::Value>(&bytes) { if let Some(etag) = v.get("etag").and_then(|s| s.as_str()) { headers.push((header::ETAG, etag.to_string())); } } }
-    let mut resp = Response::builder().status(status).header(header::CONTENT_TYPE, headers.iter().find(|(h,_)| *h==header::CONTENT_TYPE).unwrap().1.as_str());
-    if let Some(md) = metadata { resp = resp.header(header::CONTENT_LENGTH, len_header.unwrap_or(md.len())); }
-    let resp = resp.body(stream_body).unwrap();
-    resp
+    let mut attrs = ObjectAttrs {
+        content_type: headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("application/octet-stream").to_string(),
+        user_meta: parse_user_meta(&headers),
+        tags: parse_tagging(&headers),
+        // Objects written by another gateway's replication worker
+        replication_status: headers.get("x-amz-replication-status").filter(|v| *v == "REPLICA").map(|_| "REPLICA".to_string()),
+        sse: None,
+        compression: None,
+    };
+    // A body of unknown length is stored as it comes
+    let size = headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+    let level = match size {
+        Some(_) => compression::level_for(state.storage.as_ref(), &bucket, &key, &attrs.content_type).await,
+        None => None,
+    };
+    let customer = matches!(protection, Some(Protection::Customer(_)));
+    let pending = pending_replication(&state, &bucket, &key, &mut attrs, customer).await;
+    let meta = match store(&state, &bucket, &key, attrs, body_stream(body), size.zip(level), protection.as_ref()).await { Ok(m) => m, Err(resp) => return resp };
+    notify::emit(&state.cfg, state.storage.as_ref(), &origin, &bucket, &key, "ObjectCreated:Put", meta.attrs.logical_size(meta.size).unwrap_or(meta.size), &meta.etag).await;
+    pending.enqueue(&state.cfg).await;
+    sse_headers(Response::builder().status(StatusCode::OK), &meta.attrs).header(header::ETAG, meta.etag).body(Body::empty()).unwrap()
 }
 
-pub async fn object_post(State(_state): State<AppState>, Path((_bucket, _key)): Path<(String, String)>) -> impl IntoResponse {
-    (StatusCode::NOT_IMPLEMENTED, "NotImplemented")
+/// Writes a new object, compressed at the level given with its `size` if there is one and then
+/// encrypted as `protection` says.
+async fn store(state: &AppState, bucket: &str, key: &str, mut attrs: ObjectAttrs, mut body: ByteStream, compress: Option<(u64, i32)>, protection: Option<&Protection>) -> Result<ObjectMeta, Response> {
+    let etag = DeferredEtag::default();
+    // SSE-C objects keep the MD5 of their stored bytes as ETag, which says nothing of the content;
+    // the others get the MD5 of what the client sent, as on S3
+    let customer = matches!(protection, Some(Protection::Customer(_)));
+    if let Some((size, level)) = compress {
+        let reported = if customer { DeferredEtag::default() } else { etag.clone() };
+        body = compression::compress(body, level, size, reported);
+        attrs.compression = Some(compression::Compression { size, frame: compression::FRAME });
+    } else if protection.is_some() && !customer {
+        body = storage::md5_etag(body, etag.clone());
+    }
+    if let Some(protection) = protection {
+        let (enc, secret) = sse::seal_new(&state.cfg, protection).map_err(sse_error)?;
+        body = sse::encrypt(body, &secret, &enc, 0);
+        attrs.sse = Some(enc);
+    }
+    state.storage.put_object_with_etag(bucket, key, body, attrs, etag).await.map_err(storage_error)
+}
+
+/// CopyObject. Copies involving encryption or compression are streamed through the gateway,
+/// since the data has to be decoded as the source was stored and encoded as the copy will be.
+#[allow(clippy::too_many_arguments)]
+async fn copy_object(state: &AppState, origin: &RequestOrigin, headers: &HeaderMap, src_bucket: &str, src_key: &str, bucket: &str, key: &str, protection: Option<Protection>) -> Response {
+    let source_key = match request_key(state, headers, sse::COPY_SOURCE) { Ok(k) => k, Err(e) => return sse_error(e) };
+    let src = match state.storage.head_object(src_bucket, src_key).await { Ok(m) => m, Err(e) => return storage_error(e) };
+    if let Err(e) = sse::check_access(src.attrs.sse.as_ref(), source_key.as_ref()) { return sse_error(e); }
+    let mut attrs = src.attrs.clone();
+    // The copy is a new object as far as replication is concerned
+    attrs.replication_status = None;
+    // and is encrypted as the request and destination bucket say, not as the source was
+    attrs.sse = None;
+    let size = match src.attrs.logical_size(src.size) { Ok(s) => s, Err(e) => return storage_error(e) };
+    let level = compression::level_for(state.storage.as_ref(), bucket, key, &attrs.content_type).await;
+    let pending = pending_replication(state, bucket, key, &mut attrs, matches!(protection, Some(Protection::Customer(_)))).await;
+    // Data that needs no re-encoding, which includes sources already compressed, is copied as it is
+    let copied = if src.attrs.sse.is_none() && protection.is_none() && (level.is_none() || src.attrs.compression.is_some()) {
+        state.storage.copy_object(src_bucket, src_key, bucket, key, attrs).await.map_err(storage_error)
+    } else {
+        let secret = match &src.attrs.sse {
+            Some(enc) => match sse::object_secret(&state.cfg, enc, source_key.as_ref()) { Ok(s) => Some(s), Err(e) => return sse_error(e) },
+            None => None,
+        };
+        let body = match compression::read_all(state.storage.as_ref(), src_bucket, src_key, &src, secret.as_ref()).await { Ok(b) => b, Err(e) => return storage_error(e) };
+        attrs.compression = None;
+        store(state, bucket, key, attrs, body, level.map(|l| (size, l)), protection.as_ref()).await
+    };
+    let meta = match copied { Ok(m) => m, Err(resp) => return resp };
+    notify::emit(&state.cfg, state.storage.as_ref(), origin, bucket, key, "ObjectCreated:Copy", meta.attrs.logical_size(meta.size).unwrap_or(meta.size), &meta.etag).await;
+    pending.enqueue(&state.cfg).await;
+    let xml_body = format!("<CopyObjectResult><LastModified>{}</LastModified><ETag>{}</ETag></CopyObjectResult>", meta.last_modified.to_rfc3339(), meta.etag);
+    sse_headers(Response::builder().status(StatusCode::OK), &meta.attrs).header(header::CONTENT_TYPE, "application/xml").body(Body::from(xml_body)).unwrap()
+}
+
+/// Parses the URL-encoded `x-amz-tagging` request header.
+fn parse_tagging(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers.get("x-amz-tagging").and_then(|v| v.to_str().ok())
+        .map(|v| form_urlencoded::parse(v.as_bytes()).into_owned().collect())
+        .unwrap_or_default()
+}
+
+/// Collects `x-amz-meta-*` request headers, keyed by the lowercase suffix.
+fn parse_user_meta(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers.iter()
+        .filter_map(|(k, v)| Some((k.as_str().strip_prefix("x-amz-meta-")?.to_string(), v.to_str().ok()?.to_string())))
+        .collect()
 }
 
+pub async fn get_object(State(state): State<AppState>, Path((bucket, key)): Path<(String, String)>, Query(q): Query<ObjectQuery>, headers: HeaderMap) -> Response {
+    if let Some(upload_id) = &q.upload_id { return list_parts(&state, &bucket, &key, upload_id).await; }
+    // Malformed or multi-range headers are ignored and the whole object is returned, as S3 does
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok()).and_then(ByteRange::parse);
+    let customer_key = match request_key(&state, &headers, sse::CUSTOMER) { Ok(k) => k, Err(e) => return sse_error(e) };
+    let head = match customer_key {
+        Some(_) => match state.storage.head_object(&bucket, &key).await { Ok(m) => m, Err(e) => return storage_error(e) },
+        None => match state.storage.get_object(&bucket, &key, range).await {
+            Ok(obj) if obj.meta.attrs.sse.is_none() && obj.meta.attrs.compression.is_none() => return object_response(&obj.meta, obj.range, obj.meta.size, obj.body),
+            Ok(obj) => obj.meta,
+            // The range may only lie beyond the end of the stored data, which is compressed
+            Err(StorageError::InvalidRange) => match state.storage.head_object(&bucket, &key).await { Ok(m) => m, Err(e) => return storage_error(e) },
+            Err(e) => return storage_error(e),
+        },
+    };
+    get_decoded(&state, &bucket, &key, range, head, customer_key.as_ref()).await
+}
+
+/// GET of an object described by `head` whose stored data is encrypted or compressed: reads the
+/// chunks and frames covering the requested range and decodes them.
+async fn get_decoded(state: &AppState, bucket: &str, key: &str, range: Option<ByteRange>, head: ObjectMeta, customer_key: Option<&CustomerKey>) -> Response {
+    if let Err(e) = sse::check_access(head.attrs.sse.as_ref(), customer_key) { return sse_error(e); }
+    let secret = match &head.attrs.sse {
+        Some(enc) => match sse::object_secret(&state.cfg, enc, customer_key) { Ok(s) => Some(s), Err(e) => return sse_error(e) },
+        None => None,
+    };
+    let size = match head.attrs.logical_size(head.size) { Ok(s) => s, Err(e) => return storage_error(e) };
+    let plain = match range.map(|r| r.resolve(size)) {
+        Some(None) => return storage_error(StorageError::InvalidRange),
+        Some(Some(r)) => Some(r),
+        None => None,
+    };
+    let Some(wanted) = plain.or((size > 0).then(|| (0, size - 1))) else {
+        return object_response(&head, None, 0, storage::bytes_stream(Bytes::new()));
+    };
+    match compression::read_content(state.storage.as_ref(), bucket, key, &head, secret.as_ref(), wanted).await {
+        Ok(body) => object_response(&head, plain, size, body),
+        Err(e) => storage_error(e),
+    }
+}
 
+/// A GET response carrying `body`, which holds `range` of an object of `size` bytes, or all of it.
+fn object_response(meta: &ObjectMeta, range: Option<(u64, u64)>, size: u64, body: ByteStream) -> Response {
+    let resp = object_headers(Response::builder(), meta);
+    let resp = match range {
+        Some((start, end)) => resp
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size))
+            .header(header::CONTENT_LENGTH, end - start + 1),
+        None => resp.status(StatusCode::OK).header(header::CONTENT_LENGTH, size),
+    };
+    resp.body(Body::from_stream(body)).unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+pub async fn object_post(State(state): State<AppState>, Extension(origin): Extension<RequestOrigin>, Path((bucket, key)): Path<(String, String)>, Query(q): Query<ObjectQuery>, headers: HeaderMap, body: Bytes) -> Response {
+    if q.uploads.is_some() { return create_multipart_upload(&state, &bucket, &key, &headers).await; }
+    if let Some(upload_id) = &q.upload_id { return complete_multipart_upload(&state, &origin, &bucket, &key, upload_id, &body).await; }
+    (StatusCode::NOT_IMPLEMENTED, "NotImplemented").into_response()
+}
+
+fn xml_response(body: String) -> Response {
+    Response::builder().status(StatusCode::OK).header(header::CONTENT_TYPE, "application/xml").body(Body::from(body)).unwrap()
+}
+
+/// CreateMultipartUpload. An encrypted upload gets its salt, and its data key if the gateway
+/// manages it, right away; every part is then sealed under them as it arrives.
+async fn create_multipart_upload(state: &AppState, bucket: &str, key: &str, headers: &HeaderMap) -> Response {
+    let customer_key = match request_key(state, headers, sse::CUSTOMER) { Ok(k) => k, Err(e) => return sse_error(e) };
+    let protection = match new_protection(state, bucket, headers, customer_key).await { Ok(p) => p, Err(resp) => return resp };
+    let mut attrs = ObjectAttrs {
+        content_type: headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("application/octet-stream").to_string(),
+        user_meta: parse_user_meta(headers),
+        tags: parse_tagging(headers),
+        ..Default::default()
+    };
+    if let Some(protection) = &protection {
+        match sse::seal_new(&state.cfg, protection) { Ok((enc, _)) => attrs.sse = Some(enc), Err(e) => return sse_error(e) }
+    }
+    match state.storage.create_multipart_upload(bucket, key, attrs.clone()).await {
+        Ok(upload_id) => sse_headers(Response::builder().status(StatusCode::OK), &attrs)
+            .header(header::CONTENT_TYPE, "application/xml")
+            .body(Body::from(xml::to_xml(&InitiateMultipartUploadResult { Bucket: bucket.to_string(), Key: key.to_string(), UploadId: upload_id }, "InitiateMultipartUploadResult")))
+            .unwrap(),
+        Err(e) => storage_error(e),
+    }
+}
+
+/// UploadPart. Parts of an SSE-C upload need the upload's key, as on S3; those of other
+/// encrypted uploads are sealed under the data key the upload was created with.
+async fn upload_part(state: &AppState, bucket: &str, upload_id: &str, part_number: u32, headers: &HeaderMap, body: Body) -> Response {
+    let customer_key = match request_key(state, headers, sse::CUSTOMER) { Ok(k) => k, Err(e) => return sse_error(e) };
+    if !(1..=10_000).contains(&part_number) {
+        return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("InvalidArgument: Part number must be an integer between 1 and 10000")).unwrap();
+    }
+    let attrs = match state.storage.upload_attrs(bucket, upload_id).await { Ok(a) => a, Err(e) => return storage_error(e) };
+    if let Err(e) = sse::check_access(attrs.sse.as_ref(), customer_key.as_ref()) { return sse_error(e); }
+    let mut body = body_stream(body);
+    if let Some(enc) = &attrs.sse {
+        let secret = match sse::object_secret(&state.cfg, enc, customer_key.as_ref()) { Ok(s) => s, Err(e) => return sse_error(e) };
+        body = sse::encrypt(body, &secret, enc, part_number);
+    }
+    match state.storage.upload_part(bucket, upload_id, part_number, body).await {
+        Ok(etag) => sse_headers(Response::builder().status(StatusCode::OK), &attrs).header(header::ETAG, etag).body(Body::empty()).unwrap(),
+        Err(e) => storage_error(e),
+    }
+}
+
+async fn list_parts(state: &AppState, bucket: &str, key: &str, upload_id: &str) -> Response {
+    let attrs = match state.storage.upload_attrs(bucket, upload_id).await { Ok(a) => a, Err(e) => return storage_error(e) };
+    let parts = match state.storage.list_parts(bucket, upload_id).await { Ok(p) => p, Err(e) => return storage_error(e) };
+    // Encrypted parts are listed with the size the client uploaded
+    let size = |stored: u64| if attrs.sse.is_some() { sse::part_size(stored).unwrap_or(stored) } else { stored };
+    let result = ListPartsResult {
+        Bucket: bucket.to_string(),
+        Key: key.to_string(),
+        UploadId: upload_id.to_string(),
+        Part: parts.into_iter().map(|p| Part { PartNumber: p.part_number, LastModified: p.last_modified.to_rfc3339(), ETag: p.etag, Size: size(p.size) }).collect(),
+    };
+    xml_response(xml::to_xml(&result, "ListPartsResult"))
+}
+
+async fn complete_multipart_upload(state: &AppState, origin: &RequestOrigin, bucket: &str, key: &str, upload_id: &str, body: &[u8]) -> Response {
+    let req: CompleteMultipartUpload = match xml::from_xml(body) {
+        Ok(r) => r,
+        Err(_) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("MalformedXML")).unwrap(),
+    };
+    let parts: Vec<(u32, String)> = req.Part.into_iter().map(|p| (p.PartNumber, p.ETag)).collect();
+    let mut upload = match state.storage.upload_attrs(bucket, upload_id).await { Ok(a) => a, Err(e) => return storage_error(e) };
+    let sealed = match sealed_parts(state, bucket, upload_id, &upload, &parts).await { Ok(s) => s, Err(e) => return storage_error(e) };
+    let customer = upload.sse.as_ref().is_some_and(Encryption::is_customer);
+    let pending = pending_replication(state, bucket, key, &mut upload, customer).await;
+    let status = upload.replication_status;
+    let update: AttrsUpdate = Box::new(move |attrs: &mut ObjectAttrs| {
+        if let (Some(enc), Some(sealed)) = (&mut attrs.sse, sealed) { enc.parts = sealed; }
+        if status.is_some() { attrs.replication_status = status; }
+    });
+    let meta = match state.storage.complete_multipart_upload(bucket, key, upload_id, &parts, Some(update)).await { Ok(m) => m, Err(e) => return storage_error(e) };
+    notify::emit(&state.cfg, state.storage.as_ref(), origin, bucket, key, "ObjectCreated:CompleteMultipartUpload", meta.attrs.logical_size(meta.size).unwrap_or(meta.size), &meta.etag).await;
+    pending.enqueue(&state.cfg).await;
+    let result = CompleteMultipartUploadResult {
+        Location: format!("/{bucket}/{}", auth::encode_key_path(key)),
+        Bucket: bucket.to_string(),
+        Key: key.to_string(),
+        ETag: meta.etag.clone(),
+    };
+    sse_headers(Response::builder().status(StatusCode::OK), &meta.attrs)
+        .header(header::CONTENT_TYPE, "application/xml")
+        .body(Body::from(xml::to_xml(&result, "CompleteMultipartUploadResult")))
+        .unwrap()
+}
+
+/// For an encrypted upload, the plaintext size of every part being completed, which the object
+/// records since its separately sealed parts could not be told apart without it.
+async fn sealed_parts(state: &AppState, bucket: &str, upload_id: &str, upload: &ObjectAttrs, parts: &[(u32, String)]) -> Result<Option<Vec<SealedPart>>, StorageError> {
+    if upload.sse.is_none() { return Ok(None); }
+    let stored = state.storage.list_parts(bucket, upload_id).await?;
+    let mut sealed = Vec::with_capacity(parts.len());
+    for (number, etag) in parts {
+        // A part not stored under this ETag fails the completion itself. One that is cannot
+        // change size before it does, since other content would get another ETag.
+        let Some(part) = stored.iter().find(|p| p.part_number == *number && p.etag.trim_matches('"') == etag.trim_matches('"')) else { continue };
+        let size = sse::part_size(part.size).ok_or_else(|| anyhow::anyhow!("encrypted part {number} of upload {upload_id} is corrupt"))?;
+        sealed.push(SealedPart { number: *number, size });
+    }
+    Ok(Some(sealed))
+}
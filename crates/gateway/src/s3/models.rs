@@ -1,3 +1,5 @@
+#![allow(non_snake_case)]
+
 use serde::{Serialize, Deserialize};
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -6,7 +8,7 @@ pub struct ListBucketsResult {
     pub Buckets: Buckets,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Owner { pub ID: String, pub DisplayName: String }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -18,14 +20,43 @@ pub struct Bucket { pub Name: String, pub CreationDate: String }
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct ListObjectsV2Result {
     pub Name: String,
-    pub Prefix: Option<String>,
+    pub Prefix: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub Delimiter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub EncodingType: Option<String>,
     pub KeyCount: i32,
     pub MaxKeys: i32,
     pub IsTruncated: bool,
-    pub Contents: Vec<Object>,
-    pub CommonPrefixes: Option<Vec<CommonPrefix>>,    
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ContinuationToken: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub NextContinuationToken: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub StartAfter: Option<String>,
+    #[serde(default)]
+    pub Contents: Vec<Object>,
+    #[serde(default)]
+    pub CommonPrefixes: Vec<CommonPrefix>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ListObjectsV1Result {
+    pub Name: String,
+    pub Prefix: String,
+    pub Marker: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub NextMarker: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Delimiter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub EncodingType: Option<String>,
+    pub MaxKeys: i32,
+    pub IsTruncated: bool,
+    #[serde(default)]
+    pub Contents: Vec<Object>,
+    #[serde(default)]
+    pub CommonPrefixes: Vec<CommonPrefix>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
@@ -35,6 +66,8 @@ pub struct Object {
     pub ETag: String,
     pub Size: u64,
     pub StorageClass: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Owner: Option<Owner>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
@@ -43,3 +76,170 @@ pub struct CommonPrefix {
 }
 
 
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct NotificationConfiguration {
+    #[serde(default)]
+    pub QueueConfiguration: Vec<QueueConfiguration>,
+}
+
+/// A webhook target is addressed as a queue ARN, e.g. `arn:3fs3:sqs:us-east-1:ingest:webhook`,
+/// whose id (`ingest`) must be declared in `NOTIFY_WEBHOOKS`.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct QueueConfiguration {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Id: Option<String>,
+    pub Queue: String,
+    #[serde(default)]
+    pub Event: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Filter: Option<NotificationFilter>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct NotificationFilter { pub S3Key: S3KeyFilter }
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct S3KeyFilter {
+    #[serde(default)]
+    pub FilterRule: Vec<FilterRule>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct FilterRule { pub Name: String, pub Value: String }
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct BucketLoggingStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub LoggingEnabled: Option<LoggingEnabled>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq, Hash)]
+pub struct LoggingEnabled {
+    pub TargetBucket: String,
+    #[serde(default)]
+    pub TargetPrefix: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ReplicationConfiguration {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Role: Option<String>,
+    #[serde(default)]
+    pub Rule: Vec<ReplicationRule>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ReplicationRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ID: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Priority: Option<i32>,
+    pub Status: String,
+    /// Legacy (V1) rule-level prefix; V2 rules use `Filter`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Filter: Option<ReplicationFilter>,
+    pub Destination: ReplicationDestination,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub DeleteMarkerReplication: Option<DeleteMarkerReplication>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ReplicationFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Tag: Option<Tag>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub And: Option<ReplicationAnd>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ReplicationAnd {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Prefix: Option<String>,
+    #[serde(default)]
+    pub Tag: Vec<Tag>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Tag { pub Key: String, pub Value: String }
+
+/// `Bucket` is `arn:3fs3:replication:<region>:<target>:<bucket>`, where `<target>` is declared
+/// in `REPLICATION_TARGETS`.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ReplicationDestination {
+    pub Bucket: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub StorageClass: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct DeleteMarkerReplication { pub Status: String }
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ServerSideEncryptionConfiguration {
+    #[serde(default)]
+    pub Rule: Vec<ServerSideEncryptionRule>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ServerSideEncryptionRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ApplyServerSideEncryptionByDefault: Option<ServerSideEncryptionByDefault>,
+    /// Accepted for compatibility; every object has its own data key regardless
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub BucketKeyEnabled: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ServerSideEncryptionByDefault {
+    /// `AES256` or `aws:kms`
+    pub SSEAlgorithm: String,
+    /// A key ID in the gateway's keyring
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub KMSMasterKeyID: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct InitiateMultipartUploadResult {
+    pub Bucket: String,
+    pub Key: String,
+    pub UploadId: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct CompleteMultipartUpload {
+    #[serde(default)]
+    pub Part: Vec<CompletedPart>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct CompletedPart { pub PartNumber: u32, pub ETag: String }
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct CompleteMultipartUploadResult {
+    pub Location: String,
+    pub Bucket: String,
+    pub Key: String,
+    pub ETag: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ListPartsResult {
+    pub Bucket: String,
+    pub Key: String,
+    pub UploadId: String,
+    #[serde(default)]
+    pub Part: Vec<Part>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Part {
+    pub PartNumber: u32,
+    pub LastModified: String,
+    pub ETag: String,
+    pub Size: u64,
+}
@@ -1,9 +1,31 @@
-use quick_xml::se::to_string;
+use quick_xml::se::to_string_with_root;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 pub fn to_xml<T: Serialize>(v: &T, root: &str) -> String {
-    // Wrap with root manually to match S3 responses
-    let body = to_string(v).unwrap_or_default();
-    format!("<{root}>{body}</{root}>")
+    // Serialize under the S3 element name rather than the Rust struct name
+    to_string_with_root(root, v).unwrap_or_default()
 }
 
+pub fn from_xml<T: DeserializeOwned>(body: &[u8]) -> anyhow::Result<T> {
+    let s = std::str::from_utf8(body)?;
+    Ok(quick_xml::de::from_str(s)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Listing {
+        #[serde(rename = "Name")]
+        name: String,
+    }
+
+    #[test]
+    fn responses_have_a_single_s3_root_element() {
+        let body = to_xml(&Listing { name: "bkt".into() }, "ListBucketResult");
+        assert_eq!(body, "<ListBucketResult><Name>bkt</Name></ListBucketResult>");
+    }
+}
@@ -0,0 +1,126 @@
+use crate::storage::posix;
+use futures::{Future, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs as tfs;
+use tracing::warn;
+
+// An inflight claim older than this belongs to a pod that died mid-delivery
+const STALE_CLAIM_SECS: i64 = 300;
+const MAX_BACKOFF_SECS: i64 = 300;
+
+/// A durable work queue on the 3FS mount shared by every gateway pod, so queued work survives
+/// restarts and is picked up by whichever pod gets to it first:
+/// `spool/<next_attempt_ms>-<uuid>.json` -> `inflight/<name>@<claim_unix>` -> done or `dead/<name>`.
+#[derive(Clone, Debug)]
+pub struct Spool { root: PathBuf }
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    attempts: u32,
+    #[serde(flatten)]
+    entry: T,
+}
+
+impl Spool {
+    pub fn new(root: PathBuf) -> Self { Self { root } }
+
+    pub async fn push<T: Serialize>(&self, entry: &T) -> anyhow::Result<()> {
+        let name = format!("{:016}-{}.json", chrono::Utc::now().timestamp_millis(), uuid::Uuid::new_v4().simple());
+        write_envelope(&self.root.join("spool").join(name), &Envelope { attempts: 0, entry }).await
+    }
+
+    /// Number of entries waiting or being worked on.
+    pub async fn backlog(&self) -> usize {
+        let mut n = 0;
+        for dir in ["spool", "inflight"] {
+            if let Ok(mut rd) = tfs::read_dir(self.root.join(dir)).await {
+                while let Ok(Some(e)) = rd.next_entry().await {
+                    if e.file_name().to_string_lossy().contains(".json") { n += 1; }
+                }
+            }
+        }
+        n
+    }
+
+    /// Claims every due entry and hands it to `work` together with the number of failed
+    /// attempts so far. Failed entries are retried with exponential backoff until
+    /// `max_attempts`, then parked under `dead/`.
+    pub async fn run_pass<T, F, Fut>(&self, max_attempts: u32, concurrency: usize, work: F) -> anyhow::Result<()>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Fn(T, u32) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let spool = self.root.join("spool");
+        let inflight = self.root.join("inflight");
+        tfs::create_dir_all(&spool).await?;
+        tfs::create_dir_all(&inflight).await?;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let now_s = now_ms / 1000;
+
+        // Return claims abandoned by crashed pods
+        let mut rd = tfs::read_dir(&inflight).await?;
+        while let Some(e) = rd.next_entry().await? {
+            let name = e.file_name().to_string_lossy().into_owned();
+            let Some((orig, claimed)) = name.rsplit_once('@') else { continue };
+            if claimed.parse::<i64>().map(|t| now_s - t > STALE_CLAIM_SECS).unwrap_or(true) {
+                let _ = tfs::rename(e.path(), spool.join(orig)).await;
+            }
+        }
+
+        let mut due = Vec::new();
+        let mut rd = tfs::read_dir(&spool).await?;
+        while let Some(e) = rd.next_entry().await? {
+            let name = e.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".json") { continue; }
+            let Some(at) = name.split('-').next().and_then(|s| s.parse::<i64>().ok()) else { continue };
+            if at <= now_ms { due.push(name); }
+        }
+        due.sort();
+
+        let (spool, inflight, work) = (&spool, &inflight, &work);
+        futures::stream::iter(due)
+            .for_each_concurrent(concurrency, |name| async move {
+                let claimed = inflight.join(format!("{name}@{now_s}"));
+                // Losing the rename race means another pod owns this entry
+                if tfs::rename(spool.join(&name), &claimed).await.is_err() { return; }
+                if let Err(e) = self.process(&name, &claimed, max_attempts, work).await {
+                    warn!(spool = %self.root.display(), entry = %name, error = %e, "spool entry unreadable");
+                }
+            })
+            .await;
+        Ok(())
+    }
+
+    async fn process<T, F, Fut>(&self, name: &str, claimed: &Path, max_attempts: u32, work: &F) -> anyhow::Result<()>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Fn(T, u32) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let bytes = tfs::read(claimed).await?;
+        let env: Envelope<T> = serde_json::from_slice(&bytes)?;
+        let attempts = env.attempts;
+        if let Err(e) = work(env.entry, attempts).await {
+            warn!(spool = %self.root.display(), entry = %name, attempts = attempts + 1, error = %e, "spool entry failed");
+            // Re-read the original entry, `work` consumed ours
+            let mut env: Envelope<T> = serde_json::from_slice(&bytes)?;
+            env.attempts += 1;
+            if env.attempts >= max_attempts {
+                write_envelope(&self.root.join("dead").join(name), &env).await?;
+            } else {
+                let backoff = (1i64 << env.attempts.min(16)).min(MAX_BACKOFF_SECS);
+                let id = name.split_once('-').map(|(_, rest)| rest).unwrap_or(name);
+                let next = format!("{:016}-{}", chrono::Utc::now().timestamp_millis() + backoff * 1000, id);
+                write_envelope(&self.root.join("spool").join(next), &env).await?;
+            }
+        }
+        tfs::remove_file(claimed).await?;
+        Ok(())
+    }
+}
+
+async fn write_envelope<T: Serialize>(path: &Path, env: &Envelope<T>) -> anyhow::Result<()> {
+    posix::write_file_atomic(path, &serde_json::to_vec(env)?).await
+}
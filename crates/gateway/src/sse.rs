@@ -0,0 +1,668 @@
+//! Server-side encryption of object data: with customer-provided keys (SSE-C), and with keys
+//! the gateway manages in its [`keyring`](crate::keyring) (SSE-S3 and SSE-KMS).
+//!
+//! Encrypted objects are stored as a sequence of AES-256-GCM sealed chunks of [`CHUNK`]
+//! plaintext bytes, so a range GET only reads and decrypts the chunks it covers. Every object
+//! gets a random salt from which its content key is derived with HMAC-SHA256 under its secret:
+//! the customer key for SSE-C, a random data key wrapped by a keyring master key otherwise.
+//! A chunk's nonce is its index plus a flag marking the last chunk, so reordered, dropped or
+//! truncated chunks fail authentication. For SSE-C only the salt and the key's MD5 are stored;
+//! the key travels with every request that reads or writes the object.
+//!
+//! The parts of a multipart upload are sealed as they arrive, each as a chunk sequence of its
+//! own under the upload's key, and simply concatenated on completion, when the plaintext size of
+//! every part is recorded with the object. A part can be uploaded again with other content, so
+//! its chunks carry a random nonce and bind their part number, index and last flag as
+//! associated data instead.
+
+use crate::config::GatewayConfig;
+use crate::keyring::{self, WrappedKey};
+use crate::s3::models::ServerSideEncryptionConfiguration;
+use crate::storage::ByteStream;
+use axum::http::HeaderMap;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use serde::{Deserialize, Serialize};
+
+/// Plaintext bytes per sealed chunk
+pub const CHUNK: u64 = 64 << 10;
+const TAG: u64 = 16;
+/// Random nonce stored ahead of every chunk of a multipart part
+const NONCE: u64 = 12;
+
+/// Bucket configuration holding the default encryption of new objects
+pub const CONFIG_NAME: &str = "encryption";
+
+pub const ALGORITHM: &str = "AES256";
+/// Headers carrying the key for the object being written or read
+pub const CUSTOMER: &str = "x-amz-server-side-encryption-customer-";
+/// Headers carrying the key of a CopyObject source
+pub const COPY_SOURCE: &str = "x-amz-copy-source-server-side-encryption-customer-";
+/// Asks for, and reports, encryption with a gateway-managed key
+pub const SERVER_SIDE: &str = "x-amz-server-side-encryption";
+pub const KMS_KEY_ID: &str = "x-amz-server-side-encryption-aws-kms-key-id";
+
+/// Gateway-managed encryption as requested through [`SERVER_SIDE`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scheme {
+    /// SSE-S3: always wrapped by [`keyring::DEFAULT_KEY_ID`]
+    #[serde(rename = "AES256")]
+    S3,
+    /// SSE-KMS: wrapped by a key the client or the bucket default names
+    #[serde(rename = "aws:kms")]
+    Kms,
+}
+
+impl Scheme {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "AES256" => Some(Self::S3),
+            "aws:kms" => Some(Self::Kms),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::S3 => "AES256",
+            Self::Kms => "aws:kms",
+        }
+    }
+}
+
+/// How an object's data is encrypted, kept with its metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Encryption {
+    /// Base64 MD5 of the customer key for SSE-C, checked against the key sent with every read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub customer_key_md5: Option<String>,
+    /// The data key for SSE-S3 and SSE-KMS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub managed: Option<ManagedKey>,
+    /// Hex salt the object's content key is derived from
+    pub salt: String,
+    /// The parts of an object written by multipart upload; empty for objects written whole
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<SealedPart>,
+}
+
+/// A part of a multipart object: `size` bytes of plaintext sealed as a chunk sequence of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedPart {
+    pub number: u32,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManagedKey {
+    pub scheme: Scheme,
+    #[serde(flatten)]
+    pub wrapped: WrappedKey,
+}
+
+impl Encryption {
+    /// Whether reading the object takes the customer's key, which the gateway never has.
+    pub fn is_customer(&self) -> bool {
+        self.customer_key_md5.is_some()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SseError {
+    #[error("InvalidArgument: {0}")]
+    Invalid(String),
+    #[error("InvalidRequest: Requests specifying Server Side Encryption with Customer provided keys must be made over a secure connection.")]
+    Insecure,
+    #[error("InvalidRequest: The object was stored using a form of Server Side Encryption. The correct parameters must be provided to retrieve the object.")]
+    KeyRequired,
+    #[error("InvalidRequest: The encryption parameters are not applicable to this object.")]
+    NotEncrypted,
+    #[error("AccessDenied: The provided key does not match the key the object was encrypted with.")]
+    WrongKey,
+    #[error("KMS.NotFoundException: Key {0} does not exist in the keyring.")]
+    UnknownKey(String),
+    /// The keyring is unreadable or lacks a key an object was written with
+    #[error("InternalError: {0}")]
+    Keyring(String),
+}
+
+/// 256 bits of key material an object's chunks are sealed under, before salting.
+#[derive(Clone)]
+pub struct Secret([u8; 32]);
+
+impl Secret {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    pub(crate) fn expose(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for Secret {
+    fn from(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// A key sent in `x-amz-server-side-encryption-customer-*` headers.
+#[derive(Debug)]
+pub struct CustomerKey {
+    secret: Secret,
+    /// Base64, as in the `-key-MD5` header
+    pub md5: String,
+}
+
+/// The customer key in the headers starting with `prefix`, or `None` if there are none.
+pub fn customer_key(headers: &HeaderMap, prefix: &str) -> Result<Option<CustomerKey>, SseError> {
+    let get = |name: &str| headers.get(format!("{prefix}{name}")).map(|v| v.to_str().unwrap_or_default());
+    let (algorithm, key, md5) = match (get("algorithm"), get("key"), get("key-MD5")) {
+        (None, None, None) => return Ok(None),
+        (Some(a), Some(k), Some(m)) => (a, k, m),
+        _ => return Err(SseError::Invalid(format!("{prefix}algorithm, {prefix}key and {prefix}key-MD5 must be sent together"))),
+    };
+    if algorithm != ALGORITHM { return Err(SseError::Invalid(format!("unsupported encryption algorithm {algorithm}"))); }
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let key: [u8; 32] = b64.decode(key).ok().and_then(|k| k.try_into().ok())
+        .ok_or_else(|| SseError::Invalid("the customer key must be 256 bits, base64 encoded".into()))?;
+    let actual = b64.encode(md5::compute(key).0);
+    if actual != md5 { return Err(SseError::Invalid("the customer key MD5 does not match the key".into())); }
+    Ok(Some(CustomerKey { secret: Secret(key), md5: actual }))
+}
+
+/// SSE-C keys must not cross the network in the clear. The gateway itself only speaks HTTP,
+/// so the TLS-terminating proxy in front of it has to say the request arrived over HTTPS.
+pub fn require_secure(cfg: &GatewayConfig, headers: &HeaderMap) -> Result<(), SseError> {
+    let https = headers.get("x-forwarded-proto").and_then(|v| v.to_str().ok()).is_some_and(|p| p.eq_ignore_ascii_case("https"));
+    if https || cfg.sse_c_allow_http { Ok(()) } else { Err(SseError::Insecure) }
+}
+
+/// How a new object is to be encrypted.
+#[derive(Debug)]
+pub enum Protection {
+    Customer(CustomerKey),
+    Managed { scheme: Scheme, key_id: String },
+}
+
+/// The protection a write asks for: an SSE-C key, else [`SERVER_SIDE`], else the bucket's
+/// default encryption, if any.
+pub fn requested(headers: &HeaderMap, customer: Option<CustomerKey>, default: Option<&ServerSideEncryptionConfiguration>) -> Result<Option<Protection>, SseError> {
+    let header = |name: &str| headers.get(name).map(|v| v.to_str().unwrap_or_default().to_string());
+    let (scheme, key_id) = (header(SERVER_SIDE), header(KMS_KEY_ID));
+    if let Some(customer) = customer {
+        if scheme.is_some() || key_id.is_some() { return Err(SseError::Invalid(format!("{SERVER_SIDE} cannot be combined with a customer key"))); }
+        return Ok(Some(Protection::Customer(customer)));
+    }
+    let (scheme, key_id) = match scheme {
+        Some(s) => (Scheme::parse(&s).ok_or_else(|| SseError::Invalid(format!("unsupported {SERVER_SIDE} {s}")))?, key_id),
+        None if key_id.is_some() => return Err(SseError::Invalid(format!("{KMS_KEY_ID} requires {SERVER_SIDE}: aws:kms"))),
+        None => match default.and_then(bucket_default).transpose()? {
+            Some(d) => d,
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(managed(scheme, key_id)?))
+}
+
+fn managed(scheme: Scheme, key_id: Option<String>) -> Result<Protection, SseError> {
+    let key_id = match (scheme, key_id) {
+        (Scheme::S3, Some(_)) => return Err(SseError::Invalid(format!("{KMS_KEY_ID} requires {SERVER_SIDE}: aws:kms"))),
+        (Scheme::Kms, Some(id)) => id,
+        (_, None) => keyring::DEFAULT_KEY_ID.to_string(),
+    };
+    Ok(Protection::Managed { scheme, key_id })
+}
+
+/// The scheme and key a bucket's default encryption names.
+fn bucket_default(conf: &ServerSideEncryptionConfiguration) -> Option<Result<(Scheme, Option<String>), SseError>> {
+    let by_default = conf.Rule.iter().find_map(|r| r.ApplyServerSideEncryptionByDefault.as_ref())?;
+    Some(match Scheme::parse(&by_default.SSEAlgorithm) {
+        Some(scheme) => Ok((scheme, by_default.KMSMasterKeyID.clone())),
+        None => Err(SseError::Invalid(format!("unsupported SSEAlgorithm {}", by_default.SSEAlgorithm))),
+    })
+}
+
+/// Checks a PutBucketEncryption configuration against the keyring.
+pub fn validate_default(cfg: &GatewayConfig, conf: &ServerSideEncryptionConfiguration) -> Result<(), SseError> {
+    let Some(default) = bucket_default(conf) else { return Err(SseError::Invalid("ApplyServerSideEncryptionByDefault is required".into())) };
+    let (scheme, key_id) = default?;
+    let Protection::Managed { key_id, .. } = managed(scheme, key_id)? else { unreachable!() };
+    if !keyring::load(cfg)?.contains(&key_id) { return Err(SseError::UnknownKey(key_id)); }
+    Ok(())
+}
+
+/// Encryption parameters for a new object, with a fresh salt, and the secret to seal it under.
+pub fn seal_new(cfg: &GatewayConfig, protection: &Protection) -> Result<(Encryption, Secret), SseError> {
+    let salt = hex::encode(rand::random::<[u8; 16]>());
+    match protection {
+        Protection::Customer(key) => Ok((Encryption { customer_key_md5: Some(key.md5.clone()), managed: None, salt, parts: Vec::new() }, key.secret.clone())),
+        Protection::Managed { scheme, key_id } => {
+            let data_key = Secret::random();
+            let wrapped = keyring::load(cfg)?.wrap(key_id, &data_key)?;
+            Ok((Encryption { customer_key_md5: None, managed: Some(ManagedKey { scheme: *scheme, wrapped }), salt, parts: Vec::new() }, data_key))
+        }
+    }
+}
+
+/// Checks that `customer` is what reading an object encrypted with `enc` takes: its SSE-C key,
+/// or nothing for other objects.
+pub fn check_access(enc: Option<&Encryption>, customer: Option<&CustomerKey>) -> Result<(), SseError> {
+    match (enc.and_then(|e| e.customer_key_md5.as_ref()), customer) {
+        (Some(md5), Some(key)) => if *md5 == key.md5 { Ok(()) } else { Err(SseError::WrongKey) },
+        (Some(_), None) => Err(SseError::KeyRequired),
+        (None, Some(_)) => Err(SseError::NotEncrypted),
+        (None, None) => Ok(()),
+    }
+}
+
+/// The secret to decrypt an object encrypted with `enc`, after [`check_access`].
+pub fn object_secret(cfg: &GatewayConfig, enc: &Encryption, customer: Option<&CustomerKey>) -> Result<Secret, SseError> {
+    check_access(Some(enc), customer)?;
+    match (&enc.managed, customer) {
+        (_, Some(key)) => Ok(key.secret.clone()),
+        (Some(managed), None) => keyring::load(cfg)?.unwrap(&managed.wrapped),
+        (None, None) => Err(SseError::Keyring("object has neither a customer nor a managed key".into())),
+    }
+}
+
+fn content_key(secret: &Secret, enc: &Encryption) -> LessSafeKey {
+    let mac = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &secret.0);
+    let mut ctx = ring::hmac::Context::with_key(&mac);
+    // The label predates managed keys; changing it would make existing SSE-C objects unreadable
+    ctx.update(b"3fs-s3-gateway sse-c\0");
+    ctx.update(enc.salt.as_bytes());
+    let derived = ctx.sign();
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, derived.as_ref()).expect("HMAC-SHA256 yields a 256-bit key"))
+}
+
+fn nonce(index: u64, last: bool) -> Nonce {
+    let mut n = [0u8; 12];
+    n[..8].copy_from_slice(&index.to_be_bytes());
+    n[8] = last as u8;
+    Nonce::assume_unique_for_key(n)
+}
+
+/// Associated data of a chunk of a multipart part.
+fn position(part: u32, index: u64, last: bool) -> [u8; 13] {
+    let mut p = [0u8; 13];
+    p[..4].copy_from_slice(&part.to_be_bytes());
+    p[4..12].copy_from_slice(&index.to_be_bytes());
+    p[12] = last as u8;
+    p
+}
+
+/// Seals `chunk` as chunk `index` of `part`, where part 0 is an object written whole.
+fn seal_chunk(key: &LessSafeKey, part: u32, index: u64, last: bool, mut chunk: BytesMut) -> Option<Bytes> {
+    if part == 0 {
+        key.seal_in_place_append_tag(nonce(index, last), Aad::empty(), &mut chunk).ok()?;
+        return Some(chunk.freeze());
+    }
+    let n: [u8; NONCE as usize] = rand::random();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(n), Aad::from(position(part, index, last)), &mut chunk).ok()?;
+    let mut sealed = BytesMut::with_capacity(n.len() + chunk.len());
+    sealed.extend_from_slice(&n);
+    sealed.extend_from_slice(&chunk);
+    Some(sealed.freeze())
+}
+
+/// Opens sealed chunk `index` of `part` and returns its plaintext.
+fn open_chunk(key: &LessSafeKey, part: u32, index: u64, last: bool, mut chunk: BytesMut) -> Option<Bytes> {
+    let position = position(part, index, last);
+    let (nonce, aad) = match part {
+        0 => (nonce(index, last), &[][..]),
+        _ => (Nonce::try_assume_unique_for_key(&chunk.split_to((NONCE as usize).min(chunk.len()))).ok()?, &position[..]),
+    };
+    let len = key.open_in_place(nonce, Aad::from(aad), &mut chunk).ok()?.len();
+    chunk.truncate(len);
+    Some(chunk.freeze())
+}
+
+/// A chunk sequence sealed in one go: an object written whole (part 0), or one multipart part.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    part: u32,
+    plain: u64,
+}
+
+impl Span {
+    /// Bytes every chunk takes beyond its plaintext.
+    fn overhead(self) -> u64 {
+        if self.part == 0 { TAG } else { NONCE + TAG }
+    }
+
+    /// An empty span still takes one sealed chunk.
+    fn chunks(self) -> u64 {
+        self.plain.div_ceil(CHUNK).max(1)
+    }
+
+    fn sealed(self) -> u64 {
+        self.plain + self.chunks() * self.overhead()
+    }
+
+    /// Stored length of chunk `index`.
+    fn chunk_len(self, index: u64) -> u64 {
+        (self.sealed() - index * (CHUNK + self.overhead())).min(CHUNK + self.overhead())
+    }
+}
+
+/// Plaintext size of a chunk sequence stored as `stored` bytes with `overhead` bytes per chunk,
+/// or `None` if no sequence is that long.
+fn unsealed_size(stored: u64, overhead: u64) -> Option<u64> {
+    let plain = stored.checked_sub(stored.div_ceil(CHUNK + overhead) * overhead)?;
+    (plain + plain.div_ceil(CHUNK).max(1) * overhead == stored).then_some(plain)
+}
+
+/// The spans an object encrypted with `enc` consists of, or `None` if they do not add up to the
+/// `stored` bytes, so the object is corrupt.
+fn spans(enc: &Encryption, stored: u64) -> Option<Vec<Span>> {
+    let spans = match enc.parts.is_empty() {
+        true => vec![Span { part: 0, plain: unsealed_size(stored, TAG)? }],
+        false => enc.parts.iter().map(|p| Span { part: p.number, plain: p.size }).collect(),
+    };
+    (spans.iter().map(|s| s.sealed()).sum::<u64>() == stored).then_some(spans)
+}
+
+/// The chunk holding plaintext offset `at` as its span, index within the span, plaintext offset
+/// of its first byte and stored offset. Offsets past the end fall in the last chunk.
+fn locate(spans: &[Span], at: u64) -> (usize, u64, u64, u64) {
+    let (mut plain, mut stored) = (0, 0);
+    for (i, span) in spans.iter().enumerate() {
+        if at < plain + span.plain || i + 1 == spans.len() {
+            let index = ((at - plain) / CHUNK).min(span.chunks() - 1);
+            return (i, index, plain + index * CHUNK, stored + index * (CHUNK + span.overhead()));
+        }
+        plain += span.plain;
+        stored += span.sealed();
+    }
+    unreachable!("an encrypted object has at least one span")
+}
+
+/// Size of the plaintext of an object encrypted with `enc` and stored as `stored` bytes, or
+/// `None` if its sealed chunks cannot add up to that, so the object is corrupt.
+pub fn plaintext_size(enc: &Encryption, stored: u64) -> Option<u64> {
+    Some(spans(enc, stored)?.iter().map(|s| s.plain).sum())
+}
+
+/// Plaintext size of a multipart part stored as `stored` bytes, or `None` if it is corrupt.
+pub fn part_size(stored: u64) -> Option<u64> {
+    unsealed_size(stored, NONCE + TAG)
+}
+
+/// The stored bytes holding the inclusive plaintext range `plain`, whole chunks only, or `None`
+/// if the object is corrupt.
+pub fn stored_range(enc: &Encryption, plain: (u64, u64), stored: u64) -> Option<(u64, u64)> {
+    let spans = spans(enc, stored)?;
+    let (_, _, _, start) = locate(&spans, plain.0);
+    let (span, index, _, end) = locate(&spans, plain.1);
+    Some((start, end + spans[span].chunk_len(index) - 1))
+}
+
+/// Encrypts `body` as `part` of an object with encryption `enc`, where part 0 is an object
+/// written whole. Even an empty body yields one (empty) sealed chunk, so truncation to nothing
+/// is detected too.
+pub fn encrypt(body: ByteStream, secret: &Secret, enc: &Encryption, part: u32) -> ByteStream {
+    struct Sealer { inner: ByteStream, key: LessSafeKey, part: u32, buf: BytesMut, index: u64, done: bool }
+    let sealer = Sealer { inner: body, key: content_key(secret, enc), part, buf: BytesMut::new(), index: 0, done: false };
+    Box::pin(futures::stream::unfold(sealer, |mut s| async move {
+        if s.done { return None; }
+        // One byte past a full chunk shows it is not the last
+        while s.buf.len() as u64 <= CHUNK {
+            match s.inner.next().await {
+                Some(Ok(b)) => s.buf.extend_from_slice(&b),
+                Some(Err(e)) => {
+                    s.done = true;
+                    return Some((Err(e), s));
+                }
+                None => break,
+            }
+        }
+        let last = s.buf.len() as u64 <= CHUNK;
+        let chunk = s.buf.split_to(s.buf.len().min(CHUNK as usize));
+        let sealed = seal_chunk(&s.key, s.part, s.index, last, chunk).ok_or_else(|| std::io::Error::other("encryption failed"));
+        s.index += 1;
+        s.done = last || sealed.is_err();
+        Some((sealed, s))
+    }))
+}
+
+/// Decrypts the inclusive plaintext range `plain` from `body`, which must start at
+/// `stored_range(enc, plain, stored).0` of an object stored as `stored` bytes.
+pub fn decrypt(body: ByteStream, secret: &Secret, enc: &Encryption, stored: u64, plain: (u64, u64)) -> ByteStream {
+    struct Opener { inner: ByteStream, key: LessSafeKey, buf: BytesMut, spans: Vec<Span>, span: usize, index: u64, skip: usize, remaining: u64 }
+    let Some(spans) = spans(enc, stored) else {
+        return Box::pin(futures::stream::once(async { Err(std::io::Error::other("encrypted object is corrupt")) }));
+    };
+    let (span, index, chunk_start, _) = locate(&spans, plain.0);
+    let opener = Opener {
+        inner: body,
+        key: content_key(secret, enc),
+        buf: BytesMut::new(),
+        spans,
+        span,
+        index,
+        skip: (plain.0 - chunk_start) as usize,
+        remaining: plain.1 + 1 - plain.0,
+    };
+    Box::pin(futures::stream::unfold(opener, |mut s| async move {
+        if s.remaining == 0 { return None; }
+        let span = s.spans[s.span];
+        let need = span.chunk_len(s.index) as usize;
+        while s.buf.len() < need {
+            match s.inner.next().await {
+                Some(Ok(b)) => s.buf.extend_from_slice(&b),
+                Some(Err(e)) => {
+                    s.remaining = 0;
+                    return Some((Err(e), s));
+                }
+                None => {
+                    s.remaining = 0;
+                    return Some((Err(std::io::Error::other("encrypted object is truncated")), s));
+                }
+            }
+        }
+        let last = s.index + 1 == span.chunks();
+        let Some(chunk) = open_chunk(&s.key, span.part, s.index, last, s.buf.split_to(need)) else {
+            s.remaining = 0;
+            return Some((Err(std::io::Error::other("encrypted object failed authentication")), s));
+        };
+        let start = s.skip.min(chunk.len());
+        let end = (start as u64 + s.remaining).min(chunk.len() as u64) as usize;
+        let out = chunk.slice(start..end);
+        s.skip = 0;
+        s.remaining -= out.len() as u64;
+        s.index += 1;
+        if last {
+            s.span += 1;
+            s.index = 0;
+        }
+        // A range cannot end past the last chunk; stop rather than wait for more bytes
+        if s.span == s.spans.len() { s.remaining = 0; }
+        Some((Ok(out), s))
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    const SEALED: u64 = CHUNK + TAG;
+
+    fn enc() -> Encryption {
+        Encryption { customer_key_md5: None, managed: None, salt: "00112233445566778899aabbccddeeff".into(), parts: Vec::new() }
+    }
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// A stream of `data` in pieces of `piece` bytes, so chunks straddle stream items.
+    fn pieces(data: &[u8], piece: usize) -> ByteStream {
+        let items: Vec<std::io::Result<Bytes>> = data.chunks(piece.max(1)).map(|c| Ok(Bytes::copy_from_slice(c))).collect();
+        Box::pin(futures::stream::iter(items))
+    }
+
+    async fn collect(body: ByteStream) -> std::io::Result<Vec<u8>> {
+        body.map_ok(|b| b.to_vec()).try_concat().await
+    }
+
+    async fn seal(data: &[u8], secret: &Secret) -> Vec<u8> {
+        collect(encrypt(pieces(data, 1000), secret, &enc(), 0)).await.unwrap()
+    }
+
+    async fn open(stored: &[u8], secret: &Secret, plain: (u64, u64)) -> std::io::Result<Vec<u8>> {
+        open_as(stored, secret, &enc(), plain).await
+    }
+
+    async fn open_as(stored: &[u8], secret: &Secret, enc: &Encryption, plain: (u64, u64)) -> std::io::Result<Vec<u8>> {
+        let (start, end) = stored_range(enc, plain, stored.len() as u64).expect("sizes add up");
+        let body = pieces(&stored[start as usize..=end as usize], 777);
+        collect(decrypt(body, secret, enc, stored.len() as u64, plain)).await
+    }
+
+    #[tokio::test]
+    async fn round_trip_across_chunk_boundaries() {
+        let secret = Secret::random();
+        let chunk = CHUNK as usize;
+        for len in [1, chunk - 1, chunk, chunk + 1, 3 * chunk + 5] {
+            let data = plaintext(len);
+            let stored = seal(&data, &secret).await;
+            assert_eq!(stored.len() as u64, len as u64 + (len as u64).div_ceil(CHUNK) * TAG, "len {len}");
+            assert_eq!(plaintext_size(&enc(), stored.len() as u64), Some(len as u64));
+            assert_eq!(open(&stored, &secret, (0, len as u64 - 1)).await.unwrap(), data, "len {len}");
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_body_is_one_sealed_chunk() {
+        let secret = Secret::random();
+        let stored = seal(&[], &secret).await;
+        assert_eq!(stored.len() as u64, TAG);
+        assert_eq!(plaintext_size(&enc(), TAG), Some(0));
+        assert_eq!(open(&stored, &secret, (0, 0)).await.unwrap(), Vec::<u8>::new());
+        // Truncating it to nothing does not pass for an empty object
+        let body = pieces(&[], 1);
+        assert!(collect(decrypt(body, &secret, &enc(), 0, (0, 0))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ranges_decrypt_only_what_was_asked() {
+        let secret = Secret::random();
+        let data = plaintext(3 * CHUNK as usize + 100);
+        let stored = seal(&data, &secret).await;
+        let len = data.len() as u64;
+        for (start, end) in [(0, 0), (5, 10), (CHUNK - 1, CHUNK), (CHUNK, 2 * CHUNK - 1), (CHUNK + 3, 3 * CHUNK + 2), (len - 1, len - 1), (0, len - 1)] {
+            let got = open(&stored, &secret, (start, end)).await.unwrap();
+            assert_eq!(got, data[start as usize..=end as usize], "range {start}-{end}");
+        }
+    }
+
+    #[test]
+    fn sizes_no_chunks_add_up_to_are_corrupt() {
+        for stored in [0, 1, TAG - 1, SEALED + 1, SEALED + TAG - 1, 2 * SEALED + 3] {
+            assert_eq!(plaintext_size(&enc(), stored), None, "stored {stored}");
+        }
+        assert_eq!(plaintext_size(&enc(), SEALED), Some(CHUNK));
+        assert_eq!(plaintext_size(&enc(), SEALED + TAG + 1), Some(CHUNK + 1));
+    }
+
+    #[tokio::test]
+    async fn truncated_last_chunk_fails() {
+        let secret = Secret::random();
+        let len = 2 * CHUNK + 10;
+        let stored = seal(&plaintext(len as usize), &secret).await;
+        // Bytes cut from the last chunk
+        let short = &stored[..stored.len() - 4];
+        assert!(open(short, &secret, (0, plaintext_size(&enc(), short.len() as u64).unwrap() - 1)).await.is_err());
+        // A whole last chunk dropped leaves a full chunk that was not sealed as the last one
+        let whole = &stored[..2 * SEALED as usize];
+        assert!(open(whole, &secret, (0, 2 * CHUNK - 1)).await.is_err());
+        // A body that ends early is reported, not waited on
+        let body = pieces(&stored[..SEALED as usize], 1000);
+        assert!(collect(decrypt(body, &secret, &enc(), stored.len() as u64, (0, len - 1))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tampering_and_wrong_keys_fail() {
+        let secret = Secret::random();
+        let data = plaintext(2 * CHUNK as usize + 1);
+        let stored = seal(&data, &secret).await;
+        let all = (0, data.len() as u64 - 1);
+
+        let mut flipped = stored.clone();
+        flipped[SEALED as usize + 3] ^= 1;
+        assert!(open(&flipped, &secret, all).await.is_err());
+        // The untouched first chunk still reads
+        assert_eq!(open(&flipped, &secret, (0, 9)).await.unwrap(), data[..10]);
+
+        let mut swapped = stored.clone();
+        let (first, rest) = swapped.split_at_mut(SEALED as usize);
+        first.swap_with_slice(&mut rest[..SEALED as usize]);
+        assert!(open(&swapped, &secret, (0, 9)).await.is_err());
+
+        assert!(open(&stored, &Secret::random(), all).await.is_err());
+        let other_salt = Encryption { salt: "ffeeddccbbaa99887766554433221100".into(), ..enc() };
+        let body = pieces(&stored, 1000);
+        assert!(collect(decrypt(body, &secret, &other_salt, stored.len() as u64, all)).await.is_err());
+    }
+
+    async fn seal_parts(parts: &[(u32, Vec<u8>)], secret: &Secret) -> (Vec<u8>, Encryption) {
+        let mut stored = Vec::new();
+        let mut sealed_as = enc();
+        for (number, data) in parts {
+            let sealed = collect(encrypt(pieces(data, 1000), secret, &enc(), *number)).await.unwrap();
+            assert_eq!(part_size(sealed.len() as u64), Some(data.len() as u64), "part {number}");
+            stored.extend(sealed);
+            sealed_as.parts.push(SealedPart { number: *number, size: data.len() as u64 });
+        }
+        (stored, sealed_as)
+    }
+
+    #[tokio::test]
+    async fn parts_decrypt_across_their_boundaries() {
+        let secret = Secret::random();
+        let chunk = CHUNK as usize;
+        let parts = [(1, plaintext(2 * chunk + 7)), (2, Vec::new()), (4, plaintext(chunk)), (7, plaintext(5))];
+        let (stored, enc) = seal_parts(&parts, &secret).await;
+        let data: Vec<u8> = parts.iter().flat_map(|(_, d)| d.clone()).collect();
+        let len = data.len() as u64;
+        assert_eq!(plaintext_size(&enc, stored.len() as u64), Some(len));
+        let first = 2 * CHUNK + 7;
+        for (start, end) in [(0, len - 1), (CHUNK - 1, CHUNK), (first - 1, first), (first - 2, first + CHUNK), (first + CHUNK - 1, len - 1), (len - 1, len - 1)] {
+            let got = open_as(&stored, &secret, &enc, (start, end)).await.unwrap();
+            assert_eq!(got, data[start as usize..=end as usize], "range {start}-{end}");
+        }
+    }
+
+    #[tokio::test]
+    async fn parts_are_bound_to_their_number_and_place() {
+        let secret = Secret::random();
+        let (a, b) = (plaintext(CHUNK as usize + 3), vec![7u8; CHUNK as usize + 3]);
+        let (stored, enc) = seal_parts(&[(1, a.clone()), (2, b.clone())], &secret).await;
+        let all = (0, 2 * CHUNK + 5);
+        assert!(open_as(&stored, &secret, &enc, all).await.is_ok());
+
+        // Uploading the same part again seals it under fresh nonces
+        let (again, _) = seal_parts(&[(1, a.clone())], &secret).await;
+        assert_ne!(again, stored[..again.len()]);
+
+        let half = stored.len() / 2;
+        let mut swapped = stored[half..].to_vec();
+        swapped.extend_from_slice(&stored[..half]);
+        assert!(open_as(&swapped, &secret, &enc, all).await.is_err());
+
+        // A part sealed under another number does not pass for this one
+        let (renumbered, _) = seal_parts(&[(3, a), (2, b)], &secret).await;
+        assert!(open_as(&renumbered, &secret, &enc, all).await.is_err());
+    }
+}
@@ -0,0 +1,508 @@
+//! A bounded, size-aware LRU of small objects' bytes and metadata in front of another backend,
+//! for buckets that opt in with `OBJECT_CACHE_BUCKETS`. Writes through this pod drop the
+//! affected entries at once, as do writes through other pods when the change feed is on;
+//! entries older than `OBJECT_CACHE_REVALIDATE_SECS` are checked against the backend's current
+//! ETag before being served, which bounds how long a change made elsewhere can go unnoticed.
+
+use crate::changes::{Change, ChangeFeed};
+use crate::config::GatewayConfig;
+use crate::storage::{
+    bytes_stream, AttrsUpdate, BucketInfo, ByteRange, ByteStream, DeferredEtag, GetObject, ListPage, ObjectAttrs, ObjectMeta, PartInfo, StorageBackend, StorageError, StorageResult,
+};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use parking_lot::Mutex;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Opts, Registry};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub struct CacheMetrics {
+    /// By `op` (get, head) and `result` (hit, miss)
+    pub requests: IntCounterVec,
+    pub evictions: IntCounter,
+    pub bytes: IntGauge,
+    pub objects: IntGauge,
+}
+
+impl CacheMetrics {
+    pub fn register(registry: &Registry) -> Self {
+        let requests = IntCounterVec::new(Opts::new("object_cache_requests_total", "Object cache lookups by operation and result"), &["op", "result"]).unwrap();
+        let evictions = IntCounter::new("object_cache_evictions_total", "Objects evicted from the object cache to make room").unwrap();
+        let bytes = IntGauge::new("object_cache_bytes", "Object bytes held in the object cache").unwrap();
+        let objects = IntGauge::new("object_cache_objects", "Objects held in the object cache").unwrap();
+        registry.register(Box::new(requests.clone())).ok();
+        registry.register(Box::new(evictions.clone())).ok();
+        registry.register(Box::new(bytes.clone())).ok();
+        registry.register(Box::new(objects.clone())).ok();
+        Self { requests, evictions, bytes, objects }
+    }
+}
+
+type CacheKey = (String, String);
+
+struct Entry {
+    meta: ObjectMeta,
+    data: Bytes,
+    /// Position in `Lru::order`
+    tick: u64,
+    /// When the entry was last known to match the backend
+    checked: Instant,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<CacheKey, Entry>,
+    /// Least recently used first
+    order: BTreeMap<u64, CacheKey>,
+    next_tick: u64,
+    bytes: u64,
+    /// Bumped by every invalidation so a read that raced with a write does not cache stale data
+    generation: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &CacheKey) -> Option<&mut Entry> {
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        entry.tick = self.next_tick;
+        self.next_tick += 1;
+        self.order.insert(entry.tick, key.clone());
+        Some(entry)
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+            self.bytes -= entry.data.len() as u64;
+        }
+    }
+
+    /// Adds an entry and returns how many others were evicted to stay within `capacity`.
+    fn insert(&mut self, key: CacheKey, meta: ObjectMeta, data: Bytes, capacity: u64) -> u64 {
+        self.remove(&key);
+        let mut evicted = 0;
+        while self.bytes + data.len() as u64 > capacity {
+            let Some((_, oldest)) = self.order.pop_first() else { break };
+            self.remove(&oldest);
+            evicted += 1;
+        }
+        self.bytes += data.len() as u64;
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.order.insert(tick, key.clone());
+        self.entries.insert(key, Entry { meta, data, tick, checked: Instant::now() });
+        evicted
+    }
+}
+
+pub struct CachingBackend {
+    inner: Arc<dyn StorageBackend>,
+    lru: Mutex<Lru>,
+    /// `None` caches every bucket
+    buckets: Option<HashSet<String>>,
+    capacity: u64,
+    max_object: u64,
+    revalidate_after: Duration,
+    metrics: CacheMetrics,
+}
+
+/// Puts the object cache in front of `inner` when `OBJECT_CACHE_BUCKETS` names any bucket, and
+/// has `feed` drop entries other pods overwrite.
+pub fn wrap(cfg: &GatewayConfig, inner: Arc<dyn StorageBackend>, registry: &Registry, feed: Option<&ChangeFeed>) -> Arc<dyn StorageBackend> {
+    let list = cfg.object_cache_buckets.as_deref().unwrap_or("").trim();
+    if list.is_empty() || cfg.object_cache_bytes == 0 { return inner; }
+    let buckets = (list != "*").then(|| list.split(',').map(|b| b.trim().to_string()).filter(|b| !b.is_empty()).collect());
+    let cache = Arc::new(CachingBackend {
+        inner,
+        lru: Mutex::new(Lru::default()),
+        buckets,
+        capacity: cfg.object_cache_bytes,
+        max_object: cfg.object_cache_max_object_bytes.min(cfg.object_cache_bytes),
+        revalidate_after: Duration::from_secs(cfg.object_cache_revalidate_secs),
+        metrics: CacheMetrics::register(registry),
+    });
+    if let Some(feed) = feed {
+        let c = cache.clone();
+        feed.subscribe(move |change| match change {
+            Change::Object { bucket, key } => c.invalidate(bucket, key),
+            Change::Bucket { bucket } => c.invalidate_bucket(bucket),
+            Change::BucketConfig { .. } => {}
+            Change::Reset => c.invalidate_all(),
+        });
+    }
+    cache
+}
+
+fn cache_key(bucket: &str, key: &str) -> CacheKey {
+    (bucket.to_string(), key.to_string())
+}
+
+impl CachingBackend {
+    fn enabled(&self, bucket: &str) -> bool {
+        self.buckets.as_ref().is_none_or(|b| b.contains(bucket))
+    }
+
+    fn invalidate(&self, bucket: &str, key: &str) {
+        let mut lru = self.lru.lock();
+        lru.generation += 1;
+        lru.remove(&cache_key(bucket, key));
+        self.update_gauges(&lru);
+    }
+
+    fn invalidate_bucket(&self, bucket: &str) {
+        let mut lru = self.lru.lock();
+        lru.generation += 1;
+        let stale: Vec<CacheKey> = lru.entries.keys().filter(|(b, _)| b == bucket).cloned().collect();
+        stale.iter().for_each(|k| lru.remove(k));
+        self.update_gauges(&lru);
+    }
+
+    fn invalidate_all(&self) {
+        let mut lru = self.lru.lock();
+        let generation = lru.generation + 1;
+        *lru = Lru { generation, ..Lru::default() };
+        self.update_gauges(&lru);
+    }
+
+    fn update_gauges(&self, lru: &Lru) {
+        self.metrics.bytes.set(lru.bytes as i64);
+        self.metrics.objects.set(lru.entries.len() as i64);
+    }
+
+    /// The cached copy of an object, revalidated against the backend's ETag once it is older
+    /// than `revalidate_after`.
+    async fn lookup(&self, bucket: &str, key: &str) -> StorageResult<Option<(ObjectMeta, Bytes)>> {
+        let k = cache_key(bucket, key);
+        let (meta, data, checked) = match self.lru.lock().touch(&k) {
+            Some(e) => (e.meta.clone(), e.data.clone(), e.checked),
+            None => return Ok(None),
+        };
+        if checked.elapsed() < self.revalidate_after { return Ok(Some((meta, data))); }
+        match self.inner.head_object(bucket, key).await {
+            Ok(current) if current.etag == meta.etag && current.size == meta.size => {
+                if let Some(e) = self.lru.lock().entries.get_mut(&k) {
+                    e.meta = current.clone();
+                    e.checked = Instant::now();
+                }
+                Ok(Some((current, data)))
+            }
+            Ok(_) | Err(StorageError::NoSuchKey) => {
+                self.invalidate(bucket, key);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn serve(meta: ObjectMeta, data: Bytes, range: Option<ByteRange>) -> StorageResult<GetObject> {
+        let range = match range {
+            Some(r) => Some(r.resolve(data.len() as u64).ok_or(StorageError::InvalidRange)?),
+            None => None,
+        };
+        let body = match range {
+            Some((start, end)) => data.slice(start as usize..=end as usize),
+            None => data,
+        };
+        Ok(GetObject { meta, range, body: bytes_stream(body) })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for CachingBackend {
+    async fn is_ready(&self) -> bool {
+        self.inner.is_ready().await
+    }
+
+    async fn list_buckets(&self) -> StorageResult<Vec<BucketInfo>> {
+        self.inner.list_buckets().await
+    }
+
+    async fn head_bucket(&self, bucket: &str) -> StorageResult<()> {
+        self.inner.head_bucket(bucket).await
+    }
+
+    async fn create_bucket(&self, bucket: &str) -> StorageResult<()> {
+        self.inner.create_bucket(bucket).await
+    }
+
+    async fn delete_bucket(&self, bucket: &str) -> StorageResult<()> {
+        self.inner.delete_bucket(bucket).await?;
+        self.invalidate_bucket(bucket);
+        Ok(())
+    }
+
+    async fn get_bucket_config(&self, bucket: &str, name: &str) -> StorageResult<Option<Vec<u8>>> {
+        self.inner.get_bucket_config(bucket, name).await
+    }
+
+    async fn put_bucket_config(&self, bucket: &str, name: &str, value: Option<Vec<u8>>) -> StorageResult<()> {
+        self.inner.put_bucket_config(bucket, name, value).await
+    }
+
+    async fn put_object(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        let result = self.inner.put_object(bucket, key, body, attrs).await;
+        self.invalidate(bucket, key);
+        result
+    }
+
+    async fn put_object_with_etag(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs, etag: DeferredEtag) -> StorageResult<ObjectMeta> {
+        let result = self.inner.put_object_with_etag(bucket, key, body, attrs, etag).await;
+        self.invalidate(bucket, key);
+        result
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str, range: Option<ByteRange>) -> StorageResult<GetObject> {
+        if !self.enabled(bucket) { return self.inner.get_object(bucket, key, range).await; }
+        if let Some((meta, data)) = self.lookup(bucket, key).await? {
+            self.metrics.requests.with_label_values(&["get", "hit"]).inc();
+            return Self::serve(meta, data, range);
+        }
+        self.metrics.requests.with_label_values(&["get", "miss"]).inc();
+        let generation = self.lru.lock().generation;
+        let obj = self.inner.get_object(bucket, key, None).await?;
+        if obj.meta.size > self.max_object {
+            // Too big to cache; a ranged read starts over so only the range is read
+            return match range {
+                Some(_) => self.inner.get_object(bucket, key, range).await,
+                None => Ok(obj),
+            };
+        }
+        let mut data = BytesMut::with_capacity(obj.meta.size as usize);
+        let mut body = obj.body;
+        while let Some(chunk) = body.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        let data = data.freeze();
+        {
+            let mut lru = self.lru.lock();
+            if lru.generation == generation && data.len() as u64 == obj.meta.size {
+                let evicted = lru.insert(cache_key(bucket, key), obj.meta.clone(), data.clone(), self.capacity);
+                self.metrics.evictions.inc_by(evicted);
+                self.update_gauges(&lru);
+            }
+        }
+        Self::serve(obj.meta, data, range)
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> StorageResult<ObjectMeta> {
+        if self.enabled(bucket) {
+            let cached = self.lookup(bucket, key).await?;
+            self.metrics.requests.with_label_values(&["head", if cached.is_some() { "hit" } else { "miss" }]).inc();
+            if let Some((meta, _)) = cached { return Ok(meta); }
+        }
+        self.inner.head_object(bucket, key).await
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> StorageResult<bool> {
+        let result = self.inner.delete_object(bucket, key).await;
+        self.invalidate(bucket, key);
+        result
+    }
+
+    async fn copy_object(&self, src_bucket: &str, src_key: &str, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        let result = self.inner.copy_object(src_bucket, src_key, bucket, key, attrs).await;
+        self.invalidate(bucket, key);
+        result
+    }
+
+    async fn update_attrs(&self, bucket: &str, key: &str, f: AttrsUpdate) -> StorageResult<()> {
+        let result = self.inner.update_attrs(bucket, key, f).await;
+        self.invalidate(bucket, key);
+        result
+    }
+
+    async fn list_objects(&self, bucket: &str, prefix: &str, delimiter: Option<&str>, marker: &str, max_keys: usize) -> StorageResult<ListPage> {
+        self.inner.list_objects(bucket, prefix, delimiter, marker, max_keys).await
+    }
+
+    async fn create_multipart_upload(&self, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<String> {
+        self.inner.create_multipart_upload(bucket, key, attrs).await
+    }
+
+    async fn upload_attrs(&self, bucket: &str, upload_id: &str) -> StorageResult<ObjectAttrs> {
+        self.inner.upload_attrs(bucket, upload_id).await
+    }
+
+    async fn upload_part(&self, bucket: &str, upload_id: &str, part_number: u32, body: ByteStream) -> StorageResult<String> {
+        self.inner.upload_part(bucket, upload_id, part_number, body).await
+    }
+
+    async fn list_parts(&self, bucket: &str, upload_id: &str) -> StorageResult<Vec<PartInfo>> {
+        self.inner.list_parts(bucket, upload_id).await
+    }
+
+    async fn complete_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str, parts: &[(u32, String)], update: Option<AttrsUpdate>) -> StorageResult<ObjectMeta> {
+        let result = self.inner.complete_multipart_upload(bucket, key, upload_id, parts, update).await;
+        self.invalidate(bucket, key);
+        result
+    }
+
+    async fn abort_multipart_upload(&self, bucket: &str, upload_id: &str) -> StorageResult<()> {
+        self.inner.abort_multipart_upload(bucket, upload_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryBackend;
+    use tokio::sync::Semaphore;
+
+    /// A memory backend whose object bodies wait for a permit, to hold a read between the
+    /// backend answering and the cache storing what it read.
+    #[derive(Default)]
+    struct Gated {
+        inner: MemoryBackend,
+        gate: Option<Arc<Semaphore>>,
+        answered: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl StorageBackend for Gated {
+        async fn is_ready(&self) -> bool { true }
+        async fn list_buckets(&self) -> StorageResult<Vec<BucketInfo>> { self.inner.list_buckets().await }
+        async fn head_bucket(&self, bucket: &str) -> StorageResult<()> { self.inner.head_bucket(bucket).await }
+        async fn create_bucket(&self, bucket: &str) -> StorageResult<()> { self.inner.create_bucket(bucket).await }
+        async fn delete_bucket(&self, bucket: &str) -> StorageResult<()> { self.inner.delete_bucket(bucket).await }
+        async fn get_bucket_config(&self, bucket: &str, name: &str) -> StorageResult<Option<Vec<u8>>> { self.inner.get_bucket_config(bucket, name).await }
+        async fn put_bucket_config(&self, bucket: &str, name: &str, value: Option<Vec<u8>>) -> StorageResult<()> { self.inner.put_bucket_config(bucket, name, value).await }
+        async fn put_object(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> { self.inner.put_object(bucket, key, body, attrs).await }
+        async fn put_object_with_etag(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs, etag: DeferredEtag) -> StorageResult<ObjectMeta> { self.inner.put_object_with_etag(bucket, key, body, attrs, etag).await }
+        async fn get_object(&self, bucket: &str, key: &str, range: Option<ByteRange>) -> StorageResult<GetObject> {
+            let mut obj = self.inner.get_object(bucket, key, range).await?;
+            self.answered.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if let Some(gate) = self.gate.clone() {
+                let body = obj.body;
+                obj.body = Box::pin(futures::stream::once(async move { gate.acquire_owned().await.unwrap().forget(); body }).flatten());
+            }
+            Ok(obj)
+        }
+        async fn head_object(&self, bucket: &str, key: &str) -> StorageResult<ObjectMeta> { self.inner.head_object(bucket, key).await }
+        async fn delete_object(&self, bucket: &str, key: &str) -> StorageResult<bool> { self.inner.delete_object(bucket, key).await }
+        async fn copy_object(&self, src_bucket: &str, src_key: &str, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> { self.inner.copy_object(src_bucket, src_key, bucket, key, attrs).await }
+        async fn update_attrs(&self, bucket: &str, key: &str, f: AttrsUpdate) -> StorageResult<()> { self.inner.update_attrs(bucket, key, f).await }
+        async fn list_objects(&self, bucket: &str, prefix: &str, delimiter: Option<&str>, marker: &str, max_keys: usize) -> StorageResult<ListPage> { self.inner.list_objects(bucket, prefix, delimiter, marker, max_keys).await }
+        async fn create_multipart_upload(&self, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<String> { self.inner.create_multipart_upload(bucket, key, attrs).await }
+        async fn upload_attrs(&self, bucket: &str, upload_id: &str) -> StorageResult<ObjectAttrs> { self.inner.upload_attrs(bucket, upload_id).await }
+        async fn upload_part(&self, bucket: &str, upload_id: &str, part_number: u32, body: ByteStream) -> StorageResult<String> { self.inner.upload_part(bucket, upload_id, part_number, body).await }
+        async fn list_parts(&self, bucket: &str, upload_id: &str) -> StorageResult<Vec<PartInfo>> { self.inner.list_parts(bucket, upload_id).await }
+        async fn complete_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str, parts: &[(u32, String)], update: Option<AttrsUpdate>) -> StorageResult<ObjectMeta> { self.inner.complete_multipart_upload(bucket, key, upload_id, parts, update).await }
+        async fn abort_multipart_upload(&self, bucket: &str, upload_id: &str) -> StorageResult<()> { self.inner.abort_multipart_upload(bucket, upload_id).await }
+    }
+
+    fn caching(inner: Arc<dyn StorageBackend>, capacity: u64, revalidate_after: Duration) -> CachingBackend {
+        CachingBackend {
+            inner,
+            lru: Mutex::new(Lru::default()),
+            buckets: None,
+            capacity,
+            max_object: capacity / 2,
+            revalidate_after,
+            metrics: CacheMetrics::register(&Registry::new()),
+        }
+    }
+
+    async fn backend() -> Arc<MemoryBackend> {
+        let inner = Arc::new(MemoryBackend::new());
+        inner.create_bucket("bkt").await.unwrap();
+        inner
+    }
+
+    async fn write(storage: &dyn StorageBackend, key: &str, body: &str) {
+        storage.put_object("bkt", key, bytes_stream(body.to_string()), Default::default()).await.unwrap();
+    }
+
+    async fn read(storage: &dyn StorageBackend, key: &str) -> StorageResult<String> {
+        let mut body = storage.get_object("bkt", key, None).await?.body;
+        let mut out = Vec::new();
+        while let Some(chunk) = body.next().await { out.extend_from_slice(&chunk?); }
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    fn cached(cache: &CachingBackend) -> Vec<String> {
+        let lru = cache.lru.lock();
+        lru.order.values().map(|(_, key)| key.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_to_stay_within_capacity() {
+        let inner = backend().await;
+        let cache = caching(inner.clone(), 10, Duration::from_secs(3600));
+        for key in ["a", "b", "c"] { write(inner.as_ref(), key, "4444").await; }
+        write(inner.as_ref(), "big", "666666").await;
+        read(&cache, "a").await.unwrap();
+        read(&cache, "b").await.unwrap();
+        // Using `a` again leaves `b` the one to go
+        cache.head_object("bkt", "a").await.unwrap();
+        read(&cache, "c").await.unwrap();
+        assert_eq!(cached(&cache), ["a", "c"]);
+        assert_eq!((cache.metrics.evictions.get(), cache.metrics.bytes.get(), cache.metrics.objects.get()), (1, 8, 2));
+        // More than `max_object` is served without being cached
+        assert_eq!(read(&cache, "big").await.unwrap(), "666666");
+        assert_eq!(cached(&cache), ["a", "c"]);
+        assert_eq!(cache.metrics.requests.with_label_values(&["get", "miss"]).get(), 4);
+        assert_eq!(cache.metrics.requests.with_label_values(&["head", "hit"]).get(), 1);
+    }
+
+    #[tokio::test]
+    async fn writes_through_the_cache_drop_its_entries() {
+        let inner = backend().await;
+        let cache = caching(inner.clone(), 1 << 20, Duration::from_secs(3600));
+        write(inner.as_ref(), "obj", "old").await;
+        write(inner.as_ref(), "src", "copied").await;
+        assert_eq!(read(&cache, "obj").await.unwrap(), "old");
+        // Unnoticed when made behind the cache's back
+        write(inner.as_ref(), "obj", "behind").await;
+        assert_eq!(read(&cache, "obj").await.unwrap(), "old");
+
+        write(&cache, "obj", "new").await;
+        assert_eq!(read(&cache, "obj").await.unwrap(), "new");
+        cache.copy_object("bkt", "src", "bkt", "obj", Default::default()).await.unwrap();
+        assert_eq!(read(&cache, "obj").await.unwrap(), "copied");
+        assert!(cache.delete_object("bkt", "obj").await.unwrap());
+        assert!(matches!(read(&cache, "obj").await, Err(StorageError::NoSuchKey)));
+        assert!(matches!(cache.head_object("bkt", "obj").await, Err(StorageError::NoSuchKey)));
+    }
+
+    #[tokio::test]
+    async fn reads_racing_a_write_are_not_cached() {
+        let gate = Arc::new(Semaphore::new(0));
+        let inner = Arc::new(Gated { gate: Some(gate.clone()), ..Gated::default() });
+        inner.create_bucket("bkt").await.unwrap();
+        write(inner.as_ref(), "obj", "old").await;
+        let cache = Arc::new(caching(inner.clone(), 1 << 20, Duration::from_secs(3600)));
+
+        let c = cache.clone();
+        let racing = tokio::spawn(async move { read(c.as_ref(), "obj").await.unwrap() });
+        // The read got its answer from the backend and waits for the body
+        while inner.answered.load(std::sync::atomic::Ordering::SeqCst) == 0 { tokio::task::yield_now().await; }
+        write(cache.as_ref(), "obj", "new").await;
+        gate.add_permits(1);
+        assert_eq!(racing.await.unwrap(), "old");
+        assert!(cached(&cache).is_empty());
+        gate.add_permits(1);
+        assert_eq!(read(cache.as_ref(), "obj").await.unwrap(), "new");
+    }
+
+    #[tokio::test]
+    async fn old_entries_are_served_while_their_etag_is_current() {
+        let inner = backend().await;
+        let cache = caching(inner.clone(), 1 << 20, Duration::ZERO);
+        write(inner.as_ref(), "same", "content").await;
+        write(inner.as_ref(), "changed", "before").await;
+        read(&cache, "same").await.unwrap();
+        read(&cache, "changed").await.unwrap();
+
+        // Rewritten with the same content keeps the ETag, and the cached bytes stay good
+        write(inner.as_ref(), "same", "content").await;
+        write(inner.as_ref(), "changed", "after!").await;
+        assert_eq!(read(&cache, "same").await.unwrap(), "content");
+        assert_eq!(cache.metrics.requests.with_label_values(&["get", "hit"]).get(), 1);
+        assert_eq!(read(&cache, "changed").await.unwrap(), "after!");
+        inner.delete_object("bkt", "same").await.unwrap();
+        assert!(matches!(cache.head_object("bkt", "same").await, Err(StorageError::NoSuchKey)));
+        assert_eq!(cached(&cache), ["changed"]);
+    }
+}
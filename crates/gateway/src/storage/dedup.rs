@@ -0,0 +1,279 @@
+//! Content-addressed deduplication of object data (`DEDUP=1`).
+//!
+//! Every distinct content is kept once as a blob in `.dedup/<sha256[..2]>/<sha256>` on the
+//! mount, and objects of at least [`MIN_SIZE`] bytes holding it are hard links to that blob, so
+//! buckets stay plain files and reads do not change at all. The link count of a blob's inode is
+//! its reference count: one for the pool entry plus one per object. A PUT whose content is
+//! already pooled replaces its staged file by a link to the blob, and CopyObject links the source
+//! instead of copying it, so neither takes extra capacity. Deleting an object only drops a link;
+//! `3fs-s3-gateway dedup gc` removes blobs no object refers to any more.
+//!
+//! Objects sharing an inode must never be modified in place, which the gateway never does, and
+//! keep their metadata in sidecars even with `METADATA_STORE=xattr`, since an xattr would be
+//! shared as well.
+
+use crate::config::GatewayConfig;
+use crate::storage::ByteStream;
+use fs_err as fs;
+use futures::StreamExt;
+use parking_lot::Mutex;
+use ring::digest::{Context, SHA256};
+use serde::Serialize;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Smaller objects are stored as they are: sharing them saves less than the pool entry and the
+/// sidecar a shared object needs cost
+pub const MIN_SIZE: u64 = 64 << 10;
+
+pub fn pool_dir(cfg: &GatewayConfig) -> PathBuf {
+    Path::new(&cfg.mountpoint).join(".dedup")
+}
+
+fn blob_path(cfg: &GatewayConfig, digest: &str) -> PathBuf {
+    pool_dir(cfg).join(&digest[..2]).join(digest)
+}
+
+/// Whether the data file described by `md` is shared with other objects or the pool.
+pub fn is_shared(md: &std::fs::Metadata) -> bool {
+    md.nlink() > 1
+}
+
+/// The SHA-256 of everything `body` yields, read from the returned handle once it has ended.
+pub fn hashing(body: ByteStream) -> (ByteStream, Arc<Mutex<Context>>) {
+    let ctx = Arc::new(Mutex::new(Context::new(&SHA256)));
+    let tap = ctx.clone();
+    let body = body.map(move |chunk| {
+        if let Ok(b) = &chunk { tap.lock().update(b); }
+        chunk
+    });
+    (Box::pin(body), ctx)
+}
+
+pub fn hex_digest(ctx: &Mutex<Context>) -> String {
+    hex::encode(ctx.lock().clone().finish())
+}
+
+/// SHA-256 of a file's content, read in 1 MiB chunks.
+pub fn hash_file(path: &Path) -> std::io::Result<String> {
+    use std::io::Read;
+    let mut f = std::fs::File::open(path)?;
+    let mut ctx = Context::new(&SHA256);
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        match f.read(&mut buf)? {
+            0 => return Ok(hex::encode(ctx.finish())),
+            n => ctx.update(&buf[..n]),
+        }
+    }
+}
+
+/// Makes the staged file `staged`, whose content hashes to `digest`, a link to the pooled blob
+/// of that content, pooling it first if there is none. Returns whether the content was already
+/// pooled.
+pub fn intern(cfg: &GatewayConfig, staged: &Path, digest: &str) -> std::io::Result<bool> {
+    let blob = blob_path(cfg, digest);
+    if let Some(parent) = blob.parent() { fs::create_dir_all(parent)?; }
+    let size = fs::metadata(staged)?.len();
+    // A blob collected between our two links is simply pooled again
+    for _ in 0..3 {
+        match fs::hard_link(staged, &blob) {
+            Ok(()) => return Ok(false),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+        let existing = match fs::metadata(&blob) {
+            Ok(md) => md,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if existing.ino() == fs::metadata(staged)?.ino() { return Ok(true); }
+        if existing.len() != size {
+            return Err(std::io::Error::other(format!("pooled blob {} has {} bytes, not {size}", blob.display(), existing.len())));
+        }
+        let link = staged.with_extension("link");
+        match fs::hard_link(&blob, &link) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+        // Drops the staged copy, whose only link this was
+        fs::rename(&link, staged)?;
+        return Ok(true);
+    }
+    Err(std::io::Error::other(format!("could not pool {}", blob.display())))
+}
+
+/// What `dedup stats` and `dedup gc` report about the pool.
+#[derive(Debug, Default, Serialize)]
+pub struct PoolReport {
+    /// Blobs still referenced by at least one object
+    pub blobs: u64,
+    /// Objects referring to those blobs
+    pub references: u64,
+    /// Bytes the pooled blobs take
+    pub stored_bytes: u64,
+    /// Bytes the referring objects would take without deduplication
+    pub logical_bytes: u64,
+    /// Blobs without references, removed by `gc`
+    pub unreferenced: u64,
+    pub unreferenced_bytes: u64,
+    pub removed: u64,
+}
+
+/// Walks the pool, removing blobs no object links to any more when `collect` is set.
+///
+/// A PUT may link a blob between its check and its removal here; that object keeps its data,
+/// and only later duplicates of it are pooled anew instead of sharing its inode.
+pub fn scan(cfg: &GatewayConfig, collect: bool) -> anyhow::Result<PoolReport> {
+    let mut report = PoolReport::default();
+    let root = pool_dir(cfg);
+    for dir in fs::read_dir(&root).into_iter().flat_map(|rd| rd.flatten()) {
+        if !dir.file_type().is_ok_and(|t| t.is_dir()) { continue; }
+        for e in fs::read_dir(dir.path())?.flatten() {
+            let Ok(md) = e.metadata() else { continue };
+            if !md.is_file() { continue; }
+            let references = md.nlink() - 1;
+            if references > 0 {
+                report.blobs += 1;
+                report.references += references;
+                report.stored_bytes += md.len();
+                report.logical_bytes += md.len() * references;
+                continue;
+            }
+            report.unreferenced += 1;
+            report.unreferenced_bytes += md.len();
+            if collect {
+                match fs::remove_file(e.path()) {
+                    Ok(()) => report.removed += 1,
+                    Err(err) => tracing::warn!(path = %e.path().display(), error = %err, "failed to remove unreferenced blob"),
+                }
+            }
+        }
+        if collect { let _ = fs::remove_dir(dir.path()); }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MetadataStore;
+    use crate::storage::posix::{self, PosixBackend};
+    use crate::storage::{bytes_stream, xattr, ObjectAttrs, StorageBackend};
+
+    fn config(dir: &Path) -> GatewayConfig {
+        GatewayConfig {
+            mountpoint: dir.to_string_lossy().into_owned(),
+            data_root: dir.join("buckets").to_string_lossy().into_owned(),
+            dedup: true,
+            ..GatewayConfig::in_memory()
+        }
+    }
+
+    /// Writes `content` to a new staged file and returns it with its digest.
+    fn stage(dir: &Path, name: &str, content: &[u8]) -> (PathBuf, String) {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        let digest = hash_file(&path).unwrap();
+        (path, digest)
+    }
+
+    #[test]
+    fn interning_pools_new_content_and_links_known_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let (first, digest) = stage(dir.path(), "first", b"shared content");
+        assert!(!intern(&cfg, &first, &digest).unwrap());
+        let blob = fs::metadata(blob_path(&cfg, &digest)).unwrap();
+        assert_eq!(blob.ino(), fs::metadata(&first).unwrap().ino());
+        assert_eq!(blob.nlink(), 2);
+
+        let (second, _) = stage(dir.path(), "second", b"shared content");
+        assert!(intern(&cfg, &second, &digest).unwrap());
+        assert_eq!(fs::metadata(&second).unwrap().ino(), blob.ino());
+        assert_eq!(fs::metadata(blob_path(&cfg, &digest)).unwrap().nlink(), 3);
+        assert!(!second.with_extension("link").exists());
+        // Interning a file that already is the blob changes nothing
+        assert!(intern(&cfg, &second, &digest).unwrap());
+        assert_eq!(fs::metadata(blob_path(&cfg, &digest)).unwrap().nlink(), 3);
+        assert_eq!(fs::read(&second).unwrap(), b"shared content");
+    }
+
+    #[test]
+    fn content_collected_in_between_is_pooled_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let (first, digest) = stage(dir.path(), "first", b"short-lived");
+        assert!(!intern(&cfg, &first, &digest).unwrap());
+        fs::remove_file(&first).unwrap();
+        assert_eq!(scan(&cfg, true).unwrap().removed, 1);
+
+        let (second, _) = stage(dir.path(), "second", b"short-lived");
+        assert!(!intern(&cfg, &second, &digest).unwrap());
+        assert_eq!(fs::metadata(blob_path(&cfg, &digest)).unwrap().ino(), fs::metadata(&second).unwrap().ino());
+    }
+
+    #[test]
+    fn blobs_of_another_size_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let (first, digest) = stage(dir.path(), "first", b"pooled");
+        intern(&cfg, &first, &digest).unwrap();
+        let (other, _) = stage(dir.path(), "other", b"claims the same digest");
+        assert!(intern(&cfg, &other, &digest).is_err());
+        assert_eq!(fs::read(&other).unwrap(), b"claims the same digest");
+    }
+
+    #[test]
+    fn scan_counts_references_and_gc_removes_only_unreferenced_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let mut staged = Vec::new();
+        for (name, content) in [("a1", "aaaa"), ("a2", "aaaa"), ("a3", "aaaa"), ("b1", "bb")] {
+            let (path, digest) = stage(dir.path(), name, content.as_bytes());
+            intern(&cfg, &path, &digest).unwrap();
+            staged.push(path);
+        }
+        let report = scan(&cfg, false).unwrap();
+        assert_eq!((report.blobs, report.references, report.stored_bytes, report.logical_bytes), (2, 4, 6, 14));
+        assert_eq!(report.unreferenced, 0);
+
+        fs::remove_file(&staged[3]).unwrap();
+        let report = scan(&cfg, false).unwrap();
+        assert_eq!((report.blobs, report.unreferenced, report.unreferenced_bytes, report.removed), (1, 1, 2, 0));
+        let report = scan(&cfg, true).unwrap();
+        assert_eq!((report.blobs, report.removed), (1, 1));
+        assert_eq!(scan(&cfg, false).unwrap().unreferenced, 0);
+        assert_eq!(fs::read(&staged[0]).unwrap(), b"aaaa");
+    }
+
+    #[tokio::test]
+    async fn shared_objects_keep_their_metadata_in_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = GatewayConfig { metadata_store: MetadataStore::Xattr, ..config(dir.path()) };
+        let backend = PosixBackend::new(cfg.clone());
+        backend.create_bucket("bkt").await.unwrap();
+        let body = vec![7u8; MIN_SIZE as usize];
+        for (key, content_type) in [("one", "text/plain"), ("two", "image/png")] {
+            let attrs = ObjectAttrs { content_type: content_type.into(), ..Default::default() };
+            backend.put_object("bkt", key, bytes_stream(body.clone()), attrs).await.unwrap();
+        }
+        backend.put_object("bkt", "small", bytes_stream(b"not pooled".to_vec()), Default::default()).await.unwrap();
+
+        let (small, small_meta) = posix::object_paths(&cfg, "bkt", "small").unwrap();
+        // Nothing to tell apart on a filesystem that refuses user xattrs
+        if xattr::get(&small).is_none() { return; }
+        assert!(!small_meta.exists());
+        for (key, content_type) in [("one", "text/plain"), ("two", "image/png")] {
+            let (data, meta) = posix::object_paths(&cfg, "bkt", key).unwrap();
+            assert!(is_shared(&fs::metadata(&data).unwrap()));
+            assert!(xattr::get(&data).is_none() && meta.exists(), "{key}");
+            assert_eq!(backend.head_object("bkt", key).await.unwrap().attrs.content_type, content_type);
+        }
+        // Nor does migrating to xattrs move them there
+        assert_eq!(posix::migrate_metadata(&cfg, &backend, "bkt").await.unwrap(), 0);
+        assert!(posix::object_paths(&cfg, "bkt", "one").unwrap().1.exists());
+    }
+}
@@ -0,0 +1,76 @@
+//! How far object writes are synced before they are acknowledged.
+//!
+//! The gateway default is `DURABILITY`; a bucket can override it with its `durability`
+//! configuration, set through `3fs-s3-gateway bucket durability`. PUT, CopyObject,
+//! CompleteMultipartUpload and metadata updates fsync the staged data file and the sidecar
+//! (or the file carrying the metadata xattr) at [`Durability::Data`], and additionally the
+//! directories their renames changed at [`Durability::DataDir`].
+
+use crate::config::{Durability, GatewayConfig};
+use crate::storage::{self, StorageBackend};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs as tfs;
+
+pub const CONFIG_NAME: &str = "durability";
+
+/// Contents of the `durability` bucket configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BucketDurability {
+    pub mode: Durability,
+}
+
+/// The durability of writes to `bucket`.
+pub async fn for_bucket(cfg: &GatewayConfig, storage: &dyn StorageBackend, bucket: &str) -> Durability {
+    storage::bucket_setting::<BucketDurability>(storage, bucket, CONFIG_NAME).await.map_or(cfg.durability, |d| d.mode)
+}
+
+/// Overrides the gateway default for `bucket`, or goes back to it with `None`.
+pub async fn set(storage: &dyn StorageBackend, bucket: &str, mode: Option<Durability>) -> anyhow::Result<()> {
+    storage::set_bucket_setting(storage, bucket, CONFIG_NAME, mode.map(|mode| BucketDurability { mode }).as_ref()).await
+}
+
+/// Syncs the contents of the file at `path` at [`Durability::Data`] and above. fsync through a
+/// new descriptor covers writes made through any other, including the ring data paths.
+pub async fn sync_file(path: &Path, mode: Durability) -> std::io::Result<()> {
+    if mode < Durability::Data { return Ok(()); }
+    tfs::File::open(path).await?.sync_all().await
+}
+
+/// Syncs `dir` and its ancestors up to and including `root` at [`Durability::DataDir`], so
+/// entries renamed into `dir`, and any directories created on the way, survive a crash.
+pub async fn sync_dirs(dir: &Path, root: &Path, mode: Durability) -> std::io::Result<()> {
+    if mode < Durability::DataDir { return Ok(()); }
+    let mut next = Some(dir);
+    while let Some(d) = next {
+        tfs::File::open(d).await?.sync_all().await?;
+        if d == root || !d.starts_with(root) { break; }
+        next = d.parent();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryBackend;
+    use crate::storage::StorageBackend;
+
+    #[tokio::test]
+    async fn bucket_overrides_live_in_the_backend() {
+        let cfg = GatewayConfig { durability: Durability::Data, ..GatewayConfig::in_memory() };
+        let storage = MemoryBackend::new();
+        storage.create_bucket("bkt").await.unwrap();
+        assert_eq!(for_bucket(&cfg, &storage, "bkt").await, Durability::Data);
+
+        set(&storage, "bkt", Some(Durability::DataDir)).await.unwrap();
+        assert_eq!(for_bucket(&cfg, &storage, "bkt").await, Durability::DataDir);
+        set(&storage, "bkt", None).await.unwrap();
+        assert_eq!(for_bucket(&cfg, &storage, "bkt").await, Durability::Data);
+
+        assert!(set(&storage, "missing", Some(Durability::None)).await.is_err());
+        // A broken override falls back to the gateway default
+        storage.put_bucket_config("bkt", CONFIG_NAME, Some(b"{".to_vec())).await.unwrap();
+        assert_eq!(for_bucket(&cfg, &storage, "bkt").await, Durability::Data);
+    }
+}
@@ -0,0 +1,210 @@
+//! ETags for files that have no sidecar describing them, such as files written to the mount
+//! by POSIX jobs or a sidecar left behind by an out-of-band overwrite. They are MD5s of the
+//! contents, computed on demand (in the background for large files) and cached by
+//! [`FileStamp`] in memory and under `.etags/<bucket>/` on the mount, so every pod reuses
+//! them. A cached ETag is only served while the file's size, mtime and inode still match.
+
+use crate::config::GatewayConfig;
+use crate::storage::index;
+use dashmap::{DashMap, DashSet};
+use fs_err as fs;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+/// Files up to this size are hashed on the spot when a GET or HEAD needs their ETag
+pub const INLINE_MAX_BYTES: u64 = 1 << 20;
+/// Background hashes running at once
+static WORKERS: Semaphore = Semaphore::const_new(2);
+
+static MEMO: Lazy<DashMap<PathBuf, (FileStamp, String)>> = Lazy::new(DashMap::new);
+static PENDING: Lazy<DashSet<PathBuf>> = Lazy::new(DashSet::new);
+
+/// Identifies one version of a file's contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime_ns: i64,
+    pub ino: u64,
+}
+
+impl FileStamp {
+    pub fn of(md: &std::fs::Metadata) -> Self {
+        Self { size: md.len(), mtime_ns: md.mtime() * 1_000_000_000 + md.mtime_nsec(), ino: md.ino() }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cached {
+    key: String,
+    stamp: FileStamp,
+    etag: String,
+}
+
+fn cache_path(cfg: &GatewayConfig, bucket: &str, key: &str) -> PathBuf {
+    bucket_cache_dir(cfg, bucket).join(format!("{:x}.json", md5::compute(key)))
+}
+
+pub fn bucket_cache_dir(cfg: &GatewayConfig, bucket: &str) -> PathBuf {
+    Path::new(&cfg.mountpoint).join(".etags").join(bucket)
+}
+
+/// The cached ETag of `data` as described by `md`, hashing files up to `inline_max` bytes now
+/// and larger ones in the background; empty until the hash is known.
+pub fn lookup(cfg: &GatewayConfig, bucket: &str, key: &str, data: &Path, md: &std::fs::Metadata, inline_max: u64) -> String {
+    let stamp = FileStamp::of(md);
+    if let Some(m) = MEMO.get(data).filter(|m| m.0 == stamp) { return m.1.clone(); }
+    let cached = fs::read(cache_path(cfg, bucket, key)).ok().and_then(|b| serde_json::from_slice::<Cached>(&b).ok());
+    if let Some(c) = cached.filter(|c| c.stamp == stamp && c.key == key) {
+        MEMO.insert(data.to_path_buf(), (stamp, c.etag.clone()));
+        return c.etag;
+    }
+    if md.len() <= inline_max {
+        return hash(cfg, bucket, key, data, stamp).unwrap_or_default();
+    }
+    spawn_hash(cfg, bucket, key, data, stamp);
+    String::new()
+}
+
+/// Hashes `data` and caches the result, unless the file changed from `stamp` meanwhile.
+fn hash(cfg: &GatewayConfig, bucket: &str, key: &str, data: &Path, stamp: FileStamp) -> Option<String> {
+    use std::io::Read;
+    let mut f = std::fs::File::open(data).ok()?;
+    if FileStamp::of(&f.metadata().ok()?) != stamp { return None; }
+    let mut hasher = md5::Context::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        match f.read(&mut buf).ok()? {
+            0 => break,
+            n => hasher.consume(&buf[..n]),
+        }
+    }
+    // The contents may have changed underneath the read
+    if fs::metadata(data).ok().map(|md| FileStamp::of(&md)) != Some(stamp) { return None; }
+    let etag = format!("\"{:x}\"", hasher.compute());
+    MEMO.insert(data.to_path_buf(), (stamp, etag.clone()));
+    let cached = Cached { key: key.to_string(), stamp, etag: etag.clone() };
+    if let Err(e) = write_cached(&cache_path(cfg, bucket, key), &cached) { warn!(%bucket, %key, error = %e, "failed to persist computed ETag"); }
+    Some(etag)
+}
+
+fn write_cached(path: &Path, cached: &Cached) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+    let tmp = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4().simple()));
+    fs::write(&tmp, serde_json::to_vec(cached)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn spawn_hash(cfg: &GatewayConfig, bucket: &str, key: &str, data: &Path, stamp: FileStamp) {
+    // Outside the server, e.g. `index rebuild`, there is nowhere to run it
+    let Ok(rt) = tokio::runtime::Handle::try_current() else { return };
+    if !PENDING.insert(data.to_path_buf()) { return; }
+    let (cfg, bucket, key, data) = (cfg.clone(), bucket.to_string(), key.to_string(), data.to_path_buf());
+    rt.spawn(async move {
+        let Ok(_permit) = WORKERS.acquire().await else { return };
+        let (c, b, k, d) = (cfg.clone(), bucket.clone(), key.clone(), data.clone());
+        let hashed = tokio::task::spawn_blocking(move || hash(&c, &b, &k, &d, stamp)).await.ok().flatten();
+        PENDING.remove(&data);
+        if hashed.is_some() {
+            debug!(%bucket, %key, "computed ETag");
+            index::sync_key(&cfg, &bucket, &key).await;
+        }
+    });
+}
+
+/// Drops what is cached for `key` once it was rewritten or deleted through the gateway.
+pub fn forget(cfg: &GatewayConfig, bucket: &str, key: &str, data: &Path) {
+    MEMO.remove(data);
+    let _ = fs::remove_file(cache_path(cfg, bucket, key));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path) -> GatewayConfig {
+        GatewayConfig {
+            mountpoint: dir.to_string_lossy().into_owned(),
+            data_root: dir.join("buckets").to_string_lossy().into_owned(),
+            ..GatewayConfig::in_memory()
+        }
+    }
+
+    fn md5_of(content: &[u8]) -> String {
+        format!("\"{:x}\"", md5::compute(content))
+    }
+
+    #[test]
+    fn small_files_are_hashed_on_the_spot_and_cached_on_the_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let data = dir.path().join("file");
+        fs::write(&data, "written by a job").unwrap();
+        let md = fs::metadata(&data).unwrap();
+        assert_eq!(lookup(&cfg, "bkt", "file", &data, &md, INLINE_MAX_BYTES), md5_of(b"written by a job"));
+        assert!(cache_path(&cfg, "bkt", "file").exists());
+
+        // Another pod has nothing in memory and must not hash again
+        MEMO.remove(&data);
+        assert_eq!(lookup(&cfg, "bkt", "file", &data, &md, 0), md5_of(b"written by a job"));
+    }
+
+    #[test]
+    fn modified_files_are_hashed_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let data = dir.path().join("file");
+        fs::write(&data, "first").unwrap();
+        let md = fs::metadata(&data).unwrap();
+        assert_eq!(lookup(&cfg, "bkt", "file", &data, &md, INLINE_MAX_BYTES), md5_of(b"first"));
+
+        fs::write(&data, "second version").unwrap();
+        let md = fs::metadata(&data).unwrap();
+        assert_eq!(lookup(&cfg, "bkt", "file", &data, &md, 0), "", "stale ETag served");
+        assert_eq!(lookup(&cfg, "bkt", "file", &data, &md, INLINE_MAX_BYTES), md5_of(b"second version"));
+
+        // Replaced by another file of the same size and mtime, only the inode tells them apart
+        let replacement = dir.path().join("replacement");
+        fs::write(&replacement, "second VERSION").unwrap();
+        std::fs::File::options().write(true).open(&replacement).unwrap().set_modified(md.modified().unwrap()).unwrap();
+        fs::rename(&replacement, &data).unwrap();
+        let md = fs::metadata(&data).unwrap();
+        assert_eq!(lookup(&cfg, "bkt", "file", &data, &md, INLINE_MAX_BYTES), md5_of(b"second VERSION"));
+    }
+
+    #[tokio::test]
+    async fn large_files_are_hashed_in_the_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let data = dir.path().join("file");
+        fs::write(&data, vec![3u8; 4096]).unwrap();
+        let md = fs::metadata(&data).unwrap();
+        assert_eq!(lookup(&cfg, "bkt", "file", &data, &md, 1024), "");
+        for _ in 0..100 {
+            let etag = lookup(&cfg, "bkt", "file", &data, &md, 1024);
+            if !etag.is_empty() {
+                assert_eq!(etag, md5_of(&[3u8; 4096]));
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("ETag never computed");
+    }
+
+    #[test]
+    fn forgotten_etags_are_dropped_from_memory_and_the_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let data = dir.path().join("file");
+        fs::write(&data, "content").unwrap();
+        let md = fs::metadata(&data).unwrap();
+        lookup(&cfg, "bkt", "file", &data, &md, INLINE_MAX_BYTES);
+        forget(&cfg, "bkt", "file", &data);
+        assert!(!cache_path(&cfg, "bkt", "file").exists());
+        assert_eq!(lookup(&cfg, "bkt", "file", &data, &md, 0), "");
+    }
+}
@@ -0,0 +1,171 @@
+//! Directories on the mount that were written without the gateway, registered as buckets.
+//!
+//! Registering links `DATA_ROOT/<bucket>` to the directory and records the registration in the
+//! bucket's `external` configuration, so everything else treats it like any bucket: files are
+//! objects, and those without sidecars get ETags through [`super::etag`]. Unregistering, or
+//! deleting the bucket, only removes the link; the directory and its files stay.
+
+use crate::config::GatewayConfig;
+use crate::storage::{etag, posix};
+use anyhow::Context;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+pub const CONFIG_NAME: &str = "external";
+
+/// Contents of the `external` bucket configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalBucket {
+    pub path: String,
+    /// Refuses every object write and DeleteBucket
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+fn config_path(cfg: &GatewayConfig, bucket: &str) -> PathBuf {
+    posix::bucket_config_dir(cfg, bucket).join(format!("{CONFIG_NAME}.json"))
+}
+
+/// Whether `bucket` is a registered directory rather than one the gateway created.
+pub fn is_external(cfg: &GatewayConfig, bucket: &str) -> bool {
+    fs::symlink_metadata(posix::bucket_dir(cfg, bucket)).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+/// The registration of `bucket`, or `None` for buckets the gateway created.
+pub fn get(cfg: &GatewayConfig, bucket: &str) -> Option<ExternalBucket> {
+    if !is_external(cfg, bucket) { return None; }
+    fs::read(config_path(cfg, bucket)).ok().and_then(|b| serde_json::from_slice(&b).ok())
+}
+
+/// Registers the directory at `path`, which must be on the 3FS mount and outside the
+/// gateway's own directories, as `bucket`.
+pub fn register(cfg: &GatewayConfig, bucket: &str, path: &str, read_only: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(!bucket.is_empty() && !bucket.starts_with('.') && !bucket.contains('/'), "invalid bucket name: {bucket}");
+    let link = posix::bucket_dir(cfg, bucket);
+    anyhow::ensure!(fs::symlink_metadata(&link).is_err(), "bucket {bucket} already exists");
+    let target = fs::canonicalize(path)?;
+    anyhow::ensure!(target.is_dir(), "{} is not a directory", target.display());
+    let mount = fs::canonicalize(&cfg.mountpoint).context("mountpoint")?;
+    fs::create_dir_all(&cfg.data_root)?;
+    // Objects are staged on the mount and renamed into place, which only works within it
+    let rel = target.strip_prefix(&mount).map_err(|_| anyhow::anyhow!("{} is not on the mount {}", target.display(), mount.display()))?;
+    let data_root = fs::canonicalize(&cfg.data_root).context("data root")?;
+    let reserved = target.starts_with(&data_root) || data_root.starts_with(&target)
+        || rel.components().next().is_some_and(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+    anyhow::ensure!(!reserved, "{} overlaps the gateway's own directories", target.display());
+    let registration = ExternalBucket { path: target.display().to_string(), read_only };
+    let config = config_path(cfg, bucket);
+    if let Some(parent) = config.parent() { fs::create_dir_all(parent)?; }
+    fs::write(&config, serde_json::to_vec_pretty(&registration)?)?;
+    std::os::unix::fs::symlink(&target, &link).with_context(|| format!("link {}", link.display()))?;
+    Ok(())
+}
+
+/// Forgets a registered bucket, leaving its directory alone.
+pub fn unregister(cfg: &GatewayConfig, bucket: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(is_external(cfg, bucket), "{bucket} is not a registered directory");
+    fs::remove_file(posix::bucket_dir(cfg, bucket))?;
+    let _ = fs::remove_file(config_path(cfg, bucket));
+    let _ = fs::remove_dir_all(etag::bucket_cache_dir(cfg, bucket));
+    Ok(())
+}
+
+/// Registered buckets by name.
+pub fn list(cfg: &GatewayConfig) -> anyhow::Result<Vec<(String, ExternalBucket)>> {
+    let mut out = Vec::new();
+    let Ok(entries) = fs::read_dir(&cfg.data_root) else { return Ok(out) };
+    for e in entries.flatten() {
+        let name = e.file_name().to_string_lossy().into_owned();
+        if let Some(reg) = get(cfg, &name) { out.push((name, reg)); }
+    }
+    out.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{bytes_stream, posix::PosixBackend, StorageBackend, StorageError};
+    use std::path::Path;
+
+    fn config(dir: &Path) -> GatewayConfig {
+        GatewayConfig {
+            mountpoint: dir.to_string_lossy().into_owned(),
+            data_root: dir.join("buckets").to_string_lossy().into_owned(),
+            ..GatewayConfig::in_memory()
+        }
+    }
+
+    fn md5_of(content: &[u8]) -> String {
+        format!("\"{:x}\"", md5::compute(content))
+    }
+
+    #[test]
+    fn only_directories_of_the_mount_outside_the_gateway_are_registered() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let elsewhere = tempfile::tempdir().unwrap();
+        for path in ["jobs", "buckets/mine", ".etags"] { fs::create_dir_all(dir.path().join(path)).unwrap(); }
+        fs::write(dir.path().join("jobs/file"), "x").unwrap();
+        let at = |p: &str| dir.path().join(p).to_string_lossy().into_owned();
+        for (bucket, path) in [("off", elsewhere.path().to_string_lossy().into_owned()), ("root", at("buckets")), ("inside", at("buckets/mine")), ("hidden", at(".etags")), ("file", at("jobs/file")), ("bad/name", at("jobs"))] {
+            assert!(register(&cfg, bucket, &path, false).is_err(), "{bucket} registered");
+            assert!(!is_external(&cfg, bucket), "{bucket}");
+        }
+
+        register(&cfg, "jobs", &at("jobs"), true).unwrap();
+        assert!(register(&cfg, "jobs", &at("jobs"), false).is_err(), "registered twice");
+        let listed = list(&cfg).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].0.as_str(), listed[0].1.read_only), ("jobs", true));
+
+        // Unregistering keeps the directory and its files
+        unregister(&cfg, "jobs").unwrap();
+        assert!(get(&cfg, "jobs").is_none() && list(&cfg).unwrap().is_empty());
+        assert_eq!(fs::read(dir.path().join("jobs/file")).unwrap(), b"x");
+        assert!(unregister(&cfg, "jobs").is_err());
+    }
+
+    #[tokio::test]
+    async fn files_of_registered_directories_get_current_etags() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        fs::create_dir_all(dir.path().join("jobs/out")).unwrap();
+        fs::write(dir.path().join("jobs/out/result.csv"), "a,b\n1,2\n").unwrap();
+        register(&cfg, "jobs", &dir.path().join("jobs").to_string_lossy(), false).unwrap();
+        let backend = PosixBackend::new(cfg.clone());
+
+        let head = backend.head_object("jobs", "out/result.csv").await.unwrap();
+        assert_eq!((head.size, head.etag.as_str()), (8, md5_of(b"a,b\n1,2\n").as_str()));
+        // Listings only report ETags already known, which this one now is
+        let page = backend.list_objects("jobs", "", None, "", 1000).await.unwrap();
+        assert_eq!(page.objects.iter().map(|o| (o.key.as_str(), o.etag.clone())).collect::<Vec<_>>(), [("out/result.csv", md5_of(b"a,b\n1,2\n"))]);
+
+        // The job rewrites its output; the old ETag must not be served for it
+        fs::write(dir.path().join("jobs/out/result.csv"), "a,b\n3,4\n5,6\n").unwrap();
+        let get = backend.get_object("jobs", "out/result.csv", None).await.unwrap();
+        assert_eq!(get.meta.etag, md5_of(b"a,b\n3,4\n5,6\n"));
+
+        // Objects written through the gateway land in the directory like any file
+        let put = backend.put_object("jobs", "in/params.json", bytes_stream(b"{}".to_vec()), Default::default()).await.unwrap();
+        assert_eq!(put.etag, md5_of(b"{}"));
+        assert_eq!(fs::read(dir.path().join("jobs/in/params.json")).unwrap(), b"{}");
+    }
+
+    #[tokio::test]
+    async fn read_only_directories_refuse_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        fs::create_dir_all(dir.path().join("jobs")).unwrap();
+        fs::write(dir.path().join("jobs/file"), "data").unwrap();
+        register(&cfg, "jobs", &dir.path().join("jobs").to_string_lossy(), true).unwrap();
+        let backend = PosixBackend::new(cfg.clone());
+
+        assert_eq!(backend.head_object("jobs", "file").await.unwrap().etag, md5_of(b"data"));
+        assert!(matches!(backend.put_object("jobs", "new", bytes_stream(b"x".to_vec()), Default::default()).await, Err(StorageError::AccessDenied)));
+        assert!(matches!(backend.delete_object("jobs", "file").await, Err(StorageError::AccessDenied)));
+        assert!(matches!(backend.delete_bucket("jobs").await, Err(StorageError::AccessDenied)));
+        assert_eq!(fs::read(dir.path().join("jobs/file")).unwrap(), b"data");
+    }
+}
@@ -0,0 +1,335 @@
+//! `3fs-s3-gateway fsck`: finds what crashed writers and abandoned uploads leave behind on the
+//! mount, and optionally repairs it.
+//!
+//! Temporary files and uploads are only reported once they are old enough that no live writer
+//! can still own them. Registered directories are only checked for temporary files, since their
+//! files lack sidecars by design.
+
+use crate::config::GatewayConfig;
+use crate::storage::lock::KeyLocks;
+use crate::storage::{durability, external, index, keys, posix, xattr, StorageBackend};
+use fs_err as fs;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Temporary files younger than this may belong to a write in progress
+const TEMP_GRACE: Duration = Duration::from_secs(3600);
+/// Multipart uploads without a new part for this long are abandoned
+const UPLOAD_GRACE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// A staging file or atomic-write temporary that was never renamed into place
+    OrphanedTemp,
+    /// A sidecar whose data file is gone
+    OrphanedSidecar,
+    /// A data file with neither a sidecar nor an xattr
+    MissingMetadata,
+    /// Metadata whose ETag is empty or describes an older version of the data file
+    StaleEtag,
+    /// A multipart upload that was never completed or aborted, or whose bucket is gone
+    StaleUpload,
+    /// A metadata index entry that disagrees with the files
+    Index,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub kind: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<serde_json::Value>,
+    pub repaired: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub buckets: usize,
+    pub objects: usize,
+    pub summary: BTreeMap<Kind, usize>,
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    /// Findings that are still on disk.
+    pub fn unrepaired(&self) -> usize {
+        self.findings.iter().filter(|f| !f.repaired).count()
+    }
+}
+
+struct Fsck<'a> {
+    cfg: &'a GatewayConfig,
+    storage: &'a dyn StorageBackend,
+    repair: bool,
+    locks: Option<KeyLocks>,
+    now: SystemTime,
+    report: Report,
+}
+
+/// Checks `bucket`, or every bucket plus the shared staging directory, repairing what it finds
+/// if `repair` is set.
+pub async fn run(cfg: &GatewayConfig, storage: &dyn StorageBackend, bucket: Option<&str>, repair: bool) -> anyhow::Result<Report> {
+    let mut fsck = Fsck { cfg, storage, repair, locks: KeyLocks::new(cfg), now: SystemTime::now(), report: Report::default() };
+    let buckets = match bucket {
+        Some(b) => {
+            anyhow::ensure!(posix::bucket_dir(cfg, b).is_dir(), "no such bucket: {b}");
+            vec![b.to_string()]
+        }
+        None => {
+            let mut all: Vec<String> = fs::read_dir(&cfg.data_root)?.flatten()
+                .filter(|e| fs::metadata(e.path()).is_ok_and(|m| m.is_dir()))
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect();
+            all.sort();
+            all
+        }
+    };
+    for b in &buckets { fsck.bucket(b).await?; }
+    let uploads = Path::new(&cfg.mountpoint).join(".multipart");
+    match bucket {
+        Some(b) => fsck.uploads(b, &uploads.join(b)),
+        None => {
+            // Staging files are shared by all buckets
+            fsck.temp_files(None, &Path::new(&cfg.mountpoint).join(".tmp"), |_| true);
+            for e in fs::read_dir(&uploads).into_iter().flat_map(|rd| rd.flatten()) {
+                fsck.uploads(&e.file_name().to_string_lossy(), &e.path());
+            }
+        }
+    }
+    fsck.report.buckets = buckets.len();
+    for f in &fsck.report.findings { *fsck.report.summary.entry(f.kind).or_default() += 1; }
+    Ok(fsck.report)
+}
+
+impl Fsck<'_> {
+    fn push(&mut self, kind: Kind, bucket: Option<&str>, key: Option<String>, path: Option<&Path>, outcome: Option<anyhow::Result<()>>) {
+        let (repaired, error) = match outcome {
+            Some(Ok(())) => (true, None),
+            Some(Err(e)) => (false, Some(format!("{e:#}"))),
+            None => (false, None),
+        };
+        let path = path.map(|p| p.display().to_string());
+        self.report.findings.push(Finding { kind, bucket: bucket.map(str::to_string), key, path, detail: None, repaired, error });
+    }
+
+    fn old_enough(&self, path: &Path, grace: Duration) -> bool {
+        fs::symlink_metadata(path).ok().and_then(|m| m.modified().ok())
+            .is_some_and(|t| self.now.duration_since(t).unwrap_or_default() >= grace)
+    }
+
+    /// Reports old files directly in `dir` that `is_temp` accepts.
+    fn temp_files(&mut self, bucket: Option<&str>, dir: &Path, is_temp: impl Fn(&str) -> bool) {
+        for e in fs::read_dir(dir).into_iter().flat_map(|rd| rd.flatten()) {
+            let path = e.path();
+            if !e.file_type().is_ok_and(|t| t.is_file()) || !is_temp(&e.file_name().to_string_lossy()) || !self.old_enough(&path, TEMP_GRACE) { continue; }
+            let outcome = self.repair.then(|| fs::remove_file(&path).map_err(Into::into));
+            self.push(Kind::OrphanedTemp, bucket, None, Some(&path), outcome);
+        }
+    }
+
+    async fn bucket(&mut self, bucket: &str) -> anyhow::Result<()> {
+        let base = posix::bucket_dir(self.cfg, bucket);
+        let external = external::get(self.cfg, bucket);
+        // Nothing may be written to a read-only registered directory, repairs included
+        let repair = self.repair;
+        if external.as_ref().is_some_and(|reg| reg.read_only) { self.repair = false; }
+        self.temp_files(Some(bucket), &posix::bucket_config_dir(self.cfg, bucket), |n| n.ends_with(".tmp"));
+        let mut dirs = vec![base.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries: Vec<_> = fs::read_dir(&dir)?.flatten().collect();
+            entries.sort_by_key(|e| e.file_name());
+            for e in entries {
+                let Ok(ft) = e.file_type() else { continue };
+                let path = e.path();
+                if ft.is_dir() { dirs.push(path); continue; }
+                if !ft.is_file() { continue; }
+                let name = e.file_name().to_string_lossy().into_owned();
+                if name.ends_with(".tmp") && keys::is_reserved(&name) {
+                    if self.old_enough(&path, TEMP_GRACE) {
+                        let outcome = self.repair.then(|| fs::remove_file(&path).map_err(Into::into));
+                        self.push(Kind::OrphanedTemp, Some(bucket), None, Some(&path), outcome);
+                    }
+                } else if external.is_none() {
+                    self.file(bucket, &base, &path, &name).await?;
+                }
+            }
+        }
+        if external.is_none() { self.index(bucket)?; }
+        self.repair = repair;
+        Ok(())
+    }
+
+    /// Checks one file in a bucket directory: a sidecar must have its data file, and a data file
+    /// metadata with a current ETag.
+    async fn file(&mut self, bucket: &str, base: &Path, path: &Path, name: &str) -> anyhow::Result<()> {
+        let sidecar_of = name.strip_suffix(".meta.json").map(|data| path.with_file_name(data));
+        let data = sidecar_of.clone().unwrap_or_else(|| path.to_path_buf());
+        // Files no key maps to were not written by the gateway
+        let Some(key) = key_of(self.cfg, base, &data) else { return Ok(()) };
+        if let Some(data) = sidecar_of {
+            if fs::metadata(&data).is_ok_and(|m| m.is_file()) { return Ok(()); }
+            let outcome = match self.repair {
+                true => Some(self.locked(bucket, &key, async { posix::delete_if_exists(path).await }).await),
+                false => None,
+            };
+            self.push(Kind::OrphanedSidecar, Some(bucket), Some(key), Some(path), outcome);
+            return Ok(());
+        }
+        self.report.objects += 1;
+        let meta = posix::sidecar_path(&data);
+        let kind = if !meta.exists() && xattr::get(&data).is_none() {
+            Kind::MissingMetadata
+        } else {
+            let Ok(md) = fs::metadata(&data) else { return Ok(()) };
+            if posix::sidecar_etag(&posix::read_sidecar(self.cfg, &data, &meta), &md, &meta).is_some() { return Ok(()); }
+            Kind::StaleEtag
+        };
+        let outcome = match self.repair {
+            true => {
+                let mode = durability::for_bucket(self.cfg, self.storage, bucket).await;
+                Some(self.locked(bucket, &key, async { posix::recompute_etag(self.cfg, &data, &meta, mode).await.map(drop) }).await)
+            }
+            false => None,
+        };
+        if outcome.as_ref().is_some_and(|o| o.is_ok()) { index::sync_key(self.cfg, bucket, &key).await; }
+        self.push(kind, Some(bucket), Some(key), Some(&data), outcome);
+        Ok(())
+    }
+
+    /// Runs a repair of `key` while holding its lock, so it cannot race a write through the gateway.
+    async fn locked(&self, bucket: &str, key: &str, repair: impl std::future::Future<Output = anyhow::Result<()>>) -> anyhow::Result<()> {
+        let _lock = match &self.locks {
+            Some(locks) => Some(locks.lock(bucket, key).await?),
+            None => None,
+        };
+        repair.await
+    }
+
+    fn uploads(&mut self, bucket: &str, dir: &Path) {
+        let bucket_exists = posix::bucket_dir(self.cfg, bucket).is_dir();
+        for e in fs::read_dir(dir).into_iter().flat_map(|rd| rd.flatten()) {
+            let path = e.path();
+            if !e.file_type().is_ok_and(|t| t.is_dir()) { continue; }
+            if bucket_exists && !self.old_enough(&path, UPLOAD_GRACE) { continue; }
+            let outcome = self.repair.then(|| fs::remove_dir_all(&path).map_err(Into::into));
+            self.push(Kind::StaleUpload, Some(bucket), None, Some(&path), outcome);
+        }
+        if self.repair && !bucket_exists { let _ = fs::remove_dir(dir); }
+    }
+
+    fn index(&mut self, bucket: &str) -> anyhow::Result<()> {
+        if !self.cfg.metadata_index { return Ok(()); }
+        let problems = index::check(self.cfg, bucket)?;
+        if problems.is_empty() { return Ok(()); }
+        let outcome = self.repair.then(|| index::rebuild(self.cfg, bucket).map(drop));
+        let (repaired, error) = match &outcome {
+            Some(Ok(())) => (true, None),
+            Some(Err(e)) => (false, Some(format!("{e:#}"))),
+            None => (false, None),
+        };
+        for p in problems {
+            let detail = serde_json::to_value(&p)?;
+            let key = detail.get("key").and_then(|k| k.as_str()).map(str::to_string);
+            self.report.findings.push(Finding { kind: Kind::Index, bucket: Some(bucket.to_string()), key, path: None, detail: Some(detail), repaired, error: error.clone() });
+        }
+        Ok(())
+    }
+}
+
+/// The key whose data file is `data`, if its path below `base` decodes to one.
+fn key_of(cfg: &GatewayConfig, base: &Path, data: &Path) -> Option<String> {
+    let rel: PathBuf = data.strip_prefix(base).ok()?.to_path_buf();
+    let segments = rel.components().map(|c| keys::decode(cfg.key_encoding, &c.as_os_str().to_string_lossy())).collect::<Option<Vec<_>>>()?;
+    Some(segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::bytes_stream;
+    use crate::storage::posix::PosixBackend;
+
+    /// Backdates `path` past every grace period.
+    fn age(path: &Path) {
+        let old = SystemTime::now() - UPLOAD_GRACE - Duration::from_secs(60);
+        std::fs::File::open(path).unwrap().set_modified(old).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_and_repairs_crash_leftovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = GatewayConfig {
+            mountpoint: dir.path().to_string_lossy().into_owned(),
+            data_root: dir.path().join("buckets").to_string_lossy().into_owned(),
+            ..GatewayConfig::in_memory()
+        };
+        let backend = PosixBackend::new(cfg.clone());
+        backend.create_bucket("bkt").await.unwrap();
+        let base = posix::bucket_dir(&cfg, "bkt");
+        for key in ["good", "lost"] {
+            backend.put_object("bkt", key, bytes_stream(key.as_bytes().to_vec()), Default::default()).await.unwrap();
+        }
+        // A sidecar whose data file is gone, and a data file nothing describes
+        fs::remove_file(base.join("lost")).unwrap();
+        fs::write(base.join("bare"), "written behind the gateway").unwrap();
+        // Temporaries of crashed writers, next to one a live writer may still own
+        let staging = dir.path().join(".tmp");
+        fs::create_dir_all(&staging).unwrap();
+        for name in ["old.tmp", "fresh.tmp"] { fs::write(staging.join(name), "partial").unwrap(); }
+        age(&staging.join("old.tmp"));
+        fs::write(base.join("good.meta..tmp"), "{").unwrap();
+        age(&base.join("good.meta..tmp"));
+        // An abandoned upload, and one still in progress
+        let stale = backend.create_multipart_upload("bkt", "big", Default::default()).await.unwrap();
+        backend.upload_part("bkt", &stale, 1, bytes_stream(b"part".to_vec())).await.unwrap();
+        let live = backend.create_multipart_upload("bkt", "other", Default::default()).await.unwrap();
+        let uploads = dir.path().join(".multipart/bkt");
+        age(&uploads.join(&stale));
+
+        let report = run(&cfg, &backend, None, false).await.unwrap();
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["buckets"], 1);
+        assert_eq!(json["objects"], 2);
+        assert_eq!(json["summary"], serde_json::json!({ "orphaned_temp": 2, "orphaned_sidecar": 1, "missing_metadata": 1, "stale_upload": 1 }));
+        let findings: Vec<(&str, Option<&str>, &str)> = json["findings"].as_array().unwrap().iter()
+            .map(|f| (f["kind"].as_str().unwrap(), f["key"].as_str(), f["path"].as_str().unwrap()))
+            .collect();
+        let path = |p: PathBuf| p.display().to_string();
+        for expected in [
+            ("orphaned_temp", None, path(base.join("good.meta..tmp"))),
+            ("orphaned_sidecar", Some("lost"), path(base.join("lost.meta.json"))),
+            ("missing_metadata", Some("bare"), path(base.join("bare"))),
+            ("orphaned_temp", None, path(staging.join("old.tmp"))),
+            ("stale_upload", None, path(uploads.join(&stale))),
+        ] {
+            assert!(findings.contains(&(expected.0, expected.1, expected.2.as_str())), "{expected:?} missing from {findings:?}");
+        }
+        assert!(json["findings"].as_array().unwrap().iter().all(|f| f["repaired"] == false && f.get("error").is_none()));
+        assert_eq!(report.unrepaired(), 5);
+        // Checking alone changes nothing
+        assert!(base.join("lost.meta.json").exists() && staging.join("old.tmp").exists());
+
+        let report = run(&cfg, &backend, None, true).await.unwrap();
+        assert_eq!(report.findings.len(), 5);
+        assert_eq!(report.unrepaired(), 0);
+        assert!(!staging.join("old.tmp").exists() && staging.join("fresh.tmp").exists());
+        assert!(!base.join("good.meta..tmp").exists() && !base.join("lost.meta.json").exists());
+        assert!(!uploads.join(&stale).exists() && uploads.join(&live).exists());
+        let bare = backend.head_object("bkt", "bare").await.unwrap();
+        assert_eq!(bare.etag, format!("\"{:x}\"", md5::compute("written behind the gateway")));
+        assert_eq!(backend.head_object("bkt", "good").await.unwrap().etag, format!("\"{:x}\"", md5::compute("good")));
+
+        let report = run(&cfg, &backend, Some("bkt"), false).await.unwrap();
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+    }
+}
@@ -0,0 +1,648 @@
+use crate::config::GatewayConfig;
+use crate::storage::{etag, external, posix, ObjectAttrs};
+use crate::storage::walk::{KeyWalker, ListEntry};
+use dashmap::DashMap;
+use fs_err as fs;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+// Other pods' journal records become visible to this pod within this interval
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+// Start a new journal segment past this size
+const SEGMENT_MAX_BYTES: u64 = 64 << 20;
+// Compact once this many journal bytes are not covered by the snapshot
+const COMPACT_AFTER_BYTES: u64 = 256 << 20;
+// A segment untouched for this long belongs to a pod that rotated or died
+const SEGMENT_IDLE: Duration = Duration::from_secs(600);
+const LOCK_STALE: Duration = Duration::from_secs(600);
+
+/// Everything listing and HEAD need to know about an object without touching its files.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub size: u64,
+    pub etag: String,
+    pub mtime_ms: i64,
+    /// Where the data of a packed object is, see [`super::pack`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pack: Option<super::pack::PackRef>,
+    #[serde(flatten)]
+    pub attrs: ObjectAttrs,
+}
+
+impl IndexEntry {
+    pub fn mtime(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp_millis(self.mtime_ms).unwrap_or_default()
+    }
+}
+
+/// A journal line: the state of `key` as of `ts` (ns since the epoch); `entry` is `None` for deletes.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    ts: i64,
+    key: String,
+    #[serde(default)]
+    entry: Option<IndexEntry>,
+}
+
+/// First line of a snapshot: the journal bytes already folded into it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SnapshotHeader {
+    covered: HashMap<String, u64>,
+}
+
+struct Versioned {
+    ts: i64,
+    entry: Option<IndexEntry>,
+}
+
+struct ActiveSegment {
+    name: String,
+    file: std::fs::File,
+    len: u64,
+}
+
+#[derive(Default)]
+struct State {
+    loaded: bool,
+    keys: BTreeMap<String, Versioned>,
+    /// Bytes consumed per journal segment
+    offsets: HashMap<String, u64>,
+    snapshot_stamp: Option<(u64, SystemTime)>,
+    active: Option<ActiveSegment>,
+    last_refresh: Option<Instant>,
+    uncompacted: u64,
+    /// Sequence number of this process's next journal segment
+    next_segment: u32,
+}
+
+/// Per-bucket metadata index kept on the 3FS mount so every gateway pod shares it:
+///
+/// - `journal/<process>-<seq>.log`: JSON lines appended by one gateway process each, so pods
+///   never write to the same file. A torn last line from a crash is ignored.
+/// - `snapshot.jsonl`: the compacted index, replaced atomically, whose header records how much
+///   of each journal segment it already contains.
+///
+/// Each pod keeps the merged index in memory and tails the other pods' segments on access.
+/// Conflicting records for one key resolve to the newest timestamp. The data files stay the
+/// source of truth; see [`rebuild`] and [`check`].
+pub struct BucketIndex {
+    dir: PathBuf,
+    state: Mutex<State>,
+}
+
+static INDEXES: Lazy<DashMap<PathBuf, Arc<BucketIndex>>> = Lazy::new(DashMap::new);
+static PROCESS_ID: Lazy<String> = Lazy::new(|| uuid::Uuid::new_v4().simple().to_string());
+
+pub fn index_dir(cfg: &GatewayConfig, bucket: &str) -> PathBuf {
+    Path::new(&cfg.mountpoint).join(".index").join(bucket)
+}
+
+/// The bucket's index, or `None` when `METADATA_INDEX` is off or the bucket is a registered
+/// directory, whose files change behind the gateway's back. A bucket whose index was never
+/// built, because it predates `METADATA_INDEX` or the index was removed, gets one built from
+/// its data files first, since an empty index would list it as empty. This blocks.
+pub fn open(cfg: &GatewayConfig, bucket: &str) -> Option<Arc<BucketIndex>> {
+    if !cfg.metadata_index || external::is_external(cfg, bucket) { return None; }
+    let dir = index_dir(cfg, bucket);
+    if !INDEXES.contains_key(&dir) && !dir.join("snapshot.jsonl").exists() && posix::bucket_dir(cfg, bucket).is_dir() {
+        match rebuild(cfg, bucket) {
+            Ok(keys) => info!(%bucket, keys, "built missing metadata index"),
+            Err(e) => warn!(%bucket, error = %e, "failed to build missing metadata index"),
+        }
+    }
+    Some(open_at(dir))
+}
+
+/// The index kept in `dir`, for other catalogs in the same format.
+pub(crate) fn open_at(dir: PathBuf) -> Arc<BucketIndex> {
+    INDEXES.entry(dir.clone()).or_insert_with(|| Arc::new(BucketIndex { dir, state: Mutex::new(State::default()) })).clone()
+}
+
+/// Forgets the index kept in `dir` and removes it.
+pub(crate) fn drop_at(dir: &Path) {
+    INDEXES.remove(dir);
+    let _ = fs::remove_dir_all(dir);
+}
+
+/// Forgets a deleted bucket's index.
+pub fn drop_bucket(cfg: &GatewayConfig, bucket: &str) {
+    drop_at(&index_dir(cfg, bucket));
+}
+
+/// Reads an object's index entry from its data file and sidecar.
+pub fn entry_from_fs(cfg: &GatewayConfig, bucket: &str, key: &str) -> Option<IndexEntry> {
+    let (data, meta) = posix::object_paths(cfg, bucket, key).ok()?;
+    let md = fs::metadata(&data).ok().filter(|m| m.is_file())?;
+    let sidecar = posix::read_sidecar(cfg, &data, &meta);
+    let etag = posix::current_etag(cfg, bucket, key, &data, &md, &meta, &sidecar, etag::INLINE_MAX_BYTES);
+    let mtime_ms = posix::last_modified(&sidecar, &md).timestamp_millis();
+    Some(IndexEntry { size: md.len(), etag, mtime_ms, pack: None, attrs: sidecar.attrs })
+}
+
+/// Records the current on-disk state of `key` after a write or delete.
+pub async fn sync_key(cfg: &GatewayConfig, bucket: &str, key: &str) {
+    if !cfg.metadata_index { return; }
+    let (cfg, bucket, key) = (cfg.clone(), bucket.to_string(), key.to_string());
+    let _ = tokio::task::spawn_blocking(move || {
+        let Some(idx) = open(&cfg, &bucket) else { return };
+        let result = match entry_from_fs(&cfg, &bucket, &key) {
+            Some(entry) => idx.put(&key, entry),
+            None => idx.delete(&key),
+        };
+        if let Err(e) = result { warn!(%bucket, %key, error = %e, "failed to update metadata index"); }
+    }).await;
+}
+
+/// Looks `key` up in the index, falling back to the data files for keys the index does not
+/// know yet (written out of band, or before the index existed) and recording what it finds.
+pub async fn lookup(cfg: &GatewayConfig, bucket: &str, key: &str) -> Option<IndexEntry> {
+    let (cfg, bucket, key) = (cfg.clone(), bucket.to_string(), key.to_string());
+    tokio::task::spawn_blocking(move || {
+        let idx = open(&cfg, &bucket);
+        if let Some(entry) = idx.as_ref().and_then(|i| i.get(&key)) { return Some(entry); }
+        let entry = entry_from_fs(&cfg, &bucket, &key)?;
+        if let Some(idx) = idx {
+            if let Err(e) = idx.put(&key, entry.clone()) { warn!(%bucket, %key, error = %e, "failed to update metadata index"); }
+        }
+        Some(entry)
+    }).await.ok().flatten()
+}
+
+fn now_ns() -> i64 {
+    chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+}
+
+fn apply(keys: &mut BTreeMap<String, Versioned>, rec: Record) {
+    match keys.get(&rec.key) {
+        Some(v) if v.ts > rec.ts => {}
+        _ => { keys.insert(rec.key, Versioned { ts: rec.ts, entry: rec.entry }); }
+    }
+}
+
+/// Smallest string greater than every string starting with `p`.
+pub(crate) fn prefix_successor(p: &str) -> Option<String> {
+    let mut chars: Vec<char> = p.chars().collect();
+    while let Some(c) = chars.pop() {
+        // Step over the surrogate range, which has no chars
+        let next = if c == '\u{D7FF}' { Some('\u{E000}') } else { char::from_u32(c as u32 + 1) };
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+impl BucketIndex {
+    fn journal_dir(&self) -> PathBuf { self.dir.join("journal") }
+    fn snapshot_path(&self) -> PathBuf { self.dir.join("snapshot.jsonl") }
+
+    pub fn put(&self, key: &str, entry: IndexEntry) -> anyhow::Result<()> {
+        self.append(Record { ts: now_ns(), key: key.to_string(), entry: Some(entry) })
+    }
+
+    pub fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.append(Record { ts: now_ns(), key: key.to_string(), entry: None })
+    }
+
+    pub fn get(&self, key: &str) -> Option<IndexEntry> {
+        let mut st = self.state.lock();
+        self.refresh(&mut st);
+        st.keys.get(key).and_then(|v| v.entry.clone())
+    }
+
+    /// Like [`Self::get`], but sees every record other processes appended so far.
+    pub fn get_current(&self, key: &str) -> Option<IndexEntry> {
+        let mut st = self.state.lock();
+        st.last_refresh = None;
+        self.refresh(&mut st);
+        st.keys.get(key).and_then(|v| v.entry.clone())
+    }
+
+    /// Every entry, as of every record other processes appended so far.
+    pub fn entries_current(&self) -> Vec<(String, IndexEntry)> {
+        let mut st = self.state.lock();
+        st.last_refresh = None;
+        self.refresh(&mut st);
+        st.keys.iter().filter_map(|(k, v)| Some((k.clone(), v.entry.clone()?))).collect()
+    }
+
+    /// Whether the index holds no entry at all.
+    pub fn is_empty(&self) -> bool {
+        let mut st = self.state.lock();
+        st.last_refresh = None;
+        self.refresh(&mut st);
+        st.keys.values().all(|v| v.entry.is_none())
+    }
+
+    /// Syncs what this process appended to the journal.
+    pub fn sync(&self) -> std::io::Result<()> {
+        match &self.state.lock().active {
+            Some(active) => active.file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Up to `max_keys` entries after `marker`, with the same semantics as [`KeyWalker`]; the
+    /// second value is the marker to resume from when more results follow, which is `marker`
+    /// itself when `max_keys` is 0.
+    pub fn list(&self, prefix: &str, delimiter: Option<&str>, marker: &str, max_keys: usize) -> (Vec<(ListEntry, Option<IndexEntry>)>, Option<String>) {
+        let mut st = self.state.lock();
+        self.refresh(&mut st);
+        let mut out = Vec::new();
+        let mut last: Option<String> = None;
+        let mut lower = if marker >= prefix { Bound::Excluded(marker.to_string()) } else { Bound::Included(prefix.to_string()) };
+        'outer: loop {
+            for (key, v) in st.keys.range::<String, _>((lower.clone(), Bound::Unbounded)) {
+                if !key.starts_with(prefix) { break 'outer; }
+                let Some(entry) = &v.entry else { continue };
+                let cp = delimiter.and_then(|d| key[prefix.len()..].find(d).map(|i| key[..prefix.len() + i + d.len()].to_string()));
+                if out.len() == max_keys { return (out, Some(last.unwrap_or_else(|| marker.to_string()))); }
+                match cp {
+                    Some(cp) => {
+                        // Skip the rest of the rolled-up keys in one seek
+                        let next = prefix_successor(&cp);
+                        if cp != marker {
+                            last = Some(cp.clone());
+                            out.push((ListEntry::CommonPrefix(cp), None));
+                        }
+                        match next { Some(n) => { lower = Bound::Included(n); continue 'outer; } None => break 'outer }
+                    }
+                    None => {
+                        last = Some(key.clone());
+                        out.push((ListEntry::Object { key: key.clone(), size: entry.size, mtime: entry.mtime() }, Some(entry.clone())));
+                    }
+                }
+            }
+            break;
+        }
+        (out, None)
+    }
+
+    fn append(&self, rec: Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(&rec)?;
+        line.push(b'\n');
+        let mut st = self.state.lock();
+        self.refresh(&mut st);
+        if st.active.as_ref().map(|a| a.len >= SEGMENT_MAX_BYTES).unwrap_or(true) {
+            let active = self.new_segment(&mut st)?;
+            st.offsets.insert(active.name.clone(), 0);
+            st.active = Some(active);
+        }
+        let active = st.active.as_mut().expect("active segment");
+        active.file.write_all(&line)?;
+        active.len += line.len() as u64;
+        let name = active.name.clone();
+        *st.offsets.entry(name).or_default() += line.len() as u64;
+        st.uncompacted += line.len() as u64;
+        apply(&mut st.keys, rec);
+        if st.uncompacted >= COMPACT_AFTER_BYTES {
+            st.uncompacted = 0;
+            if let Err(e) = self.compact(&mut st) { warn!(index = %self.dir.display(), error = %e, "index compaction failed"); }
+        }
+        Ok(())
+    }
+
+    /// Creates this process's next journal segment. Names are never reused, even for segments a
+    /// compaction already removed, since other processes may still hold offsets into them.
+    fn new_segment(&self, st: &mut State) -> std::io::Result<ActiveSegment> {
+        fs::create_dir_all(self.journal_dir())?;
+        loop {
+            let name = format!("{}-{:06}.log", *PROCESS_ID, st.next_segment);
+            st.next_segment += 1;
+            match std::fs::OpenOptions::new().append(true).create_new(true).open(self.journal_dir().join(&name)) {
+                Ok(file) => return Ok(ActiveSegment { name, file, len: 0 }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn snapshot_stamp(&self) -> Option<(u64, SystemTime)> {
+        fs::metadata(self.snapshot_path()).ok().and_then(|m| Some((m.len(), m.modified().ok()?)))
+    }
+
+    /// Loads the index on first use, reloads it when another process replaced the snapshot,
+    /// and otherwise tails the journal segments.
+    fn refresh(&self, st: &mut State) {
+        if st.loaded && st.last_refresh.map(|t| t.elapsed() < REFRESH_INTERVAL).unwrap_or(false) { return; }
+        st.last_refresh = Some(Instant::now());
+        let stamp = self.snapshot_stamp();
+        if !st.loaded || stamp != st.snapshot_stamp {
+            let active = st.active.take();
+            *st = State { last_refresh: st.last_refresh, next_segment: st.next_segment, ..State::default() };
+            if let Err(e) = self.load_snapshot(st) { warn!(index = %self.dir.display(), error = %e, "failed to load index snapshot"); }
+            st.snapshot_stamp = stamp;
+            st.loaded = true;
+            // Keep appending to our own segment; its bytes are re-read like everyone else's
+            if let Some(a) = active { if fs::metadata(self.journal_dir().join(&a.name)).is_ok() { st.active = Some(a); } }
+        }
+        let Ok(rd) = fs::read_dir(self.journal_dir()) else { return };
+        for e in rd.flatten() {
+            let name = e.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".log") { continue; }
+            let offset = st.offsets.get(&name).copied().unwrap_or(0);
+            let Ok(len) = e.metadata().map(|m| m.len()) else { continue };
+            if len <= offset { continue; }
+            match read_records(&e.path(), offset) {
+                Ok((records, consumed)) => {
+                    for r in records { apply(&mut st.keys, r); }
+                    st.offsets.insert(name, offset + consumed);
+                    st.uncompacted += consumed;
+                }
+                Err(e) => warn!(index = %self.dir.display(), segment = %name, error = %e, "failed to read index journal"),
+            }
+        }
+    }
+
+    fn load_snapshot(&self, st: &mut State) -> anyhow::Result<()> {
+        let f = match std::fs::File::open(self.snapshot_path()) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = BufReader::new(f).lines();
+        let header: SnapshotHeader = match lines.next() { Some(l) => serde_json::from_str(&l?)?, None => return Ok(()) };
+        for line in lines {
+            let rec: Record = serde_json::from_str(&line?)?;
+            st.keys.insert(rec.key, Versioned { ts: rec.ts, entry: rec.entry });
+        }
+        st.offsets = header.covered;
+        Ok(())
+    }
+
+    /// Folds the journal into a new snapshot and removes segments nobody appends to anymore.
+    /// Only one process compacts a bucket at a time.
+    fn compact(&self, st: &mut State) -> anyhow::Result<()> {
+        let lock = self.dir.join("compact.lock");
+        if let Ok(md) = fs::metadata(&lock) {
+            if md.modified().ok().and_then(|t| t.elapsed().ok()).map(|age| age > LOCK_STALE).unwrap_or(false) { let _ = fs::remove_file(&lock); }
+        }
+        if std::fs::OpenOptions::new().write(true).create_new(true).open(&lock).is_err() { return Ok(()); }
+        let result = (|| {
+            st.last_refresh = None;
+            self.refresh(st);
+            write_snapshot(&self.snapshot_path(), &st.offsets, st.keys.iter().filter_map(|(k, v)| v.entry.as_ref().map(|e| (k.as_str(), v.ts, e))))?;
+            st.snapshot_stamp = self.snapshot_stamp();
+            let own = st.active.as_ref().map(|a| a.name.clone());
+            for e in fs::read_dir(self.journal_dir())?.flatten() {
+                let name = e.file_name().to_string_lossy().into_owned();
+                let Ok(md) = e.metadata() else { continue };
+                let idle = md.modified().ok().and_then(|t| t.elapsed().ok()).map(|age| age > SEGMENT_IDLE).unwrap_or(false);
+                let covered = st.offsets.get(&name).copied().unwrap_or(0) >= md.len();
+                if Some(&name) != own.as_ref() && idle && covered { let _ = fs::remove_file(e.path()); }
+            }
+            info!(index = %self.dir.display(), keys = st.keys.len(), "compacted metadata index");
+            Ok(())
+        })();
+        let _ = fs::remove_file(&lock);
+        result
+    }
+}
+
+fn write_snapshot<'a>(path: &Path, covered: &HashMap<String, u64>, entries: impl Iterator<Item = (&'a str, i64, &'a IndexEntry)>) -> anyhow::Result<()> {
+    let tmp = path.with_extension("jsonl.tmp");
+    if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+    {
+        let mut w = std::io::BufWriter::new(fs::File::create(&tmp)?);
+        serde_json::to_writer(&mut w, &SnapshotHeader { covered: covered.clone() })?;
+        w.write_all(b"\n")?;
+        for (key, ts, entry) in entries {
+            serde_json::to_writer(&mut w, &Record { ts, key: key.to_string(), entry: Some(entry.clone()) })?;
+            w.write_all(b"\n")?;
+        }
+        // Compaction removes the journal segments this replaces, so it has to be on disk first
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    if let Some(parent) = path.parent() { fs::File::open(parent)?.sync_all()?; }
+    Ok(())
+}
+
+/// Complete records from `offset` on, and how many bytes they span.
+fn read_records(path: &Path, offset: u64) -> anyhow::Result<(Vec<Record>, u64)> {
+    let mut f = fs::File::open(path)?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    // A partially written last line is picked up once it is complete
+    let Some(end) = buf.iter().rposition(|b| *b == b'\n') else { return Ok((Vec::new(), 0)) };
+    let mut records = Vec::new();
+    for line in buf[..end].split(|b| *b == b'\n') {
+        match serde_json::from_slice::<Record>(line) {
+            Ok(r) => records.push(r),
+            // Garbage left by a crashed writer; skip it rather than wedging the index
+            Err(e) => warn!(segment = %path.display(), error = %e, "skipping corrupt index record"),
+        }
+    }
+    Ok((records, end as u64 + 1))
+}
+
+/// Replaces the bucket's index with one built from the data files and sidecars. Journal bytes
+/// written before the rebuild are superseded; records appended afterwards still apply.
+pub fn rebuild(cfg: &GatewayConfig, bucket: &str) -> anyhow::Result<usize> {
+    let base = posix::bucket_dir(cfg, bucket);
+    anyhow::ensure!(base.is_dir(), "no such bucket: {bucket}");
+    let dir = index_dir(cfg, bucket);
+    let mut covered = HashMap::new();
+    if let Ok(rd) = fs::read_dir(dir.join("journal")) {
+        for e in rd.flatten() {
+            if let Ok(md) = e.metadata() { covered.insert(e.file_name().to_string_lossy().into_owned(), md.len()); }
+        }
+    }
+    let ts = now_ns();
+    let entries: Vec<(String, IndexEntry)> = KeyWalker::new(&base, cfg.key_encoding, "", None, "")
+        .filter_map(|e| match e { ListEntry::Object { key, .. } => entry_from_fs(cfg, bucket, &key).map(|en| (key, en)), ListEntry::CommonPrefix(_) => None })
+        .collect();
+    write_snapshot(&dir.join("snapshot.jsonl"), &covered, entries.iter().map(|(k, e)| (k.as_str(), ts, e)))?;
+    INDEXES.remove(&dir);
+    Ok(entries.len())
+}
+
+/// One way the index and the data files disagree.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Inconsistency {
+    MissingFromIndex { key: String },
+    MissingDataFile { key: String },
+    Stale { key: String, indexed: Box<IndexEntry>, actual: Box<IndexEntry> },
+}
+
+/// Compares the index against the data files and sidecars without modifying either.
+pub fn check(cfg: &GatewayConfig, bucket: &str) -> anyhow::Result<Vec<Inconsistency>> {
+    let base = posix::bucket_dir(cfg, bucket);
+    anyhow::ensure!(base.is_dir(), "no such bucket: {bucket}");
+    let idx = BucketIndex { dir: index_dir(cfg, bucket), state: Mutex::new(State::default()) };
+    let mut st = idx.state.lock();
+    idx.refresh(&mut st);
+    let mut indexed: BTreeMap<&str, &IndexEntry> = st.keys.iter().filter_map(|(k, v)| v.entry.as_ref().map(|e| (k.as_str(), e))).collect();
+    let mut out = Vec::new();
+    for e in KeyWalker::new(&base, cfg.key_encoding, "", None, "") {
+        let ListEntry::Object { key, .. } = e else { continue };
+        let Some(actual) = entry_from_fs(cfg, bucket, &key) else { continue };
+        match indexed.remove(key.as_str()) {
+            None => out.push(Inconsistency::MissingFromIndex { key }),
+            Some(ix) if *ix != actual => out.push(Inconsistency::Stale { key, indexed: Box::new(ix.clone()), actual: Box::new(actual) }),
+            Some(_) => {}
+        }
+    }
+    out.extend(indexed.into_keys().map(|k| Inconsistency::MissingDataFile { key: k.to_string() }));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::posix::PosixBackend;
+    use crate::storage::{bytes_stream, StorageBackend};
+
+    fn config(dir: &Path) -> GatewayConfig {
+        GatewayConfig {
+            mountpoint: dir.to_string_lossy().into_owned(),
+            data_root: dir.join("buckets").to_string_lossy().into_owned(),
+            metadata_index: true,
+            ..GatewayConfig::in_memory()
+        }
+    }
+
+    fn entry(etag: &str) -> IndexEntry {
+        IndexEntry { size: etag.len() as u64, etag: etag.to_string(), mtime_ms: 0, pack: None, attrs: Default::default() }
+    }
+
+    /// Another process's view of the index in `dir`.
+    fn reader(dir: &Path) -> BucketIndex {
+        BucketIndex { dir: dir.to_path_buf(), state: Mutex::new(State::default()) }
+    }
+
+    fn etags(idx: &BucketIndex) -> Vec<(String, String)> {
+        idx.entries_current().into_iter().map(|(k, e)| (k, e.etag)).collect()
+    }
+
+    #[test]
+    fn replays_what_a_crashed_writer_left_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = dir.path().join("journal");
+        fs::create_dir_all(&journal).unwrap();
+        let line = |key: &str| serde_json::to_string(&Record { ts: now_ns(), key: key.to_string(), entry: Some(entry(key)) }).unwrap();
+        // A complete record, a corrupt one and the first half of a torn one
+        let (whole, torn) = (line("a"), line("c"));
+        let (head, tail) = torn.split_at(torn.len() / 2);
+        fs::write(journal.join("crashed-000000.log"), format!("{whole}\n{{not json\n{head}")).unwrap();
+
+        let idx = reader(dir.path());
+        assert_eq!(etags(&idx), [("a".to_string(), "a".to_string())]);
+        // The torn record counts once the rest of it arrives
+        let mut f = std::fs::OpenOptions::new().append(true).open(journal.join("crashed-000000.log")).unwrap();
+        writeln!(f, "{tail}").unwrap();
+        assert_eq!(etags(&idx), [("a".to_string(), "a".to_string()), ("c".to_string(), "c".to_string())]);
+    }
+
+    #[test]
+    fn compaction_folds_the_journal_into_the_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let idx = reader(dir.path());
+        for key in ["a", "b", "c"] { idx.put(key, entry(key)).unwrap(); }
+        idx.delete("b").unwrap();
+        idx.compact(&mut idx.state.lock()).unwrap();
+        assert!(dir.path().join("snapshot.jsonl").exists());
+        assert!(!dir.path().join("compact.lock").exists());
+
+        // A fresh reader takes the folded records from the snapshot, not again from the journal
+        let fresh = reader(dir.path());
+        assert_eq!(etags(&fresh), etags(&idx));
+        let st = fresh.state.lock();
+        assert_eq!(st.uncompacted, 0);
+        assert!(!st.keys.contains_key("b"));
+        drop(st);
+        // Records appended after the compaction still apply on top of it
+        idx.put("d", entry("d")).unwrap();
+        idx.delete("a").unwrap();
+        assert_eq!(etags(&fresh), [("c".to_string(), "c".to_string()), ("d".to_string(), "d".to_string())]);
+    }
+
+    #[test]
+    fn compaction_leaves_segments_alone_while_another_process_compacts() {
+        let dir = tempfile::tempdir().unwrap();
+        let idx = reader(dir.path());
+        idx.put("a", entry("a")).unwrap();
+        fs::write(dir.path().join("compact.lock"), "").unwrap();
+        idx.compact(&mut idx.state.lock()).unwrap();
+        assert!(!dir.path().join("snapshot.jsonl").exists());
+        assert!(dir.path().join("compact.lock").exists());
+    }
+
+    #[test]
+    fn segment_names_are_not_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let idx = reader(dir.path());
+        idx.put("a", entry("a")).unwrap();
+        let first = idx.state.lock().active.as_ref().unwrap().name.clone();
+        // Fill the segment, then have it removed and a snapshot written that no longer mentions it
+        let mut st = idx.state.lock();
+        st.active.as_mut().unwrap().len = SEGMENT_MAX_BYTES;
+        st.last_refresh = None;
+        drop(st);
+        fs::remove_file(dir.path().join("journal").join(&first)).unwrap();
+        write_snapshot(&dir.path().join("snapshot.jsonl"), &HashMap::new(), [("a", now_ns(), &entry("a"))].into_iter()).unwrap();
+
+        idx.put("b", entry("b")).unwrap();
+        let second = idx.state.lock().active.as_ref().unwrap().name.clone();
+        assert_ne!(first, second);
+        assert_eq!(etags(&reader(dir.path())), [("a".to_string(), "a".to_string()), ("b".to_string(), "b".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn rebuild_replaces_the_index_with_the_data_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let backend = PosixBackend::new(cfg.clone());
+        backend.create_bucket("bkt").await.unwrap();
+        for key in ["kept", "lost"] {
+            backend.put_object("bkt", key, bytes_stream(key.as_bytes().to_vec()), Default::default()).await.unwrap();
+        }
+        let base = posix::bucket_dir(&cfg, "bkt");
+        fs::remove_file(base.join("lost")).unwrap();
+        fs::write(base.join("bare"), "written behind the gateway").unwrap();
+        let kinds = |cfg: &GatewayConfig| {
+            let mut found: Vec<String> = check(cfg, "bkt").unwrap().iter().map(|i| serde_json::to_value(i).unwrap()["key"].as_str().unwrap().to_string()).collect();
+            found.sort();
+            found
+        };
+        assert_eq!(kinds(&cfg), ["bare", "lost"]);
+
+        assert_eq!(rebuild(&cfg, "bkt").unwrap(), 2);
+        assert!(kinds(&cfg).is_empty());
+        let idx = open(&cfg, "bkt").unwrap();
+        let keys: Vec<_> = idx.entries_current().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, ["bare", "kept"]);
+        // Writes after the rebuild go on top of it
+        backend.put_object("bkt", "new", bytes_stream(b"new".to_vec()), Default::default()).await.unwrap();
+        assert!(kinds(&cfg).is_empty());
+        assert!(idx.get_current("new").is_some());
+    }
+
+    #[tokio::test]
+    async fn bucket_without_index_is_listed_from_its_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let backend = PosixBackend::new(GatewayConfig { metadata_index: false, ..cfg.clone() });
+        backend.create_bucket("bkt").await.unwrap();
+        for key in ["a", "b/c"] {
+            backend.put_object("bkt", key, bytes_stream(key.as_bytes().to_vec()), Default::default()).await.unwrap();
+        }
+        assert!(!index_dir(&cfg, "bkt").join("snapshot.jsonl").exists());
+
+        let page = PosixBackend::new(cfg.clone()).list_objects("bkt", "", None, "", 100).await.unwrap();
+        let keys: Vec<_> = page.objects.iter().map(|o| o.key.as_str()).collect();
+        assert_eq!(keys, ["a", "b/c"]);
+        assert!(check(&cfg, "bkt").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+//! How object keys map to paths under a bucket directory.
+//!
+//! A key is split on `/` and each segment becomes one path component, so ordinary keys stay
+//! plain, browsable paths. What a segment may not be as a file name is either rejected
+//! (`KEY_ENCODING=compat`, the layout of existing deployments) or escaped reversibly
+//! (`KEY_ENCODING=escaped`):
+//!
+//! - `%` becomes `%25` and NUL `%00`; any other `%XX` decodes to the byte it names
+//! - `.` and `..` become `%2E` and `%2E%2E`, so no key reaches outside its bucket
+//! - an empty segment, from a leading `/`, `//`, or a trailing `/` as in folder marker keys,
+//!   becomes a lone `%`
+//! - a segment ending in a suffix reserved for sidecars gets the suffix's dot escaped
+//! - an object whose name is also needed as a directory (`a` next to `a/b`) is stored under
+//!   its name plus a trailing `%`
+//!
+//! Every escaped name contains a `%` that a plain key segment would have turned into `%25`,
+//! so decoding is unambiguous.
+
+use crate::config::KeyEncoding;
+use crate::storage::{StorageError, StorageResult};
+
+/// Sidecar names, and the temporary names sidecars are written under.
+const RESERVED_SUFFIXES: [&str; 2] = [".meta.json", ".meta..tmp"];
+/// Room for an aside `%` and the sidecar suffix within a 255-byte file name
+const MAX_SEGMENT_BYTES: usize = 255 - 1 - ".meta.json".len();
+
+/// Whether a file name belongs to the gateway rather than to an object.
+pub fn is_reserved(name: &str) -> bool {
+    RESERVED_SUFFIXES.iter().any(|s| name.ends_with(s))
+}
+
+/// The path components for `key` below the bucket directory, the last one naming the object.
+pub fn encode(encoding: KeyEncoding, key: &str) -> StorageResult<Vec<String>> {
+    let segments: Vec<String> = match encoding {
+        KeyEncoding::Compat => key.split('/').map(|s| {
+            let plain = !s.is_empty() && s != "." && s != ".." && !s.contains('\0') && !is_reserved(s);
+            if plain { Ok(s.to_string()) } else { Err(StorageError::InvalidArgument(format!("key {key:?} cannot be stored with KEY_ENCODING=compat"))) }
+        }).collect::<StorageResult<_>>()?,
+        KeyEncoding::Escaped => key.split('/').map(escape_segment).collect(),
+    };
+    if segments.iter().any(|s| s.len() > MAX_SEGMENT_BYTES) {
+        return Err(StorageError::InvalidArgument(format!("key segments are limited to {MAX_SEGMENT_BYTES} bytes")));
+    }
+    Ok(segments)
+}
+
+fn escape_segment(s: &str) -> String {
+    match s {
+        "" => return "%".into(),
+        "." => return "%2E".into(),
+        ".." => return "%2E%2E".into(),
+        _ => {}
+    }
+    let mut out = s.replace('%', "%25").replace('\0', "%00");
+    if let Some(suffix) = RESERVED_SUFFIXES.iter().find(|suffix| out.ends_with(*suffix)) {
+        out.truncate(out.len() - suffix.len());
+        out.push_str("%2E");
+        out.push_str(&suffix[1..]);
+    }
+    out
+}
+
+/// The name an object is stored under when its own name is taken by a directory.
+pub fn aside(name: &str) -> String {
+    format!("{name}%")
+}
+
+/// The key segment a file or directory name stands for, or `None` for names no key maps to,
+/// such as files put on the mount by other tools that do not follow the escaping.
+pub fn decode(encoding: KeyEncoding, name: &str) -> Option<String> {
+    if encoding == KeyEncoding::Compat { return Some(name.to_string()); }
+    let name = if name.len() > 1 { name.strip_suffix('%').unwrap_or(name) } else { name };
+    let segment = unescape(name)?;
+    (escape_segment(&segment) == name).then_some(segment)
+}
+
+fn unescape(name: &str) -> Option<String> {
+    if name == "%" { return Some(String::new()); }
+    let mut out = Vec::with_capacity(name.len());
+    let mut bytes = name.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AWKWARD: [&str; 8] = ["", ".", "..", "%", "%2E", "x.meta.json", "a%", "nul\0byte"];
+
+    #[test]
+    fn escaped_segments_decode_back() {
+        for s in AWKWARD {
+            let name = escape_segment(s);
+            assert!(!name.is_empty() && name != "." && name != ".." && !is_reserved(&name) && !name.contains('\0'), "{s:?} escapes to {name:?}");
+            assert_eq!(decode(KeyEncoding::Escaped, &name).as_deref(), Some(s), "{s:?} via {name:?}");
+            assert_eq!(decode(KeyEncoding::Escaped, &aside(&name)).as_deref(), Some(s), "{s:?} set aside");
+        }
+        assert_eq!(encode(KeyEncoding::Escaped, "a//./x.meta.json").unwrap(), ["a", "%", "%2E", "x%2Emeta.json"]);
+    }
+
+    #[test]
+    fn foreign_names_do_not_decode() {
+        // Unescaped, or escaped in a way the gateway never writes
+        for name in ["a%2", "a%zz", "%41", "x.meta.json"] {
+            assert_eq!(decode(KeyEncoding::Escaped, name), None, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn compat_refuses_what_it_cannot_store() {
+        assert_eq!(encode(KeyEncoding::Compat, "a/b.txt").unwrap(), ["a", "b.txt"]);
+        for key in ["/a", "a//b", "a/", ".", "a/../b", "x.meta.json", "nul\0byte"] {
+            assert!(matches!(encode(KeyEncoding::Compat, key), Err(StorageError::InvalidArgument(_))), "{key:?}");
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! Per-key write locks shared by every gateway pod through lock files on the 3FS mount, so
+//! concurrent writers of one key commit one after another instead of mixing one writer's data
+//! with another's sidecar.
+
+use crate::config::GatewayConfig;
+use crate::storage::{StorageError, StorageResult};
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Contents of a lock file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Lease {
+    owner: String,
+    expires_ms: i64,
+}
+
+/// Hands out [`KeyLock`]s under `.locks/<bucket>/` on the mount.
+#[derive(Clone)]
+pub struct KeyLocks {
+    root: PathBuf,
+    lease: Duration,
+    wait: Duration,
+}
+
+/// Holds a key until dropped, renewing the lease in the background so a slow commit keeps it.
+/// The lease is rewritten through the file this pod created, so once another pod took the lock
+/// over, renewals land in the file it moved away and never in its own lock.
+pub struct KeyLock {
+    path: PathBuf,
+    file: Arc<File>,
+    renewer: tokio::task::JoinHandle<()>,
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn read_lease(path: &Path) -> Option<Lease> {
+    fs::read(path).ok().and_then(|b| serde_json::from_slice(&b).ok())
+}
+
+fn lease_bytes(owner: &str, lease: Duration) -> std::io::Result<Vec<u8>> {
+    serde_json::to_vec(&Lease { owner: owner.to_string(), expires_ms: now_ms() + lease.as_millis() as i64 }).map_err(std::io::Error::other)
+}
+
+/// Whether the lock file at `path`, holding `held`, has lapsed.
+fn lapsed(path: &Path, held: Option<&Lease>, lease: Duration) -> bool {
+    match held {
+        Some(held) => held.expires_ms < now_ms(),
+        // Unreadable or half written: judge by when it was last touched
+        None => fs::metadata(path).ok().and_then(|m| m.modified().ok()).and_then(|t| t.elapsed().ok()).is_some_and(|age| age > lease),
+    }
+}
+
+/// Whether `path` is still the lock file `file` was opened as.
+fn still_at(file: &File, path: &Path) -> bool {
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(ours), Ok(current)) => ours.dev() == current.dev() && ours.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+impl KeyLocks {
+    /// The lock manager, or `None` when `KEY_LOCKS` is off.
+    pub fn new(cfg: &GatewayConfig) -> Option<Self> {
+        if !cfg.key_locks { return None; }
+        Some(Self {
+            root: Path::new(&cfg.mountpoint).join(".locks"),
+            lease: Duration::from_secs(cfg.key_lock_lease_secs.max(1)),
+            wait: Duration::from_secs(cfg.key_lock_wait_secs),
+        })
+    }
+
+    fn lock_path(&self, bucket: &str, key: &str) -> PathBuf {
+        self.root.join(bucket).join(format!("{:x}.lock", md5::compute(key)))
+    }
+
+    /// Waits for `key` to be free and takes it, failing with `SlowDown` after `KEY_LOCK_WAIT_SECS`.
+    pub async fn lock(&self, bucket: &str, key: &str) -> StorageResult<KeyLock> {
+        let path = self.lock_path(bucket, key);
+        let owner = uuid::Uuid::new_v4().simple().to_string();
+        let deadline = tokio::time::Instant::now() + self.wait;
+        let mut backoff = Duration::from_millis(5);
+        let file = loop {
+            let (p, o, lease) = (path.clone(), owner.clone(), self.lease);
+            let acquired = tokio::task::spawn_blocking(move || try_acquire(&p, &o, lease)).await.map_err(anyhow::Error::from)??;
+            if let Some(file) = acquired { break Arc::new(file); }
+            if tokio::time::Instant::now() + backoff > deadline { return Err(StorageError::SlowDown); }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_millis(200));
+        };
+        let renewer = tokio::spawn(renew(path.clone(), file.clone(), owner, self.lease));
+        Ok(KeyLock { path, file, renewer })
+    }
+}
+
+/// Creates the lock file unless a live lease holds it, taking over expired leases.
+fn try_acquire(path: &Path, owner: &str, lease: Duration) -> std::io::Result<Option<File>> {
+    let contents = lease_bytes(owner, lease)?;
+    if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+    match std::fs::OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(f) => {
+            f.write_all_at(&contents, 0)?;
+            return Ok(Some(f));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    let judged = read_lease(path);
+    if lapsed(path, judged.as_ref(), lease) { take_over(path, judged.as_ref(), lease); }
+    Ok(None)
+}
+
+/// Removes the lock file at `path`, judged lapsed while it held `judged`.
+fn take_over(path: &Path, judged: Option<&Lease>, lease: Duration) {
+    // Renaming the stale file away succeeds for only one of several pods racing to take it over
+    let tombstone = path.with_extension(format!("stale-{}", uuid::Uuid::new_v4().simple()));
+    if fs::rename(path, &tombstone).is_ok() {
+        // What was renamed may no longer be what was judged: a newer lock that replaced it,
+        // or the same one renewed in the meantime
+        let taken = read_lease(&tombstone);
+        let same = match (judged, &taken) {
+            (Some(j), Some(t)) => j.owner == t.owner && j.expires_ms == t.expires_ms,
+            (None, None) => lapsed(&tombstone, None, lease),
+            _ => false,
+        };
+        if same {
+            warn!(lock = %path.display(), "took over an expired key lock");
+        } else {
+            // Put the live lock back unless yet another took its place, whose holder then
+            // wins and the other finds out on its next renewal
+            let _ = std::fs::hard_link(&tombstone, path);
+        }
+        let _ = fs::remove_file(&tombstone);
+    }
+}
+
+async fn renew(path: PathBuf, file: Arc<File>, owner: String, lease: Duration) {
+    loop {
+        tokio::time::sleep(lease / 3).await;
+        let (p, f, o) = (path.clone(), file.clone(), owner.clone());
+        let still_held = tokio::task::spawn_blocking(move || -> std::io::Result<bool> {
+            let contents = lease_bytes(&o, lease)?;
+            f.write_all_at(&contents, 0)?;
+            f.set_len(contents.len() as u64)?;
+            Ok(still_at(&f, &p))
+        }).await;
+        match still_held {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => {
+                // Only after this pod stalled past its lease
+                warn!(lock = %path.display(), "key lock was taken over by another pod");
+                return;
+            }
+            // Keep trying; the lease only lapses if renewals keep failing
+            Ok(Err(e)) => warn!(lock = %path.display(), error = %e, "failed to renew key lock"),
+            Err(_) => return,
+        }
+    }
+}
+
+impl Drop for KeyLock {
+    fn drop(&mut self) {
+        self.renewer.abort();
+        if still_at(&self.file, &self.path) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locks(dir: &Path) -> KeyLocks {
+        let cfg = GatewayConfig { mountpoint: dir.to_string_lossy().into_owned(), key_locks: true, key_lock_lease_secs: 1, key_lock_wait_secs: 1, ..GatewayConfig::in_memory() };
+        KeyLocks::new(&cfg).unwrap()
+    }
+
+    fn write_lease(path: &Path, owner: &str, expires_ms: i64) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, serde_json::to_vec(&Lease { owner: owner.into(), expires_ms }).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_held_key_is_busy_until_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let locks = locks(dir.path());
+        let held = locks.lock("b", "k").await.unwrap();
+        assert!(matches!(locks.lock("b", "k").await, Err(StorageError::SlowDown)));
+        // Other keys are independent
+        drop(locks.lock("b", "other").await.unwrap());
+        drop(held);
+        assert!(!locks.lock_path("b", "k").exists());
+        locks.lock("b", "k").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_leases_are_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let locks = locks(dir.path());
+        let path = locks.lock_path("b", "k");
+        write_lease(&path, "crashed", now_ms() - 1);
+        let lock = locks.lock("b", "k").await.unwrap();
+        assert_ne!(read_lease(&path).unwrap().owner, "crashed");
+        drop(lock);
+        // and live ones are not
+        write_lease(&path, "alive", now_ms() + 60_000);
+        assert!(matches!(locks.lock("b", "k").await, Err(StorageError::SlowDown)));
+        assert_eq!(read_lease(&path).unwrap().owner, "alive");
+        assert!(std::fs::read_dir(path.parent().unwrap()).unwrap().count() == 1, "no tombstones left behind");
+    }
+
+    #[test]
+    fn a_lock_replaced_after_it_was_judged_is_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("k.lock");
+        let judged = Lease { owner: "crashed".into(), expires_ms: now_ms() - 1 };
+        // Between the check and the rename another pod took the stale lock over and made its own
+        write_lease(&path, "fresh", now_ms() + 60_000);
+        let inode = std::fs::metadata(&path).unwrap().ino();
+        take_over(&path, Some(&judged), Duration::from_secs(1));
+        assert_eq!(read_lease(&path).unwrap().owner, "fresh");
+        assert_eq!(std::fs::metadata(&path).unwrap().ino(), inode);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // The lock judged is removed
+        write_lease(&path, "crashed", judged.expires_ms);
+        take_over(&path, Some(&judged), Duration::from_secs(1));
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_lock_taken_over_is_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let locks = locks(dir.path());
+        let path = locks.lock_path("b", "k");
+        let lock = locks.lock("b", "k").await.unwrap();
+        // Another pod moved the lock away and holds a lock of its own at the same path
+        std::fs::rename(&path, dir.path().join("moved")).unwrap();
+        write_lease(&path, "other", now_ms() + 60_000);
+        // Renewals go to the moved file, and releasing does not remove the other pod's lock
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        let other = read_lease(&path).unwrap();
+        assert_eq!(other.owner, "other");
+        drop(lock);
+        assert_eq!(read_lease(&path).unwrap().expires_ms, other.expires_ms);
+        assert!(read_lease(&dir.path().join("moved")).unwrap().expires_ms > now_ms());
+    }
+
+    #[tokio::test]
+    async fn renewals_keep_a_slow_holder_s_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let locks = locks(dir.path());
+        let _lock = locks.lock("b", "k").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(read_lease(&locks.lock_path("b", "k")).unwrap().expires_ms > now_ms());
+        assert!(matches!(locks.lock("b", "k").await, Err(StorageError::SlowDown)));
+    }
+}
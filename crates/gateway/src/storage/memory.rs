@@ -0,0 +1,276 @@
+use crate::storage::index::prefix_successor;
+use crate::storage::{
+    bytes_stream, multipart_etag, AttrsUpdate, BucketInfo, ByteRange, ByteStream, DeferredEtag, GetObject, ListPage, ListedObject, ObjectAttrs, ObjectMeta, PartInfo, StorageBackend,
+    StorageError, StorageResult,
+};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use parking_lot::RwLock;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+/// Keeps every bucket, object and upload in process memory. Nothing touches the filesystem, so
+/// a router built on it can run in unit tests and throwaway local setups; contents are lost
+/// when the process exits.
+#[derive(Default)]
+pub struct MemoryBackend {
+    state: RwLock<State>,
+}
+
+#[derive(Default)]
+struct State {
+    buckets: BTreeMap<String, Bucket>,
+    uploads: HashMap<String, Upload>,
+}
+
+struct Bucket {
+    created: DateTime<Utc>,
+    objects: BTreeMap<String, Object>,
+    configs: HashMap<String, Vec<u8>>,
+}
+
+#[derive(Clone)]
+struct Object {
+    data: Bytes,
+    etag: String,
+    last_modified: DateTime<Utc>,
+    attrs: ObjectAttrs,
+}
+
+struct Upload {
+    bucket: String,
+    key: String,
+    attrs: ObjectAttrs,
+    parts: BTreeMap<u32, Object>,
+}
+
+impl Object {
+    fn new(data: Bytes, etag: String, attrs: ObjectAttrs) -> Self {
+        Self { data, etag, last_modified: Utc::now(), attrs }
+    }
+
+    fn meta(&self) -> ObjectMeta {
+        ObjectMeta { size: self.data.len() as u64, etag: self.etag.clone(), last_modified: self.last_modified, attrs: self.attrs.clone() }
+    }
+}
+
+impl Upload {
+    fn part_infos(&self) -> Vec<PartInfo> {
+        self.parts.iter().map(|(n, p)| PartInfo { part_number: *n, etag: p.etag.clone(), size: p.data.len() as u64, last_modified: p.last_modified }).collect()
+    }
+}
+
+async fn collect(mut body: ByteStream) -> StorageResult<Bytes> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        buf.extend_from_slice(&chunk?);
+    }
+    Ok(buf.freeze())
+}
+
+fn quoted_md5(data: &[u8]) -> String {
+    format!("\"{:x}\"", md5::compute(data))
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_bucket<T>(&self, bucket: &str, f: impl FnOnce(&Bucket) -> StorageResult<T>) -> StorageResult<T> {
+        f(self.state.read().buckets.get(bucket).ok_or(StorageError::NoSuchBucket)?)
+    }
+
+    fn with_bucket_mut<T>(&self, bucket: &str, f: impl FnOnce(&mut Bucket) -> StorageResult<T>) -> StorageResult<T> {
+        f(self.state.write().buckets.get_mut(bucket).ok_or(StorageError::NoSuchBucket)?)
+    }
+
+    fn store(&self, bucket: &str, key: &str, object: Object) -> StorageResult<ObjectMeta> {
+        let meta = object.meta();
+        self.with_bucket_mut(bucket, |b| {
+            b.objects.insert(key.to_string(), object);
+            Ok(meta)
+        })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn is_ready(&self) -> bool {
+        true
+    }
+
+    async fn list_buckets(&self) -> StorageResult<Vec<BucketInfo>> {
+        Ok(self.state.read().buckets.iter().map(|(name, b)| BucketInfo { name: name.clone(), created: b.created }).collect())
+    }
+
+    async fn head_bucket(&self, bucket: &str) -> StorageResult<()> {
+        self.with_bucket(bucket, |_| Ok(()))
+    }
+
+    async fn create_bucket(&self, bucket: &str) -> StorageResult<()> {
+        let mut st = self.state.write();
+        if st.buckets.contains_key(bucket) { return Err(StorageError::BucketAlreadyExists); }
+        st.buckets.insert(bucket.to_string(), Bucket { created: Utc::now(), objects: BTreeMap::new(), configs: HashMap::new() });
+        Ok(())
+    }
+
+    async fn delete_bucket(&self, bucket: &str) -> StorageResult<()> {
+        let mut st = self.state.write();
+        let b = st.buckets.get(bucket).ok_or(StorageError::NoSuchBucket)?;
+        if !b.objects.is_empty() { return Err(StorageError::BucketNotEmpty); }
+        st.buckets.remove(bucket);
+        st.uploads.retain(|_, u| u.bucket != bucket);
+        Ok(())
+    }
+
+    async fn get_bucket_config(&self, bucket: &str, name: &str) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.state.read().buckets.get(bucket).and_then(|b| b.configs.get(name).cloned()))
+    }
+
+    async fn put_bucket_config(&self, bucket: &str, name: &str, value: Option<Vec<u8>>) -> StorageResult<()> {
+        self.with_bucket_mut(bucket, |b| {
+            match value {
+                Some(v) => { b.configs.insert(name.to_string(), v); }
+                None => { b.configs.remove(name); }
+            }
+            Ok(())
+        })
+    }
+
+    async fn put_object(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        self.put_object_with_etag(bucket, key, body, attrs, DeferredEtag::default()).await
+    }
+
+    async fn put_object_with_etag(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs, etag: DeferredEtag) -> StorageResult<ObjectMeta> {
+        self.head_bucket(bucket).await?;
+        let data = collect(body).await?;
+        let etag = etag.get().cloned().unwrap_or_else(|| quoted_md5(&data));
+        self.store(bucket, key, Object::new(data, etag, attrs))
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str, range: Option<ByteRange>) -> StorageResult<GetObject> {
+        let object = self.with_bucket(bucket, |b| b.objects.get(key).cloned().ok_or(StorageError::NoSuchKey))?;
+        let len = object.data.len() as u64;
+        let range = match range {
+            Some(r) => Some(r.resolve(len).ok_or(StorageError::InvalidRange)?),
+            None => None,
+        };
+        let body = match range {
+            Some((start, end)) => object.data.slice(start as usize..=end as usize),
+            None => object.data.clone(),
+        };
+        Ok(GetObject { meta: object.meta(), range, body: bytes_stream(body) })
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> StorageResult<ObjectMeta> {
+        self.with_bucket(bucket, |b| b.objects.get(key).map(Object::meta).ok_or(StorageError::NoSuchKey))
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> StorageResult<bool> {
+        self.with_bucket_mut(bucket, |b| Ok(b.objects.remove(key).is_some()))
+    }
+
+    async fn copy_object(&self, src_bucket: &str, src_key: &str, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        self.head_bucket(bucket).await?;
+        let src = self.with_bucket(src_bucket, |b| b.objects.get(src_key).cloned().ok_or(StorageError::NoSuchKey))?;
+        self.store(bucket, key, Object::new(src.data, src.etag, attrs))
+    }
+
+    async fn update_attrs(&self, bucket: &str, key: &str, f: AttrsUpdate) -> StorageResult<()> {
+        self.with_bucket_mut(bucket, |b| {
+            f(&mut b.objects.get_mut(key).ok_or(StorageError::NoSuchKey)?.attrs);
+            Ok(())
+        })
+    }
+
+    async fn list_objects(&self, bucket: &str, prefix: &str, delimiter: Option<&str>, marker: &str, max_keys: usize) -> StorageResult<ListPage> {
+        self.with_bucket(bucket, |b| {
+            let mut page = ListPage { objects: Vec::new(), common_prefixes: Vec::new(), next_marker: None };
+            let mut last: Option<String> = None;
+            let mut lower = if marker >= prefix { Bound::Excluded(marker.to_string()) } else { Bound::Included(prefix.to_string()) };
+            'outer: loop {
+                for (key, object) in b.objects.range::<String, _>((lower.clone(), Bound::Unbounded)) {
+                    if !key.starts_with(prefix) { break 'outer; }
+                    if page.objects.len() + page.common_prefixes.len() == max_keys {
+                        page.next_marker = Some(last.unwrap_or_else(|| marker.to_string()));
+                        break 'outer;
+                    }
+                    match delimiter.and_then(|d| key[prefix.len()..].find(d).map(|i| key[..prefix.len() + i + d.len()].to_string())) {
+                        Some(cp) => {
+                            let next = prefix_successor(&cp);
+                            if cp != marker {
+                                last = Some(cp.clone());
+                                page.common_prefixes.push(cp);
+                            }
+                            match next { Some(n) => { lower = Bound::Included(n); continue 'outer; } None => break 'outer }
+                        }
+                        None => {
+                            last = Some(key.clone());
+                            page.objects.push(ListedObject { key: key.clone(), size: object.attrs.logical_size(object.data.len() as u64).unwrap_or(object.data.len() as u64), last_modified: object.last_modified, etag: object.etag.clone() });
+                        }
+                    }
+                }
+                break;
+            }
+            Ok(page)
+        })
+    }
+
+    async fn create_multipart_upload(&self, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<String> {
+        self.head_bucket(bucket).await?;
+        let upload_id = uuid::Uuid::new_v4().simple().to_string();
+        let upload = Upload { bucket: bucket.to_string(), key: key.to_string(), attrs, parts: BTreeMap::new() };
+        self.state.write().uploads.insert(upload_id.clone(), upload);
+        Ok(upload_id)
+    }
+
+    async fn upload_attrs(&self, bucket: &str, upload_id: &str) -> StorageResult<ObjectAttrs> {
+        let st = self.state.read();
+        st.uploads.get(upload_id).filter(|u| u.bucket == bucket).map(|u| u.attrs.clone()).ok_or(StorageError::NoSuchUpload)
+    }
+
+    async fn upload_part(&self, bucket: &str, upload_id: &str, part_number: u32, body: ByteStream) -> StorageResult<String> {
+        if self.state.read().uploads.get(upload_id).is_none_or(|u| u.bucket != bucket) { return Err(StorageError::NoSuchUpload); }
+        if !(1..=10_000).contains(&part_number) { return Err(StorageError::InvalidArgument("part number must be between 1 and 10000".into())); }
+        let data = collect(body).await?;
+        let etag = quoted_md5(&data);
+        // The upload may have been completed or aborted while the body was arriving
+        let mut st = self.state.write();
+        let upload = st.uploads.get_mut(upload_id).ok_or(StorageError::NoSuchUpload)?;
+        upload.parts.insert(part_number, Object::new(data, etag.clone(), ObjectAttrs::default()));
+        Ok(etag)
+    }
+
+    async fn list_parts(&self, bucket: &str, upload_id: &str) -> StorageResult<Vec<PartInfo>> {
+        let st = self.state.read();
+        let upload = st.uploads.get(upload_id).filter(|u| u.bucket == bucket).ok_or(StorageError::NoSuchUpload)?;
+        Ok(upload.part_infos())
+    }
+
+    async fn complete_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str, parts: &[(u32, String)], update: Option<AttrsUpdate>) -> StorageResult<ObjectMeta> {
+        let mut st = self.state.write();
+        if !st.buckets.contains_key(bucket) { return Err(StorageError::NoSuchBucket); }
+        let upload = st.uploads.get(upload_id).filter(|u| u.bucket == bucket && u.key == key).ok_or(StorageError::NoSuchUpload)?;
+        let etag = multipart_etag(parts, &upload.part_infos())?;
+        let mut data = BytesMut::new();
+        for (number, _) in parts {
+            data.extend_from_slice(&upload.parts[number].data);
+        }
+        let mut upload = st.uploads.remove(upload_id).expect("upload checked above");
+        if let Some(update) = update { update(&mut upload.attrs); }
+        let object = Object::new(data.freeze(), etag, upload.attrs);
+        let meta = object.meta();
+        st.buckets.get_mut(bucket).expect("bucket checked above").objects.insert(key.to_string(), object);
+        Ok(meta)
+    }
+
+    async fn abort_multipart_upload(&self, bucket: &str, upload_id: &str) -> StorageResult<()> {
+        let mut st = self.state.write();
+        if st.uploads.get(upload_id).is_none_or(|u| u.bucket != bucket) { return Err(StorageError::NoSuchUpload); }
+        st.uploads.remove(upload_id);
+        Ok(())
+    }
+}
@@ -1,2 +1,317 @@
+pub mod cache;
+pub mod dedup;
+pub mod durability;
+pub mod etag;
+pub mod external;
+pub mod fsck;
+pub mod index;
+pub mod keys;
+pub mod lock;
+#[cfg(feature = "memory")]
+pub mod memory;
+pub mod pack;
 pub mod posix;
+#[cfg(any(feature = "usrbio", feature = "io-uring"))]
+pub mod ring;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+#[cfg(feature = "usrbio")]
+pub mod usrbio;
+pub mod walk;
+pub mod xattr;
 
+use crate::config::{GatewayConfig, StorageKind};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Object bodies travel as streams so no layer has to hold a whole object in memory.
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+pub fn bytes_stream(bytes: impl Into<Bytes>) -> ByteStream {
+    Box::pin(futures::stream::once(futures::future::ready(Ok(bytes.into()))))
+}
+
+/// Failures a backend reports to the S3 layer; each maps onto an S3 error code.
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("NoSuchBucket")]
+    NoSuchBucket,
+    #[error("NoSuchKey")]
+    NoSuchKey,
+    #[error("NoSuchUpload")]
+    NoSuchUpload,
+    #[error("BucketAlreadyOwnedByYou")]
+    BucketAlreadyExists,
+    #[error("BucketNotEmpty")]
+    BucketNotEmpty,
+    #[error("InvalidRange")]
+    InvalidRange,
+    #[error("InvalidPart: {0}")]
+    InvalidPart(String),
+    #[error("InvalidPartOrder")]
+    InvalidPartOrder,
+    #[error("EntityTooSmall")]
+    EntityTooSmall,
+    #[error("InvalidArgument: {0}")]
+    InvalidArgument(String),
+    /// The bucket is read-only
+    #[error("AccessDenied")]
+    AccessDenied,
+    /// Another writer held the key for too long
+    #[error("SlowDown")]
+    SlowDown,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// Metadata supplied by the client when an object is written.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectAttrs {
+    #[serde(default)]
+    pub content_type: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub user_meta: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replication_status: Option<String>,
+    /// Set when the stored data is encrypted, see [`crate::sse`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sse: Option<crate::sse::Encryption>,
+    /// Set when the stored data is compressed, see [`crate::compression`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<crate::compression::Compression>,
+}
+
+impl ObjectAttrs {
+    /// Size of the object as clients see it, given the size of its data file; an error if the
+    /// file cannot hold the encrypted data.
+    pub fn logical_size(&self, stored: u64) -> StorageResult<u64> {
+        match (&self.compression, &self.sse) {
+            (Some(c), _) => Ok(c.size),
+            (None, Some(enc)) => crate::sse::plaintext_size(enc, stored).ok_or_else(|| anyhow::anyhow!("encrypted object of {stored} bytes is corrupt").into()),
+            (None, None) => Ok(stored),
+        }
+    }
+}
+
+/// The ETag of an object whose stored bytes are not what the client sent, known only once the
+/// body handed to [`StorageBackend::put_object_with_etag`] has been read to its end.
+pub type DeferredEtag = Arc<std::sync::OnceLock<String>>;
+
+/// Passes `body` through and sets `etag` to the MD5 of everything it yielded once it ends.
+pub fn md5_etag(body: ByteStream, etag: DeferredEtag) -> ByteStream {
+    use futures::StreamExt;
+    let tap = (body, md5::Context::new(), etag);
+    Box::pin(futures::stream::unfold(Some(tap), |tap| async move {
+        let (mut body, mut md5, etag) = tap?;
+        match body.next().await {
+            Some(Ok(b)) => {
+                md5.consume(&b);
+                Some((Ok(b), Some((body, md5, etag))))
+            }
+            Some(Err(e)) => Some((Err(e), None)),
+            None => {
+                let _ = etag.set(format!("\"{:x}\"", md5.compute()));
+                None
+            }
+        }
+    }))
+}
+
+/// An in-place change to an object's [`ObjectAttrs`].
+pub type AttrsUpdate = Box<dyn FnOnce(&mut ObjectAttrs) + Send>;
+
+#[derive(Debug, Clone)]
+pub struct ObjectMeta {
+    pub size: u64,
+    /// Quoted, as sent in the `ETag` header
+    pub etag: String,
+    pub last_modified: chrono::DateTime<chrono::Utc>,
+    pub attrs: ObjectAttrs,
+}
+
+/// An HTTP byte range before it is resolved against the object size.
+#[derive(Debug, Clone, Copy)]
+pub enum ByteRange {
+    /// `bytes=start-end`, both inclusive
+    Bounded(u64, u64),
+    /// `bytes=start-`
+    From(u64),
+    /// `bytes=-n`, the last `n` bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parses a single-range `Range` header value; multi-range requests are not supported.
+    pub fn parse(header: &str) -> Option<Self> {
+        let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+        match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+            (Some(s), Some(e)) => Some(Self::Bounded(s, e)),
+            (Some(s), None) if end.is_empty() => Some(Self::From(s)),
+            (None, Some(n)) if start.is_empty() => Some(Self::Suffix(n)),
+            _ => None,
+        }
+    }
+
+    /// Inclusive `(start, end)` within an object of `len` bytes, or `None` if unsatisfiable.
+    pub fn resolve(self, len: u64) -> Option<(u64, u64)> {
+        let (start, end) = match self {
+            Self::Bounded(s, e) => (s, e.min(len.saturating_sub(1))),
+            Self::From(s) => (s, len.saturating_sub(1)),
+            Self::Suffix(n) => (len.saturating_sub(n), len.saturating_sub(1)),
+        };
+        (len > 0 && start <= end && start < len).then_some((start, end))
+    }
+}
+
+pub struct GetObject {
+    pub meta: ObjectMeta,
+    /// The inclusive byte range `body` covers when a range was requested
+    pub range: Option<(u64, u64)>,
+    pub body: ByteStream,
+}
+
+pub struct BucketInfo {
+    pub name: String,
+    pub created: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct ListedObject {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: chrono::DateTime<chrono::Utc>,
+    pub etag: String,
+}
+
+pub struct ListPage {
+    pub objects: Vec<ListedObject>,
+    pub common_prefixes: Vec<String>,
+    /// Where the next page starts, set only when more results follow: the last key or common
+    /// prefix returned, or the request's own marker when the page is empty (`max_keys` of 0)
+    pub next_marker: Option<String>,
+}
+
+pub struct PartInfo {
+    pub part_number: u32,
+    pub etag: String,
+    pub size: u64,
+    pub last_modified: chrono::DateTime<chrono::Utc>,
+}
+
+/// Minimum size of every multipart part but the last
+pub const MIN_PART_SIZE: u64 = 5 << 20;
+
+/// Where buckets, objects and their metadata live. Handlers only talk to storage through this
+/// trait; [`posix::PosixBackend`] stores everything on the 3FS mount and, with the `memory`
+/// feature, `memory::MemoryBackend` keeps it in process memory.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Whether the backend can currently serve requests; backs `/readyz`.
+    async fn is_ready(&self) -> bool;
+
+    async fn list_buckets(&self) -> StorageResult<Vec<BucketInfo>>;
+    async fn head_bucket(&self, bucket: &str) -> StorageResult<()>;
+    async fn create_bucket(&self, bucket: &str) -> StorageResult<()>;
+    /// Removes an empty bucket together with its configuration.
+    async fn delete_bucket(&self, bucket: &str) -> StorageResult<()>;
+
+    /// Raw bucket sub-resource configuration (`notification`, `logging`, ...).
+    async fn get_bucket_config(&self, bucket: &str, name: &str) -> StorageResult<Option<Vec<u8>>>;
+    /// Stores, or with `None` removes, a bucket sub-resource configuration.
+    async fn put_bucket_config(&self, bucket: &str, name: &str, value: Option<Vec<u8>>) -> StorageResult<()>;
+
+    /// Writes an object; readers see either the old or the complete new object.
+    async fn put_object(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs) -> StorageResult<ObjectMeta>;
+    /// Like [`put_object`](Self::put_object), but the object gets `etag` instead of the MD5 of
+    /// `body` if it was set by the time `body` ended.
+    async fn put_object_with_etag(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs, etag: DeferredEtag) -> StorageResult<ObjectMeta>;
+    async fn get_object(&self, bucket: &str, key: &str, range: Option<ByteRange>) -> StorageResult<GetObject>;
+    async fn head_object(&self, bucket: &str, key: &str) -> StorageResult<ObjectMeta>;
+    /// Returns whether the object existed.
+    async fn delete_object(&self, bucket: &str, key: &str) -> StorageResult<bool>;
+    /// Copies the source's content to the destination, which gets `attrs`.
+    async fn copy_object(&self, src_bucket: &str, src_key: &str, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<ObjectMeta>;
+    /// Changes an existing object's metadata without rewriting its content.
+    async fn update_attrs(&self, bucket: &str, key: &str, f: AttrsUpdate) -> StorageResult<()>;
+    /// Up to `max_keys` keys and common prefixes after `marker`, in lexicographic order.
+    async fn list_objects(&self, bucket: &str, prefix: &str, delimiter: Option<&str>, marker: &str, max_keys: usize) -> StorageResult<ListPage>;
+
+    /// Starts a multipart upload and returns its id.
+    async fn create_multipart_upload(&self, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<String>;
+    /// The attrs an upload was created with, which its object will get.
+    async fn upload_attrs(&self, bucket: &str, upload_id: &str) -> StorageResult<ObjectAttrs>;
+    /// Stores (or replaces) one part and returns its quoted ETag.
+    async fn upload_part(&self, bucket: &str, upload_id: &str, part_number: u32, body: ByteStream) -> StorageResult<String>;
+    async fn list_parts(&self, bucket: &str, upload_id: &str) -> StorageResult<Vec<PartInfo>>;
+    /// Assembles the listed `(part number, ETag)` parts into the object, which gets an
+    /// S3-style `"<md5 of part md5s>-<part count>"` ETag, and the upload's attrs after `update`.
+    async fn complete_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str, parts: &[(u32, String)], update: Option<AttrsUpdate>) -> StorageResult<ObjectMeta>;
+    async fn abort_multipart_upload(&self, bucket: &str, upload_id: &str) -> StorageResult<()>;
+}
+
+/// Creates the backend selected by `STORAGE_BACKEND`.
+pub async fn open(cfg: &GatewayConfig) -> anyhow::Result<Arc<dyn StorageBackend>> {
+    match cfg.storage_backend {
+        StorageKind::Posix => {
+            let posix = posix::PosixBackend::new(cfg.clone());
+            posix.ensure_roots().await?;
+            Ok(Arc::new(posix))
+        }
+        #[cfg(feature = "memory")]
+        StorageKind::Memory => Ok(Arc::new(memory::MemoryBackend::new())),
+        #[cfg(not(feature = "memory"))]
+        StorageKind::Memory => anyhow::bail!("STORAGE_BACKEND=memory requires a build with the `memory` feature"),
+    }
+}
+
+pub async fn read_bucket_config<T: DeserializeOwned>(storage: &dyn StorageBackend, bucket: &str, name: &str) -> anyhow::Result<Option<T>> {
+    match storage.get_bucket_config(bucket, name).await? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Stores `value` as the bucket's `name` configuration, or removes it when `None`.
+pub async fn write_bucket_config<T: Serialize>(storage: &dyn StorageBackend, bucket: &str, name: &str, value: Option<&T>) -> anyhow::Result<()> {
+    let bytes = value.map(serde_json::to_vec).transpose()?;
+    Ok(storage.put_bucket_config(bucket, name, bytes).await?)
+}
+
+/// The bucket's `name` configuration for a request that goes on without it when it cannot be
+/// read, which is logged.
+pub async fn bucket_setting<T: DeserializeOwned>(storage: &dyn StorageBackend, bucket: &str, name: &str) -> Option<T> {
+    read_bucket_config(storage, bucket, name).await.unwrap_or_else(|e| {
+        tracing::warn!(%bucket, config = name, error = %e, "failed to load bucket config");
+        None
+    })
+}
+
+/// Sets the `name` configuration of an existing bucket, or removes it with `None`.
+pub async fn set_bucket_setting<T: Serialize>(storage: &dyn StorageBackend, bucket: &str, name: &str, value: Option<&T>) -> anyhow::Result<()> {
+    anyhow::ensure!(storage.head_bucket(bucket).await.is_ok(), "no such bucket: {bucket}");
+    write_bucket_config(storage, bucket, name, value).await
+}
+
+/// Validates the part list of a CompleteMultipartUpload against the stored parts and returns
+/// the composite ETag; shared by all backends so they agree on S3's rules.
+pub fn multipart_etag(requested: &[(u32, String)], stored: &[PartInfo]) -> StorageResult<String> {
+    if requested.is_empty() { return Err(StorageError::InvalidPart("no parts given".into())); }
+    if requested.windows(2).any(|w| w[0].0 >= w[1].0) { return Err(StorageError::InvalidPartOrder); }
+    let mut digests = Vec::with_capacity(requested.len() * 16);
+    for (i, (number, etag)) in requested.iter().enumerate() {
+        let part = stored.iter().find(|p| p.part_number == *number).ok_or_else(|| StorageError::InvalidPart(format!("part {number} not uploaded")))?;
+        if part.etag.trim_matches('"') != etag.trim_matches('"') { return Err(StorageError::InvalidPart(format!("ETag mismatch for part {number}"))); }
+        if i + 1 < requested.len() && part.size < MIN_PART_SIZE { return Err(StorageError::EntityTooSmall); }
+        digests.extend(hex::decode(part.etag.trim_matches('"')).map_err(|_| StorageError::InvalidPart(format!("bad ETag for part {number}")))?);
+    }
+    Ok(format!("\"{:x}-{}\"", md5::compute(&digests), requested.len()))
+}
@@ -0,0 +1,435 @@
+//! Small objects packed into shared files, for buckets with very many tiny objects.
+//!
+//! A bucket's `pack` configuration, set with `3fs-s3-gateway bucket pack`, names a size up to
+//! which new objects are appended to a pack file instead of getting a data file and a sidecar
+//! each, which spares 3FS two inodes per object. Under `.packs/<bucket>/`:
+//!
+//! - `data/<id>.pack`: object data back to back. Each gateway process appends to its own pack
+//!   and starts a new one past [`PACK_MAX_BYTES`] or after [`APPEND_IDLE`] without appends.
+//! - `catalog/`: where each packed object lives, with its size, ETag, mtime and attributes, as
+//!   journal segments and a snapshot in the format of the [`index`] and shared the same way.
+//!
+//! A key is either packed or a file: writing one form removes the other under the key's lock,
+//! and should both briefly exist the packed entry wins. Overwritten and deleted objects leave
+//! their bytes behind in the packs until `3fs-s3-gateway pack compact <bucket>` moves the live
+//! entries of mostly dead packs into a new pack and deletes the old ones.
+
+use crate::config::{Durability, GatewayConfig};
+use crate::storage::index::{self, BucketIndex, IndexEntry};
+use crate::storage::lock::KeyLocks;
+use crate::storage::{self, bytes_stream, external, ByteRange, ByteStream, GetObject, ObjectMeta, StorageBackend, StorageError, StorageResult};
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use fs_err as fs;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+pub const CONFIG_NAME: &str = "pack";
+pub const DEFAULT_MAX_BYTES: u64 = 64 << 10;
+/// A pack grows to about this size before its writer starts a new one
+pub const PACK_MAX_BYTES: u64 = 1 << 30;
+/// A pack its writer left alone this long is never appended to again
+pub const APPEND_IDLE: Duration = Duration::from_secs(300);
+/// Compaction leaves packs alone that may still be appended to
+const COMPACT_IDLE: Duration = Duration::from_secs(600);
+
+/// Contents of the `pack` bucket configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BucketPacking {
+    /// Objects up to this size are packed
+    pub max_bytes: u64,
+}
+
+/// Where a packed object's data is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackRef {
+    /// File name under `data/`
+    pub file: String,
+    pub offset: u64,
+}
+
+/// The pack this process appends to in one bucket.
+struct Writer {
+    name: String,
+    file: std::fs::File,
+    len: u64,
+    last_append: Instant,
+}
+
+static WRITERS: Lazy<DashMap<PathBuf, Arc<Mutex<Option<Writer>>>>> = Lazy::new(DashMap::new);
+
+fn pack_dir(cfg: &GatewayConfig, bucket: &str) -> PathBuf {
+    Path::new(&cfg.mountpoint).join(".packs").join(bucket)
+}
+
+fn data_dir(cfg: &GatewayConfig, bucket: &str) -> PathBuf {
+    pack_dir(cfg, bucket).join("data")
+}
+
+fn catalog_dir(cfg: &GatewayConfig, bucket: &str) -> PathBuf {
+    pack_dir(cfg, bucket).join("catalog")
+}
+
+/// The size up to which new objects in `bucket` are packed, if it packs them.
+pub async fn for_bucket(storage: &dyn StorageBackend, bucket: &str) -> Option<u64> {
+    storage::bucket_setting::<BucketPacking>(storage, bucket, CONFIG_NAME).await.map(|p| p.max_bytes)
+}
+
+pub async fn get(storage: &dyn StorageBackend, bucket: &str) -> anyhow::Result<Option<BucketPacking>> {
+    storage::read_bucket_config(storage, bucket, CONFIG_NAME).await
+}
+
+/// Packs new objects in `bucket` up to `max_bytes`, or stops packing with `None`. Objects
+/// already packed stay where they are.
+pub async fn set(cfg: &GatewayConfig, storage: &dyn StorageBackend, bucket: &str, max_bytes: Option<u64>) -> anyhow::Result<()> {
+    anyhow::ensure!(!external::is_external(cfg, bucket), "{bucket} is a registered directory, whose files cannot be packed");
+    if let Some(max_bytes) = max_bytes {
+        anyhow::ensure!(max_bytes > 0 && max_bytes <= PACK_MAX_BYTES / 16, "pack threshold must be between 1 and {} bytes", PACK_MAX_BYTES / 16);
+    }
+    storage::set_bucket_setting(storage, bucket, CONFIG_NAME, max_bytes.map(|max_bytes| BucketPacking { max_bytes }).as_ref()).await
+}
+
+/// The catalog of `bucket`, if anything was ever packed in it.
+pub fn catalog(cfg: &GatewayConfig, bucket: &str) -> Option<Arc<BucketIndex>> {
+    let dir = catalog_dir(cfg, bucket);
+    dir.is_dir().then(|| index::open_at(dir))
+}
+
+/// Reads `body` as long as it stays within `max` bytes. Returns all of it, or what was read and
+/// the stream with the rest once it turned out longer.
+pub async fn read_small(mut body: ByteStream, max: u64) -> StorageResult<(Bytes, Option<ByteStream>)> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() as u64 > max { return Ok((buf.freeze(), Some(body))); }
+    }
+    Ok((buf.freeze(), None))
+}
+
+/// The packed entry of `key`, as of the last catalog refresh.
+pub async fn lookup(cfg: &GatewayConfig, bucket: &str, key: &str) -> Option<IndexEntry> {
+    let (cfg, bucket, key) = (cfg.clone(), bucket.to_string(), key.to_string());
+    tokio::task::spawn_blocking(move || catalog(&cfg, &bucket)?.get(&key).filter(|e| e.pack.is_some())).await.ok().flatten()
+}
+
+/// The packed entry of `key`, as of every record written so far; for callers holding its lock.
+pub async fn current(cfg: &GatewayConfig, bucket: &str, key: &str) -> Option<IndexEntry> {
+    let (cfg, bucket, key) = (cfg.clone(), bucket.to_string(), key.to_string());
+    tokio::task::spawn_blocking(move || catalog(&cfg, &bucket)?.get_current(&key).filter(|e| e.pack.is_some())).await.ok().flatten()
+}
+
+/// Records `entry` as the packed object `key`.
+pub async fn record(cfg: &GatewayConfig, bucket: &str, key: &str, entry: IndexEntry, mode: Durability) -> anyhow::Result<()> {
+    let dir = catalog_dir(cfg, bucket);
+    let key = key.to_string();
+    tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(&dir)?;
+        let catalog = index::open_at(dir);
+        catalog.put(&key, entry)?;
+        if mode >= Durability::Data { catalog.sync()?; }
+        Ok(())
+    }).await?
+}
+
+/// Drops the packed object `key`, if there is one, returning whether there was.
+pub async fn forget(cfg: &GatewayConfig, bucket: &str, key: &str, mode: Durability) -> StorageResult<bool> {
+    let (cfg, bucket, key) = (cfg.clone(), bucket.to_string(), key.to_string());
+    let forgotten = tokio::task::spawn_blocking(move || {
+        let Some(catalog) = catalog(&cfg, &bucket) else { return Ok(false) };
+        if catalog.get_current(&key).is_none() { return Ok(false); }
+        catalog.delete(&key)?;
+        if mode >= Durability::Data { catalog.sync()?; }
+        Ok::<_, anyhow::Error>(true)
+    }).await.map_err(anyhow::Error::from)??;
+    Ok(forgotten)
+}
+
+/// Appends `data` to this process's pack in `bucket`.
+pub async fn append(cfg: &GatewayConfig, bucket: &str, data: Bytes, mode: Durability) -> std::io::Result<PackRef> {
+    let dir = data_dir(cfg, bucket);
+    tokio::task::spawn_blocking(move || append_blocking(&dir, &data, mode)).await.map_err(std::io::Error::other)?
+}
+
+fn append_blocking(dir: &Path, data: &[u8], mode: Durability) -> std::io::Result<PackRef> {
+    let slot = WRITERS.entry(dir.to_path_buf()).or_default().clone();
+    let mut writer = slot.lock();
+    let stale = writer.as_ref().is_none_or(|w| w.len + data.len() as u64 > PACK_MAX_BYTES || w.last_append.elapsed() > APPEND_IDLE);
+    if stale {
+        fs::create_dir_all(dir)?;
+        let name = format!("{}.pack", uuid::Uuid::new_v4().simple());
+        let file = std::fs::OpenOptions::new().append(true).create_new(true).open(dir.join(&name))?;
+        if mode >= Durability::DataDir { std::fs::File::open(dir)?.sync_all()?; }
+        *writer = Some(Writer { name, file, len: 0, last_append: Instant::now() });
+    }
+    let w = writer.as_mut().expect("writer opened above");
+    let offset = w.len;
+    let written = w.file.write_all(data).and_then(|_| if mode >= Durability::Data { w.file.sync_data() } else { Ok(()) });
+    if let Err(e) = written {
+        // The pack's length is unknown now; the next append starts a new one
+        *writer = None;
+        return Err(e);
+    }
+    w.len += data.len() as u64;
+    w.last_append = Instant::now();
+    Ok(PackRef { file: w.name.clone(), offset })
+}
+
+fn read_blocking(dir: &Path, at: &PackRef, start: u64, len: u64) -> std::io::Result<Bytes> {
+    let file = std::fs::File::open(dir.join(&at.file))?;
+    let mut buf = vec![0u8; len as usize];
+    file.read_exact_at(&mut buf, at.offset + start)?;
+    Ok(buf.into())
+}
+
+/// Reads `len` bytes from `start` of the packed object `key` described by `entry`. A pack
+/// compacted away since `entry` was looked up is retried at the object's new place.
+async fn read(cfg: &GatewayConfig, bucket: &str, key: &str, entry: IndexEntry, start: u64, len: u64) -> StorageResult<(IndexEntry, Bytes)> {
+    let dir = data_dir(cfg, bucket);
+    let mut entry = entry;
+    for _ in 0..2 {
+        let Some(at) = entry.pack.clone() else { break };
+        let d = dir.clone();
+        match tokio::task::spawn_blocking(move || read_blocking(&d, &at, start, len)).await.map_err(anyhow::Error::from)? {
+            Ok(data) => return Ok((entry, data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let moved = current(cfg, bucket, key).await.ok_or(StorageError::NoSuchKey)?;
+        // Replaced rather than moved: the requested range may not fit
+        if moved.size != entry.size || moved.etag != entry.etag { return Err(StorageError::SlowDown); }
+        entry = moved;
+    }
+    Err(StorageError::NoSuchKey)
+}
+
+/// GET of the packed object `key`.
+pub async fn get_object(cfg: &GatewayConfig, bucket: &str, key: &str, entry: IndexEntry, range: Option<ByteRange>) -> StorageResult<GetObject> {
+    let range = match range {
+        Some(r) => Some(r.resolve(entry.size).ok_or(StorageError::InvalidRange)?),
+        None => None,
+    };
+    let (start, len) = range.map(|(s, e)| (s, e - s + 1)).unwrap_or((0, entry.size));
+    let (entry, data) = read(cfg, bucket, key, entry, start, len).await?;
+    Ok(GetObject { meta: ObjectMeta::from(entry), range, body: bytes_stream(data) })
+}
+
+/// The whole data of the packed object `key`, with the entry it was read by.
+pub async fn read_all(cfg: &GatewayConfig, bucket: &str, key: &str, entry: IndexEntry) -> StorageResult<(IndexEntry, Bytes)> {
+    let size = entry.size;
+    read(cfg, bucket, key, entry, 0, size).await
+}
+
+/// Whether `bucket` holds no packed objects.
+pub fn is_empty(cfg: &GatewayConfig, bucket: &str) -> bool {
+    catalog(cfg, bucket).is_none_or(|c| c.is_empty())
+}
+
+/// Removes the packs and catalog of a deleted bucket.
+pub fn drop_bucket(cfg: &GatewayConfig, bucket: &str) {
+    index::drop_at(&catalog_dir(cfg, bucket));
+    WRITERS.remove(&data_dir(cfg, bucket));
+    let _ = fs::remove_dir_all(pack_dir(cfg, bucket));
+}
+
+/// What `pack compact` found and did.
+#[derive(Debug, Default, Serialize)]
+pub struct CompactReport {
+    pub packs: u64,
+    pub pack_bytes: u64,
+    pub live_objects: u64,
+    pub live_bytes: u64,
+    /// Packs that may still be appended to and were left alone
+    pub active_packs: u64,
+    /// Live objects moved out of compacted packs
+    pub moved_objects: u64,
+    pub removed_packs: u64,
+    pub reclaimed_bytes: u64,
+    /// Compacted packs an entry still pointed to afterwards, kept for the next run
+    pub kept_packs: u64,
+}
+
+/// Rewrites the live objects of idle packs that are at least half dead into a new pack and
+/// removes the old packs, along with idle packs holding nothing live at all.
+pub async fn compact(cfg: &GatewayConfig, storage: &dyn StorageBackend, bucket: &str) -> anyhow::Result<CompactReport> {
+    let mut report = CompactReport::default();
+    let Some(catalog) = catalog(cfg, bucket) else { return Ok(report) };
+    let mode = storage::durability::for_bucket(cfg, storage, bucket).await;
+    let locks = KeyLocks::new(cfg);
+    let c = catalog.clone();
+    let entries = tokio::task::spawn_blocking(move || c.entries_current()).await?;
+    let mut live: HashMap<String, Vec<(String, IndexEntry)>> = HashMap::new();
+    for (key, entry) in entries {
+        let Some(at) = &entry.pack else { continue };
+        report.live_objects += 1;
+        report.live_bytes += entry.size;
+        live.entry(at.file.clone()).or_default().push((key, entry));
+    }
+    let dir = data_dir(cfg, bucket);
+    let mut candidates = Vec::new();
+    for e in fs::read_dir(&dir).into_iter().flat_map(|rd| rd.flatten()) {
+        let name = e.file_name().to_string_lossy().into_owned();
+        let Ok(md) = e.metadata() else { continue };
+        if !name.ends_with(".pack") || !md.is_file() { continue; }
+        report.packs += 1;
+        report.pack_bytes += md.len();
+        let idle = md.modified().ok().and_then(|t| SystemTime::now().duration_since(t).ok()).is_some_and(|age| age >= COMPACT_IDLE);
+        if !idle {
+            report.active_packs += 1;
+            continue;
+        }
+        let live_bytes: u64 = live.get(&name).map_or(0, |v| v.iter().map(|(_, e)| e.size).sum());
+        if live_bytes * 2 > md.len() { continue; }
+        candidates.push((name, md.len(), live_bytes));
+    }
+    for (name, _, _) in &candidates {
+        for (key, _) in live.remove(name).unwrap_or_default() {
+            let _lock = match &locks { Some(locks) => Some(locks.lock(bucket, &key).await?), None => None };
+            // Overwritten or deleted since the listing above
+            let Some(entry) = current(cfg, bucket, &key).await else { continue };
+            if entry.pack.as_ref().is_none_or(|at| at.file != *name) { continue; }
+            let (entry, data) = read_all(cfg, bucket, &key, entry).await?;
+            let at = append(cfg, bucket, data, mode).await?;
+            record(cfg, bucket, &key, IndexEntry { pack: Some(at), ..entry }, mode).await?;
+            report.moved_objects += 1;
+        }
+    }
+    // A move can lose to a concurrent record stamped by a clock running ahead; such packs stay
+    let c = catalog.clone();
+    let referenced: HashSet<String> = tokio::task::spawn_blocking(move || c.entries_current()).await?
+        .into_iter().filter_map(|(_, e)| e.pack.map(|at| at.file)).collect();
+    for (name, len, live_bytes) in candidates {
+        if referenced.contains(&name) {
+            report.kept_packs += 1;
+            continue;
+        }
+        fs::remove_file(dir.join(&name))?;
+        report.removed_packs += 1;
+        report.reclaimed_bytes += len - live_bytes;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::posix::{self, PosixBackend};
+    use futures::TryStreamExt;
+
+    fn cfg(dir: &Path) -> GatewayConfig {
+        GatewayConfig {
+            mountpoint: dir.to_string_lossy().into_owned(),
+            data_root: dir.join("buckets").to_string_lossy().into_owned(),
+            ..GatewayConfig::in_memory()
+        }
+    }
+
+    async fn packing_backend(dir: &Path) -> (GatewayConfig, PosixBackend) {
+        let cfg = cfg(dir);
+        let backend = PosixBackend::new(cfg.clone());
+        backend.create_bucket("bkt").await.unwrap();
+        set(&cfg, &backend, "bkt", Some(1024)).await.unwrap();
+        (cfg, backend)
+    }
+
+    async fn put(backend: &PosixBackend, key: &str, data: &[u8]) {
+        backend.put_object("bkt", key, bytes_stream(data.to_vec()), Default::default()).await.unwrap();
+    }
+
+    async fn get(backend: &PosixBackend, key: &str, range: Option<&str>) -> (Option<(u64, u64)>, Vec<u8>) {
+        let obj = backend.get_object("bkt", key, range.and_then(ByteRange::parse)).await.unwrap();
+        (obj.range, obj.body.map_ok(|b| b.to_vec()).try_concat().await.unwrap())
+    }
+
+    fn pack_files(cfg: &GatewayConfig) -> Vec<PathBuf> {
+        fs::read_dir(data_dir(cfg, "bkt")).unwrap().flatten().map(|e| e.path()).collect()
+    }
+
+    #[tokio::test]
+    async fn small_objects_are_packed_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cfg, backend) = packing_backend(dir.path()).await;
+        put(&backend, "a", b"first object").await;
+        put(&backend, "b", b"second object").await;
+        put(&backend, "large", &[7u8; 2048]).await;
+
+        assert_eq!(pack_files(&cfg).len(), 1);
+        assert!(lookup(&cfg, "bkt", "a").await.is_some());
+        assert!(lookup(&cfg, "bkt", "large").await.is_none());
+        assert!(!posix::bucket_dir(&cfg, "bkt").join("a").exists());
+
+        assert_eq!(get(&backend, "b", None).await, (None, b"second object".to_vec()));
+        assert_eq!(get(&backend, "b", Some("bytes=7-12")).await, (Some((7, 12)), b"object".to_vec()));
+        assert_eq!(get(&backend, "a", Some("bytes=-6")).await, (Some((6, 11)), b"object".to_vec()));
+        assert!(matches!(backend.get_object("bkt", "a", ByteRange::parse("bytes=50-")).await, Err(StorageError::InvalidRange)));
+        let head = backend.head_object("bkt", "a").await.unwrap();
+        assert_eq!((head.size, head.etag), (12, format!("\"{:x}\"", md5::compute(b"first object"))));
+
+        // Writing a key as a file drops its packed entry, and deleting it drops the entry too
+        set(&cfg, &backend, "bkt", None).await.unwrap();
+        put(&backend, "a", b"now a file").await;
+        assert!(lookup(&cfg, "bkt", "a").await.is_none());
+        assert_eq!(get(&backend, "a", None).await.1, b"now a file");
+        assert!(backend.delete_object("bkt", "b").await.unwrap());
+        assert!(matches!(backend.get_object("bkt", "b", None).await, Err(StorageError::NoSuchKey)));
+    }
+
+    #[tokio::test]
+    async fn listings_merge_packed_objects_and_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cfg, backend) = packing_backend(dir.path()).await;
+        for key in ["a", "c", "dir/x"] { put(&backend, key, key.as_bytes()).await; }
+        set(&cfg, &backend, "bkt", None).await.unwrap();
+        for key in ["b", "d", "dir/y"] { put(&backend, key, key.as_bytes()).await; }
+
+        let page = backend.list_objects("bkt", "", None, "", 1000).await.unwrap();
+        assert_eq!(page.objects.iter().map(|o| o.key.as_str()).collect::<Vec<_>>(), ["a", "b", "c", "d", "dir/x", "dir/y"]);
+        let page = backend.list_objects("bkt", "", Some("/"), "", 1000).await.unwrap();
+        assert_eq!(page.objects.iter().map(|o| o.key.as_str()).collect::<Vec<_>>(), ["a", "b", "c", "d"]);
+        assert_eq!(page.common_prefixes, ["dir/"]);
+
+        let mut keys = Vec::new();
+        let mut marker = String::new();
+        loop {
+            let page = backend.list_objects("bkt", "", None, &marker, 2).await.unwrap();
+            keys.extend(page.objects.into_iter().map(|o| o.key));
+            match page.next_marker { Some(m) => marker = m, None => break }
+        }
+        assert_eq!(keys, ["a", "b", "c", "d", "dir/x", "dir/y"]);
+    }
+
+    #[tokio::test]
+    async fn compaction_moves_live_objects_out_of_dead_packs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cfg, backend) = packing_backend(dir.path()).await;
+        put(&backend, "keep", b"kept object").await;
+        for i in 0..8 { put(&backend, &format!("gone{i}"), &[i; 100]).await; }
+        for i in 0..8 { backend.delete_object("bkt", &format!("gone{i}")).await.unwrap(); }
+        let [old] = pack_files(&cfg).try_into().unwrap();
+
+        // Recently written packs are left alone
+        let report = compact(&cfg, &backend, "bkt").await.unwrap();
+        assert_eq!((report.packs, report.active_packs, report.removed_packs), (1, 1, 0));
+
+        let aged = SystemTime::now() - COMPACT_IDLE - Duration::from_secs(1);
+        std::fs::File::options().append(true).open(&old).unwrap().set_modified(aged).unwrap();
+        // Compaction runs in its own process, which has no pack of its own yet
+        WRITERS.remove(&data_dir(&cfg, "bkt"));
+        let report = compact(&cfg, &backend, "bkt").await.unwrap();
+        assert_eq!((report.live_objects, report.moved_objects, report.removed_packs, report.kept_packs), (1, 1, 1, 0));
+        assert_eq!(report.reclaimed_bytes, 800);
+        assert!(!old.exists());
+        let [new] = pack_files(&cfg).try_into().unwrap();
+        assert_eq!(std::fs::metadata(new).unwrap().len(), 11);
+
+        assert_eq!(get(&backend, "keep", Some("bytes=5-10")).await.1, b"object");
+        assert_eq!(lookup(&cfg, "bkt", "keep").await.unwrap().etag, format!("\"{:x}\"", md5::compute(b"kept object")));
+    }
+}
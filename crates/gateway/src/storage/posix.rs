@@ -1,25 +1,191 @@
-use crate::config::GatewayConfig;
+use crate::config::{Durability, GatewayConfig, KeyEncoding, MetadataStore};
+use crate::storage::etag::{self, FileStamp};
+use crate::storage::{dedup, durability, external, index, keys, pack, xattr};
+use crate::storage::lock::{KeyLock, KeyLocks};
+use crate::storage::walk::{KeyWalker, ListEntry};
+use crate::storage::{
+    multipart_etag, AttrsUpdate, BucketInfo, ByteRange, ByteStream, DeferredEtag, GetObject, ListPage, ListedObject, ObjectAttrs, ObjectMeta, PartInfo, StorageBackend, StorageError,
+    StorageResult,
+};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
 use fs_err as fs;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::fs as tfs;
 use anyhow::Context;
 
-pub async fn ensure_roots(cfg: &GatewayConfig) -> anyhow::Result<()> {
-    fs::create_dir_all(&cfg.data_root)?;
-    let multipart = Path::new(&cfg.mountpoint).join(".multipart");
-    fs::create_dir_all(multipart)?;
-    Ok(())
+/// Stores each object as a plain file under `DATA_ROOT/<bucket>/<key>` with its metadata in a
+/// `<key>.meta.json` sidecar, so the bucket stays browsable through the 3FS mount. Keys that
+/// are not safe paths as they are go through [`keys`].
+#[derive(Clone)]
+pub struct PosixBackend {
+    cfg: GatewayConfig,
+    /// Serializes writers of one key across pods
+    locks: Option<KeyLocks>,
+    /// Object data goes through USRBIO or io_uring instead of tokio file I/O when set
+    #[cfg(any(feature = "usrbio", feature = "io-uring"))]
+    ring: Option<std::sync::Arc<super::ring::RingIo>>,
+}
+
+/// Contents of a `.meta.json` sidecar.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Sidecar {
+    #[serde(default)]
+    pub etag: String,
+    /// The data file `etag` describes; sidecars written before stamps existed have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stamp: Option<FileStamp>,
+    /// When the object was written, in ms since the epoch, for data files shared through
+    /// [`dedup`], whose mtime is that of the first object with their content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_ms: Option<i64>,
+    #[serde(flatten)]
+    pub attrs: ObjectAttrs,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadInfo {
+    key: String,
+    attrs: ObjectAttrs,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PartMeta {
+    etag: String,
+    size: u64,
 }
 
 pub fn bucket_dir(cfg: &GatewayConfig, bucket: &str) -> PathBuf {
     Path::new(&cfg.data_root).join(bucket)
 }
 
-pub fn object_paths(cfg: &GatewayConfig, bucket: &str, key: &str) -> (PathBuf, PathBuf) {
-    let data = bucket_dir(cfg, bucket).join(key);
-    let meta = Path::new(&format!("{}.meta.json", data.display())).to_path_buf();
-    (data, meta)
+/// The data file and sidecar of `key`, or `InvalidArgument` for keys the configured
+/// [`KeyEncoding`] cannot store.
+pub fn object_paths(cfg: &GatewayConfig, bucket: &str, key: &str) -> StorageResult<(PathBuf, PathBuf)> {
+    let segments = keys::encode(cfg.key_encoding, key)?;
+    let (leaf, dirs) = segments.split_last().expect("split yields at least one segment");
+    let dir = dirs.iter().fold(bucket_dir(cfg, bucket), |p, d| p.join(d));
+    let mut data = dir.join(leaf);
+    if cfg.key_encoding == KeyEncoding::Escaped {
+        // Objects sharing their name with a directory live aside
+        let aside = dir.join(keys::aside(leaf));
+        if data.is_dir() || (!data.exists() && aside.exists()) { data = aside; }
+    }
+    Ok((data.clone(), sidecar_path(&data)))
+}
+
+pub(crate) fn sidecar_path(data: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.meta.json", data.display()))
+}
+
+/// The sidecar's ETag, unless the data file was replaced or modified after the sidecar was
+/// written. Without a stamp the sidecar must at least be newer than the data.
+pub(crate) fn sidecar_etag(sidecar: &Sidecar, md: &std::fs::Metadata, meta: &Path) -> Option<String> {
+    if sidecar.etag.is_empty() { return None; }
+    let current = match sidecar.stamp {
+        Some(stamp) => stamp == FileStamp::of(md),
+        None => fs::metadata(meta).ok().and_then(|m| m.modified().ok()).zip(md.modified().ok()).is_some_and(|(written, modified)| written >= modified),
+    };
+    current.then(|| sidecar.etag.clone())
+}
+
+/// The ETag of the object in `data`: its sidecar's while that is current, else one computed
+/// from the contents, which is empty while a file over `inline_max` bytes is still being hashed.
+#[allow(clippy::too_many_arguments)]
+pub fn current_etag(cfg: &GatewayConfig, bucket: &str, key: &str, data: &Path, md: &std::fs::Metadata, meta: &Path, sidecar: &Sidecar, inline_max: u64) -> String {
+    sidecar_etag(sidecar, md, meta).unwrap_or_else(|| etag::lookup(cfg, bucket, key, data, md, inline_max))
+}
+
+/// When the object in the data file described by `md` was last written.
+pub fn last_modified(sidecar: &Sidecar, md: &std::fs::Metadata) -> chrono::DateTime<chrono::Utc> {
+    sidecar.modified_ms.and_then(chrono::DateTime::from_timestamp_millis).unwrap_or_else(|| mtime(md))
+}
+
+/// Per-bucket configuration (notification, logging, ...) lives outside the bucket
+/// directory so it never shows up in listings.
+pub fn bucket_config_dir(cfg: &GatewayConfig, bucket: &str) -> PathBuf {
+    Path::new(&cfg.mountpoint).join(".bucket-config").join(bucket)
+}
+
+/// The metadata of the object in `data`, looked up in the configured [`MetadataStore`] first
+/// and in the other one after, so objects written before a switch keep their metadata.
+pub fn read_sidecar(cfg: &GatewayConfig, data: &Path, meta: &Path) -> Sidecar {
+    let from_xattr = || xattr::get(data).and_then(|b| serde_json::from_slice(&b).ok());
+    let from_file = || fs::read(meta).ok().and_then(|b| serde_json::from_slice(&b).ok());
+    let found: Option<Sidecar> = match cfg.metadata_store {
+        MetadataStore::Xattr => from_xattr().or_else(from_file),
+        MetadataStore::Sidecar => from_file().or_else(from_xattr),
+    };
+    let mut sidecar = found.unwrap_or_default();
+    if sidecar.attrs.content_type.is_empty() { sidecar.attrs.content_type = "application/octet-stream".into(); }
+    sidecar
+}
+
+/// Records the metadata of the object in `data` in the configured [`MetadataStore`], removing
+/// what the other store held so the two never disagree. Data files shared with other objects
+/// always get a sidecar.
+async fn write_metadata(cfg: &GatewayConfig, data: &Path, meta: &Path, sidecar: &Sidecar, mode: Durability) -> anyhow::Result<()> {
+    let bytes = serde_json::to_vec(sidecar)?;
+    let shared = tfs::metadata(data).await.is_ok_and(|md| dedup::is_shared(&md));
+    if cfg.metadata_store == MetadataStore::Xattr && !shared && xattr::set(data, &bytes)? {
+        durability::sync_file(data, mode).await?;
+        return delete_if_exists(meta).await;
+    }
+    write_file_durable(meta, &bytes, mode).await?;
+    if let Some(dir) = meta.parent() { durability::sync_dirs(dir, dir, mode).await?; }
+    xattr::remove(data);
+    Ok(())
+}
+
+/// Stamps a sidecar from before stamps existed while its ETag is still current, since outside
+/// its own file it can no longer be compared against the data file's mtime.
+fn fill_stamp(sidecar: &mut Sidecar, md: &std::fs::Metadata, meta: &Path) {
+    if sidecar.stamp.is_none() && sidecar_etag(sidecar, md, meta).is_some() { sidecar.stamp = Some(FileStamp::of(md)); }
+}
+
+/// Moves the metadata of every object in `bucket` into the configured [`MetadataStore`],
+/// returning how many objects were moved.
+pub async fn migrate_metadata(cfg: &GatewayConfig, storage: &dyn StorageBackend, bucket: &str) -> anyhow::Result<usize> {
+    let base = bucket_dir(cfg, bucket);
+    anyhow::ensure!(base.is_dir(), "no such bucket: {bucket}");
+    anyhow::ensure!(!external::get(cfg, bucket).is_some_and(|reg| reg.read_only), "{bucket} is read-only");
+    let locks = KeyLocks::new(cfg);
+    let mode = durability::for_bucket(cfg, storage, bucket).await;
+    let mut moved = 0;
+    for e in KeyWalker::new(&base, cfg.key_encoding, "", None, "") {
+        let ListEntry::Object { key, .. } = e else { continue };
+        let (data, meta) = object_paths(cfg, bucket, &key)?;
+        let _lock = match &locks { Some(locks) => Some(locks.lock(bucket, &key).await?), None => None };
+        let pending = match cfg.metadata_store {
+            MetadataStore::Xattr => meta.exists(),
+            MetadataStore::Sidecar => xattr::get(&data).is_some(),
+        };
+        let Ok(md) = fs::metadata(&data) else { continue };
+        if !pending || (cfg.metadata_store == MetadataStore::Xattr && dedup::is_shared(&md)) { continue; }
+        let mut sidecar = read_sidecar(cfg, &data, &meta);
+        fill_stamp(&mut sidecar, &md, &meta);
+        write_metadata(cfg, &data, &meta, &sidecar, mode).await?;
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+/// Rewrites the metadata of the object in `data` with the MD5 of its current contents, keeping
+/// its attributes. Fails if the file changes while it is being hashed.
+pub(crate) async fn recompute_etag(cfg: &GatewayConfig, data: &Path, meta: &Path, mode: Durability) -> anyhow::Result<String> {
+    let before = tfs::metadata(data).await?;
+    let path = data.to_path_buf();
+    let digest = tokio::task::spawn_blocking(move || md5_file(&path)).await??;
+    let after = tfs::metadata(data).await?;
+    anyhow::ensure!(same_file_version(&before, &after), "{} changed while it was hashed", data.display());
+    let mut sidecar = read_sidecar(cfg, data, meta);
+    sidecar.etag = format!("\"{digest:x}\"");
+    sidecar.stamp = Some(FileStamp::of(&after));
+    write_metadata(cfg, data, meta, &sidecar, mode).await?;
+    Ok(sidecar.etag)
 }
 
 pub async fn ensure_parent_dirs(p: &Path) -> anyhow::Result<()> {
@@ -28,12 +194,19 @@ pub async fn ensure_parent_dirs(p: &Path) -> anyhow::Result<()> {
 }
 
 pub async fn write_file_atomic(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    write_file_durable(path, bytes, Durability::None).await
+}
+
+/// Like [`write_file_atomic`], syncing the new contents before the rename at
+/// [`Durability::Data`] and above; syncing the directory is left to the caller.
+pub async fn write_file_durable(path: &Path, bytes: &[u8], mode: Durability) -> anyhow::Result<()> {
     ensure_parent_dirs(path).await?;
     let tmp = path.with_extension(".tmp");
     {
         let mut f = tfs::File::create(&tmp).await?;
         f.write_all(bytes).await?;
         f.flush().await?;
+        if mode >= Durability::Data { f.sync_all().await?; }
     }
     tfs::rename(&tmp, path).await?;
     Ok(())
@@ -53,4 +226,894 @@ pub async fn delete_if_exists(path: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Streams `body` into a new file at `path`, returning its size and MD5.
+async fn write_stream(path: &Path, mut body: ByteStream) -> StorageResult<(u64, md5::Digest)> {
+    ensure_parent_dirs(path).await?;
+    let mut f = tfs::File::create(path).await?;
+    let mut hasher = md5::Context::new();
+    let mut size = 0u64;
+    let result = async {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            hasher.consume(&chunk);
+            size += chunk.len() as u64;
+            f.write_all(&chunk).await?;
+        }
+        f.flush().await
+    }.await;
+    if let Err(e) = result {
+        let _ = tfs::remove_file(path).await;
+        return Err(e.into());
+    }
+    Ok((size, hasher.compute()))
+}
+
+fn not_found_as(e: std::io::Error, err: StorageError) -> StorageError {
+    // A file where the path needs a directory means nothing exists below it either
+    if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory) { err } else { e.into() }
+}
+
+/// Copies `src` into a new file at `dst` without moving the data through userspace where the
+/// filesystem allows: a reflink shares the extents, `copy_file_range` copies in the kernel (or
+/// server-side), and only filesystems supporting neither get a read/write loop.
+fn copy_file(src: &std::fs::File, dst: &Path) -> std::io::Result<()> {
+    if let Some(parent) = dst.parent() { fs::create_dir_all(parent)?; }
+    let mut out = std::fs::File::create(dst)?;
+    if rustix::fs::ioctl_ficlone(&out, src).is_ok() { return Ok(()); }
+    let len = src.metadata()?.len();
+    let (mut off_in, mut off_out) = (0u64, 0u64);
+    while off_in < len {
+        let chunk = (len - off_in).min(1 << 30) as usize;
+        match rustix::fs::copy_file_range(src, Some(&mut off_in), &out, Some(&mut off_out), chunk) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(rustix::io::Errno::INTR) => {}
+            Err(rustix::io::Errno::XDEV | rustix::io::Errno::NOSYS | rustix::io::Errno::OPNOTSUPP | rustix::io::Errno::INVAL) if off_in == 0 => {
+                std::io::copy(&mut &*src, &mut out)?;
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Reads `len` bytes from `start` in `chunk_size` pieces, keeping up to `depth` reads in flight
+/// ahead of the client so a single GET is not bound by the latency of each read. At most
+/// `depth` chunks are held per request, whether in flight or waiting to be sent.
+fn prefetch_stream(file: std::fs::File, start: u64, len: u64, chunk_size: usize, depth: usize) -> ByteStream {
+    use std::os::unix::fs::FileExt;
+    let (file, chunk, end) = (std::sync::Arc::new(file), chunk_size as u64, start + len);
+    let offsets = (0..len.div_ceil(chunk)).map(move |i| start + i * chunk);
+    Box::pin(futures::stream::iter(offsets).map(move |offset| {
+        let file = file.clone();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let mut buf = vec![0u8; chunk.min(end - offset) as usize];
+                file.read_exact_at(&mut buf, offset)?;
+                Ok(bytes::Bytes::from(buf))
+            }).await.map_err(std::io::Error::other)?
+        }
+    }).buffered(depth))
+}
+
+/// Whether two stats of a path show the same, unmodified file.
+fn same_file_version(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    (a.dev(), a.ino(), a.len(), a.mtime(), a.mtime_nsec(), a.ctime(), a.ctime_nsec()) == (b.dev(), b.ino(), b.len(), b.mtime(), b.mtime_nsec(), b.ctime(), b.ctime_nsec())
+}
+
+/// MD5 of a file's content, read in 1 MiB chunks.
+fn md5_file(path: &Path) -> std::io::Result<md5::Digest> {
+    use std::io::Read;
+    let mut f = std::fs::File::open(path)?;
+    let mut hasher = md5::Context::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        match f.read(&mut buf)? {
+            0 => return Ok(hasher.compute()),
+            n => hasher.consume(&buf[..n]),
+        }
+    }
+}
+
+fn mtime(md: &std::fs::Metadata) -> chrono::DateTime<chrono::Utc> {
+    md.modified().map(chrono::DateTime::<chrono::Utc>::from).unwrap_or_else(|_| chrono::Utc::now())
+}
+
+async fn collect(mut body: ByteStream) -> StorageResult<Bytes> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        buf.extend_from_slice(&chunk?);
+    }
+    Ok(buf.freeze())
+}
+
+impl From<index::IndexEntry> for ObjectMeta {
+    fn from(e: index::IndexEntry) -> Self {
+        Self { size: e.size, last_modified: e.mtime(), etag: e.etag, attrs: e.attrs }
+    }
+}
+
+impl PosixBackend {
+    pub fn new(cfg: GatewayConfig) -> Self {
+        #[cfg(not(feature = "usrbio"))]
+        if cfg.use_usrbio { tracing::warn!("UseUsrBio is set but the gateway was built without the `usrbio` feature; using FUSE"); }
+        #[cfg(not(feature = "io-uring"))]
+        if cfg.io_uring { tracing::warn!("IO_URING is set but the gateway was built without the `io-uring` feature"); }
+        Self {
+            #[cfg(any(feature = "usrbio", feature = "io-uring"))]
+            ring: super::ring::open(&cfg),
+            locks: KeyLocks::new(&cfg),
+            cfg,
+        }
+    }
+
+    /// Streams `body` into a new file at `path`, through the ring data path when it can take
+    /// the file.
+    async fn write_stream(&self, path: &Path, body: ByteStream) -> StorageResult<(u64, md5::Digest)> {
+        #[cfg(any(feature = "usrbio", feature = "io-uring"))]
+        if let Some(ring) = &self.ring {
+            ensure_parent_dirs(path).await?;
+            let file = std::fs::File::create(path)?;
+            match ring.register(&file) {
+                Ok(reg) => {
+                    let result = reg.write_stream(body).await;
+                    if result.is_err() { let _ = tfs::remove_file(path).await; }
+                    return Ok(result?);
+                }
+                Err(e) => tracing::debug!(path = %path.display(), error = %e, "ring registration failed, writing through tokio"),
+            }
+        }
+        write_stream(path, body).await
+    }
+
+    pub async fn ensure_roots(&self) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.cfg.data_root)?;
+        fs::create_dir_all(self.staging_dir())?;
+        fs::create_dir_all(Path::new(&self.cfg.mountpoint).join(".multipart"))?;
+        Ok(())
+    }
+
+    /// New objects are assembled here and renamed into place, so listings and readers never
+    /// see a partial object.
+    fn staging_dir(&self) -> PathBuf {
+        Path::new(&self.cfg.mountpoint).join(".tmp")
+    }
+
+    fn staging_path(&self) -> PathBuf {
+        self.staging_dir().join(format!("{}.tmp", uuid::Uuid::new_v4().simple()))
+    }
+
+    fn upload_dir(&self, bucket: &str, upload_id: &str) -> StorageResult<PathBuf> {
+        if upload_id.is_empty() || !upload_id.chars().all(|c| c.is_ascii_alphanumeric()) { return Err(StorageError::NoSuchUpload); }
+        Ok(Path::new(&self.cfg.mountpoint).join(".multipart").join(bucket).join(upload_id))
+    }
+
+    fn require_bucket(&self, bucket: &str) -> StorageResult<PathBuf> {
+        let dir = bucket_dir(&self.cfg, bucket);
+        if dir.is_dir() { Ok(dir) } else { Err(StorageError::NoSuchBucket) }
+    }
+
+    /// Like [`Self::require_bucket`], but also refuses registered read-only directories.
+    fn require_writable_bucket(&self, bucket: &str) -> StorageResult<PathBuf> {
+        let dir = self.require_bucket(bucket)?;
+        if external::get(&self.cfg, bucket).is_some_and(|reg| reg.read_only) { return Err(StorageError::AccessDenied); }
+        Ok(dir)
+    }
+
+    /// Whether writes to `bucket` go through the dedup pool. Registered directories are left
+    /// alone, since their files may be modified in place behind the gateway's back.
+    fn dedups(&self, bucket: &str) -> bool {
+        self.cfg.dedup && !external::is_external(&self.cfg, bucket)
+    }
+
+    /// Swaps a staged file for its pooled twin, see [`dedup::intern`]. A failure only costs the
+    /// space the staged file takes, so it is logged and the file committed as it is.
+    async fn intern(&self, staged: &Path, digest: String) {
+        let (cfg, path) = (self.cfg.clone(), staged.to_path_buf());
+        match tokio::task::spawn_blocking(move || dedup::intern(&cfg, &path, &digest)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => tracing::warn!(path = %staged.display(), error = %e, "failed to deduplicate object"),
+            Err(e) => tracing::warn!(path = %staged.display(), error = %e, "failed to deduplicate object"),
+        }
+    }
+
+    async fn lock_key(&self, bucket: &str, key: &str) -> StorageResult<Option<KeyLock>> {
+        match &self.locks {
+            Some(locks) => locks.lock(bucket, key).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Makes sure no object occupies a directory `key` needs: with escaped keys such objects are
+    /// moved aside, otherwise the key is refused.
+    async fn make_room(&self, bucket: &str, key: &str) -> StorageResult<()> {
+        let segments = keys::encode(self.cfg.key_encoding, key)?;
+        let conflict = || StorageError::InvalidArgument(format!("key {key:?} conflicts with an existing object or prefix"));
+        let (leaf, dirs) = segments.split_last().expect("split yields at least one segment");
+        let mut dir = bucket_dir(&self.cfg, bucket);
+        for (depth, segment) in dirs.iter().enumerate() {
+            let path = dir.join(segment);
+            if tfs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+                if self.cfg.key_encoding == KeyEncoding::Compat { return Err(conflict()); }
+                let occupant = key.split('/').take(depth + 1).collect::<Vec<_>>().join("/");
+                let _lock = self.lock_key(bucket, &occupant).await?;
+                let aside = dir.join(keys::aside(segment));
+                tfs::rename(&path, &aside).await?;
+                let _ = tfs::rename(sidecar_path(&path), sidecar_path(&aside)).await;
+            }
+            dir = path;
+        }
+        if self.cfg.key_encoding == KeyEncoding::Compat && tfs::metadata(dir.join(leaf)).await.is_ok_and(|m| m.is_dir()) {
+            return Err(conflict());
+        }
+        Ok(())
+    }
+
+    /// Moves a fully written staging file into place as `key` and records its metadata.
+    async fn commit(&self, staged: &Path, bucket: &str, key: &str, etag: String, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        let mode = durability::for_bucket(&self.cfg, self, bucket).await;
+        let prepared = async {
+            // Renaming keeps the inode, size and mtime the stamp records
+            let md = tfs::metadata(staged).await?;
+            let shared = dedup::is_shared(&md);
+            let modified_ms = shared.then(|| chrono::Utc::now().timestamp_millis());
+            let sidecar = Sidecar { etag: etag.clone(), stamp: Some(FileStamp::of(&md)), modified_ms, attrs: attrs.clone() };
+            let sidecar = serde_json::to_vec(&sidecar).map_err(anyhow::Error::from)?;
+            // An xattr set before the rename makes data and metadata appear together
+            let in_xattr = self.cfg.metadata_store == MetadataStore::Xattr && !shared && xattr::set(staged, &sidecar)?;
+            let last_modified = modified_ms.and_then(chrono::DateTime::from_timestamp_millis);
+            durability::sync_file(staged, mode).await?;
+            self.make_room(bucket, key).await?;
+            let lock = self.lock_key(bucket, key).await?;
+            Ok::<_, StorageError>((sidecar, in_xattr, last_modified, lock, object_paths(&self.cfg, bucket, key)?))
+        }.await;
+        let (sidecar, in_xattr, last_modified, lock, (data, meta)) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                let _ = tfs::remove_file(staged).await;
+                return Err(e);
+            }
+        };
+        let result = async {
+            ensure_parent_dirs(&data).await?;
+            tfs::rename(staged, &data).await?;
+            if in_xattr { delete_if_exists(&meta).await? } else { write_file_durable(&meta, &sidecar, mode).await? }
+            // Covers the directories make_room and ensure_parent_dirs may have created
+            if let Some(dir) = data.parent() { durability::sync_dirs(dir, &bucket_dir(&self.cfg, bucket), mode).await?; }
+            Ok::<_, anyhow::Error>(())
+        }.await;
+        if let Err(e) = result {
+            let _ = tfs::remove_file(staged).await;
+            return Err(e.into());
+        }
+        // A packed entry would shadow the file
+        pack::forget(&self.cfg, bucket, key, mode).await?;
+        etag::forget(&self.cfg, bucket, key, &data);
+        index::sync_key(&self.cfg, bucket, key).await;
+        let md = tfs::metadata(&data).await?;
+        drop(lock);
+        Ok(ObjectMeta { size: md.len(), etag, last_modified: last_modified.unwrap_or_else(|| mtime(&md)), attrs })
+    }
+
+    /// Appends a small object to the bucket's pack as `key`, replacing a file of that key.
+    async fn put_packed(&self, bucket: &str, key: &str, data: Bytes, etag: String, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        let (path, meta) = object_paths(&self.cfg, bucket, key)?;
+        let mode = durability::for_bucket(&self.cfg, self, bucket).await;
+        let size = data.len() as u64;
+        let at = pack::append(&self.cfg, bucket, data, mode).await?;
+        let _lock = self.lock_key(bucket, key).await?;
+        let entry = index::IndexEntry { size, etag, mtime_ms: chrono::Utc::now().timestamp_millis(), pack: Some(at), attrs };
+        pack::record(&self.cfg, bucket, key, entry.clone(), mode).await?;
+        if tfs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+            let _ = tfs::remove_file(&path).await;
+            let _ = tfs::remove_file(&meta).await;
+            etag::forget(&self.cfg, bucket, key, &path);
+            index::sync_key(&self.cfg, bucket, key).await;
+            self.prune_empty_dirs(bucket, &path).await;
+        }
+        Ok(ObjectMeta::from(entry))
+    }
+
+    /// Stores `data`, whose ETag is known, as `key`: packed when the bucket packs objects of
+    /// its size, as a file otherwise.
+    async fn write_bytes(&self, bucket: &str, key: &str, data: Bytes, etag: String, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        if pack::for_bucket(self, bucket).await.is_some_and(|max| data.len() as u64 <= max) {
+            return self.put_packed(bucket, key, data, etag, attrs).await;
+        }
+        let staged = self.staging_path();
+        if let Err(e) = tfs::write(&staged, &data).await {
+            let _ = tfs::remove_file(&staged).await;
+            return Err(e.into());
+        }
+        self.commit(&staged, bucket, key, etag, attrs).await
+    }
+
+    /// Removes directories emptied by a delete, up to but excluding the bucket directory.
+    async fn prune_empty_dirs(&self, bucket: &str, data: &Path) {
+        let root = bucket_dir(&self.cfg, bucket);
+        let mut dir = data.parent();
+        while let Some(d) = dir {
+            if d == root || !d.starts_with(&root) || tfs::remove_dir(d).await.is_err() { break; }
+            dir = d.parent();
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for PosixBackend {
+    async fn is_ready(&self) -> bool {
+        crate::mount::is_mounted_and_writeable(&self.cfg.mountpoint).await
+    }
+
+    async fn list_buckets(&self) -> StorageResult<Vec<BucketInfo>> {
+        let mut buckets = Vec::new();
+        for e in fs::read_dir(&self.cfg.data_root)?.flatten() {
+            // Registered directories are symlinks
+            let Ok(md) = fs::metadata(e.path()) else { continue };
+            if !md.is_dir() { continue; }
+            let created = md.created().or_else(|_| md.modified()).map(chrono::DateTime::<chrono::Utc>::from).unwrap_or_else(|_| chrono::Utc::now());
+            buckets.push(BucketInfo { name: e.file_name().to_string_lossy().into_owned(), created });
+        }
+        buckets.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(buckets)
+    }
+
+    async fn head_bucket(&self, bucket: &str) -> StorageResult<()> {
+        self.require_bucket(bucket).map(|_| ())
+    }
+
+    async fn create_bucket(&self, bucket: &str) -> StorageResult<()> {
+        let dir = bucket_dir(&self.cfg, bucket);
+        if dir.exists() { return Err(StorageError::BucketAlreadyExists); }
+        tfs::create_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    async fn delete_bucket(&self, bucket: &str) -> StorageResult<()> {
+        let dir = self.require_writable_bucket(bucket)?;
+        if external::is_external(&self.cfg, bucket) {
+            external::unregister(&self.cfg, bucket)?;
+            let _ = tfs::remove_dir_all(bucket_config_dir(&self.cfg, bucket)).await;
+            index::drop_bucket(&self.cfg, bucket);
+            return Ok(());
+        }
+        // Only an empty bucket can be removed
+        if !pack::is_empty(&self.cfg, bucket) { return Err(StorageError::BucketNotEmpty); }
+        tfs::remove_dir(&dir).await.map_err(|_| StorageError::BucketNotEmpty)?;
+        let _ = tfs::remove_dir_all(bucket_config_dir(&self.cfg, bucket)).await;
+        let _ = tfs::remove_dir_all(Path::new(&self.cfg.mountpoint).join(".multipart").join(bucket)).await;
+        let _ = tfs::remove_dir_all(etag::bucket_cache_dir(&self.cfg, bucket)).await;
+        index::drop_bucket(&self.cfg, bucket);
+        pack::drop_bucket(&self.cfg, bucket);
+        Ok(())
+    }
+
+    async fn get_bucket_config(&self, bucket: &str, name: &str) -> StorageResult<Option<Vec<u8>>> {
+        match tfs::read(bucket_config_dir(&self.cfg, bucket).join(format!("{name}.json"))).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn put_bucket_config(&self, bucket: &str, name: &str, value: Option<Vec<u8>>) -> StorageResult<()> {
+        let path = bucket_config_dir(&self.cfg, bucket).join(format!("{name}.json"));
+        match value {
+            Some(v) => write_file_atomic(&path, &v).await?,
+            None => delete_if_exists(&path).await?,
+        }
+        Ok(())
+    }
+
+    async fn put_object(&self, bucket: &str, key: &str, body: ByteStream, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        self.put_object_with_etag(bucket, key, body, attrs, DeferredEtag::default()).await
+    }
+
+    async fn put_object_with_etag(&self, bucket: &str, key: &str, mut body: ByteStream, attrs: ObjectAttrs, etag: DeferredEtag) -> StorageResult<ObjectMeta> {
+        self.require_writable_bucket(bucket)?;
+        if let Some(max) = pack::for_bucket(self, bucket).await {
+            match pack::read_small(body, max).await? {
+                (data, None) => {
+                    let etag = etag.get().cloned().unwrap_or_else(|| format!("\"{:x}\"", md5::compute(&data)));
+                    return self.put_packed(bucket, key, data, etag, attrs).await;
+                }
+                (head, Some(rest)) => body = Box::pin(futures::stream::once(async { Ok(head) }).chain(rest)),
+            }
+        }
+        let staged = self.staging_path();
+        // Encrypted objects have a random salt, so no two are ever identical
+        let (body, sha256) = match self.dedups(bucket) && attrs.sse.is_none() {
+            true => { let (body, sha256) = dedup::hashing(body); (body, Some(sha256)) }
+            false => (body, None),
+        };
+        let (size, digest) = self.write_stream(&staged, body).await?;
+        if let Some(sha256) = sha256.filter(|_| size >= dedup::MIN_SIZE) { self.intern(&staged, dedup::hex_digest(&sha256)).await; }
+        let etag = etag.get().cloned().unwrap_or_else(|| format!("\"{digest:x}\""));
+        self.commit(&staged, bucket, key, etag, attrs).await
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str, range: Option<ByteRange>) -> StorageResult<GetObject> {
+        self.require_bucket(bucket)?;
+        if let Some(entry) = pack::lookup(&self.cfg, bucket, key).await { return pack::get_object(&self.cfg, bucket, key, entry, range).await; }
+        let (data, meta_path) = object_paths(&self.cfg, bucket, key)?;
+        let mut file = tfs::File::open(&data).await.map_err(|e| not_found_as(e, StorageError::NoSuchKey))?;
+        let md = file.metadata().await?;
+        if !md.is_file() { return Err(StorageError::NoSuchKey); }
+        let sidecar = read_sidecar(&self.cfg, &data, &meta_path);
+        let etag = match sidecar_etag(&sidecar, &md, &meta_path) {
+            Some(etag) => etag,
+            None => {
+                let (cfg, b, k, d, m) = (self.cfg.clone(), bucket.to_string(), key.to_string(), data.clone(), md.clone());
+                tokio::task::spawn_blocking(move || etag::lookup(&cfg, &b, &k, &d, &m, etag::INLINE_MAX_BYTES)).await.map_err(anyhow::Error::from)?
+            }
+        };
+        let meta = ObjectMeta { size: md.len(), etag, last_modified: last_modified(&sidecar, &md), attrs: sidecar.attrs };
+        let range = match range {
+            Some(r) => Some(r.resolve(md.len()).ok_or(StorageError::InvalidRange)?),
+            None => None,
+        };
+        let (start, len) = range.map(|(s, e)| (s, e - s + 1)).unwrap_or((0, md.len()));
+        #[cfg(any(feature = "usrbio", feature = "io-uring"))]
+        if let Some(ring) = &self.ring {
+            let std_file = file.into_std().await;
+            match ring.register(&std_file) {
+                Ok(reg) => return Ok(GetObject { meta, range, body: reg.read_stream(start, len) }),
+                Err(e) => tracing::debug!(path = %data.display(), error = %e, "ring registration failed, reading through tokio"),
+            }
+            file = tfs::File::from_std(std_file);
+        }
+        if self.cfg.get_readahead > 0 {
+            let body = prefetch_stream(file.into_std().await, start, len, self.cfg.get_chunk_size, self.cfg.get_readahead);
+            return Ok(GetObject { meta, range, body });
+        }
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        Ok(GetObject { meta, range, body: Box::pin(tokio_util::io::ReaderStream::new(file.take(len))) })
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> StorageResult<ObjectMeta> {
+        self.require_bucket(bucket)?;
+        if let Some(entry) = pack::lookup(&self.cfg, bucket, key).await { return Ok(entry.into()); }
+        index::lookup(&self.cfg, bucket, key).await.map(ObjectMeta::from).ok_or(StorageError::NoSuchKey)
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> StorageResult<bool> {
+        self.require_writable_bucket(bucket)?;
+        let (data, meta) = object_paths(&self.cfg, bucket, key)?;
+        let is_dir = tfs::metadata(&data).await.map(|m| m.is_dir()).unwrap_or(false);
+        let _lock = self.lock_key(bucket, key).await?;
+        let packed = pack::forget(&self.cfg, bucket, key, durability::for_bucket(&self.cfg, self, bucket).await).await?;
+        if is_dir { return Ok(packed); }
+        let removed = tfs::remove_file(&data).await.is_ok();
+        let _ = tfs::remove_file(&meta).await;
+        etag::forget(&self.cfg, bucket, key, &data);
+        index::sync_key(&self.cfg, bucket, key).await;
+        if removed { self.prune_empty_dirs(bucket, &data).await; }
+        Ok(removed || packed)
+    }
+
+    async fn copy_object(&self, src_bucket: &str, src_key: &str, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<ObjectMeta> {
+        self.require_bucket(src_bucket)?;
+        self.require_writable_bucket(bucket)?;
+        let (src, src_meta) = object_paths(&self.cfg, src_bucket, src_key)?;
+        // Packed sources and small sources of packing buckets are copied through memory
+        let packs_src = match pack::for_bucket(self, bucket).await {
+            Some(max) => tfs::metadata(&src).await.is_ok_and(|m| m.is_file() && m.len() <= max),
+            None => false,
+        };
+        if packs_src || pack::lookup(&self.cfg, src_bucket, src_key).await.is_some() {
+            let object = self.get_object(src_bucket, src_key, None).await?;
+            let data = collect(object.body).await?;
+            return self.write_bytes(bucket, key, data, object.meta.etag, attrs).await;
+        }
+        let staged = self.staging_path();
+        let target = staged.clone();
+        // A source with an xattr would share it with the copy
+        let link = self.dedups(bucket) && !external::is_external(&self.cfg, src_bucket) && xattr::get(&src).is_none();
+        let (cfg, src_bucket_name, src_key_name) = (self.cfg.clone(), src_bucket.to_string(), src_key.to_string());
+        let etag = tokio::task::spawn_blocking(move || -> StorageResult<String> {
+            let file = std::fs::File::open(&src).map_err(|e| not_found_as(e, StorageError::NoSuchKey))?;
+            let before = file.metadata()?;
+            if !before.is_file() { return Err(StorageError::NoSuchKey); }
+            // Only an already known ETag; hashing the copy below is as cheap as hashing the source
+            let etag = sidecar_etag(&read_sidecar(&cfg, &src, &src_meta), &before, &src_meta).unwrap_or_else(|| etag::lookup(&cfg, &src_bucket_name, &src_key_name, &src, &before, 0));
+            let copied = match link && before.len() >= dedup::MIN_SIZE {
+                true => fs::hard_link(&src, &target).or_else(|_| copy_file(&file, &target)),
+                false => copy_file(&file, &target),
+            };
+            let result = copied.and_then(|_| {
+                // The sidecar ETag still describes what was copied only if neither the data
+                // file nor the sidecar changed meanwhile; otherwise hash the copy itself
+                let unchanged = fs::metadata(&src).is_ok_and(|after| same_file_version(&before, &after))
+                    && file.metadata().is_ok_and(|after| same_file_version(&before, &after))
+                    && sidecar_etag(&read_sidecar(&cfg, &src, &src_meta), &before, &src_meta).is_none_or(|e| e == etag);
+                if unchanged && !etag.is_empty() { return Ok(etag); }
+                Ok(format!("\"{:x}\"", md5_file(&target)?))
+            });
+            if result.is_err() { let _ = fs::remove_file(&target); }
+            Ok(result?)
+        }).await.map_err(anyhow::Error::from)??;
+        self.commit(&staged, bucket, key, etag, attrs).await
+    }
+
+    async fn update_attrs(&self, bucket: &str, key: &str, f: AttrsUpdate) -> StorageResult<()> {
+        self.require_writable_bucket(bucket)?;
+        let (data, meta) = object_paths(&self.cfg, bucket, key)?;
+        let _lock = self.lock_key(bucket, key).await?;
+        let mode = durability::for_bucket(&self.cfg, self, bucket).await;
+        if let Some(mut entry) = pack::current(&self.cfg, bucket, key).await {
+            f(&mut entry.attrs);
+            return Ok(pack::record(&self.cfg, bucket, key, entry, mode).await?);
+        }
+        if !data.is_file() { return Err(StorageError::NoSuchKey); }
+        let md = tfs::metadata(&data).await.map_err(|e| not_found_as(e, StorageError::NoSuchKey))?;
+        let mut sidecar = read_sidecar(&self.cfg, &data, &meta);
+        fill_stamp(&mut sidecar, &md, &meta);
+        f(&mut sidecar.attrs);
+        write_metadata(&self.cfg, &data, &meta, &sidecar, mode).await?;
+        index::sync_key(&self.cfg, bucket, key).await;
+        Ok(())
+    }
+
+    async fn list_objects(&self, bucket: &str, prefix: &str, delimiter: Option<&str>, marker: &str, max_keys: usize) -> StorageResult<ListPage> {
+        let base = self.require_bucket(bucket)?;
+        let (cfg, catalog) = (self.cfg.clone(), pack::catalog(&self.cfg, bucket));
+        let (bucket, prefix, delimiter, marker) = (bucket.to_string(), prefix.to_string(), delimiter.map(str::to_string), marker.to_string());
+        let page = tokio::task::spawn_blocking(move || {
+            let files = match index::open(&cfg, &bucket) {
+                Some(idx) => index_page(&idx, &prefix, delimiter.as_deref(), &marker, max_keys),
+                None => list_page(&cfg, &bucket, &base, &prefix, delimiter.as_deref(), &marker, max_keys),
+            };
+            match catalog {
+                Some(catalog) => merge_pages(index_page(&catalog, &prefix, delimiter.as_deref(), &marker, max_keys), files, max_keys),
+                None => files,
+            }
+        }).await.map_err(anyhow::Error::from)?;
+        Ok(page)
+    }
 
+    async fn create_multipart_upload(&self, bucket: &str, key: &str, attrs: ObjectAttrs) -> StorageResult<String> {
+        self.require_writable_bucket(bucket)?;
+        let upload_id = uuid::Uuid::new_v4().simple().to_string();
+        let info = serde_json::to_vec(&UploadInfo { key: key.to_string(), attrs }).map_err(anyhow::Error::from)?;
+        write_file_atomic(&self.upload_dir(bucket, &upload_id)?.join("upload.json"), &info).await?;
+        Ok(upload_id)
+    }
+
+    async fn upload_attrs(&self, bucket: &str, upload_id: &str) -> StorageResult<ObjectAttrs> {
+        let dir = self.upload_dir(bucket, upload_id)?;
+        match tfs::read(dir.join("upload.json")).await {
+            Ok(b) => Ok(serde_json::from_slice::<UploadInfo>(&b).map_err(anyhow::Error::from)?.attrs),
+            Err(e) => Err(not_found_as(e, StorageError::NoSuchUpload)),
+        }
+    }
+
+    async fn upload_part(&self, bucket: &str, upload_id: &str, part_number: u32, body: ByteStream) -> StorageResult<String> {
+        let dir = self.upload_dir(bucket, upload_id)?;
+        if !dir.join("upload.json").is_file() { return Err(StorageError::NoSuchUpload); }
+        if !(1..=10_000).contains(&part_number) { return Err(StorageError::InvalidArgument("part number must be between 1 and 10000".into())); }
+        let staged = self.staging_path();
+        let (size, digest) = self.write_stream(&staged, body).await?;
+        let etag = format!("\"{digest:x}\"");
+        let part = dir.join(part_number.to_string());
+        tfs::rename(&staged, &part).await?;
+        let meta = serde_json::to_vec(&PartMeta { etag: etag.clone(), size }).map_err(anyhow::Error::from)?;
+        write_file_atomic(&dir.join(format!("{part_number}.meta.json")), &meta).await?;
+        Ok(etag)
+    }
+
+    async fn list_parts(&self, bucket: &str, upload_id: &str) -> StorageResult<Vec<PartInfo>> {
+        let dir = self.upload_dir(bucket, upload_id)?;
+        let rd = fs::read_dir(&dir).map_err(|e| not_found_as(e, StorageError::NoSuchUpload))?;
+        let mut parts = Vec::new();
+        for e in rd.flatten() {
+            let name = e.file_name().to_string_lossy().into_owned();
+            let Some(part_number) = name.strip_suffix(".meta.json").and_then(|n| n.parse::<u32>().ok()) else { continue };
+            let Ok(meta) = fs::read(e.path()).map_err(anyhow::Error::from).and_then(|b| Ok(serde_json::from_slice::<PartMeta>(&b)?)) else { continue };
+            let Ok(md) = fs::metadata(dir.join(part_number.to_string())) else { continue };
+            parts.push(PartInfo { part_number, etag: meta.etag, size: meta.size, last_modified: mtime(&md) });
+        }
+        parts.sort_by_key(|p| p.part_number);
+        Ok(parts)
+    }
+
+    async fn complete_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str, parts: &[(u32, String)], update: Option<AttrsUpdate>) -> StorageResult<ObjectMeta> {
+        self.require_writable_bucket(bucket)?;
+        let dir = self.upload_dir(bucket, upload_id)?;
+        let mut info: UploadInfo = match tfs::read(dir.join("upload.json")).await {
+            Ok(b) => serde_json::from_slice(&b).map_err(anyhow::Error::from)?,
+            Err(e) => return Err(not_found_as(e, StorageError::NoSuchUpload)),
+        };
+        if info.key != key { return Err(StorageError::NoSuchUpload); }
+        if let Some(update) = update { update(&mut info.attrs); }
+        let etag = multipart_etag(parts, &self.list_parts(bucket, upload_id).await?)?;
+        let staged = self.staging_path();
+        let result = async {
+            let mut out = tfs::File::create(&staged).await?;
+            for (number, _) in parts {
+                let mut part = tfs::File::open(dir.join(number.to_string())).await?;
+                tokio::io::copy(&mut part, &mut out).await?;
+            }
+            out.flush().await
+        }.await;
+        if let Err(e) = result {
+            let _ = tfs::remove_file(&staged).await;
+            return Err(e.into());
+        }
+        if self.dedups(bucket) && info.attrs.sse.is_none() && tfs::metadata(&staged).await?.len() >= dedup::MIN_SIZE {
+            let path = staged.clone();
+            match tokio::task::spawn_blocking(move || dedup::hash_file(&path)).await.map_err(anyhow::Error::from)? {
+                Ok(sha256) => self.intern(&staged, sha256).await,
+                Err(e) => tracing::warn!(path = %staged.display(), error = %e, "failed to hash object for deduplication"),
+            }
+        }
+        let meta = self.commit(&staged, bucket, key, etag, info.attrs).await?;
+        let _ = tfs::remove_dir_all(&dir).await;
+        Ok(meta)
+    }
+
+    async fn abort_multipart_upload(&self, bucket: &str, upload_id: &str) -> StorageResult<()> {
+        let dir = self.upload_dir(bucket, upload_id)?;
+        tfs::remove_dir_all(&dir).await.map_err(|e| not_found_as(e, StorageError::NoSuchUpload))
+    }
+}
+
+/// A listing page read straight from the bucket directory.
+fn list_page(cfg: &GatewayConfig, bucket: &str, base: &Path, prefix: &str, delimiter: Option<&str>, marker: &str, max_keys: usize) -> ListPage {
+    let mut page = ListPage { objects: Vec::new(), common_prefixes: Vec::new(), next_marker: None };
+    let mut last: Option<String> = None;
+    for (i, entry) in KeyWalker::new(base, cfg.key_encoding, prefix, delimiter, marker).enumerate() {
+        // One entry beyond the page only tells us the listing is truncated
+        if i == max_keys {
+            page.next_marker = Some(last.unwrap_or_else(|| marker.to_string()));
+            break;
+        }
+        match entry {
+            ListEntry::CommonPrefix(cp) => { last = Some(cp.clone()); page.common_prefixes.push(cp); }
+            ListEntry::Object { key, size, mtime } => {
+                last = Some(key.clone());
+                // Listings never wait for a hash; large files show an empty ETag until it is known
+                let (etag, size, mtime) = object_paths(cfg, bucket, &key).ok().and_then(|(data, meta)| {
+                    let md = fs::metadata(&data).ok()?;
+                    let sidecar = read_sidecar(cfg, &data, &meta);
+                    // A corrupt object is listed at its stored size; reading it fails
+                    let size = sidecar.attrs.logical_size(size).unwrap_or(size);
+                    Some((current_etag(cfg, bucket, &key, &data, &md, &meta, &sidecar, 0), size, last_modified(&sidecar, &md)))
+                }).unwrap_or((String::new(), size, mtime));
+                page.objects.push(ListedObject { key, size, last_modified: mtime, etag });
+            }
+        }
+    }
+    page
+}
+
+/// A listing page answered from the bucket's metadata index.
+fn index_page(idx: &index::BucketIndex, prefix: &str, delimiter: Option<&str>, marker: &str, max_keys: usize) -> ListPage {
+    let (entries, next_marker) = idx.list(prefix, delimiter, marker, max_keys);
+    let mut page = ListPage { objects: Vec::new(), common_prefixes: Vec::new(), next_marker };
+    for (entry, meta) in entries {
+        match entry {
+            ListEntry::CommonPrefix(cp) => page.common_prefixes.push(cp),
+            ListEntry::Object { key, size, mtime } => {
+                let (etag, size) = meta.map_or((String::new(), size), |m| (m.etag, m.attrs.logical_size(size).unwrap_or(size)));
+                page.objects.push(ListedObject { key, size, last_modified: mtime, etag });
+            }
+        }
+    }
+    page
+}
+
+/// Merges the page of packed objects with the page of files for the same request. A key in
+/// both is the packed object. A truncated page only covers keys up to its marker, so the merged
+/// page stops there as well.
+fn merge_pages(packed: ListPage, files: ListPage, max_keys: usize) -> ListPage {
+    let bound = [&packed.next_marker, &files.next_marker].into_iter().flatten().min().cloned();
+    let mut items: Vec<(String, Option<ListedObject>)> = Vec::new();
+    for page in [packed, files] {
+        items.extend(page.objects.into_iter().map(|o| (o.key.clone(), Some(o))));
+        items.extend(page.common_prefixes.into_iter().map(|cp| (cp, None)));
+    }
+    // Stable, so the packed object stays first among equal keys
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    items.dedup_by(|a, b| a.0 == b.0);
+    if let Some(bound) = &bound { items.retain(|(key, _)| key <= bound); }
+    let truncated = bound.is_some() || items.len() > max_keys;
+    items.truncate(max_keys);
+    let mut page = ListPage { objects: Vec::new(), common_prefixes: Vec::new(), next_marker: None };
+    // An empty truncated page resumes where both inputs stopped
+    if truncated { page.next_marker = items.last().map(|(key, _)| key.clone()).or(bound); }
+    for (key, object) in items {
+        match object {
+            Some(object) => page.objects.push(object),
+            None => page.common_prefixes.push(key),
+        }
+    }
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(objects: &[&str], common_prefixes: &[&str], next_marker: Option<&str>) -> ListPage {
+        ListPage {
+            objects: objects.iter().map(|k| ListedObject { key: k.to_string(), size: 0, last_modified: chrono::Utc::now(), etag: format!("\"{k}\"") }).collect(),
+            common_prefixes: common_prefixes.iter().map(|p| p.to_string()).collect(),
+            next_marker: next_marker.map(String::from),
+        }
+    }
+
+    fn keys(page: &ListPage) -> Vec<&str> {
+        page.objects.iter().map(|o| o.key.as_str()).collect()
+    }
+
+    #[test]
+    fn merged_pages_interleave_and_prefer_packed_objects() {
+        let mut packed = page(&["a", "c"], &["dir/"], None);
+        packed.objects[1].etag = "\"packed\"".into();
+        let merged = merge_pages(packed, page(&["b", "c", "d"], &["dir/"], None), 10);
+        assert_eq!(keys(&merged), ["a", "b", "c", "d"]);
+        assert_eq!(merged.objects[2].etag, "\"packed\"");
+        assert_eq!(merged.common_prefixes, ["dir/"]);
+        assert_eq!(merged.next_marker, None);
+    }
+
+    #[test]
+    fn merged_pages_stop_at_the_first_truncation() {
+        // The files past "c" are unknown, so "d" from the packed page cannot be returned yet
+        let merged = merge_pages(page(&["a", "d"], &[], None), page(&["b", "c"], &[], Some("c")), 10);
+        assert_eq!(keys(&merged), ["a", "b", "c"]);
+        assert_eq!(merged.next_marker.as_deref(), Some("c"));
+
+        let merged = merge_pages(page(&["a", "b", "e"], &[], None), page(&["c", "d"], &[], None), 3);
+        assert_eq!(keys(&merged), ["a", "b", "c"]);
+        assert_eq!(merged.next_marker.as_deref(), Some("c"));
+    }
+
+    async fn read(backend: &PosixBackend, key: &str) -> Vec<u8> {
+        use futures::TryStreamExt;
+        let obj = backend.get_object("bkt", key, None).await.unwrap();
+        obj.body.map_ok(|b| b.to_vec()).try_concat().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn escaped_objects_make_room_for_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = GatewayConfig {
+            mountpoint: dir.path().to_string_lossy().into_owned(),
+            data_root: dir.path().join("buckets").to_string_lossy().into_owned(),
+            key_encoding: KeyEncoding::Escaped,
+            ..GatewayConfig::in_memory()
+        };
+        let backend = PosixBackend::new(cfg.clone());
+        backend.create_bucket("bkt").await.unwrap();
+        for (key, body) in [("a", "file"), ("a/b", "nested"), ("c/d", "nested"), ("c", "file")] {
+            backend.put_object("bkt", key, crate::storage::bytes_stream(body.as_bytes().to_vec()), Default::default()).await.unwrap();
+        }
+        for (key, body) in [("a", "file"), ("a/b", "nested"), ("c", "file"), ("c/d", "nested")] {
+            assert_eq!(read(&backend, key).await, body.as_bytes(), "{key}");
+        }
+        // "a" was moved aside when "a/b" needed its name as a directory
+        assert!(bucket_dir(&cfg, "bkt").join("a").is_dir());
+        assert!(bucket_dir(&cfg, "bkt").join(keys::aside("a")).is_file());
+
+        let page = backend.list_objects("bkt", "", None, "", 10).await.unwrap();
+        assert_eq!(keys(&page), ["a", "a/b", "c", "c/d"]);
+        let page = backend.list_objects("bkt", "", Some("/"), "", 10).await.unwrap();
+        assert_eq!(keys(&page), ["a", "c"]);
+        assert_eq!(page.common_prefixes, ["a/", "c/"]);
+
+        backend.delete_object("bkt", "a").await.unwrap();
+        assert!(matches!(backend.get_object("bkt", "a", None).await, Err(StorageError::NoSuchKey)));
+        assert_eq!(read(&backend, "a/b").await, b"nested");
+    }
+
+    fn config(dir: &Path) -> GatewayConfig {
+        GatewayConfig {
+            mountpoint: dir.to_string_lossy().into_owned(),
+            data_root: dir.join("buckets").to_string_lossy().into_owned(),
+            ..GatewayConfig::in_memory()
+        }
+    }
+
+    #[test]
+    fn copies_files_within_and_across_filesystems() {
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        // Another filesystem leaves `copy_file_range` nothing to do but refuse
+        let elsewhere = Path::new("/dev/shm").is_dir().then(|| tempfile::tempdir_in("/dev/shm").unwrap());
+        let sources = std::iter::once(dir.path()).chain(elsewhere.as_ref().map(|d| d.path()));
+        for (i, src_dir) in sources.enumerate() {
+            for (name, body) in [("empty", &content[..0]), ("full", &content[..])] {
+                let src = src_dir.join(format!("{name}.src"));
+                fs::write(&src, body).unwrap();
+                let dst = dir.path().join(format!("{i}/nested/{name}"));
+                copy_file(&std::fs::File::open(&src).unwrap(), &dst).unwrap();
+                assert_eq!(fs::read(&dst).unwrap(), body, "{}", src.display());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn copies_keep_a_current_etag_and_hash_a_stale_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config(dir.path());
+        let backend = PosixBackend::new(cfg.clone());
+        backend.create_bucket("bkt").await.unwrap();
+        // A multipart ETag is no MD5 of the content, so only reuse gives the copy the same one
+        let upload = backend.create_multipart_upload("bkt", "src", Default::default()).await.unwrap();
+        let part = backend.upload_part("bkt", &upload, 1, crate::storage::bytes_stream(b"multipart".to_vec())).await.unwrap();
+        let src = backend.complete_multipart_upload("bkt", "src", &upload, &[(1, part)], None).await.unwrap();
+        assert!(src.etag.ends_with("-1\""));
+        let copy = backend.copy_object("bkt", "src", "bkt", "copy", Default::default()).await.unwrap();
+        assert_eq!(copy.etag, src.etag);
+        assert_eq!(read(&backend, "copy").await, b"multipart");
+
+        // Rewritten behind the gateway's back, the sidecar no longer describes the data
+        let (data, _) = object_paths(&cfg, "bkt", "src").unwrap();
+        fs::write(&data, "rewritten").unwrap();
+        let copy = backend.copy_object("bkt", "src", "bkt", "copy", Default::default()).await.unwrap();
+        assert_eq!(copy.etag, format!("\"{:x}\"", md5::compute("rewritten")));
+        assert_eq!(read(&backend, "copy").await, b"rewritten");
+    }
+
+    #[tokio::test]
+    async fn deduplicating_copies_link_the_source() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = GatewayConfig { dedup: true, ..config(dir.path()) };
+        let backend = PosixBackend::new(cfg.clone());
+        backend.create_bucket("bkt").await.unwrap();
+        for (key, size) in [("big", dedup::MIN_SIZE as usize), ("small", 10)] {
+            let src = backend.put_object("bkt", key, crate::storage::bytes_stream(vec![1u8; size]), Default::default()).await.unwrap();
+            let copy = backend.copy_object("bkt", key, "bkt", &format!("{key}-copy"), Default::default()).await.unwrap();
+            assert_eq!(copy.etag, src.etag);
+            let ino = |k: &str| fs::metadata(object_paths(&cfg, "bkt", k).unwrap().0).unwrap().ino();
+            assert_eq!(ino(key) == ino(&format!("{key}-copy")), key == "big", "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn readahead_yields_the_same_ranges_as_sequential_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+        for (start, len) in [(0, 10_000), (0, 0), (1, 4096), (123, 9_000), (9_999, 1), (4096, 5_904)] {
+            for chunk in [1, 1000, 4096, 20_000] {
+                let mut bodies = Vec::new();
+                for depth in [1, 4] {
+                    let stream = prefetch_stream(std::fs::File::open(&path).unwrap(), start, len, chunk, depth);
+                    let chunks: Vec<Bytes> = stream.map(|c| c.unwrap()).collect().await;
+                    // Every chunk but the last is full, so the client sees a steady stream
+                    let sizes: Vec<usize> = chunks.iter().map(Bytes::len).collect();
+                    assert!(sizes.iter().rev().skip(1).all(|&n| n == chunk), "{start}+{len} by {chunk}: {sizes:?}");
+                    bodies.push(chunks.concat());
+                }
+                let want = &content[start as usize..(start + len) as usize];
+                assert!(bodies.iter().all(|b| b == want), "{start}+{len} by {chunk}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn readahead_past_the_end_of_a_shrunk_file_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        fs::write(&path, vec![7u8; 3000]).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(1500).unwrap();
+        let results: Vec<_> = prefetch_stream(file, 0, 3000, 1000, 4).collect().await;
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn empty_merged_pages_resume_from_the_request_marker() {
+        let merged = merge_pages(page(&[], &[], Some("m")), page(&[], &[], Some("m")), 0);
+        assert!(keys(&merged).is_empty());
+        assert_eq!(merged.next_marker.as_deref(), Some("m"));
+        assert_eq!(merge_pages(page(&[], &[], None), page(&[], &[], None), 0).next_marker, None);
+    }
+}
@@ -0,0 +1,238 @@
+//! Batched object data I/O through fixed, pre-allocated buffers, shared by the USRBIO and
+//! io_uring data paths. A driver provides channels, each a buffer of `depth` blocks plus the ring
+//! that moves data between those blocks and files; requests borrow a channel for one batch of up
+//! to `depth` concurrent block I/Os, so memory per request stays bounded by one channel.
+
+use crate::config::GatewayConfig;
+use crate::storage::ByteStream;
+use bytes::{Bytes, BytesMut};
+use futures::{StreamExt, TryStreamExt};
+use parking_lot::Mutex;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+/// The ring data path selected by configuration, preferring USRBIO over io_uring, or `None`
+/// when neither is enabled or can be set up.
+pub fn open(cfg: &GatewayConfig) -> Option<Arc<RingIo>> {
+    #[cfg(feature = "usrbio")]
+    if cfg.use_usrbio {
+        match super::usrbio::open(cfg) {
+            Ok(ring) => return Some(ring),
+            Err(e) => warn!(error = %e, "USRBIO unavailable, falling back to FUSE"),
+        }
+    }
+    #[cfg(feature = "io-uring")]
+    if cfg.io_uring {
+        match super::uring::open() {
+            Ok(ring) => return Some(ring),
+            Err(e) => warn!(error = %e, "io_uring unavailable, falling back to tokio file I/O"),
+        }
+    }
+    None
+}
+
+/// Creates channels and manages which files they may touch.
+pub(crate) trait Driver: Send + Sync {
+    /// Makes `fd` usable on this driver's rings.
+    fn register_fd(&self, fd: RawFd) -> io::Result<()>;
+    fn deregister_fd(&self, fd: RawFd);
+    fn channel(&self, depth: usize, block_size: usize) -> io::Result<Box<dyn Channel>>;
+}
+
+/// A buffer of `depth` blocks with the ring that moves data between it and files.
+pub(crate) trait Channel: Send {
+    fn block(&mut self, index: usize) -> &mut [u8];
+    /// Runs one I/O per `(block, file offset, len)`, all in flight at once, and returns the
+    /// bytes moved by each; fewer than `len` only at end of file.
+    fn run(&mut self, read: bool, file: &File, ops: &[(usize, u64, usize)]) -> io::Result<Vec<usize>>;
+}
+
+/// A driver plus the pool of channels requests borrow from.
+pub struct RingIo {
+    driver: Box<dyn Driver>,
+    channels: Mutex<Vec<Box<dyn Channel>>>,
+    available: Arc<Semaphore>,
+    depth: usize,
+    block_size: usize,
+}
+
+/// A file registered with a [`RingIo`] for as long as this lives.
+pub struct RegisteredFile {
+    ring: Arc<RingIo>,
+    file: File,
+}
+
+impl RingIo {
+    pub(crate) fn new(driver: Box<dyn Driver>, channels: usize, depth: usize, block_size: usize) -> io::Result<Arc<Self>> {
+        let pool = (0..channels).map(|_| driver.channel(depth, block_size)).collect::<io::Result<Vec<_>>>()?;
+        Ok(Arc::new(Self { driver, channels: Mutex::new(pool), available: Arc::new(Semaphore::new(channels)), depth, block_size }))
+    }
+
+    /// Registers `file` for ring I/O; callers fall back to plain file I/O when this fails.
+    pub fn register(self: &Arc<Self>, file: &File) -> io::Result<Arc<RegisteredFile>> {
+        let file = file.try_clone()?;
+        self.driver.register_fd(file.as_raw_fd())?;
+        Ok(Arc::new(RegisteredFile { ring: self.clone(), file }))
+    }
+
+    /// Borrows a channel for one blocking batch of I/O. The lease goes with the batch, so the
+    /// channel is back in the pool when the batch ends even if the caller stopped waiting.
+    async fn with_channel<T: Send + 'static>(self: &Arc<Self>, f: impl FnOnce(&mut dyn Channel) -> io::Result<T> + Send + 'static) -> io::Result<T> {
+        let permit = self.available.clone().acquire_owned().await.map_err(io::Error::other)?;
+        let channel = self.channels.lock().pop().expect("a permit guarantees a free channel");
+        let mut lease = ChannelLease { ring: self.clone(), channel: Some(channel), permit: Some(permit) };
+        tokio::task::spawn_blocking(move || f(lease.channel()))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+/// A channel taken from a [`RingIo`]'s pool, returned when this drops.
+struct ChannelLease {
+    ring: Arc<RingIo>,
+    channel: Option<Box<dyn Channel>>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ChannelLease {
+    fn channel(&mut self) -> &mut dyn Channel {
+        self.channel.as_deref_mut().expect("a lease holds its channel until dropped")
+    }
+}
+
+impl Drop for ChannelLease {
+    fn drop(&mut self) {
+        // A channel whose batch panicked may still have I/O in flight; retire it and its permit
+        if std::thread::panicking() {
+            if let Some(permit) = self.permit.take() { permit.forget(); }
+            return;
+        }
+        if let Some(channel) = self.channel.take() { self.ring.channels.lock().push(channel); }
+    }
+}
+
+impl RegisteredFile {
+    /// Streams `len` bytes from `start`, reading up to `depth` blocks per batch.
+    pub fn read_stream(self: Arc<Self>, start: u64, len: u64) -> ByteStream {
+        let batches = futures::stream::try_unfold((self, start, start + len), |(reg, pos, end)| async move {
+            if pos >= end { return Ok::<_, io::Error>(None); }
+            let (depth, block_size) = (reg.ring.depth, reg.ring.block_size);
+            let batch_reg = reg.clone();
+            let chunks = reg.ring.with_channel(move |ch| {
+                let mut ops = Vec::with_capacity(depth);
+                let mut off = pos;
+                while ops.len() < depth && off < end {
+                    let n = (end - off).min(block_size as u64) as usize;
+                    ops.push((ops.len(), off, n));
+                    off += n as u64;
+                }
+                let done = ch.run(true, &batch_reg.file, &ops)?;
+                let mut chunks = Vec::with_capacity(ops.len());
+                for (&(block, _, want), got) in ops.iter().zip(done) {
+                    if got < want { return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "object shrank while being read")); }
+                    chunks.push(Ok(Bytes::copy_from_slice(&ch.block(block)[..want])));
+                }
+                Ok(chunks)
+            }).await?;
+            let next = pos + (chunks.len() * block_size) as u64;
+            Ok(Some((futures::stream::iter(chunks), (reg, next.min(end), end))))
+        });
+        Box::pin(batches.try_flatten())
+    }
+
+    /// Writes `body` from the start of the file in batches of up to `depth` blocks, returning
+    /// its size and MD5.
+    pub async fn write_stream(self: &Arc<Self>, mut body: ByteStream) -> io::Result<(u64, md5::Digest)> {
+        let (block_size, batch_size) = (self.ring.block_size, self.ring.depth * self.ring.block_size);
+        let mut hasher = md5::Context::new();
+        let mut size = 0u64;
+        let mut pending = BytesMut::with_capacity(batch_size);
+        loop {
+            let chunk = body.next().await.transpose()?;
+            if let Some(chunk) = &chunk {
+                hasher.consume(chunk);
+                pending.extend_from_slice(chunk);
+            }
+            // Flush whole batches as they fill, and the remainder at the end
+            while pending.len() >= batch_size || (chunk.is_none() && !pending.is_empty()) {
+                let batch = pending.split_to(pending.len().min(batch_size)).freeze();
+                let (reg, offset) = (self.clone(), size);
+                size += batch.len() as u64;
+                self.ring.with_channel(move |ch| {
+                    let ops: Vec<_> = batch.chunks(block_size).enumerate().map(|(i, c)| {
+                        ch.block(i)[..c.len()].copy_from_slice(c);
+                        (i, offset + (i * block_size) as u64, c.len())
+                    }).collect();
+                    let done = ch.run(false, &reg.file, &ops)?;
+                    if ops.iter().zip(done).any(|(&(_, _, want), got)| got < want) { return Err(io::Error::from(io::ErrorKind::WriteZero)); }
+                    Ok(())
+                }).await?;
+            }
+            if chunk.is_none() { break; }
+        }
+        Ok((size, hasher.compute()))
+    }
+}
+
+impl Drop for RegisteredFile {
+    fn drop(&mut self) {
+        self.ring.driver.deregister_fd(self.file.as_raw_fd());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Channels whose batches take a while and touch no file.
+    struct SlowDriver;
+    struct SlowChannel(Vec<u8>);
+
+    impl Driver for SlowDriver {
+        fn register_fd(&self, _fd: RawFd) -> io::Result<()> { Ok(()) }
+        fn deregister_fd(&self, _fd: RawFd) {}
+        fn channel(&self, _depth: usize, block_size: usize) -> io::Result<Box<dyn Channel>> {
+            Ok(Box::new(SlowChannel(vec![0; block_size])))
+        }
+    }
+
+    impl Channel for SlowChannel {
+        fn block(&mut self, _index: usize) -> &mut [u8] { &mut self.0 }
+        fn run(&mut self, _read: bool, _file: &File, ops: &[(usize, u64, usize)]) -> io::Result<Vec<usize>> {
+            std::thread::sleep(Duration::from_millis(50));
+            Ok(ops.iter().map(|op| op.2).collect())
+        }
+    }
+
+    fn slow_batch(ch: &mut dyn Channel) -> io::Result<()> {
+        let file = File::open("/dev/null")?;
+        ch.run(true, &file, &[(0, 0, 1)]).map(drop)
+    }
+
+    #[tokio::test]
+    async fn abandoned_batches_return_their_channel() {
+        let ring = RingIo::new(Box::new(SlowDriver), 2, 1, 16).unwrap();
+        // Callers that give up mid-batch, as a client disconnecting mid-GET does
+        for _ in 0..6 {
+            assert!(tokio::time::timeout(Duration::from_millis(5), ring.with_channel(slow_batch)).await.is_err());
+        }
+        let batches = (0..4).map(|_| ring.with_channel(slow_batch));
+        let done = tokio::time::timeout(Duration::from_secs(5), futures::future::join_all(batches)).await.unwrap();
+        assert!(done.iter().all(Result::is_ok));
+        assert_eq!(ring.channels.lock().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_panicked_batch_retires_its_channel() {
+        let ring = RingIo::new(Box::new(SlowDriver), 2, 1, 16).unwrap();
+        assert!(ring.with_channel(|_| -> io::Result<()> { panic!("batch failed") }).await.is_err());
+        assert_eq!(ring.available.available_permits(), 1);
+        ring.with_channel(slow_batch).await.unwrap();
+        assert_eq!(ring.channels.lock().len(), 1);
+    }
+}
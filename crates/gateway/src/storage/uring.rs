@@ -0,0 +1,112 @@
+//! Object data I/O through io_uring instead of tokio's blocking-pool file operations. Each
+//! channel owns a ring and one page-aligned buffer registered with the kernel, so a batch of
+//! block reads or writes is issued with a single syscall and lands directly in pinned memory.
+
+use crate::storage::ring::{Channel, Driver, RingIo};
+use io_uring::{opcode, types, IoUring};
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+use tracing::info;
+
+/// Size of one read or write; a multiple of the page size so every block stays page-aligned
+const BLOCK_SIZE: usize = 1 << 20;
+/// Reads or writes one request keeps in flight
+const IO_DEPTH: usize = 8;
+/// Rings shared by all requests; each pins `IO_DEPTH * BLOCK_SIZE` of memory
+const CHANNELS: usize = 4;
+const ALIGN: usize = 4096;
+
+/// Sets up the io_uring channels. Fails where io_uring is unavailable, e.g. under a seccomp
+/// profile that blocks it or a too small `RLIMIT_MEMLOCK` for the registered buffers.
+pub fn open() -> io::Result<Arc<RingIo>> {
+    let ring = RingIo::new(Box::new(UringDriver), CHANNELS, IO_DEPTH, BLOCK_SIZE)?;
+    info!(channels = CHANNELS, depth = IO_DEPTH, block_size = BLOCK_SIZE, "io_uring data path enabled");
+    Ok(ring)
+}
+
+struct UringDriver;
+
+struct UringChannel {
+    ring: IoUring,
+    buf: *mut u8,
+    layout: Layout,
+    block_size: usize,
+}
+
+// The ring and buffer are only touched by whichever request has borrowed the channel
+unsafe impl Send for UringChannel {}
+
+impl Driver for UringDriver {
+    // Plain file descriptors work with io_uring as they are
+    fn register_fd(&self, _fd: RawFd) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister_fd(&self, _fd: RawFd) {}
+
+    fn channel(&self, depth: usize, block_size: usize) -> io::Result<Box<dyn Channel>> {
+        let ring = IoUring::new(depth as u32)?;
+        let layout = Layout::from_size_align(depth * block_size, ALIGN).map_err(io::Error::other)?;
+        // SAFETY: the layout is non-zero sized; the buffer is freed in Drop after the ring, which
+        // unregisters it, is gone
+        let buf = unsafe { alloc::alloc_zeroed(layout) };
+        if buf.is_null() { return Err(io::ErrorKind::OutOfMemory.into()); }
+        let channel = UringChannel { ring, buf, layout, block_size };
+        let iov = libc::iovec { iov_base: buf.cast(), iov_len: layout.size() };
+        // SAFETY: the buffer outlives the registration, see above
+        unsafe { channel.ring.submitter().register_buffers(&[iov])? };
+        Ok(Box::new(channel))
+    }
+}
+
+impl Channel for UringChannel {
+    fn block(&mut self, index: usize) -> &mut [u8] {
+        assert!((index + 1) * self.block_size <= self.layout.size());
+        // SAFETY: the block lies within the buffer, and only this channel's holder uses it
+        unsafe { std::slice::from_raw_parts_mut(self.buf.add(index * self.block_size), self.block_size) }
+    }
+
+    fn run(&mut self, read: bool, file: &File, ops: &[(usize, u64, usize)]) -> io::Result<Vec<usize>> {
+        let fd = types::Fd(file.as_raw_fd());
+        let mut done = vec![0usize; ops.len()];
+        let mut queue: Vec<usize> = (0..ops.len()).collect();
+        while !queue.is_empty() {
+            for &i in &queue {
+                let (block, offset, len) = ops[i];
+                assert!((block + 1) * self.block_size <= self.layout.size() && len <= self.block_size);
+                // SAFETY: the target lies within the registered buffer (index 0)
+                let ptr = unsafe { self.buf.add(block * self.block_size + done[i]) };
+                let (remaining, at) = ((len - done[i]) as u32, offset + done[i] as u64);
+                let entry = match read {
+                    true => opcode::ReadFixed::new(fd, ptr, remaining, 0).offset(at).build(),
+                    false => opcode::WriteFixed::new(fd, ptr, remaining, 0).offset(at).build(),
+                };
+                // SAFETY: the buffer and `file` stay valid until the completion is reaped below
+                unsafe { self.ring.submission().push(&entry.user_data(i as u64)) }.map_err(|_| io::Error::other("io_uring submission queue full"))?;
+            }
+            self.ring.submit_and_wait(queue.len())?;
+            // Reap every completion before looking at results so none is left for the next batch
+            let completions: Vec<_> = self.ring.completion().map(|c| (c.user_data() as usize, c.result())).collect();
+            queue.clear();
+            for (i, result) in completions {
+                if result < 0 { return Err(io::Error::from_raw_os_error(-result)); }
+                done[i] += result as usize;
+                // Resubmit short transfers; a zero-length read means end of file
+                if result > 0 && done[i] < ops[i].2 { queue.push(i); }
+                if result == 0 && !read { return Err(io::ErrorKind::WriteZero.into()); }
+            }
+        }
+        Ok(done)
+    }
+}
+
+impl Drop for UringChannel {
+    fn drop(&mut self) {
+        let _ = self.ring.submitter().unregister_buffers();
+        // SAFETY: allocated in `channel` with this layout and no longer registered
+        unsafe { alloc::dealloc(self.buf, self.layout) }
+    }
+}
@@ -0,0 +1,314 @@
+//! Object data I/O through 3FS's USRBIO API instead of FUSE. Data moves through a shared-memory
+//! iov that the 3FS client reads and writes directly; requests are queued on an I/O ring and
+//! completed in batches. The client library is loaded at runtime, so the gateway still starts
+//! (and falls back to FUSE) on hosts without it.
+
+use crate::config::GatewayConfig;
+use crate::storage::ring::{Channel, Driver, RingIo};
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+use tracing::info;
+
+/// Size of one iov block, the unit of every ring I/O
+const BLOCK_SIZE: usize = 1 << 20;
+/// Blocks per channel, i.e. how many I/Os one request keeps in flight
+const IO_DEPTH: usize = 8;
+/// Channels shared by all requests; each holds `IO_DEPTH * BLOCK_SIZE` of shared memory
+const CHANNELS: usize = 4;
+const LIBRARY: &str = "libhf3fs_api_usrbio.so";
+
+/// Sets up USRBIO channels against the 3FS mount, or against the mock ring with `USRBIO_MOCK`.
+pub fn open(cfg: &GatewayConfig) -> anyhow::Result<Arc<RingIo>> {
+    let driver: Box<dyn Driver> = if cfg.usrbio_mock { Box::new(MockDriver) } else { Box::new(NativeDriver::load(&cfg.mountpoint)?) };
+    let ring = RingIo::new(driver, CHANNELS, IO_DEPTH, BLOCK_SIZE)?;
+    info!(mock = cfg.usrbio_mock, channels = CHANNELS, depth = IO_DEPTH, block_size = BLOCK_SIZE, "USRBIO data path enabled");
+    Ok(ring)
+}
+
+/// Mirrors `struct hf3fs_iov` from `hf3fs_usrbio.h`.
+#[repr(C)]
+struct Hf3fsIov {
+    base: *mut u8,
+    iovh: *mut c_void,
+    id: [u8; 16],
+    mount_point: [c_char; 256],
+    size: usize,
+    block_size: usize,
+    numa: c_int,
+}
+
+/// Mirrors `struct hf3fs_ior`.
+#[repr(C)]
+struct Hf3fsIor {
+    iov: Hf3fsIov,
+    iorh: *mut c_void,
+    mount_point: [c_char; 256],
+    for_read: bool,
+    io_depth: c_int,
+    priority: c_int,
+    timeout: c_int,
+    flags: u64,
+}
+
+/// Mirrors `struct hf3fs_cqe`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Hf3fsCqe {
+    index: i32,
+    reserved: i32,
+    result: i64,
+    userdata: *const c_void,
+}
+
+type IovCreate = unsafe extern "C" fn(*mut Hf3fsIov, *const c_char, usize, usize, c_int) -> c_int;
+type IovDestroy = unsafe extern "C" fn(*mut Hf3fsIov);
+type IorCreate4 = unsafe extern "C" fn(*mut Hf3fsIor, *const c_char, c_int, bool, c_int, c_int, c_int, u64) -> c_int;
+type IorDestroy = unsafe extern "C" fn(*mut Hf3fsIor);
+type RegFd = unsafe extern "C" fn(c_int, u64) -> c_int;
+type DeregFd = unsafe extern "C" fn(c_int);
+type PrepIo = unsafe extern "C" fn(*const Hf3fsIor, *const Hf3fsIov, bool, *mut c_void, c_int, usize, u64, *const c_void) -> c_int;
+type SubmitIos = unsafe extern "C" fn(*const Hf3fsIor) -> c_int;
+// The deadline is a `const struct timespec *`; the gateway always passes null to wait indefinitely
+type WaitForIos = unsafe extern "C" fn(*const Hf3fsIor, *mut Hf3fsCqe, c_int, c_int, *const c_void) -> c_int;
+
+/// Entry points resolved from the 3FS client library.
+struct Api {
+    _lib: libloading::Library,
+    iov_create: IovCreate,
+    iov_destroy: IovDestroy,
+    ior_create: IorCreate4,
+    ior_destroy: IorDestroy,
+    reg_fd: RegFd,
+    dereg_fd: DeregFd,
+    prep_io: PrepIo,
+    submit_ios: SubmitIos,
+    wait_for_ios: WaitForIos,
+}
+
+struct NativeDriver {
+    api: Arc<Api>,
+    mountpoint: CString,
+}
+
+struct NativeChannel {
+    api: Arc<Api>,
+    block_size: usize,
+    iov: Box<Hf3fsIov>,
+    read_ring: Box<Hf3fsIor>,
+    write_ring: Box<Hf3fsIor>,
+}
+
+// The iov and rings are only touched by whichever request has borrowed the channel
+unsafe impl Send for NativeChannel {}
+
+/// USRBIO calls return `-errno` on failure.
+fn check(rc: c_int) -> io::Result<c_int> {
+    if rc < 0 { Err(io::Error::from_raw_os_error(-rc)) } else { Ok(rc) }
+}
+
+impl NativeDriver {
+    fn load(mountpoint: &str) -> anyhow::Result<Self> {
+        // SAFETY: the symbols are resolved with the signatures from hf3fs_usrbio.h
+        let api = unsafe {
+            let lib = libloading::Library::new(LIBRARY)?;
+            Api {
+                iov_create: *lib.get(b"hf3fs_iovcreate\0")?,
+                iov_destroy: *lib.get(b"hf3fs_iovdestroy\0")?,
+                ior_create: *lib.get(b"hf3fs_iorcreate4\0")?,
+                ior_destroy: *lib.get(b"hf3fs_iordestroy\0")?,
+                reg_fd: *lib.get(b"hf3fs_reg_fd\0")?,
+                dereg_fd: *lib.get(b"hf3fs_dereg_fd\0")?,
+                prep_io: *lib.get(b"hf3fs_prep_io\0")?,
+                submit_ios: *lib.get(b"hf3fs_submit_ios\0")?,
+                wait_for_ios: *lib.get(b"hf3fs_wait_for_ios\0")?,
+                _lib: lib,
+            }
+        };
+        Ok(Self { api: Arc::new(api), mountpoint: CString::new(mountpoint)? })
+    }
+}
+
+impl Driver for NativeDriver {
+    fn register_fd(&self, fd: RawFd) -> io::Result<()> {
+        // hf3fs_reg_fd returns a positive errno on failure
+        match unsafe { (self.api.reg_fd)(fd, 0) } {
+            0 => Ok(()),
+            rc => Err(io::Error::from_raw_os_error(rc.abs())),
+        }
+    }
+
+    fn deregister_fd(&self, fd: RawFd) {
+        unsafe { (self.api.dereg_fd)(fd) }
+    }
+
+    fn channel(&self, depth: usize, block_size: usize) -> io::Result<Box<dyn Channel>> {
+        // SAFETY: zeroed structs are what the library expects to fill in; they are boxed so the
+        // addresses it records stay valid, and destroyed in reverse order in Drop
+        unsafe {
+            let mut iov: Box<Hf3fsIov> = Box::new(std::mem::zeroed());
+            check((self.api.iov_create)(iov.as_mut(), self.mountpoint.as_ptr(), depth * block_size, 0, -1))?;
+            let mut read_ring: Box<Hf3fsIor> = Box::new(std::mem::zeroed());
+            if let Err(e) = check((self.api.ior_create)(read_ring.as_mut(), self.mountpoint.as_ptr(), depth as c_int, true, 0, 0, -1, 0)) {
+                (self.api.iov_destroy)(iov.as_mut());
+                return Err(e);
+            }
+            let mut write_ring: Box<Hf3fsIor> = Box::new(std::mem::zeroed());
+            if let Err(e) = check((self.api.ior_create)(write_ring.as_mut(), self.mountpoint.as_ptr(), depth as c_int, false, 0, 0, -1, 0)) {
+                (self.api.ior_destroy)(read_ring.as_mut());
+                (self.api.iov_destroy)(iov.as_mut());
+                return Err(e);
+            }
+            Ok(Box::new(NativeChannel { api: self.api.clone(), block_size, iov, read_ring, write_ring }))
+        }
+    }
+}
+
+impl Channel for NativeChannel {
+    fn block(&mut self, index: usize) -> &mut [u8] {
+        assert!((index + 1) * self.block_size <= self.iov.size);
+        // SAFETY: the block lies within the iov, and only this channel's holder uses it
+        unsafe { std::slice::from_raw_parts_mut(self.iov.base.add(index * self.block_size), self.block_size) }
+    }
+
+    fn run(&mut self, read: bool, file: &File, ops: &[(usize, u64, usize)]) -> io::Result<Vec<usize>> {
+        let ring: *const Hf3fsIor = if read { self.read_ring.as_ref() } else { self.write_ring.as_ref() };
+        let mut done = vec![0usize; ops.len()];
+        // SAFETY: every I/O targets a block of this channel's iov and completes before returning
+        unsafe {
+            for (i, &(block, offset, len)) in ops.iter().enumerate() {
+                let ptr = self.iov.base.add(block * self.block_size) as *mut c_void;
+                check((self.api.prep_io)(ring, self.iov.as_ref(), read, ptr, file.as_raw_fd(), offset as usize, len as u64, i as *const c_void))?;
+            }
+            check((self.api.submit_ios)(ring))?;
+            let mut cqes = vec![Hf3fsCqe { index: 0, reserved: 0, result: 0, userdata: std::ptr::null() }; ops.len()];
+            let mut completed = 0;
+            while completed < ops.len() {
+                let remaining = (ops.len() - completed) as c_int;
+                let n = check((self.api.wait_for_ios)(ring, cqes.as_mut_ptr(), remaining, remaining, std::ptr::null()))? as usize;
+                for cqe in &cqes[..n] {
+                    if cqe.result < 0 { return Err(io::Error::from_raw_os_error((-cqe.result) as i32)); }
+                    done[cqe.userdata as usize] = cqe.result as usize;
+                }
+                completed += n;
+            }
+        }
+        Ok(done)
+    }
+}
+
+impl Drop for NativeChannel {
+    fn drop(&mut self) {
+        unsafe {
+            (self.api.ior_destroy)(self.write_ring.as_mut());
+            (self.api.ior_destroy)(self.read_ring.as_mut());
+            (self.api.iov_destroy)(self.iov.as_mut());
+        }
+    }
+}
+
+/// Stands in for the 3FS client with ordinary memory and positioned reads/writes, so the USRBIO
+/// path can be exercised on any filesystem.
+struct MockDriver;
+
+struct MockChannel {
+    iov: Vec<u8>,
+    block_size: usize,
+}
+
+impl Driver for MockDriver {
+    fn register_fd(&self, _fd: RawFd) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister_fd(&self, _fd: RawFd) {}
+
+    fn channel(&self, depth: usize, block_size: usize) -> io::Result<Box<dyn Channel>> {
+        Ok(Box::new(MockChannel { iov: vec![0; depth * block_size], block_size }))
+    }
+}
+
+impl Channel for MockChannel {
+    fn block(&mut self, index: usize) -> &mut [u8] {
+        &mut self.iov[index * self.block_size..(index + 1) * self.block_size]
+    }
+
+    fn run(&mut self, read: bool, file: &File, ops: &[(usize, u64, usize)]) -> io::Result<Vec<usize>> {
+        use std::os::unix::fs::FileExt;
+        ops.iter().map(|&(block, offset, len)| {
+            let buf = &mut self.block(block)[..len];
+            if read {
+                let mut n = 0;
+                while n < len {
+                    match file.read_at(&mut buf[n..], offset + n as u64)? {
+                        0 => break,
+                        m => n += m,
+                    }
+                }
+                Ok(n)
+            } else {
+                file.write_all_at(buf, offset).map(|_| len)
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::posix::PosixBackend;
+    use crate::storage::{bytes_stream, ByteRange, StorageBackend};
+    use futures::TryStreamExt;
+
+    fn mock_cfg(dir: &std::path::Path) -> GatewayConfig {
+        GatewayConfig {
+            mountpoint: dir.to_string_lossy().into_owned(),
+            data_root: dir.join("buckets").to_string_lossy().into_owned(),
+            use_usrbio: true,
+            usrbio_mock: true,
+            ..GatewayConfig::in_memory()
+        }
+    }
+
+    /// More than one batch, ending mid-block.
+    fn content() -> Vec<u8> {
+        (0..IO_DEPTH * BLOCK_SIZE + BLOCK_SIZE + 12345).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn ring_writes_and_reads_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let ring = open(&mock_cfg(dir.path())).unwrap();
+        let data = content();
+        let path = dir.path().join("object");
+
+        let reg = ring.register(&File::create(&path).unwrap()).unwrap();
+        let (size, md5) = reg.write_stream(bytes_stream(data.clone())).await.unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(md5, md5::compute(&data));
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        let reg = ring.register(&File::open(&path).unwrap()).unwrap();
+        for (start, len) in [(0, data.len()), (BLOCK_SIZE - 1, 2), (3, IO_DEPTH * BLOCK_SIZE + 7), (data.len() - 5, 5)] {
+            let got: Vec<u8> = reg.clone().read_stream(start as u64, len as u64).map_ok(|b| b.to_vec()).try_concat().await.unwrap();
+            assert_eq!(got, data[start..start + len], "range {start}+{len}");
+        }
+    }
+
+    #[tokio::test]
+    async fn put_and_ranged_get_over_the_mock_ring() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(mock_cfg(dir.path()));
+        backend.create_bucket("bkt").await.unwrap();
+        let data = content();
+        backend.put_object("bkt", "big", bytes_stream(data.clone()), Default::default()).await.unwrap();
+
+        let start = BLOCK_SIZE as u64 - 10;
+        let obj = backend.get_object("bkt", "big", ByteRange::parse(&format!("bytes={start}-{}", start + 2 * BLOCK_SIZE as u64))).await.unwrap();
+        assert_eq!(obj.range, Some((start, start + 2 * BLOCK_SIZE as u64)));
+        let got: Vec<u8> = obj.body.map_ok(|b| b.to_vec()).try_concat().await.unwrap();
+        assert_eq!(got, data[start as usize..=start as usize + 2 * BLOCK_SIZE]);
+    }
+}
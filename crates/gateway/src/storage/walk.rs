@@ -0,0 +1,194 @@
+use crate::config::KeyEncoding;
+use crate::storage::keys;
+use fs_err as fs;
+use std::path::{Path, PathBuf};
+
+/// One listing result, in S3 key order.
+#[derive(Debug)]
+pub enum ListEntry {
+    Object { key: String, size: u64, mtime: chrono::DateTime<chrono::Utc> },
+    CommonPrefix(String),
+}
+
+struct DirEntry {
+    /// File name, with a trailing `/` for directories so siblings sort in key order
+    /// (`a.txt` < `a/b` < `a0`)
+    name: String,
+    path: PathBuf,
+    is_dir: bool,
+}
+
+struct Level {
+    key: String,
+    entries: Vec<DirEntry>,
+    pos: usize,
+}
+
+/// Streams the objects of a bucket directory in lexicographic key order without reading
+/// the whole tree: only directories that can contain keys under `prefix` and after
+/// `marker` are opened, and a directory that rolls up into a single common prefix is
+/// reported without being descended into.
+pub struct KeyWalker {
+    encoding: KeyEncoding,
+    prefix: String,
+    delimiter: Option<String>,
+    marker: String,
+    stack: Vec<Level>,
+    last_prefix: Option<String>,
+}
+
+impl KeyWalker {
+    pub fn new(base: &Path, encoding: KeyEncoding, prefix: &str, delimiter: Option<&str>, marker: &str) -> Self {
+        let mut walker = Self { encoding, prefix: prefix.to_string(), delimiter: delimiter.map(str::to_string), marker: marker.to_string(), stack: Vec::new(), last_prefix: None };
+        // Start in the deepest directory the prefix fully names
+        let start = prefix.rfind('/').map(|i| &prefix[..=i]).unwrap_or("");
+        let dir = match start.strip_suffix('/') {
+            None => Some(base.to_path_buf()),
+            Some(dirs) => keys::encode(encoding, dirs).ok().map(|segments| segments.iter().fold(base.to_path_buf(), |p, s| p.join(s))),
+        };
+        if let Some(dir) = dir.filter(|d| d.is_dir()) {
+            walker.stack.push(Level { key: start.to_string(), entries: read_sorted(&dir, encoding), pos: 0 });
+        }
+        walker
+    }
+
+    fn common_prefix(&self, key: &str) -> Option<String> {
+        let d = self.delimiter.as_deref()?;
+        let idx = key.get(self.prefix.len()..)?.find(d)?;
+        Some(key[..self.prefix.len() + idx + d.len()].to_string())
+    }
+
+    /// Reports `cp` unless it was already returned on this page or the previous one.
+    fn roll_up(&mut self, cp: String) -> Option<ListEntry> {
+        if cp == self.marker || self.last_prefix.as_deref() == Some(cp.as_str()) { return None; }
+        self.last_prefix = Some(cp.clone());
+        Some(ListEntry::CommonPrefix(cp))
+    }
+}
+
+impl Iterator for KeyWalker {
+    type Item = ListEntry;
+
+    fn next(&mut self) -> Option<ListEntry> {
+        loop {
+            let level = self.stack.last_mut()?;
+            let Some(entry) = level.entries.get(level.pos) else { self.stack.pop(); continue };
+            level.pos += 1;
+            let key = format!("{}{}", level.key, entry.name);
+            let (path, is_dir) = (entry.path.clone(), entry.is_dir);
+
+            if is_dir {
+                // Not under the prefix, or entirely before the marker
+                if !(key.starts_with(&self.prefix) || self.prefix.starts_with(&key)) { continue; }
+                if key < self.marker && !self.marker.starts_with(&key) { continue; }
+                if !self.marker.starts_with(&key) {
+                    if let Some(cp) = self.common_prefix(&key) {
+                        if has_object(&path) {
+                            if let Some(e) = self.roll_up(cp) { return Some(e); }
+                        }
+                        continue;
+                    }
+                }
+                self.stack.push(Level { key, entries: read_sorted(&path, self.encoding), pos: 0 });
+                continue;
+            }
+
+            if !key.starts_with(&self.prefix) || key <= self.marker { continue; }
+            if let Some(cp) = self.common_prefix(&key) {
+                if let Some(e) = self.roll_up(cp) { return Some(e); }
+                continue;
+            }
+            let Ok(md) = fs::metadata(&path) else { continue };
+            let mtime = md.modified().map(chrono::DateTime::<chrono::Utc>::from).unwrap_or_else(|_| chrono::Utc::now());
+            return Some(ListEntry::Object { key, size: md.len(), mtime });
+        }
+    }
+}
+
+/// The entries of `dir` by the key segments their names decode to.
+fn read_sorted(dir: &Path, encoding: KeyEncoding) -> Vec<DirEntry> {
+    let Ok(rd) = fs::read_dir(dir) else { return Vec::new() };
+    let mut entries: Vec<DirEntry> = rd.flatten().filter_map(|e| {
+        let ft = e.file_type().ok()?;
+        let file_name = e.file_name().to_string_lossy().into_owned();
+        if ft.is_file() && keys::is_reserved(&file_name) { return None; }
+        let mut name = keys::decode(encoding, &file_name)?;
+        if ft.is_dir() {
+            name.push('/');
+        } else if !ft.is_file() {
+            return None;
+        }
+        Some(DirEntry { name, path: e.path(), is_dir: ft.is_dir() })
+    }).collect();
+    entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    entries
+}
+
+/// Whether any object exists below `dir`, stopping at the first one found.
+fn has_object(dir: &Path) -> bool {
+    let Ok(rd) = fs::read_dir(dir) else { return false };
+    let mut subdirs = Vec::new();
+    for e in rd.flatten() {
+        let Ok(ft) = e.file_type() else { continue };
+        if ft.is_file() && !keys::is_reserved(&e.file_name().to_string_lossy()) { return true; }
+        if ft.is_dir() { subdirs.push(e.path()); }
+    }
+    subdirs.iter().any(|d| has_object(d))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bucket directory holding a file for every key in `keys`, and the directories `dirs`.
+    fn bucket(keys: &[&str], dirs: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for key in keys {
+            let path = dir.path().join(key);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, key).unwrap();
+        }
+        for d in dirs { fs::create_dir_all(dir.path().join(d)).unwrap(); }
+        dir
+    }
+
+    /// What a walk yields, with common prefixes told apart by their trailing delimiter.
+    fn walk(base: &Path, prefix: &str, delimiter: Option<&str>, marker: &str) -> Vec<String> {
+        KeyWalker::new(base, KeyEncoding::Compat, prefix, delimiter, marker).map(|e| match e {
+            ListEntry::Object { key, .. } => key,
+            ListEntry::CommonPrefix(cp) => format!("{cp} (prefix)"),
+        }).collect()
+    }
+
+    #[test]
+    fn keys_come_in_byte_order_across_directories() {
+        let b = bucket(&["a0", "a/c", "a.b", "a-b", "a/b/c", "a/b.c", "z"], &[]);
+        assert_eq!(walk(b.path(), "", None, ""), ["a-b", "a.b", "a/b.c", "a/b/c", "a/c", "a0", "z"]);
+        assert_eq!(walk(b.path(), "a/b", None, ""), ["a/b.c", "a/b/c"]);
+        assert_eq!(walk(b.path(), "", None, "a/b.c"), ["a/b/c", "a/c", "a0", "z"]);
+        // Sidecars are never keys
+        fs::write(b.path().join("a/c.meta.json"), "{}").unwrap();
+        assert_eq!(walk(b.path(), "a/", None, ""), ["a/b.c", "a/b/c", "a/c"]);
+    }
+
+    #[test]
+    fn delimiters_roll_keys_up_into_common_prefixes() {
+        let b = bucket(&["a0", "a/c", "a.b", "a-b", "a/b/c", "a/b/d", "a/b.c", "logs/2024-01-01.gz"], &[]);
+        assert_eq!(walk(b.path(), "", Some("/"), ""), ["a-b", "a.b", "a/ (prefix)", "a0", "logs/ (prefix)"]);
+        assert_eq!(walk(b.path(), "a/", Some("/"), ""), ["a/b.c", "a/b/ (prefix)", "a/c"]);
+        // A prefix already returned is not returned again on the next page
+        assert_eq!(walk(b.path(), "", Some("/"), "a/"), ["a0", "logs/ (prefix)"]);
+        // Delimiters other than `/` roll up within file names too
+        assert_eq!(walk(b.path(), "", Some("."), ""), ["a-b", "a. (prefix)", "a/b. (prefix)", "a/b/c", "a/b/d", "a/c", "a0", "logs/2024-01-01. (prefix)"]);
+    }
+
+    #[test]
+    fn directories_without_objects_are_left_out() {
+        let b = bucket(&["kept/a"], &["empty", "nested/deeper/still"]);
+        fs::write(b.path().join("nested/deeper/only.meta.json"), "{}").unwrap();
+        assert_eq!(walk(b.path(), "", Some("/"), ""), ["kept/ (prefix)"]);
+        assert_eq!(walk(b.path(), "", None, ""), ["kept/a"]);
+        assert_eq!(walk(b.path(), "nested/", Some("/"), ""), Vec::<String>::new());
+        assert!(walk(b.path(), "missing/", None, "").is_empty());
+    }
+}
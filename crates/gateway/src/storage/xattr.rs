@@ -0,0 +1,160 @@
+//! Object metadata in an extended attribute of the data file (`METADATA_STORE=xattr`).
+//!
+//! Sidecar files double the inode count of a bucket and show up next to the data in POSIX
+//! views. With xattrs the whole [`super::posix::Sidecar`] is kept, in the same JSON form, in
+//! one `user.s3.meta` attribute, so it is replaced atomically and follows the file through
+//! renames. Filesystems that refuse user xattrs, or the size of a particular object's
+//! metadata, fall back to a sidecar file.
+
+use rustix::io::Errno;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::warn;
+
+pub const NAME: &str = "user.s3.meta";
+
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// The attribute's value, or `None` when `data` has none or it cannot be read.
+pub fn get(data: &Path) -> Option<Vec<u8>> {
+    loop {
+        let len = rustix::fs::getxattr(data, NAME, &mut []).ok()?;
+        let mut buf = vec![0u8; len];
+        match rustix::fs::getxattr(data, NAME, &mut buf) {
+            Ok(n) => {
+                buf.truncate(n);
+                return Some(buf);
+            }
+            // Grew between the two calls
+            Err(Errno::RANGE) => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Stores `value` on `data`, returning `false` if the filesystem cannot hold it there.
+pub fn set(data: &Path, value: &[u8]) -> std::io::Result<bool> {
+    match rustix::fs::setxattr(data, NAME, value, rustix::fs::XattrFlags::empty()) {
+        Ok(()) => Ok(true),
+        Err(e @ (Errno::NOTSUP | Errno::PERM | Errno::TOOBIG | Errno::NOSPC | Errno::RANGE)) => {
+            if !WARNED.swap(true, Ordering::Relaxed) {
+                warn!(path = %data.display(), error = %e, "cannot keep object metadata in xattrs, using sidecar files");
+            }
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Drops the attribute, if any, once the metadata lives in a sidecar file instead.
+pub fn remove(data: &Path) {
+    let _ = rustix::fs::removexattr(data, NAME);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{GatewayConfig, MetadataStore};
+    use crate::storage::posix::{self, PosixBackend};
+    use crate::storage::{bytes_stream, ObjectAttrs, StorageBackend};
+
+    fn config(dir: &Path, metadata_store: MetadataStore) -> GatewayConfig {
+        GatewayConfig {
+            mountpoint: dir.to_string_lossy().into_owned(),
+            data_root: dir.join("buckets").to_string_lossy().into_owned(),
+            metadata_store,
+            ..GatewayConfig::in_memory()
+        }
+    }
+
+    /// Whether the filesystem of `dir` takes user xattrs; the tests have nothing to check otherwise.
+    fn supported(dir: &Path) -> bool {
+        let probe = dir.join("probe");
+        std::fs::write(&probe, "").unwrap();
+        set(&probe, b"{}").unwrap()
+    }
+
+    fn attrs() -> ObjectAttrs {
+        ObjectAttrs {
+            content_type: "text/csv".into(),
+            user_meta: [("owner".to_string(), "ops".to_string())].into(),
+            tags: [("tier".to_string(), "hot".to_string())].into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn values_are_read_back_replaced_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        if !supported(dir.path()) { return; }
+        let data = dir.path().join("data");
+        std::fs::write(&data, "content").unwrap();
+        assert_eq!(get(&data), None);
+        assert!(set(&data, b"short").unwrap());
+        assert_eq!(get(&data).as_deref(), Some(&b"short"[..]));
+        let long = vec![b'x'; 3000];
+        assert!(set(&data, &long).unwrap());
+        assert_eq!(get(&data), Some(long));
+        remove(&data);
+        assert_eq!(get(&data), None);
+        // Neither a missing file nor a missing attribute is an error to remove
+        remove(&data);
+        assert!(set(&dir.path().join("missing"), b"x").is_err());
+    }
+
+    #[tokio::test]
+    async fn objects_keep_their_metadata_in_the_data_file() {
+        let dir = tempfile::tempdir().unwrap();
+        if !supported(dir.path()) { return; }
+        let cfg = config(dir.path(), MetadataStore::Xattr);
+        let backend = PosixBackend::new(cfg.clone());
+        backend.create_bucket("bkt").await.unwrap();
+        let put = backend.put_object("bkt", "a/b.csv", bytes_stream(b"1,2".to_vec()), attrs()).await.unwrap();
+
+        let (data, meta) = posix::object_paths(&cfg, "bkt", "a/b.csv").unwrap();
+        assert!(!meta.exists());
+        let sidecar: posix::Sidecar = serde_json::from_slice(&get(&data).unwrap()).unwrap();
+        assert_eq!((sidecar.etag.as_str(), &sidecar.attrs), (put.etag.as_str(), &attrs()));
+        // Renames carry the attribute along
+        let moved = dir.path().join("moved");
+        std::fs::rename(&data, &moved).unwrap();
+        assert!(get(&moved).is_some());
+        std::fs::rename(&moved, &data).unwrap();
+        let head = backend.head_object("bkt", "a/b.csv").await.unwrap();
+        assert_eq!((head.etag, head.attrs), (put.etag, attrs()));
+        let listed = backend.list_objects("bkt", "", None, "", 1000).await.unwrap();
+        assert_eq!(listed.objects.iter().map(|o| o.key.as_str()).collect::<Vec<_>>(), ["a/b.csv"]);
+    }
+
+    #[tokio::test]
+    async fn migration_moves_metadata_between_stores_and_back() {
+        let dir = tempfile::tempdir().unwrap();
+        if !supported(dir.path()) { return; }
+        let sidecars = config(dir.path(), MetadataStore::Sidecar);
+        let xattrs = config(dir.path(), MetadataStore::Xattr);
+        let backend = PosixBackend::new(sidecars.clone());
+        backend.create_bucket("bkt").await.unwrap();
+        let mut etags = Vec::new();
+        for key in ["one", "dir/two"] {
+            etags.push(backend.put_object("bkt", key, bytes_stream(key.as_bytes().to_vec()), attrs()).await.unwrap().etag);
+        }
+        let paths = || ["one", "dir/two"].map(|key| posix::object_paths(&sidecars, "bkt", key).unwrap());
+        assert!(paths().iter().all(|(data, meta)| meta.exists() && get(data).is_none()));
+        // Either store is read regardless of the configured one, so nothing is lost before migrating
+        let before_xattrs = PosixBackend::new(xattrs.clone());
+        assert_eq!(before_xattrs.head_object("bkt", "one").await.unwrap().attrs, attrs());
+
+        assert_eq!(posix::migrate_metadata(&xattrs, &before_xattrs, "bkt").await.unwrap(), 2);
+        assert!(paths().iter().all(|(data, meta)| !meta.exists() && get(data).is_some()));
+        assert_eq!(posix::migrate_metadata(&xattrs, &before_xattrs, "bkt").await.unwrap(), 0);
+        for (key, etag) in ["one", "dir/two"].into_iter().zip(&etags) {
+            let head = before_xattrs.head_object("bkt", key).await.unwrap();
+            assert_eq!((&head.etag, head.attrs), (etag, attrs()), "{key}");
+        }
+
+        assert_eq!(posix::migrate_metadata(&sidecars, &backend, "bkt").await.unwrap(), 2);
+        assert!(paths().iter().all(|(data, meta)| meta.exists() && get(data).is_none()));
+        assert_eq!(backend.head_object("bkt", "dir/two").await.unwrap().etag, etags[1]);
+        assert!(posix::migrate_metadata(&sidecars, &backend, "nowhere").await.is_err());
+    }
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use threefs_gateway::changes::{self, ChangeFeed};
+use threefs_gateway::compression::{self, BucketCompression};
+use threefs_gateway::config::{Durability, GatewayConfig, StorageKind};
+use threefs_gateway::storage::posix::PosixBackend;
+use threefs_gateway::storage::{bytes_stream, cache, durability, pack, StorageBackend};
+
+const POLL_MS: u64 = 100;
+
+fn cfg(mount: &Path) -> GatewayConfig {
+    GatewayConfig {
+        mountpoint: mount.to_string_lossy().into_owned(),
+        data_root: mount.join("buckets").to_string_lossy().into_owned(),
+        storage_backend: StorageKind::Posix,
+        change_feed: true,
+        change_feed_poll_ms: POLL_MS,
+        object_cache_buckets: Some("*".into()),
+        // Only the change feed can tell the cache an object changed
+        object_cache_revalidate_secs: 3600,
+        ..GatewayConfig::in_memory()
+    }
+}
+
+/// One gateway pod's storage stack, as `run_server` assembles it, and its bare backend.
+fn pod(cfg: &GatewayConfig) -> (Arc<ChangeFeed>, Arc<dyn StorageBackend>, Arc<dyn StorageBackend>) {
+    let feed = changes::open(cfg).unwrap();
+    let posix: Arc<dyn StorageBackend> = Arc::new(PosixBackend::new(cfg.clone()));
+    let cached = cache::wrap(cfg, posix.clone(), &prometheus::Registry::new(), Some(&feed));
+    let storage = changes::wrap(feed.clone(), cached);
+    feed.spawn_tailer();
+    (feed, storage, posix)
+}
+
+async fn read(storage: &dyn StorageBackend, key: &str) -> Vec<u8> {
+    common::collect(storage.get_object("bkt", key, None).await.unwrap().body).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_write_through_one_pod_invalidates_the_other_pods_cache() {
+    let mount = tempfile::tempdir().unwrap();
+    let cfg = cfg(mount.path());
+    let (_feed_a, a, a_posix) = pod(&cfg);
+    let (_feed_b, b, _) = pod(&cfg);
+    a.create_bucket("bkt").await.unwrap();
+    a.put_object("bkt", "k", bytes_stream("v1"), Default::default()).await.unwrap();
+    // Let B take in those changes before it caches anything
+    tokio::time::sleep(Duration::from_millis(3 * POLL_MS)).await;
+    assert_eq!(read(b.as_ref(), "k").await, b"v1");
+
+    // A write the feed never hears of leaves B serving its cached copy
+    a_posix.put_object("bkt", "k", bytes_stream("v2"), Default::default()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(3 * POLL_MS)).await;
+    assert_eq!(read(b.as_ref(), "k").await, b"v1");
+
+    let written = Instant::now();
+    a.put_object("bkt", "k", bytes_stream("v3"), Default::default()).await.unwrap();
+    assert!(common::eventually(2, || async { read(b.as_ref(), "k").await == b"v3" }).await);
+    // One poll interval, plus scheduling slack
+    assert!(written.elapsed() < Duration::from_millis(2 * POLL_MS + 500), "took {:?}", written.elapsed());
+
+    // Deletes reach the other pod too
+    a.delete_object("bkt", "k").await.unwrap();
+    assert!(common::eventually(2, || async { b.get_object("bkt", "k", None).await.is_err() }).await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bucket_settings_are_published_to_other_pods() {
+    let mount = tempfile::tempdir().unwrap();
+    let cfg = cfg(mount.path());
+    let (_feed_a, a, _) = pod(&cfg);
+    let (feed_b, _b, _) = pod(&cfg);
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let s = seen.clone();
+    feed_b.subscribe(move |change| if let changes::Change::BucketConfig { bucket, name } = change { s.lock().unwrap().push(format!("{bucket}/{name}")) });
+    a.create_bucket("bkt").await.unwrap();
+
+    durability::set(a.as_ref(), "bkt", Some(Durability::Data)).await.unwrap();
+    compression::set(a.as_ref(), "bkt", Some(&BucketCompression { content_types: vec!["text/plain".into()], prefixes: Vec::new(), level: 3 })).await.unwrap();
+    pack::set(&cfg, a.as_ref(), "bkt", Some(4096)).await.unwrap();
+    assert!(common::eventually(2, || async { seen.lock().unwrap().len() == 3 }).await);
+    assert_eq!(*seen.lock().unwrap(), ["bkt/durability", "bkt/compression", "bkt/pack"]);
+    assert_eq!(pack::for_bucket(a.as_ref(), "bkt").await, Some(4096));
+}
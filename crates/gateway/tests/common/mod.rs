@@ -0,0 +1,34 @@
+//! Gateways served on ephemeral ports for the integration tests.
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use threefs_gateway::config::GatewayConfig;
+use threefs_gateway::storage::StorageBackend;
+use threefs_gateway::{build_router, AppState};
+
+/// Serves a router over `storage` in the background and returns its base URL.
+pub async fn serve(cfg: GatewayConfig, storage: Arc<dyn StorageBackend>) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = build_router(AppState::new(cfg, prometheus::Registry::new(), storage));
+    tokio::spawn(async move { axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap() });
+    format!("http://{addr}")
+}
+
+/// Polls `check` every 50 ms until it holds, for at most `secs` seconds.
+pub async fn eventually<F, Fut>(secs: u64, mut check: F) -> bool
+where F: FnMut() -> Fut, Fut: std::future::Future<Output = bool> {
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(secs);
+    while tokio::time::Instant::now() < deadline {
+        if check().await { return true; }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    false
+}
+
+/// Everything a storage body yields.
+pub async fn collect(body: threefs_gateway::storage::ByteStream) -> Vec<u8> {
+    use futures::TryStreamExt;
+    body.map_ok(|b| b.to_vec()).try_concat().await.unwrap()
+}
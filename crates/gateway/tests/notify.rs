@@ -0,0 +1,59 @@
+mod common;
+
+use axum::{routing::post, Json, Router};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use threefs_gateway::config::GatewayConfig;
+use threefs_gateway::notify;
+use threefs_gateway::storage::memory::MemoryBackend;
+
+const RULES: &str = "<NotificationConfiguration><QueueConfiguration><Id>gz</Id><Queue>arn:3fs3:sqs:us-east-1:hook:webhook</Queue><Event>s3:ObjectCreated:*</Event>\
+<Filter><S3Key><FilterRule><Name>suffix</Name><Value>.gz</Value></FilterRule></S3Key></Filter></QueueConfiguration>\
+<QueueConfiguration><Id>removed</Id><Queue>arn:3fs3:sqs:us-east-1:hook:webhook</Queue><Event>s3:ObjectRemoved:Delete</Event></QueueConfiguration></NotificationConfiguration>";
+
+/// Serves a webhook that keeps every event posted to it.
+async fn webhook() -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let sink = received.clone();
+    let app = Router::new().route("/hook", post(move |Json(ev): Json<serde_json::Value>| async move { sink.lock().push(ev) }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (format!("http://{addr}/hook"), received)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matching_events_are_delivered_from_the_spool() {
+    let (hook, received) = webhook().await;
+    let spool = tempfile::tempdir().unwrap();
+    let cfg = GatewayConfig {
+        mountpoint: spool.path().to_string_lossy().into_owned(),
+        notify_webhooks: Some(format!("hook={hook}")),
+        ..GatewayConfig::in_memory()
+    };
+    notify::spawn_worker(cfg.clone());
+    let base = common::serve(cfg, Arc::new(MemoryBackend::new())).await;
+    let client = reqwest::Client::new();
+    assert!(client.put(format!("{base}/bkt")).send().await.unwrap().status().is_success());
+    assert!(client.put(format!("{base}/bkt?notification")).body(RULES).send().await.unwrap().status().is_success());
+
+    // Only the first write passes the filters
+    let put = client.put(format!("{base}/bkt/logs/app.gz")).body("zipped").send().await.unwrap();
+    let request_id = put.headers()["x-amz-request-id"].to_str().unwrap().to_string();
+    assert!(client.put(format!("{base}/bkt/logs/app.txt")).body("plain").send().await.unwrap().status().is_success());
+    let delete = client.delete(format!("{base}/bkt/logs/app.txt")).send().await.unwrap();
+    let delete_id = delete.headers()["x-amz-request-id"].to_str().unwrap().to_string();
+
+    assert!(common::eventually(10, || async { received.lock().len() >= 2 }).await);
+    let events = received.lock().clone();
+    assert_eq!(events.len(), 2);
+    let created = events.iter().map(|e| &e["Records"][0]).find(|r| r["eventName"] == "s3:ObjectCreated:Put").unwrap();
+    assert_eq!(created["s3"]["configurationId"], "gz");
+    assert_eq!(created["s3"]["object"]["key"], "logs%2Fapp.gz");
+    assert_eq!(created["s3"]["object"]["size"], 6);
+    assert_eq!(created["responseElements"]["x-amz-request-id"], request_id.as_str());
+    assert_eq!(created["requestParameters"]["sourceIPAddress"], "127.0.0.1");
+    let removed = events.iter().map(|e| &e["Records"][0]).find(|r| r["eventName"] == "s3:ObjectRemoved:Delete").unwrap();
+    assert_eq!(removed["s3"]["object"]["key"], "logs%2Fapp.txt");
+    assert_eq!(removed["responseElements"]["x-amz-request-id"], delete_id.as_str());
+}
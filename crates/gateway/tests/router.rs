@@ -0,0 +1,403 @@
+mod common;
+
+use reqwest::{Client, StatusCode};
+use std::sync::Arc;
+use threefs_gateway::config::GatewayConfig;
+use threefs_gateway::s3::models::*;
+use threefs_gateway::s3::xml;
+use threefs_gateway::storage::memory::MemoryBackend;
+use threefs_gateway::storage::posix::PosixBackend;
+
+async fn gateway() -> (Client, String) {
+    with_bucket(common::serve(GatewayConfig::in_memory(), Arc::new(MemoryBackend::new())).await).await
+}
+
+/// A gateway on the POSIX backend, for listings that walk real directories.
+async fn posix_gateway(dir: &std::path::Path) -> (Client, String) {
+    let cfg = GatewayConfig {
+        mountpoint: dir.to_string_lossy().into_owned(),
+        data_root: dir.join("buckets").to_string_lossy().into_owned(),
+        ..GatewayConfig::in_memory()
+    };
+    with_bucket(common::serve(cfg.clone(), Arc::new(PosixBackend::new(cfg))).await).await
+}
+
+async fn with_bucket(base: String) -> (Client, String) {
+    let client = Client::new();
+    assert!(client.put(format!("{base}/bkt")).send().await.unwrap().status().is_success());
+    (client, base)
+}
+
+async fn put(client: &Client, url: String, body: impl Into<reqwest::Body>) -> reqwest::Response {
+    let resp = client.put(url).body(body).send().await.unwrap();
+    assert!(resp.status().is_success(), "PUT returned {}", resp.status());
+    resp
+}
+
+async fn get_xml<T: serde::de::DeserializeOwned>(client: &Client, url: String) -> T {
+    let resp = client.get(url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    xml::from_xml(&resp.bytes().await.unwrap()).unwrap()
+}
+
+fn keys(objects: &[Object]) -> Vec<&str> {
+    objects.iter().map(|o| o.Key.as_str()).collect()
+}
+
+fn prefixes(prefixes: &[CommonPrefix]) -> Vec<&str> {
+    prefixes.iter().map(|p| p.Prefix.as_str()).collect()
+}
+
+#[tokio::test]
+async fn put_get_head_delete() {
+    let (client, base) = gateway().await;
+    let url = format!("{base}/bkt/dir/hello.txt");
+    let etag = put(&client, url.clone(), "hello, world").await.headers()["etag"].clone();
+    assert_eq!(etag.to_str().unwrap(), format!("\"{:x}\"", md5::compute("hello, world")));
+
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["etag"], etag);
+    assert_eq!(resp.text().await.unwrap(), "hello, world");
+
+    let resp = client.get(&url).header("range", "bytes=7-11").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.headers()["content-range"], "bytes 7-11/12");
+    assert_eq!(resp.text().await.unwrap(), "world");
+    let resp = client.get(&url).header("range", "bytes=-5").send().await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "world");
+    let resp = client.get(&url).header("range", "bytes=100-").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+    let resp = client.head(&url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-length"], "12");
+    assert_eq!(resp.headers()["etag"], etag);
+
+    assert_eq!(client.delete(&url).send().await.unwrap().status(), StatusCode::NO_CONTENT);
+    assert_eq!(client.get(&url).send().await.unwrap().status(), StatusCode::NOT_FOUND);
+    assert_eq!(client.head(&url).send().await.unwrap().status(), StatusCode::NOT_FOUND);
+    // Deleting a missing key succeeds, as on S3
+    assert_eq!(client.delete(&url).send().await.unwrap().status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn list_v1_with_delimiter_and_markers() {
+    let (client, base) = gateway().await;
+    for key in ["a.txt", "b/1", "b/2", "c/1", "d.txt"] { put(&client, format!("{base}/bkt/{key}"), key).await; }
+
+    let all: ListObjectsV1Result = get_xml(&client, format!("{base}/bkt?delimiter=/")).await;
+    assert_eq!(keys(&all.Contents), ["a.txt", "d.txt"]);
+    assert_eq!(prefixes(&all.CommonPrefixes), ["b/", "c/"]);
+    assert!(!all.IsTruncated);
+
+    let mut seen = Vec::new();
+    let mut marker = String::new();
+    loop {
+        let page: ListObjectsV1Result = get_xml(&client, format!("{base}/bkt?delimiter=/&max-keys=2&marker={marker}")).await;
+        seen.extend(keys(&page.Contents).into_iter().map(String::from));
+        seen.extend(prefixes(&page.CommonPrefixes).into_iter().map(String::from));
+        if !page.IsTruncated { break; }
+        marker = page.NextMarker.expect("a truncated V1 listing with a delimiter has a NextMarker");
+    }
+    seen.sort();
+    assert_eq!(seen, ["a.txt", "b/", "c/", "d.txt"]);
+
+    let nested: ListObjectsV1Result = get_xml(&client, format!("{base}/bkt?prefix=b/&delimiter=/")).await;
+    assert_eq!(keys(&nested.Contents), ["b/1", "b/2"]);
+    assert!(nested.CommonPrefixes.is_empty());
+}
+
+#[tokio::test]
+async fn list_v2_with_continuation() {
+    let (client, base) = gateway().await;
+    for key in ["a.txt", "b/1", "b/2", "c/1", "d.txt"] { put(&client, format!("{base}/bkt/{key}"), key).await; }
+
+    let mut seen = Vec::new();
+    let mut token: Option<String> = None;
+    loop {
+        let mut url = format!("{base}/bkt?list-type=2&max-keys=2");
+        if let Some(t) = &token { url.push_str(&format!("&continuation-token={}", urlencoding(t))); }
+        let page: ListObjectsV2Result = get_xml(&client, url).await;
+        assert_eq!(page.KeyCount as usize, page.Contents.len());
+        seen.extend(keys(&page.Contents).into_iter().map(String::from));
+        if !page.IsTruncated { break; }
+        token = Some(page.NextContinuationToken.expect("a truncated V2 listing has a token"));
+    }
+    assert_eq!(seen, ["a.txt", "b/1", "b/2", "c/1", "d.txt"]);
+
+    let page: ListObjectsV2Result = get_xml(&client, format!("{base}/bkt?list-type=2&delimiter=/&start-after=a.txt")).await;
+    assert_eq!(keys(&page.Contents), ["d.txt"]);
+    assert_eq!(prefixes(&page.CommonPrefixes), ["b/", "c/"]);
+    assert_eq!(page.KeyCount, 3);
+}
+
+#[tokio::test]
+async fn empty_pages_are_truncated() {
+    let dir = tempfile::tempdir().unwrap();
+    for (client, base) in [gateway().await, posix_gateway(dir.path()).await] {
+        let v1: ListObjectsV1Result = get_xml(&client, format!("{base}/bkt?max-keys=0")).await;
+        assert!(!v1.IsTruncated, "an empty bucket has nothing past the page");
+        for key in ["a.txt", "b/1", "c.txt"] { put(&client, format!("{base}/bkt/{key}"), key).await; }
+
+        let v1: ListObjectsV1Result = get_xml(&client, format!("{base}/bkt?max-keys=0&delimiter=/&marker=a.txt")).await;
+        assert!(v1.IsTruncated && v1.Contents.is_empty() && v1.CommonPrefixes.is_empty());
+        assert_eq!(v1.NextMarker.as_deref(), Some("a.txt"));
+
+        let v2: ListObjectsV2Result = get_xml(&client, format!("{base}/bkt?list-type=2&max-keys=0&start-after=a.txt")).await;
+        assert!(v2.IsTruncated);
+        assert_eq!(v2.KeyCount, 0);
+        // The token resumes exactly where the empty page stood
+        let token = v2.NextContinuationToken.expect("a truncated V2 listing has a token");
+        let rest: ListObjectsV2Result = get_xml(&client, format!("{base}/bkt?list-type=2&continuation-token={}", urlencoding(&token))).await;
+        assert_eq!(keys(&rest.Contents), ["b/1", "c.txt"]);
+        assert!(!rest.IsTruncated);
+        assert_eq!(rest.NextContinuationToken, None);
+    }
+}
+
+#[tokio::test]
+async fn url_encoded_listings() {
+    let (client, base) = gateway().await;
+    for key in ["a b/1", "a b/2", "x+y.txt", "\u{e9}t\u{e9}.txt"] { put(&client, format!("{base}/bkt/{}", urlencoding(key).replace("%2F", "/").replace('+', "%20")), "x").await; }
+
+    let v1: ListObjectsV1Result = get_xml(&client, format!("{base}/bkt?encoding-type=url&delimiter=/&max-keys=2")).await;
+    assert_eq!(v1.EncodingType.as_deref(), Some("url"));
+    assert_eq!(prefixes(&v1.CommonPrefixes), ["a%20b/"]);
+    assert_eq!(keys(&v1.Contents), ["x%2By.txt"]);
+    assert!(v1.IsTruncated);
+    assert_eq!(v1.NextMarker.as_deref(), Some("x%2By.txt"));
+
+    let v1: ListObjectsV1Result = get_xml(&client, format!("{base}/bkt?encoding-type=url&delimiter=/&marker={}", urlencoding("x+y.txt"))).await;
+    assert_eq!(keys(&v1.Contents), ["%C3%A9t%C3%A9.txt"]);
+    assert_eq!(v1.Marker, "x%2By.txt");
+    assert!(!v1.IsTruncated);
+
+    let v2: ListObjectsV2Result = get_xml(&client, format!("{base}/bkt?list-type=2&encoding-type=url&prefix={}", urlencoding("a b/"))).await;
+    assert_eq!(v2.Prefix, "a%20b/");
+    assert_eq!(keys(&v2.Contents), ["a%20b/1", "a%20b/2"]);
+
+    // Without encoding-type the same keys come back raw
+    let v2: ListObjectsV2Result = get_xml(&client, format!("{base}/bkt?list-type=2&delimiter=/")).await;
+    assert_eq!(prefixes(&v2.CommonPrefixes), ["a b/"]);
+    assert_eq!(keys(&v2.Contents), ["x+y.txt", "\u{e9}t\u{e9}.txt"]);
+}
+
+fn urlencoding(s: &str) -> String {
+    form_urlencoded::byte_serialize(s.as_bytes()).collect()
+}
+
+#[tokio::test]
+async fn multipart_upload() {
+    let (client, base) = gateway().await;
+    let url = format!("{base}/bkt/big.bin");
+    let resp = client.post(format!("{url}?uploads")).header("content-type", "application/x-test").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let init: InitiateMultipartUploadResult = xml::from_xml(&resp.bytes().await.unwrap()).unwrap();
+    assert_eq!(init.Key, "big.bin");
+    let upload_id = init.UploadId;
+
+    let first = vec![b'a'; 5 << 20];
+    let etag1 = put(&client, format!("{url}?partNumber=1&uploadId={upload_id}"), first.clone()).await.headers()["etag"].to_str().unwrap().to_string();
+    let etag2 = put(&client, format!("{url}?partNumber=2&uploadId={upload_id}"), "tail").await.headers()["etag"].to_str().unwrap().to_string();
+
+    let parts: ListPartsResult = get_xml(&client, format!("{url}?uploadId={upload_id}")).await;
+    assert_eq!(parts.Part.iter().map(|p| (p.PartNumber, p.Size)).collect::<Vec<_>>(), [(1, 5 << 20), (2, 4)]);
+
+    let complete = format!(
+        "<CompleteMultipartUpload><Part><PartNumber>1</PartNumber><ETag>{etag1}</ETag></Part><Part><PartNumber>2</PartNumber><ETag>{etag2}</ETag></Part></CompleteMultipartUpload>"
+    );
+    let resp = client.post(format!("{url}?uploadId={upload_id}")).body(complete).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let done: CompleteMultipartUploadResult = xml::from_xml(&resp.bytes().await.unwrap()).unwrap();
+    assert!(done.ETag.ends_with("-2\""), "composed ETag {}", done.ETag);
+
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.headers()["content-type"], "application/x-test");
+    assert_eq!(resp.headers()["etag"].to_str().unwrap(), done.ETag);
+    let body = resp.bytes().await.unwrap();
+    assert_eq!(body.len(), (5 << 20) + 4);
+    assert!(body.starts_with(&first) && body.ends_with(b"tail"));
+
+    // The upload is gone once completed
+    assert_eq!(client.get(format!("{url}?uploadId={upload_id}")).send().await.unwrap().status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn aborted_multipart_upload_leaves_no_object() {
+    let (client, base) = gateway().await;
+    let url = format!("{base}/bkt/aborted");
+    let resp = client.post(format!("{url}?uploads")).send().await.unwrap();
+    let init: InitiateMultipartUploadResult = xml::from_xml(&resp.bytes().await.unwrap()).unwrap();
+    put(&client, format!("{url}?partNumber=1&uploadId={}", init.UploadId), "part").await;
+
+    assert_eq!(client.delete(format!("{url}?uploadId={}", init.UploadId)).send().await.unwrap().status(), StatusCode::NO_CONTENT);
+    assert_eq!(client.get(format!("{url}?uploadId={}", init.UploadId)).send().await.unwrap().status(), StatusCode::NOT_FOUND);
+    assert_eq!(client.get(&url).send().await.unwrap().status(), StatusCode::NOT_FOUND);
+}
+
+/// `x-amz-server-side-encryption-customer-*` headers for `key`.
+fn customer_key(key: [u8; 32]) -> reqwest::header::HeaderMap {
+    use base64::Engine;
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert("x-amz-server-side-encryption-customer-algorithm", "AES256".parse().unwrap());
+    headers.insert("x-amz-server-side-encryption-customer-key", b64.encode(key).parse().unwrap());
+    headers.insert("x-amz-server-side-encryption-customer-key-MD5", b64.encode(md5::compute(key).0).parse().unwrap());
+    headers
+}
+
+#[tokio::test]
+async fn sse_c_multipart_upload() {
+    let cfg = GatewayConfig { sse_c_allow_http: true, ..GatewayConfig::in_memory() };
+    let (client, base) = with_bucket(common::serve(cfg, Arc::new(MemoryBackend::new())).await).await;
+    sse_c_multipart(&client, &base).await;
+}
+
+#[tokio::test]
+async fn sse_c_multipart_upload_on_posix() {
+    let dir = tempfile::tempdir().unwrap();
+    let cfg = GatewayConfig {
+        mountpoint: dir.path().to_string_lossy().into_owned(),
+        data_root: dir.path().join("buckets").to_string_lossy().into_owned(),
+        sse_c_allow_http: true,
+        ..GatewayConfig::in_memory()
+    };
+    let (client, base) = with_bucket(common::serve(cfg.clone(), Arc::new(PosixBackend::new(cfg))).await).await;
+    sse_c_multipart(&client, &base).await;
+}
+
+/// Parts sealed under a customer key as they arrive read back as one object, ranges included.
+async fn sse_c_multipart(client: &Client, base: &str) {
+    let url = format!("{base}/bkt/sealed.bin");
+    let key = customer_key([7; 32]);
+    let resp = client.post(format!("{url}?uploads")).headers(key.clone()).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["x-amz-server-side-encryption-customer-algorithm"], "AES256");
+    let init: InitiateMultipartUploadResult = xml::from_xml(&resp.bytes().await.unwrap()).unwrap();
+    let part_url = |n: u32| format!("{url}?partNumber={n}&uploadId={}", init.UploadId);
+
+    // Parts need the upload's key
+    let first: Vec<u8> = (0..(5 << 20) + 1000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(client.put(part_url(1)).body(first.clone()).send().await.unwrap().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(client.put(part_url(1)).headers(customer_key([8; 32])).body(first.clone()).send().await.unwrap().status(), StatusCode::FORBIDDEN);
+    let upload = |n: u32, body: Vec<u8>| {
+        let req = client.put(part_url(n)).headers(key.clone()).body(body);
+        async move { req.send().await.unwrap().headers()["etag"].to_str().unwrap().to_string() }
+    };
+    let etag1 = upload(1, first.clone()).await;
+    // A part uploaded again replaces the earlier one
+    upload(2, b"an earlier tail".to_vec()).await;
+    let etag2 = upload(2, b"tail".to_vec()).await;
+
+    let parts: ListPartsResult = get_xml(client, format!("{url}?uploadId={}", init.UploadId)).await;
+    assert_eq!(parts.Part.iter().map(|p| (p.PartNumber, p.Size)).collect::<Vec<_>>(), [(1, first.len() as u64), (2, 4)]);
+
+    let complete = format!(
+        "<CompleteMultipartUpload><Part><PartNumber>1</PartNumber><ETag>{etag1}</ETag></Part><Part><PartNumber>2</PartNumber><ETag>{etag2}</ETag></Part></CompleteMultipartUpload>"
+    );
+    let resp = client.post(format!("{url}?uploadId={}", init.UploadId)).body(complete).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let mut content = first.clone();
+    content.extend_from_slice(b"tail");
+    assert_eq!(client.get(&url).send().await.unwrap().status(), StatusCode::BAD_REQUEST);
+    let resp = client.head(&url).headers(key.clone()).send().await.unwrap();
+    assert_eq!(resp.headers()["content-length"], content.len().to_string().as_str());
+    let resp = client.get(&url).headers(key.clone()).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.bytes().await.unwrap() == content);
+    // A range across the part boundary
+    let (start, end) = (first.len() - 3, first.len() + 1);
+    let resp = client.get(&url).headers(key.clone()).header("range", format!("bytes={start}-{end}")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.bytes().await.unwrap(), content[start..=end]);
+}
+
+/// A gateway over `storage` with a keyring in `dir` holding the default master key.
+async fn keyring_gateway(dir: &std::path::Path, storage: Arc<MemoryBackend>) -> (Client, String) {
+    let cfg = GatewayConfig { sse_keyring: Some(dir.join("keyring.json").to_string_lossy().into_owned()), ..GatewayConfig::in_memory() };
+    threefs_gateway::keyring::rotate(&cfg, "s3").unwrap();
+    with_bucket(common::serve(cfg, storage).await).await
+}
+
+#[tokio::test]
+async fn managed_encryption_keeps_the_plaintext_md5_as_etag() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(MemoryBackend::new());
+    let (client, base) = keyring_gateway(dir.path(), storage.clone()).await;
+    let body = vec![b'x'; 100_000];
+    let md5 = format!("\"{:x}\"", md5::compute(&body));
+    let url = format!("{base}/bkt/sealed");
+    let resp = client.put(&url).header("x-amz-server-side-encryption", "AES256").body(body.clone()).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["etag"], md5.as_str());
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.headers()["etag"], md5.as_str());
+    assert_eq!(resp.bytes().await.unwrap(), body);
+
+    // The stored bytes are sealed and hash to something else
+    use threefs_gateway::storage::StorageBackend;
+    let stored = common::collect(storage.get_object("bkt", "sealed", None).await.unwrap().body).await;
+    assert_ne!(format!("\"{:x}\"", md5::compute(&stored)), md5);
+}
+
+#[tokio::test]
+async fn default_encryption_covers_multipart_uploads() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(MemoryBackend::new());
+    let (client, base) = keyring_gateway(dir.path(), storage.clone()).await;
+    let default = "<ServerSideEncryptionConfiguration><Rule><ApplyServerSideEncryptionByDefault><SSEAlgorithm>AES256</SSEAlgorithm></ApplyServerSideEncryptionByDefault></Rule></ServerSideEncryptionConfiguration>";
+    put(&client, format!("{base}/bkt?encryption"), default).await;
+
+    let url = format!("{base}/bkt/big.bin");
+    let resp = client.post(format!("{url}?uploads")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["x-amz-server-side-encryption"], "AES256");
+    let init: InitiateMultipartUploadResult = xml::from_xml(&resp.bytes().await.unwrap()).unwrap();
+    let first: Vec<u8> = (0..5 << 20).map(|i| (i % 253) as u8).collect();
+    let etag1 = put(&client, format!("{url}?partNumber=1&uploadId={}", init.UploadId), first.clone()).await.headers()["etag"].to_str().unwrap().to_string();
+    let etag2 = put(&client, format!("{url}?partNumber=2&uploadId={}", init.UploadId), "tail").await.headers()["etag"].to_str().unwrap().to_string();
+    let complete = format!(
+        "<CompleteMultipartUpload><Part><PartNumber>1</PartNumber><ETag>{etag1}</ETag></Part><Part><PartNumber>2</PartNumber><ETag>{etag2}</ETag></Part></CompleteMultipartUpload>"
+    );
+    let resp = client.post(format!("{url}?uploadId={}", init.UploadId)).body(complete).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["x-amz-server-side-encryption"], "AES256");
+
+    // Stored sealed, read back in the clear without any headers
+    use threefs_gateway::storage::StorageBackend;
+    let stored = common::collect(storage.get_object("bkt", "big.bin", None).await.unwrap().body).await;
+    assert!(stored.len() > first.len() + 4 && !stored.starts_with(&first[..1024]));
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.headers()["x-amz-server-side-encryption"], "AES256");
+    assert_eq!(resp.headers()["content-length"], ((5 << 20) + 4).to_string().as_str());
+    let body = resp.bytes().await.unwrap();
+    assert!(body.starts_with(&first) && body.ends_with(b"tail"));
+}
+
+#[tokio::test]
+async fn bodies_of_unknown_length_are_stored_uncompressed() {
+    use threefs_gateway::compression::{self, BucketCompression};
+    use threefs_gateway::storage::StorageBackend;
+    let storage = Arc::new(MemoryBackend::new());
+    let (client, base) = with_bucket(common::serve(GatewayConfig::in_memory(), storage.clone()).await).await;
+    let config = BucketCompression { content_types: vec!["text/*".into()], prefixes: Vec::new(), level: 3 };
+    compression::set(storage.as_ref(), "bkt", Some(&config)).await.unwrap();
+
+    let body = "a line of text\n".repeat(10_000);
+    let sized = client.put(format!("{base}/bkt/sized.txt")).header("content-type", "text/plain").body(body.clone()).send().await.unwrap();
+    assert_eq!(sized.status(), StatusCode::OK);
+    // Compression needs the size up front; a chunked body is stored as it comes
+    let chunks = futures::stream::iter(body.as_bytes().chunks(4096).map(|c| Ok::<_, std::io::Error>(c.to_vec())).collect::<Vec<_>>());
+    let streamed = client.put(format!("{base}/bkt/streamed.txt")).header("content-type", "text/plain").body(reqwest::Body::wrap_stream(chunks)).send().await.unwrap();
+    assert_eq!(streamed.status(), StatusCode::OK);
+    assert_eq!(sized.headers()["etag"], streamed.headers()["etag"]);
+
+    let (sized, streamed) = (storage.head_object("bkt", "sized.txt").await.unwrap(), storage.head_object("bkt", "streamed.txt").await.unwrap());
+    assert!(sized.attrs.compression.is_some() && sized.size < body.len() as u64);
+    assert!(streamed.attrs.compression.is_none() && streamed.size == body.len() as u64);
+    for key in ["sized.txt", "streamed.txt"] {
+        assert_eq!(client.get(format!("{base}/bkt/{key}")).send().await.unwrap().text().await.unwrap(), body);
+    }
+}